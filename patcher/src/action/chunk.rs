@@ -1,62 +1,273 @@
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use anyhow::{anyhow, Result};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use tokio::fs;
 use sophon::proto::chunk::SophonChunkProto;
-use sophon::sophon::chunk_diff;
-use crate::serialize::PkgVersion;
-use crate::util;
+use sophon::sophon::{chunk_diff_to, mismatched_chunks, ChunkLayout, DirectoryTarget, ImageTarget};
+use crate::action::{clean_up, Detail, PatchOptions, PatchOutcome};
+use crate::bars::{self, Bars};
+use crate::disk::{self, Stage};
+use crate::extractor::ArchiveExtractor;
+use crate::inputs::Inputs;
+use crate::quirks::Quirks;
+use crate::{consolidate, log, metrics, mods, overall, receipt, state, util, verify};
 
-pub async fn chunk(game_path: &Path, chunk_folder: String, manifest_name: String) -> Result<()> {
-    println!();
+// Chunk archives given instead of a chunk folder are extracted here
+const ARCHIVE_FOLDER: &str = "chunk_archive";
+
+pub async fn chunk(
+    game_path: &Path,
+    chunk_folder: String,
+    manifest_name: String,
+    options: &PatchOptions,
+) -> Result<PatchOutcome> {
+    log::blank();
 
     let chunk_path = game_path.join(chunk_folder);
     if !chunk_path.exists() {
         return Err(anyhow!("{:?} does not exist", chunk_path));
     }
+    let manifest_path = game_path.join(&manifest_name);
+    let mut quirks = Quirks::detect(game_path)?;
+    quirks.check_root(game_path, &chunk_path)?;
+    let game_path = &quirks.root(game_path);
+    quirks.skip.extend(state::skipped(game_path)?);
+    let image = options.output_image.as_deref();
+    if image.is_none() && receipt::is_applied(game_path, std::slice::from_ref(&chunk_path), std::slice::from_ref(&manifest_path)) {
+        log::ok!("{} is already applied according to the receipt", manifest_name);
+        return Ok(PatchOutcome::default());
+    }
+    log::info!("Using {} quirks", quirks.name);
+    let _mods = if quirks.checks_mods() { mods::set_aside(game_path)? } else { None };
+    let outcome = Mutex::new(PatchOutcome::default());
+
+    // A chunk archive and the manifest are hashed so the receipt and the event log name the exact inputs
+    let mut inputs = Inputs::of_packages(std::slice::from_ref(&chunk_path))?;
+    inputs.add_metadata(std::slice::from_ref(&manifest_path))?;
 
-    // Read manifest
-    let manifest = SophonChunkProto::from(
-        game_path.join(&manifest_name).to_string_lossy().to_string()
-    )?;
+    // Read manifest, leaving protected and skipped files alone
+    let mut manifest = util::with_spinner(&format!("Reading {}", manifest_name), || {
+        SophonChunkProto::from(manifest_path.to_string_lossy().to_string())
+    })?;
+
+    // Wrong or truncated files can decode as an empty manifest, which is refused before anything is touched
+    if manifest.assets.is_empty() {
+        return Err(anyhow!(
+            "manifest {} holds no assets, it is not a chunk manifest or its download was cut short",
+            manifest_name,
+        ));
+    }
+    for asset in &mut manifest.assets {
+        asset.asset_name = quirks.remap(&asset.asset_name);
+    }
+    manifest.assets.retain(|asset| {
+        if quirks.is_protected(&asset.asset_name) {
+            let mut outcome = outcome.lock().unwrap();
+            outcome.skipped += 1;
+            outcome.detail(&asset.asset_name, Detail::Protected);
+            return false;
+        }
+        if quirks.is_skipped(&asset.asset_name) {
+            outcome.lock().unwrap().skipped += 1;
+            return false;
+        }
+        true
+    });
+
+    // A single archive holding the chunk folder is extracted first, the layout inside is detected on assembly, while
+    // a chunk archive with its `_db` index next to it is assembled from as it is
+    let archive_folder = game_path.join(ARCHIVE_FOLDER);
+    let chunk_archive = matches!(ChunkLayout::detect(&chunk_path, &HashMap::new()), Ok(ChunkLayout::Archive));
+    let chunk_folder = if chunk_path.is_file() && !chunk_archive {
+        disk::reserve(game_path, &[(Stage::Extract, ArchiveExtractor::uncompressed_size(&chunk_path)?)])?;
+        extract_archive(&chunk_path, &archive_folder)?
+    } else {
+        chunk_path.clone()
+    };
+
+    // Chunks left over from another update would assemble garbage, so nothing is written with them
+    if let Some(mismatch) = mismatched_chunks(&manifest, &chunk_folder).first() {
+        remove_archive_folder(&archive_folder, options.keep_workdir).await;
+        return Err(anyhow!(
+            "manifest {} does not describe package {}, it lists {} with {} bytes but the package has {}",
+            manifest_name,
+            chunk_path.display(),
+            mismatch.name,
+            mismatch.listed,
+            mismatch.found,
+        ));
+    }
 
-    // Potentially memory leak game path
-    let game_path_owned = game_path.to_path_buf();
-    let game_path_static: &'static Path = Box::leak(game_path_owned.into_boxed_path());
+    // Decompressed chunks are staged before assembly, assembled files replace the old ones
+    let mut chunk_sizes = HashMap::new();
+    for chunk in manifest.assets.iter().flat_map(|asset| &asset.asset_chunks) {
+        chunk_sizes.insert(&chunk.chunk_name, chunk.chunk_size_decompressed as u64);
+    }
+    let staged = chunk_sizes.values().sum();
+    let work_path = options.staging_dir.as_deref().unwrap_or(game_path);
+    if let Some(image) = image {
+        let written = manifest.assets.iter().map(|asset| asset.asset_size as u64).sum();
+        disk::reserve(work_path, &[(Stage::Staging, staged)])?;
+        disk::reserve(image, &[(Stage::Write, written)])?;
+        let result = pack_image(work_path, &chunk_folder, &manifest, image, options.keep_workdir, outcome).await;
+        remove_archive_folder(&archive_folder, options.keep_workdir).await;
+        return result;
+    }
+    let written = manifest.assets.iter()
+        .map(|asset| {
+            let existing = game_path.join(&asset.asset_name).metadata().map_or(0, |metadata| metadata.len());
+            (asset.asset_size as u64).saturating_sub(existing)
+        })
+        .sum();
+    match &options.staging_dir {
+        Some(staging_dir) => {
+            disk::reserve(staging_dir, &[(Stage::Staging, staged)])?;
+            disk::reserve(game_path, &[(Stage::Write, written)])?;
+        }
+        None => disk::reserve(game_path, &[(Stage::Staging, staged), (Stage::Write, written)])?,
+    }
 
     // Extract chunks
-    chunk_diff(&manifest, game_path_static, &chunk_path, Some(None)).await?;
+    let stage = metrics::stage("assemble");
+    let target = Arc::new(DirectoryTarget::new(game_path).with_existing(options.existing));
+    let result = chunk_diff_to(
+        &manifest,
+        work_path,
+        &chunk_folder,
+        bars::sink(),
+        options.keep_workdir,
+        target.clone(),
+    ).await;
+    remove_archive_folder(&archive_folder, options.keep_workdir).await;
+    if options.keep_workdir {
+        log::info!("Keeping decompressed chunks in {}", work_path.join("chunk_tmp").display());
+    } else {
+        disk::release(Stage::Staging);
+    }
+    result?;
+    {
+        // Files in place already are neither written nor reported
+        let mut outcome = outcome.lock().unwrap();
+        let unchanged = target.unchanged().into_iter().collect::<HashSet<_>>();
+        let kept = target.kept().into_iter().collect::<HashSet<_>>();
+        outcome.skipped += (unchanged.len() + kept.len()) as u64;
+        for name in target.kept() {
+            outcome.detail(&name, Detail::Kept);
+        }
+        for name in target.backed_up() {
+            outcome.detail(&name, Detail::BackedUp);
+        }
+        for asset in manifest.assets.iter().filter(|asset| {
+            !unchanged.contains(&asset.asset_name) && !kept.contains(&asset.asset_name)
+        }) {
+            let size = game_path.join(&asset.asset_name).metadata().map_or(0, |metadata| metadata.len());
+            outcome.check_size(&asset.asset_name, Some(asset.asset_size as u64), size);
+            outcome.written.push(asset.asset_name.clone());
+        }
+    }
+    drop(stage);
+    if options.consolidate {
+        let _stage = metrics::stage("consolidate");
+        consolidate::consolidate(game_path, &outcome.lock().unwrap().written);
+    }
 
     // Verify file integrity
-    let verify = util::input("Chunk patching done, verify file integrity? (Y/n) [n]: ");
-    if verify.to_lowercase() == "y" || verify.to_lowercase() == "yes" {
-        let pkg_version = PkgVersion::from(&game_path.join("pkg_version"))?;
-        let pb = util::create_progress_bar(pkg_version.len() as u64);
-        pkg_version.into_par_iter().for_each(|file| {
-            pb.inc(1u64);
-
-            let file_path = game_path.join(&file.remote_file);
-            if let Ok(md5) = util::calculate_md5_hash(&file_path) {
-                if md5.to_lowercase() != file.md5 {
-                    println!(
-                        "{} md5 hash does not match! Expected: {}, found: {}",
-                        &file.remote_file,
-                        &file.md5,
-                        &md5,
-                    );
-                }
-            } else {
-                println!("{} does not exist!", &file.remote_file);
-            }
-        });
-    }
-
-    // Delete ldiff folder
-    let delete = util::input("Delete chunk folder and manifest? (Y/n) [Y]: ");
-    if delete.to_lowercase() != "n" && delete.to_lowercase() != "no" {
-        let _ = fs::remove_file(game_path.join(manifest_name)).await;
-        let _ = fs::remove_dir_all(chunk_path).await;
-    }
-
-    Ok(())
+    let question = "Chunk patching done, verify file integrity? (Y/n) [n]: ";
+    if quirks.offers_verify() && util::confirm("verify", question, false) {
+        verify::verify(game_path, &quirks, std::slice::from_ref(&manifest_path), options.verify_mode, &outcome)?;
+    }
+
+    let outcome = outcome.into_inner().unwrap();
+    if outcome.is_clean() {
+        receipt::write(
+            game_path,
+            std::slice::from_ref(&chunk_path),
+            std::slice::from_ref(&manifest_path),
+            &inputs,
+            &outcome.written,
+        )?;
+    }
+    if options.skip_failed {
+        state::skip_failed(game_path, &outcome)?;
+    }
+
+    let mut packages = vec![chunk_path.clone()];
+    if chunk_archive {
+        let mut index = chunk_path.clone().into_os_string();
+        index.push("_db");
+        packages.push(index.into());
+    }
+    clean_up(options, &packages, std::slice::from_ref(&manifest_path), "chunk folder").await;
+
+    Ok(outcome)
+}
+
+/// Extract a chunk archive, descending into the folder it wraps everything in if any
+fn extract_archive(archive: &Path, folder: &Path) -> Result<PathBuf> {
+    log::info!(
+        "Extracting {}",
+        util::ellipsize(
+            &archive.file_name().unwrap_or_default().to_string_lossy(),
+            util::terminal_width().saturating_sub(11),
+        ),
+    );
+    let _stage = metrics::stage("extract");
+    let mut bars = Bars::new();
+    let mut progress_bar = None;
+    ArchiveExtractor::extract_with_progress(archive, folder, |cur, max| {
+        progress_bar
+            .get_or_insert_with(|| bars.add(max as u64))
+            .set_position(cur as u64);
+        overall::set_fraction(Stage::Extract, cur, max);
+    })?;
+    if let Some(pb) = &progress_bar {
+        bars.finish(pb, "Extracted", "files");
+    }
+
+    let entries = folder.read_dir()?.collect::<Result<Vec<_>, _>>()?;
+    match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() => Ok(entry.path()),
+        _ => Ok(folder.to_path_buf()),
+    }
+}
+
+async fn remove_archive_folder(folder: &Path, keep_workdir: bool) {
+    if folder.exists() && !keep_workdir {
+        let _ = fs::remove_dir_all(folder).await;
+        disk::release(Stage::Extract);
+    }
+}
+
+/// Assemble the assets into an image instead of the game folder, chunks are staged in `work_path`
+async fn pack_image(
+    work_path: &Path,
+    chunk_path: &Path,
+    manifest: &SophonChunkProto,
+    image: &Path,
+    keep_workdir: bool,
+    outcome: Mutex<PatchOutcome>,
+) -> Result<PatchOutcome> {
+    let mut index = image.as_os_str().to_owned();
+    index.push(".index.json");
+    let index = PathBuf::from(index);
+    let target = Arc::new(ImageTarget::create(image, &index)?);
+
+    let stage = metrics::stage("assemble");
+    let result = chunk_diff_to(
+        manifest,
+        work_path,
+        chunk_path,
+        bars::sink(),
+        keep_workdir,
+        target,
+    ).await;
+    if !keep_workdir {
+        disk::release(Stage::Staging);
+    }
+    result?;
+    drop(stage);
+
+    log::ok!("Packed {} assets into {}, index at {}", manifest.assets.len(), image.display(), index.display());
+    Ok(outcome.into_inner().unwrap())
 }
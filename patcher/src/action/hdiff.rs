@@ -1,146 +1,343 @@
 use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use anyhow::{anyhow, Result};
 use indicatif::ProgressBar;
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use tokio::fs;
+use sophon::sophon::create_parent_dirs;
+use crate::action::{clean_up, OnError, PatchOptions};
+use crate::adaptive::Throttle;
+use crate::bars::Bars;
+use crate::action::patch::apply_patch;
+use crate::action::{Detail, PatchOutcome};
+use crate::disk::{self, Stage};
 use crate::extractor::ArchiveExtractor;
-use crate::hpatchz::HPatchZ;
-use crate::serialize::{DeleteFiles, HDiffData, HDiffFiles, HDiffMap, PkgVersion};
-use crate::util;
+use crate::inputs::Inputs;
+use crate::serialize::{DeleteFiles, HDiffData, HDiffMap, Metadata, ENTRY_FILES};
+use crate::quirks::Quirks;
+use crate::relocate::Relocator;
+use crate::{events, log, metrics, mods, overall, receipt, state, status, util, verify};
 
-pub async fn hdiff(game_path: &Path, hdiff_file: String) -> Result<()> {
-    println!();
+pub async fn hdiff(game_path: &Path, hdiff_file: String, options: &PatchOptions) -> Result<PatchOutcome> {
+    log::blank();
 
     let hdiff_path = game_path.join(&hdiff_file);
     if !hdiff_path.exists() {
         return Err(anyhow!("{:?} does not exist", hdiff_file));
     }
+    let mut quirks = Quirks::detect(game_path)?;
+    quirks.check_root(game_path, &hdiff_path)?;
+    let game_path = &quirks.root(game_path);
+    quirks.skip.extend(state::skipped(game_path)?);
+    if receipt::is_applied(game_path, std::slice::from_ref(&hdiff_path), &[]) {
+        log::ok!("{} is already applied according to the receipt", hdiff_file);
+        return Ok(PatchOutcome::default());
+    }
+    log::info!("Using {} quirks", quirks.name);
+    let _mods = if quirks.checks_mods() { mods::set_aside(game_path)? } else { None };
+    let outcome = Mutex::new(PatchOutcome::default());
 
-    // Make progress bar
-    println!("Extracting {}", hdiff_path.file_name().unwrap().to_string_lossy());
-    let mut bars: Vec<ProgressBar> = Vec::new();
-    let mut progress_bar: Option<ProgressBar> = None;
+    // Hashed up front so the receipt and the event log name the exact package, and so what an attempt that stopped
+    // early extracted from the same package is used again instead of extracted over
+    let mut inputs = Inputs::of_packages(std::slice::from_ref(&hdiff_path))?;
+    let reuse = state::reuse_extracted(game_path, std::slice::from_ref(&hdiff_path), game_path, &inputs)?;
 
-    // Extract hdiff file
-    ArchiveExtractor::extract_with_progress(&hdiff_path, game_path, |cur, max| {
-        let pb = progress_bar.get_or_insert_with(|| {
-            util::create_progress_bar(max as u64)
-        });
-        pb.set_position(cur as u64);
-    })?;
-    bars.push(progress_bar.unwrap());
+    // Fail before extracting anything when the package does not fit
+    let extract_size = ArchiveExtractor::uncompressed_size(&hdiff_path)?;
+    disk::reserve(game_path, &[(Stage::Extract, if reuse { 0 } else { extract_size })])?;
+
+    // Later stages work through about as much as the package holds until their sizes are known
+    overall::estimate(Stage::Write, extract_size);
+
+    let mut bars = Bars::new();
+    let extracted = if reuse {
+        overall::set_fraction(Stage::Extract, 1, 1);
+        ArchiveExtractor::entry_sizes(&hdiff_path)?.into_iter()
+            .map(|(name, _)| game_path.join(name))
+            .collect::<Vec<_>>()
+    } else {
+        // Make progress bar
+        log::info!(
+            "Extracting {}",
+            util::ellipsize(
+                &hdiff_path.file_name().unwrap().to_string_lossy(),
+                util::terminal_width().saturating_sub(11),
+            ),
+        );
+        let mut progress_bar: Option<ProgressBar> = None;
+
+        // Extract hdiff file
+        let stage = metrics::stage("extract");
+        let extracted = ArchiveExtractor::extract_with_progress(&hdiff_path, game_path, |cur, max| {
+            let pb = progress_bar.get_or_insert_with(|| bars.add(max as u64));
+            pb.set_position(cur as u64);
+            overall::set_fraction(Stage::Extract, cur, max);
+        })?;
+        drop(stage);
+        bars.finish(&progress_bar.unwrap(), "Extracted", "files");
+        extracted
+    };
+
+    // The hdiff map, delete list and manifests the package brings are inputs of the run as well
+    let metadata = extracted.into_iter()
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            path.parent() == Some(game_path)
+                && (ENTRY_FILES.contains(&&*name) || name == "deletefiles.txt" || quirks.is_manifest(&name))
+        })
+        .collect::<Vec<_>>();
+    inputs.add_metadata(&metadata)?;
+    if !reuse {
+        state::extracted(game_path, std::slice::from_ref(&hdiff_path), &inputs)?;
+    }
 
     // Load hdiff map
-    println!("Patching game files");
-    let hdiff_map = load_diff_map(&game_path).await?;
+    log::info!("Patching game files");
+    let manifests = metadata.into_iter()
+        .filter(|path| quirks.is_manifest(&path.file_name().unwrap_or_default().to_string_lossy()))
+        .collect::<Vec<_>>();
+    let mut hdiff_map = Metadata::load(game_path, &manifests)?;
+    for data in &mut hdiff_map.diff_map {
+        data.source_file_name = quirks.remap(&data.source_file_name);
+        data.target_file_name = quirks.remap(&data.target_file_name);
+    }
+
+    // Patched files are written next to their sources, which are only removed once patched
+    let written = hdiff_map.diff_map.iter()
+        .filter(|data| game_path.join(&data.patch_file_name).exists())
+        .map(|data| data.target_file_size.unwrap_or_else(|| {
+            game_path.join(&data.source_file_name).metadata().map_or(0, |metadata| metadata.len())
+        }))
+        .sum();
+    if let Err(err) = disk::reserve(game_path, &[(Stage::Write, written)]) {
+        if !options.keep_workdir {
+            remove_extracted(game_path, &hdiff_map).await;
+            let _ = state::forget_extracted(game_path, std::slice::from_ref(&hdiff_path));
+            disk::release(Stage::Extract);
+        }
+        return Err(err);
+    }
+
+    // New files may live in folders that do not exist yet, all of them are made before patching in parallel
+    create_parent_dirs(game_path, hdiff_map.diff_map.iter()
+        .map(|data| &data.target_file_name)
+        .filter(|target| !quirks.is_protected(target) && !quirks.is_skipped(target)))?;
 
     // Patch game files
-    let pb = util::create_progress_bar(hdiff_map.diff_map.len() as u64);
+    let stage = metrics::stage("patch");
+    let pb = bars.add(hdiff_map.diff_map.len() as u64);
+    status::attach(&pb);
+    let throttle = Throttle::for_pool();
+    let relocator = Relocator::new(game_path, &quirks);
+    let aborted = AtomicBool::new(false);
     hdiff_map.diff_map.into_par_iter().for_each(|data| {
         pb.inc(1u64);
+        if aborted.load(Ordering::Relaxed) {
+            outcome.lock().unwrap().skipped += 1;
+            return;
+        }
+
+        // Never overwrite user data
+        if quirks.is_protected(&data.target_file_name) {
+            let mut outcome = outcome.lock().unwrap();
+            outcome.skipped += 1;
+            outcome.detail(&data.target_file_name, Detail::Protected);
+            return;
+        }
+        if quirks.is_skipped(&data.target_file_name) {
+            outcome.lock().unwrap().skipped += 1;
+            return;
+        }
 
         // Check if patch file exist
         let patch_path = game_path.join(&data.patch_file_name);
         if !patch_path.exists() {
+            outcome.lock().unwrap().skipped += 1;
             return;
         }
 
         // Run hpatchz
         let source_path = game_path.join(&data.source_file_name);
-        if data.source_file_name.is_empty() || !source_path.exists() {
-            let target_path = game_path.join(&data.target_file_name);
-            if let Err(_) = HPatchZ::apply_patch_empty(&patch_path, &target_path) {
-                eprintln!("{} failed to patch!", &data.target_file_name);
-                std::fs::remove_file(&patch_path).unwrap();
-                return;
-            }
-
-            std::fs::remove_file(&patch_path).unwrap();
-        } else {
-            let source_path = game_path.join(&data.source_file_name);
-            if !source_path.exists() {
-                return;
-            }
+        let Some(source_path) = resolve_source(&data, &source_path, &relocator, &quirks, &outcome) else {
+            outcome.lock().unwrap().skipped += 1;
+            return;
+        };
 
-            let target_path = game_path.join(&data.target_file_name);
-            if let Err(_) = HPatchZ::apply_patch(&source_path, &patch_path, &target_path) {
-                eprintln!("{} failed to patch!", &data.target_file_name);
-                std::fs::remove_file(&patch_path).unwrap();
-                return;
+        let target_path = game_path.join(&data.target_file_name);
+        let bytes = patch_path.metadata().map_or(0, |metadata| metadata.len());
+        let started = Instant::now();
+        let working = status::working(&data.target_file_name);
+        let result = throttle.run(bytes, || {
+            apply_patch(source_path, &patch_path, &target_path, options.keep_workdir)
+        });
+        drop(working);
+        events::file(&data.target_file_name, started.elapsed(), result.is_ok());
+        let mut outcome = outcome.lock().unwrap();
+        match result {
+            Ok(applied) => {
+                if applied.readonly {
+                    outcome.detail(&data.target_file_name, Detail::ReadOnly);
+                }
+                outcome.patched += 1;
+                outcome.written.push(data.target_file_name.clone());
+                let size = target_path.metadata().map_or(0, |metadata| metadata.len());
+                outcome.check_size(&data.target_file_name, data.target_file_size, size);
+                overall::advance(Stage::Write, size);
+                metrics::add(&metrics::BYTES_WRITTEN, size);
+                metrics::inc(&metrics::FILES_PATCHED);
             }
-
-            if data.source_file_name != data.target_file_name {
-                std::fs::remove_file(&source_path).unwrap();
+            Err(_) => {
+                outcome.failed += 1;
+                outcome.detail(&data.target_file_name, Detail::Failed);
+                metrics::inc(&metrics::FILES_FAILED);
+                if options.on_error == OnError::Abort {
+                    aborted.store(true, Ordering::Relaxed);
+                }
             }
-            std::fs::remove_file(patch_path).unwrap();
         }
     });
-    bars.push(pb);
+    bars.finish(&pb, "Patched", "files");
+
+    // Deletions and clean-up are skipped and the package stays, but the files patched so far already replaced their
+    // sources and had their patch files removed, so the package only applies again to a restored game folder
+    if aborted.into_inner() {
+        log::error!(
+            "Patching stopped after a file failed, the files patched so far are updated and the package is kept, \
+            restore the game folder before applying it again",
+        );
+        return Ok(outcome.into_inner().unwrap());
+    }
 
     // Remove files in deletefiles.txt
     if let Ok(deletes) = DeleteFiles::from(&game_path.join("deletefiles.txt")) {
-        deletes.par_iter().for_each(|path| {
-            let _ = std::fs::remove_file(game_path.join(path));
+        deletes.par_iter().map(|path| quirks.remap(path)).for_each(|path| {
+            if quirks.is_protected(&path) {
+                outcome.lock().unwrap().detail(&path, Detail::Protected);
+                return;
+            }
+            let full_path = game_path.join(&path);
+            if util::clear_readonly(&full_path).unwrap_or(false) {
+                outcome.lock().unwrap().detail(&path, Detail::ReadOnly);
+            }
+            if std::fs::remove_file(full_path).is_ok() {
+                outcome.lock().unwrap().deleted += 1;
+                metrics::inc(&metrics::FILES_DELETED);
+            }
         })
     };
+    drop(stage);
 
     // Remove hdiff entries files
-    let _ = fs::remove_file(game_path.join("hdiffmap.json")).await;
-    let _ = fs::remove_file(game_path.join("hdifffiles.txt")).await;
-    let _ = fs::remove_file(game_path.join("deletefiles.txt")).await;
-
-    // Cleanup hpatchz temp file
-    HPatchZ::cleanup()?;
+    if options.keep_workdir {
+        log::info!("Keeping patch files and hdiff entries files in {}", game_path.display());
+    } else {
+        for name in ENTRY_FILES {
+            let _ = fs::remove_file(game_path.join(name)).await;
+        }
+        let _ = fs::remove_file(game_path.join("deletefiles.txt")).await;
+        state::forget_extracted(game_path, std::slice::from_ref(&hdiff_path))?;
+        disk::release(Stage::Extract);
+    }
 
     // Verify file integrity
-    let verify = util::input("Hdiff patching done, verify file integrity? (Y/n) [n]: ");
-    if verify.to_lowercase() == "y" || verify.to_lowercase() == "yes" {
-        let pkg_version = PkgVersion::from(&game_path.join("pkg_version"))?;
-        let pb = util::create_progress_bar(pkg_version.len() as u64);
-        pkg_version.into_par_iter().for_each(|file| {
-            pb.inc(1u64);
-
-            let file_path = game_path.join(&file.remote_file);
-            if let Ok(md5) = util::calculate_md5_hash(&file_path) {
-                if md5.to_lowercase() != file.md5 {
-                    println!(
-                        "{} md5 hash does not match! Expected: {}, found: {}",
-                        &file.remote_file,
-                        &file.md5,
-                        &md5,
-                    );
-                }
-            } else {
-                println!("{} does not exist!", &file.remote_file);
-            }
-        });
-        bars.push(pb);
+    let question = "Hdiff patching done, verify file integrity? (Y/n) [n]: ";
+    if quirks.offers_verify() && util::confirm("verify", question, false) {
+        verify::verify(game_path, &quirks, &[], options.verify_mode, &outcome)?;
     }
 
-    // Delete hdiff file
-    let delete = util::input("Delete hdiff file? (Y/n) [Y]: ");
-    if delete.to_lowercase() != "n" && delete.to_lowercase() != "no" {
-        let _ = fs::remove_file(hdiff_path).await;
+    let outcome = outcome.into_inner().unwrap();
+    if outcome.is_clean() {
+        receipt::write(game_path, std::slice::from_ref(&hdiff_path), &[], &inputs, &outcome.written)?;
+    }
+    if options.skip_failed {
+        state::skip_failed(game_path, &outcome)?;
     }
 
-    Ok(())
+    clean_up(options, std::slice::from_ref(&hdiff_path), &manifests, "hdiff file").await;
+
+    Ok(outcome)
 }
 
-async fn load_diff_map(path: &Path) -> Result<HDiffMap> {
-    if path.join("hdiffmap.json").exists() {
-        HDiffMap::from(&path.join("hdiffmap.json"))
-    } else if path.join("hdifffiles.txt").exists() {
-        let files = HDiffFiles::from(&path.join("hdifffiles.txt"));
-        Ok(HDiffMap {
-            diff_map: files?.iter().map(|file| {
-                HDiffData {
-                    source_file_name: file.remote_file.clone(),
-                    target_file_name: file.remote_file.clone(),
-                    patch_file_name: format!("{}.hdiff", file.remote_file.clone()),
-                }
-            }).collect::<Vec<_>>(),
-        })
+/// Remove everything extracted from the package when patching cannot go ahead
+async fn remove_extracted(game_path: &Path, hdiff_map: &HDiffMap) {
+    for data in &hdiff_map.diff_map {
+        let _ = fs::remove_file(game_path.join(&data.patch_file_name)).await;
+    }
+    for name in ENTRY_FILES {
+        let _ = fs::remove_file(game_path.join(name)).await;
+    }
+    let _ = fs::remove_file(game_path.join("deletefiles.txt")).await;
+}
+
+/// The source a diff is applied to, `Some(None)` being an empty file, or `None` when the source is missing and the
+/// entry is skipped
+///
+/// A missing source is copied back from a file with the same content elsewhere first, profiles shipping new files as
+/// diffs against an empty file patch the rest from empty
+fn resolve_source<'a>(
+    data: &HDiffData,
+    source_path: &'a Path,
+    relocator: &Relocator,
+    quirks: &Quirks,
+    outcome: &Mutex<PatchOutcome>,
+) -> Option<Option<&'a Path>> {
+    if data.source_file_name.is_empty() {
+        Some(None)
+    } else if source_path.exists() {
+        Some(Some(source_path))
+    } else if let Some(from) = relocator.restore(&data.source_file_name, data.source_file_hash.as_ref()) {
+        outcome.lock().unwrap().detail(&data.source_file_name, Detail::Relocated { from });
+        Some(Some(source_path))
+    } else if quirks.empty_source_diffs {
+        Some(None)
     } else {
-        Err(anyhow!("No hdiff entries map exist"))
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hpatchz::tests::diff;
+
+    // HSR ships new files as diffs against an empty file even when hdiffmap.json lists a source that is missing,
+    // every profile patching from empty has to get the new file and every other one has to skip it
+    #[test]
+    fn missing_source_by_profile() {
+        let game_path = std::env::temp_dir().join(format!("sophon_hdiff_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&game_path);
+        std::fs::create_dir_all(&game_path).unwrap();
+        let profiles = Quirks::builtin().unwrap();
+        assert!(profiles.iter().any(|(name, quirks)| *name == "hsr" && quirks.empty_source_diffs));
+
+        for (name, quirks) in &profiles {
+            let data = HDiffData {
+                source_file_name: format!("{}.old", name),
+                target_file_name: format!("{}.new", name),
+                patch_file_name: format!("{}.hdiff", name),
+                target_file_size: None,
+                target_file_md5: None,
+                source_file_hash: None,
+            };
+            let patch_path = game_path.join(&data.patch_file_name);
+            let target_path = game_path.join(&data.target_file_name);
+            std::fs::write(&patch_path, diff(0, 0, name.as_bytes(), true)).unwrap();
+
+            let relocator = Relocator::new(&game_path, quirks);
+            let outcome = Mutex::new(PatchOutcome::default());
+            let source_path = game_path.join(&data.source_file_name);
+            match resolve_source(&data, &source_path, &relocator, quirks, &outcome) {
+                Some(source) => {
+                    assert!(quirks.empty_source_diffs, "{} patches a missing source", name);
+                    assert_eq!(source, None, "{}", name);
+                    apply_patch(source, &patch_path, &target_path, false).unwrap();
+                    assert_eq!(std::fs::read(&target_path).unwrap(), name.as_bytes(), "{}", name);
+                }
+                None => assert!(!quirks.empty_source_diffs, "{} skips a missing source", name),
+            }
+        }
+        std::fs::remove_dir_all(game_path).unwrap();
     }
 }
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+use tokio::fs;
+use sophon::download::BuildQuery;
+use sophon::proto::chunk::{AssetProperty, SophonChunkProto};
+use sophon::sophon::{chunk_diff_to, DirectoryTarget};
+use crate::action::{Detail, PatchOptions, PatchOutcome};
+use crate::disk::{self, Stage};
+use crate::quirks::Quirks;
+use crate::verify::{self, VerifyMode};
+use crate::{bars, download, log, metrics, util};
+
+// Folders a stopped install leaves behind, a target holding nothing else counts as empty
+const WORK_FOLDERS: &[&str] = &[download::CHUNK_FOLDER, "chunk_tmp"];
+
+/// Install a game into an empty folder from the chunks of its full build
+///
+/// Every chunk is downloaded, every asset is assembled along with the folders it is in and every file is hashed
+/// against the manifest. The downloaded chunks are removed once the install checks out, the manifest is kept
+pub async fn install(
+    target: &Path,
+    query: &BuildQuery,
+    matching_field: &str,
+    options: &PatchOptions,
+) -> Result<PatchOutcome> {
+    log::blank();
+
+    std::fs::create_dir_all(target)?;
+    let quirks = Quirks::detect(target)?;
+    for entry in target.read_dir()? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !WORK_FOLDERS.contains(&name.as_str()) && !quirks.is_manifest(&name) {
+            return Err(anyhow!(
+                "{:?} is not empty, it holds {}, patch an installed game with action 2 instead",
+                target,
+                name,
+            ));
+        }
+    }
+
+    // Nothing is installed yet, so every chunk is downloaded
+    let manifest_name = download::fetch_chunks(target, query, matching_field, true)?;
+    let manifest_path = target.join(&manifest_name);
+    let chunk_path = target.join(download::CHUNK_FOLDER);
+    let mut manifest = util::with_spinner(&format!("Reading {}", manifest_name), || {
+        SophonChunkProto::from(manifest_path.to_string_lossy().to_string())
+    })?;
+    if manifest.assets.is_empty() {
+        return Err(anyhow!("manifest {} holds no assets", manifest_name));
+    }
+    for asset in &mut manifest.assets {
+        asset.asset_name = quirks.remap(&asset.asset_name);
+    }
+
+    // Decompressed chunks are staged before assembly
+    let mut chunk_sizes = HashMap::new();
+    for chunk in manifest.assets.iter().flat_map(|asset| &asset.asset_chunks) {
+        chunk_sizes.insert(&chunk.chunk_name, chunk.chunk_size_decompressed as u64);
+    }
+    let staged = chunk_sizes.values().sum();
+    let written = manifest.assets.iter().map(|asset| asset.asset_size as u64).sum();
+    let work_path = options.staging_dir.as_deref().unwrap_or(target);
+    match &options.staging_dir {
+        Some(staging_dir) => {
+            disk::reserve(staging_dir, &[(Stage::Staging, staged)])?;
+            disk::reserve(target, &[(Stage::Write, written)])?;
+        }
+        None => disk::reserve(target, &[(Stage::Staging, staged), (Stage::Write, written)])?,
+    }
+
+    let stage = metrics::stage("assemble");
+    let result = chunk_diff_to(
+        &manifest,
+        work_path,
+        &chunk_path,
+        bars::sink(),
+        options.keep_workdir,
+        Arc::new(DirectoryTarget::new(target)),
+    ).await;
+    if !options.keep_workdir {
+        disk::release(Stage::Staging);
+    }
+    result?;
+
+    create_folder_assets(target, &manifest.assets).await?;
+    let outcome = Mutex::new(PatchOutcome::default());
+    check_assets(target, &manifest.assets, &mut outcome.lock().unwrap());
+    drop(stage);
+
+    // A fresh install has nothing to trust, so every file is hashed without asking
+    verify::verify(target, &quirks, std::slice::from_ref(&manifest_path), VerifyMode::Manifest, &outcome)?;
+
+    let outcome = outcome.into_inner().unwrap();
+    if outcome.is_clean() && !options.keep_workdir {
+        let _ = fs::remove_dir_all(&chunk_path).await;
+    } else {
+        log::info!("Keeping the downloaded chunks in {}", chunk_path.display());
+    }
+    Ok(outcome)
+}
+
+/// Flag every asset of the manifest missing from the tree or written with another size, empty files and folders
+/// included
+fn check_assets(root: &Path, assets: &[AssetProperty], outcome: &mut PatchOutcome) {
+    for asset in assets {
+        let path = root.join(&asset.asset_name);
+        if asset.asset_hash_md5.is_empty() {
+            if !path.is_dir() {
+                outcome.detail(&asset.asset_name, Detail::Missing);
+            }
+            continue;
+        }
+        match path.metadata() {
+            Ok(metadata) if metadata.is_file() => {
+                outcome.check_size(&asset.asset_name, Some(asset.asset_size as u64), metadata.len());
+            }
+            _ => outcome.detail(&asset.asset_name, Detail::Missing),
+        }
+        outcome.written.push(asset.asset_name.clone());
+    }
+}
+
+/// Create the folders a manifest lists, assembly leaves out assets with neither chunks nor hash
+pub(super) async fn create_folder_assets(root: &Path, assets: &[AssetProperty]) -> Result<()> {
+    let folders = assets.iter().filter(|asset| asset.asset_chunks.is_empty() && asset.asset_hash_md5.is_empty());
+    for asset in folders {
+        fs::create_dir_all(root.join(&asset.asset_name)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_asset_has_to_be_installed() {
+        let root = std::env::temp_dir().join(format!("sophon_install_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("Data")).unwrap();
+        std::fs::write(root.join("Data/a.pck"), b"hello").unwrap();
+        std::fs::write(root.join("Data/empty.pck"), b"").unwrap();
+
+        let asset = |name: &str, size: i64, md5: &str| AssetProperty {
+            asset_name: name.to_string(),
+            asset_size: size,
+            asset_hash_md5: md5.to_string(),
+            ..Default::default()
+        };
+        let mut installed = PatchOutcome::default();
+        let assets = [asset("Data/a.pck", 5, "a"), asset("Data/empty.pck", 0, "b"), asset("Data", 0, "")];
+        check_assets(&root, &assets, &mut installed);
+        assert!(installed.is_clean());
+
+        let mut missing = PatchOutcome::default();
+        check_assets(&root, &[asset("Data/gone.pck", 0, "c"), asset("Logs", 0, "")], &mut missing);
+        let missing = missing.details.iter().map(|(path, _)| path.as_str()).collect::<Vec<_>>();
+        assert_eq!(missing, ["Data/gone.pck", "Logs"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,211 +1,405 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use anyhow::{anyhow, Result};
 use indicatif::ProgressBar;
-use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use tokio::fs;
 use sophon::proto::sophon::SophonManifestProto;
+use sophon::sophon::{create_parent_dirs, ldiff_entries, ldiff_files, mismatched_ldiff_chunks, LdiffEntry, Phase};
+use crate::action::{clean_up, OnError, PatchOptions};
+use crate::adaptive::Throttle;
+use crate::bars::{self, Bars};
+use crate::action::patch::apply_patch;
+use crate::action::{Detail, PatchOutcome};
+use crate::disk::{self, Stage};
 use crate::extractor::ArchiveExtractor;
-use crate::hpatchz::HPatchZ;
-use crate::serialize::{HDiffData, PkgVersion};
-use crate::util;
+use crate::inputs::Inputs;
+use crate::serialize::HDiffData;
+use crate::quirks::Quirks;
+use crate::relocate::Relocator;
+use crate::{events, log, metrics, mods, overall, receipt, state, status, util, verify};
 
+// Payloads are extracted here and moved into place once applied
+const STAGING_FOLDER: &str = "ldiff_staging";
+
+/// Patch the game folder with one or more ldiff archives
+///
+/// Updates shipped in parts, e.g. the game and its audio packs, are extracted together so their manifests are applied
+/// in one pass and verified once
 pub async fn ldiff(
     game_path: &Path,
-    ldiff_file: String,
-) -> Result<()> {
-    println!();
+    ldiff_archives: Vec<String>,
+    options: &PatchOptions,
+) -> Result<PatchOutcome> {
+    log::blank();
 
-    let ldiff_file_path = game_path.join(&ldiff_file);
-    if !ldiff_file_path.exists() {
-        return Err(anyhow!("{:?} does not exist", ldiff_file_path));
+    let ldiff_file_paths = ldiff_archives.iter().map(|ldiff_file| game_path.join(ldiff_file)).collect::<Vec<_>>();
+    if let Some(missing) = ldiff_file_paths.iter().find(|path| !path.exists()) {
+        return Err(anyhow!("{:?} does not exist", missing));
     }
+    let Some(first_path) = ldiff_file_paths.first() else {
+        return Err(anyhow!("No ldiff archive given"));
+    };
+    let ldiff_file = ldiff_archives.join(", ");
+    let mut quirks = Quirks::detect(game_path)?;
+    quirks.check_root(game_path, first_path)?;
+    let game_path = &quirks.root(game_path);
+    quirks.skip.extend(state::skipped(game_path)?);
     let ldiff_path = game_path.join("ldiff");
+    let staging_path = options.staging_dir.as_deref().unwrap_or(game_path).join(STAGING_FOLDER);
+    if receipt::is_applied(game_path, &ldiff_file_paths, &[]) {
+        log::ok!("{} is already applied according to the receipt", ldiff_file);
+        return Ok(PatchOutcome::default());
+    }
+    log::info!("Using {} quirks", quirks.name);
+    let _mods = if quirks.checks_mods() { mods::set_aside(game_path)? } else { None };
+    let outcome = Mutex::new(PatchOutcome::default());
 
-    // Make progress bar
-    println!("Extracting {}", ldiff_file_path.file_name().unwrap().to_string_lossy());
-    let mut bars: Vec<ProgressBar> = Vec::new();
-    let mut progress_bar: Option<ProgressBar> = None;
+    // Chunk files the download command fetched are used where they are, and what an attempt that stopped early
+    // extracted from the same archives is used again instead of extracted over
+    // The archives are hashed up front so the receipt and the event log name the exact packages
+    let downloaded = is_downloaded(&ldiff_file_paths, &ldiff_path)?;
+    let mut inputs = Inputs::of_packages(&ldiff_file_paths)?;
+    let extract = !downloaded && !state::reuse_extracted(game_path, &ldiff_file_paths, game_path, &inputs)?;
 
-    // Extract hdiff file
-    ArchiveExtractor::extract_with_progress(&ldiff_file_path, &game_path, |cur, max| {
-        let pb = progress_bar.get_or_insert_with(|| {
-            util::create_progress_bar(max as u64)
-        });
-        pb.set_position(cur as u64);
-    })?;
-    bars.push(progress_bar.unwrap());
+    // Fail before extracting anything when the packages do not fit
+    let mut extract_size = 0;
+    for path in &ldiff_file_paths {
+        extract_size += match downloaded {
+            true => folder_size(path)?,
+            false => ArchiveExtractor::uncompressed_size(path)?,
+        };
+    }
+    disk::reserve(game_path, &[(Stage::Extract, if extract { extract_size } else { 0 })])?;
 
-    // Extract hdiff file
-    println!("Extracting hdiff files from ldiff");
+    // Later stages work through about as much as the packages hold until their sizes are known
+    overall::estimate(Stage::Staging, extract_size);
+    overall::estimate(Stage::Write, extract_size);
+
+    // Extract every archive into the same folders, one bar going through all of them
+    let mut bars = Bars::new();
+    let mut progress_bar: Option<ProgressBar> = None;
+    let stage = metrics::stage("extract");
+    for path in ldiff_file_paths.iter().filter(|_| extract) {
+        log::info!(
+            "Extracting {}",
+            util::ellipsize(&path.file_name().unwrap().to_string_lossy(), util::terminal_width().saturating_sub(11)),
+        );
+        let done = progress_bar.as_ref().map_or(0, ProgressBar::position);
+        ArchiveExtractor::extract_with_progress(path, game_path, |cur, max| {
+            let pb = progress_bar.get_or_insert_with(|| bars.add(max as u64));
+            pb.set_length(done + max as u64);
+            pb.set_position(done + cur as u64);
+            overall::set_fraction(Stage::Extract, cur, max);
+        })?;
+    }
+    drop(stage);
+    if let Some(pb) = &progress_bar {
+        bars.finish(pb, "Extracted", "files");
+    }
+    if !extract {
+        overall::set_fraction(Stage::Extract, 1, 1);
+    }
+
+    // Read manifests
+    log::info!("Extracting hdiff files from ldiff");
+    let mut manifests = Vec::new();
+    let mut protos = Vec::new();
     for game_entry in game_path.read_dir()? {
         let entry = game_entry?;
-        if entry.file_type()?.is_file() && entry.file_name().to_string_lossy().starts_with("manifest") {
+        if entry.file_type()?.is_file() && quirks.is_manifest(&entry.file_name().to_string_lossy()) {
             let manifest_name = entry.file_name().to_string_lossy().to_string();
-            let manifest = match SophonManifestProto::from(
-                game_path.join(&manifest_name).to_string_lossy().to_string()
-            ) {
-                Ok(manifest) => {
-                    manifest
-                }
-                Err(_) => {
-                    continue;
-                }
-            };
-
-            let entries = ldiff_path.read_dir()?.collect::<Result<Vec<_>, _>>()?;
-            let pb = util::create_progress_bar(entries.len() as u64);
-            for entry in ldiff_path.read_dir()? {
-                pb.inc(1u64);
-
-                let asset_name = entry?.file_name().to_string_lossy().into_owned();
-                let matching_assets = manifest.assets
-                    .par_iter()
-                    .filter_map(|asset_group| {
-                        if let Some(data) = &asset_group.asset_data {
-                            let asset = data.assets
-                                .iter()
-                                .find(|asset| asset.chunk_file_name == asset_name);
-                            if let Some(asset) = asset {
-                                let asset_name = asset_group.asset_name.clone();
-                                let asset_size = asset_group.asset_size.clone();
-                                return Some((asset_name, asset_size, asset.clone()));
-                            }
-                        }
-                        None
-                    })
-                    .collect::<Vec<_>>();
-                for (asset_name, asset_size, asset) in matching_assets {
-                    sophon::sophon::ldiff_file(
-                        &asset,
-                        &asset_name,
-                        asset_size,
-                        &ldiff_path,
-                        &game_path,
-                    ).await?;
-                }
+            let manifest = util::with_spinner(&format!("Reading {}", manifest_name), || {
+                SophonManifestProto::from(game_path.join(&manifest_name).to_string_lossy().to_string())
+            });
+            if let Ok(manifest) = manifest {
+                manifests.push(game_path.join(&manifest_name));
+                protos.push((manifest_name, manifest));
             }
-            bars.push(pb);
-
-            // Make hdiff map
-            println!("Patching game files");
-            let hdiff_map = make_diff_map(
-                &manifest,
-                entries.iter()
-                    .map(|e| e.file_name().to_string_lossy().into_owned())
-                    .collect::<Vec<_>>(),
-            ).await?;
-
-            // Patch game files
-            let pb = util::create_progress_bar(hdiff_map.len() as u64);
-            hdiff_map.into_par_iter().for_each(|data| {
-                pb.inc(1u64);
-
-                // Check if patch file exist
-                let patch_path = game_path.join(&data.patch_file_name);
-                if !patch_path.exists() {
-                    return;
-                }
+        }
+    }
+    inputs.add_metadata(&manifests)?;
+    if extract {
+        state::extracted(game_path, &ldiff_file_paths, &inputs)?;
+    }
+    let chunk_names = ldiff_path.read_dir()?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
 
-                // Run hpatchz
-                if !data.source_file_name.is_empty() {
-                    let source_path = game_path.join(&data.source_file_name);
-                    if !source_path.exists() {
-                        return;
-                    }
-
-                    let target_path = game_path.join(&data.target_file_name);
-                    if let Err(_) = HPatchZ::apply_patch(&source_path, &patch_path, &target_path) {
-                        eprintln!("{} failed to patch!", &data.target_file_name);
-                        std::fs::remove_file(&patch_path).unwrap();
-                        return;
-                    }
-
-                    if data.source_file_name != data.target_file_name {
-                        std::fs::remove_file(&source_path).unwrap();
-                    }
-                    std::fs::remove_file(patch_path).unwrap();
-                } else {
-                    let target_path = game_path.join(&data.target_file_name);
-                    if let Err(_) = HPatchZ::apply_patch_empty(&patch_path, &target_path) {
-                        eprintln!("{} failed to patch!", &data.target_file_name);
-                        std::fs::remove_file(&patch_path).unwrap();
-                        return;
-                    }
-
-                    std::fs::remove_file(&patch_path).unwrap();
-                }
-            });
-            bars.push(pb);
+    // Manifests left over from another update would assemble garbage, so nothing is patched with them
+    if let Err(err) = check_manifests(&protos, &ldiff_path, &chunk_names, &ldiff_file) {
+        if !options.keep_workdir {
+            if !downloaded {
+                let _ = fs::remove_dir_all(&ldiff_path).await;
+            }
+            let _ = state::forget_extracted(game_path, &ldiff_file_paths);
+            disk::release(Stage::Extract);
         }
+        return Err(err);
     }
 
-    // Cleanup hpatchz temp file
-    HPatchZ::cleanup()?;
+    // Extract hdiff file
+    let stage = metrics::stage("ldiff");
 
-    // Verify file integrity
-    let verify = util::input("Ldiff patching done, verify file integrity? (Y/n) [n]: ");
-    if verify.to_lowercase() == "y" || verify.to_lowercase() == "yes" {
-        let pkg_version = PkgVersion::from(&game_path.join("pkg_version"))?;
-        let pb = util::create_progress_bar(pkg_version.len() as u64);
-        pkg_version.into_par_iter().for_each(|file| {
-            pb.inc(1u64);
-
-            let file_path = game_path.join(&file.remote_file);
-            if let Ok(md5) = util::calculate_md5_hash(&file_path) {
-                if md5.to_lowercase() != file.md5 {
-                    println!(
-                        "{} md5 hash does not match! Expected: {}, found: {}",
-                        &file.remote_file,
-                        &file.md5,
-                        &md5,
-                    );
-                }
-            } else {
-                println!("{} does not exist!", &file.remote_file);
+    // Group the payloads of every manifest by chunk file so every chunk file is mapped once
+    let mut groups: BTreeMap<String, Vec<LdiffEntry>> = BTreeMap::new();
+    for entry in protos.iter().flat_map(|(_, manifest)| ldiff_entries(manifest, &chunk_names)) {
+        groups.entry(entry.data.chunk_file_name.clone()).or_default().push(entry);
+    }
+
+    // Every payload is staged, diffs then write their target next to it while new files are moved
+    let entries = groups.values().flatten();
+    let staged = entries.clone().map(|entry| entry.data.hdiff_file_size as u64).sum();
+    let written = entries
+        .filter(|entry| entry.kind.is_patch())
+        .map(|entry| entry.asset_size as u64)
+        .sum();
+    let reserved = match &options.staging_dir {
+        Some(staging_dir) => disk::reserve(staging_dir, &[(Stage::Staging, staged)])
+            .and_then(|_| disk::reserve(game_path, &[(Stage::Write, written)])),
+        None => disk::reserve(game_path, &[(Stage::Staging, staged), (Stage::Write, written)]),
+    };
+    if let Err(err) = reserved {
+        if !options.keep_workdir {
+            if !downloaded {
+                let _ = fs::remove_dir_all(&ldiff_path).await;
             }
+            let _ = fs::remove_dir_all(&staging_path).await;
+            let _ = state::forget_extracted(game_path, &ldiff_file_paths);
+            disk::release(Stage::Extract);
+        }
+        return Err(err);
+    }
+    create_parent_dirs(&staging_path, groups.values().flatten().map(|entry| &entry.asset_name))?;
+    let progress = bars::sink();
+    progress.on_stage_start(Phase::Staging, groups.len() as u64);
+    for entries in groups.values() {
+        ldiff_files(entries, &ldiff_path, &staging_path, Arc::clone(&progress)).await?;
+    }
+    progress.on_finish(Phase::Staging);
+    drop(stage);
+
+    // Make hdiff map
+    log::info!("Patching game files");
+    let mut hdiff_map = Vec::new();
+    for (_, manifest) in &protos {
+        hdiff_map.extend(make_diff_map(manifest, &chunk_names, &quirks).await?);
+    }
+
+    // Patch game files, their folders are made before patching in parallel
+    create_parent_dirs(game_path, hdiff_map.iter()
+        .map(|data| &data.target_file_name)
+        .filter(|target| !quirks.is_protected(target) && !quirks.is_skipped(target)))?;
+    let _stage = metrics::stage("patch");
+    let pb = bars.add(hdiff_map.len() as u64);
+    status::attach(&pb);
+    let throttle = Throttle::for_pool();
+    let relocator = Relocator::new(game_path, &quirks);
+    let aborted = AtomicBool::new(false);
+    hdiff_map.into_par_iter().for_each(|data| {
+        pb.inc(1u64);
+        if aborted.load(Ordering::Relaxed) {
+            outcome.lock().unwrap().skipped += 1;
+            return;
+        }
+
+        // Never overwrite user data
+        if quirks.is_protected(&data.target_file_name) {
+            let mut outcome = outcome.lock().unwrap();
+            outcome.skipped += 1;
+            outcome.detail(&data.target_file_name, Detail::Protected);
+            return;
+        }
+        if quirks.is_skipped(&data.target_file_name) {
+            outcome.lock().unwrap().skipped += 1;
+            return;
+        }
+
+        // Check if patch file exist
+        let patch_path = staging_path.join(&data.patch_file_name);
+        if !patch_path.exists() {
+            outcome.lock().unwrap().skipped += 1;
+            return;
+        }
+
+        // Run hpatchz
+        let source_path = game_path.join(&data.source_file_name);
+        let source_path = if data.source_file_name.is_empty() {
+            None
+        } else if source_path.exists() {
+            Some(source_path.as_path())
+        } else if let Some(from) = relocator.restore(&data.source_file_name, data.source_file_hash.as_ref()) {
+            outcome.lock().unwrap().detail(&data.source_file_name, Detail::Relocated { from });
+            Some(source_path.as_path())
+        } else {
+            outcome.lock().unwrap().skipped += 1;
+            return;
+        };
+
+        let target_path = game_path.join(&data.target_file_name);
+        let bytes = patch_path.metadata().map_or(0, |metadata| metadata.len());
+        let started = Instant::now();
+        let working = status::working(&data.target_file_name);
+        let result = throttle.run(bytes, || {
+            apply_patch(source_path, &patch_path, &target_path, options.keep_workdir)
         });
-        bars.push(pb);
+        drop(working);
+        events::file(&data.target_file_name, started.elapsed(), result.is_ok());
+        let mut outcome = outcome.lock().unwrap();
+        match result {
+            Ok(applied) => {
+                if applied.readonly {
+                    outcome.detail(&data.target_file_name, Detail::ReadOnly);
+                }
+                outcome.patched += 1;
+                outcome.written.push(data.target_file_name.clone());
+                let size = target_path.metadata().map_or(0, |metadata| metadata.len());
+                outcome.check_size(&data.target_file_name, data.target_file_size, size);
+                overall::advance(Stage::Write, size);
+                metrics::add(&metrics::BYTES_WRITTEN, size);
+                metrics::inc(&metrics::FILES_PATCHED);
+            }
+            Err(_) => {
+                outcome.failed += 1;
+                outcome.detail(&data.target_file_name, Detail::Failed);
+                metrics::inc(&metrics::FILES_FAILED);
+                if options.on_error == OnError::Abort {
+                    aborted.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+    bars.finish(&pb, "Patched", "files");
+
+    // Nothing is cleaned up, so the same archive can be applied again
+    if aborted.into_inner() {
+        log::error!("Patching stopped after a file failed, the archive and staged payloads are kept");
+        return Ok(outcome.into_inner().unwrap());
+    }
+    if !options.keep_workdir {
+        disk::release(Stage::Staging);
     }
-    let _ = fs::remove_dir_all(ldiff_path).await;
 
-    // Delete ldiff folder
-    let delete = util::input("Delete ldiff folder and manifest? (Y/n) [Y]: ");
-    if delete.to_lowercase() != "n" && delete.to_lowercase() != "no" {
-        let _ = fs::remove_file(ldiff_file_path).await;
+    // Verify file integrity
+    let question = "Ldiff patching done, verify file integrity? (Y/n) [n]: ";
+    if quirks.offers_verify() && util::confirm("verify", question, false) {
+        verify::verify(game_path, &quirks, &[], options.verify_mode, &outcome)?;
+    }
+    if options.keep_workdir {
+        log::info!("Keeping chunk files in {} and payloads in {}", ldiff_path.display(), staging_path.display());
+    } else {
+        // Downloaded chunk files are the package itself, removed with it below
+        if !downloaded {
+            let _ = fs::remove_dir_all(ldiff_path).await;
+        }
+        let _ = fs::remove_dir_all(staging_path).await;
+        state::forget_extracted(game_path, &ldiff_file_paths)?;
+        disk::release(Stage::Extract);
     }
 
-    Ok(())
+    let outcome = outcome.into_inner().unwrap();
+    if outcome.is_clean() {
+        receipt::write(game_path, &ldiff_file_paths, &manifests, &inputs, &outcome.written)?;
+    }
+    if options.skip_failed {
+        state::skip_failed(game_path, &outcome)?;
+    }
+
+    let name = match ldiff_file_paths.len() {
+        _ if downloaded => "ldiff folder",
+        1 => "ldiff archive",
+        _ => "ldiff archives",
+    };
+    clean_up(options, &ldiff_file_paths, &manifests, name).await;
+
+    Ok(outcome)
 }
 
 async fn make_diff_map(
     manifest: &SophonManifestProto,
-    chunk_names: Vec<String>,
+    chunk_names: &[String],
+    quirks: &Quirks,
 ) -> Result<Vec<HDiffData>> {
-    // Iterate all assets in proto
-    let hdiff_files = manifest.assets.iter().filter_map(|asset| {
-        let asset_name = asset.asset_name.clone();
-        let asset_size = asset.asset_size.clone();
-        if let Some(chunk) = asset.asset_data.clone() {
-            let assets = chunk.assets
-                .iter()
-                .filter(|&asset| chunk_names.iter().any(|name| name == &asset.chunk_file_name))
-                .filter_map(|asset| {
-                    if asset.original_file_size != 0 || asset.hdiff_file_size != asset_size {
-                        Some(HDiffData {
-                            source_file_name: asset.original_file_path.clone(),
-                            target_file_name: asset_name.clone(),
-                            patch_file_name: format!("{asset_name}.hdiff"),
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
-            Some(assets)
-        } else {
-            None
+    // New files go through the same flow, apply_patch moves them into place as they are no diff
+    ldiff_entries(manifest, chunk_names)
+        .into_iter()
+        .map(|entry| {
+            let data = HDiffData::new(
+                &quirks.remap(&entry.data.original_file_path),
+                &quirks.remap(&entry.asset_name),
+                &entry.asset_name,
+            )?;
+            Ok(data
+                .with_target_size(entry.asset_size as u64)
+                .with_target_md5(&entry.asset_hash_md5)
+                .with_source_hash(entry.data.original_file_size as u64, &entry.data.original_file_md5))
+        })
+        .collect()
+}
+
+/// Whether the package is the ldiff folder of the game the download command fetched, which needs no extraction
+fn is_downloaded(ldiff_file_paths: &[PathBuf], ldiff_path: &Path) -> Result<bool> {
+    match ldiff_file_paths {
+        [path] if path.is_dir() && path == ldiff_path => Ok(true),
+        paths => match paths.iter().find(|path| path.is_dir()) {
+            Some(path) => Err(anyhow!(
+                "{:?} is a folder, only the ldiff folder of the game fetched by the download command is used as is",
+                path,
+            )),
+            None => Ok(false),
+        },
+    }
+}
+
+/// Bytes held by the files of a flat folder
+fn folder_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in path.read_dir()? {
+        size += entry?.metadata()?.len();
+    }
+    Ok(size)
+}
+
+/// Make sure the manifests describe the chunk files of the archive, failing before anything is patched otherwise
+fn check_manifests(
+    manifests: &[(String, SophonManifestProto)],
+    ldiff_path: &Path,
+    chunk_names: &[String],
+    ldiff_file: &str,
+) -> Result<()> {
+    for (name, manifest) in manifests {
+        if manifest.assets.is_empty() {
+            return Err(anyhow!(
+                "manifest {} holds no assets, it is not an ldiff manifest or its download was cut short",
+                name,
+            ));
         }
-    }).collect::<Vec<_>>();
+        if let Some(mismatch) = mismatched_ldiff_chunks(manifest, ldiff_path).first() {
+            return Err(anyhow!(
+                "manifest {} does not describe package {}, it lists {} with {} bytes but the archive has {}",
+                name,
+                ldiff_file,
+                mismatch.name,
+                mismatch.listed,
+                mismatch.found,
+            ));
+        }
+    }
 
-    Ok(hdiff_files.into_iter().flatten().collect())
+    // Several manifests can share one archive, but at least one of them has to list its chunk files
+    let described = manifests.iter().any(|(_, manifest)| !ldiff_entries(manifest, chunk_names).is_empty());
+    if described || chunk_names.is_empty() {
+        return Ok(());
+    }
+    match manifests.first() {
+        Some((name, _)) => Err(anyhow!(
+            "manifest {} does not describe package {}, none of its chunk files are listed",
+            name,
+            ldiff_file,
+        )),
+        None => Err(anyhow!("package {} holds chunk files but no manifest describing them", ldiff_file)),
+    }
 }
@@ -1,7 +1,72 @@
 mod ldiff;
 mod hdiff;
 mod chunk;
+mod install;
+mod repair;
+mod patch;
+mod outcome;
+mod options;
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use tokio::fs;
+use crate::util;
 
 pub use ldiff::*;
 pub use hdiff::*;
 pub use chunk::*;
+pub use install::*;
+pub use repair::*;
+pub use outcome::*;
+pub use options::*;
+
+/// What is removed once a package is applied
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Cleanup {
+    /// Keep the package and its manifests
+    Keep,
+    /// Remove the package, keep its manifests in the game folder
+    ArchiveOnly,
+    /// Remove the package and its manifests
+    All,
+}
+
+impl FromStr for Cleanup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Cleanup::Keep),
+            "archive-only" => Ok(Cleanup::ArchiveOnly),
+            "all" => Ok(Cleanup::All),
+            _ => Err(anyhow!("Unknown cleanup policy {}, expected keep, archive-only or all", s)),
+        }
+    }
+}
+
+/// Remove the packages, files or folders, and their manifests as the cleanup policy says
+///
+/// Without a policy the user is asked, `name` describing the packages in the question
+async fn clean_up(options: &PatchOptions, packages: &[PathBuf], manifests: &[PathBuf], name: &str) {
+    let cleanup = match options.cleanup {
+        Some(cleanup) => cleanup,
+        None if util::confirm("delete", &format!("Delete {} and manifest? (Y/n) [Y]: ", name), true) => Cleanup::All,
+        None => Cleanup::Keep,
+    };
+
+    if cleanup != Cleanup::Keep && !options.shared_package {
+        for package in packages {
+            if package.is_dir() {
+                let _ = fs::remove_dir_all(package).await;
+            } else {
+                let _ = fs::remove_file(package).await;
+            }
+        }
+    }
+    if cleanup == Cleanup::All {
+        for manifest in manifests {
+            let _ = fs::remove_file(manifest).await;
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use sophon::sophon::Existing;
+use crate::action::Cleanup;
+use crate::options::Options;
+use crate::verify::VerifyMode;
+
+/// What happens to the remaining files once one fails to patch
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Patch every other file and report the failures at the end
+    Continue,
+    /// Stop patching, keeping the package and everything extracted from it for another attempt
+    Abort,
+}
+
+impl FromStr for OnError {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "continue" => Ok(OnError::Continue),
+            "abort" => Ok(OnError::Abort),
+            _ => Err(anyhow!("Unknown error policy {}, expected continue or abort", s)),
+        }
+    }
+}
+
+/// How progress is shown
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Interactive bars
+    Bars,
+    /// A plain percentage line every interval, for logs and CI
+    Plain(Duration),
+}
+
+/// Everything the actions are told by the user, resolved once from the command line, environment and config file
+#[derive(Clone)]
+pub struct PatchOptions {
+    pub verify_mode: VerifyMode,
+    /// What is removed after patching, the user is asked when none
+    pub cleanup: Option<Cleanup>,
+    /// Set when several targets share the package, which then is never removed
+    pub shared_package: bool,
+    /// Leave extracted and staged files behind for inspection
+    pub keep_workdir: bool,
+    pub on_error: OnError,
+    /// Remember files that failed to patch and skip them in later runs
+    pub skip_failed: bool,
+    pub progress: ProgressMode,
+    pub hash_threads: Option<usize>,
+    pub io_threads: Option<usize>,
+    /// Folder for hpatchz and other temporary files, the system temp folder when none
+    pub temp_dir: Option<PathBuf>,
+    /// Folder payloads and decompressed chunks are staged in before they reach the game folder, the game folder when
+    /// none
+    pub staging_dir: Option<PathBuf>,
+    /// Image file or block device action `2` packs assets into instead of the game folder
+    pub output_image: Option<PathBuf>,
+    /// Rewrite heavily fragmented large files once assembled
+    pub consolidate: bool,
+    /// What happens to files differing from the chunk assets assembled in their place
+    pub existing: Existing,
+}
+
+/// What the actions do when the user gives no options
+impl Default for PatchOptions {
+    fn default() -> PatchOptions {
+        PatchOptions {
+            verify_mode: VerifyMode::Touched,
+            cleanup: None,
+            shared_package: false,
+            keep_workdir: false,
+            on_error: OnError::Continue,
+            skip_failed: false,
+            progress: ProgressMode::Bars,
+            hash_threads: None,
+            io_threads: None,
+            temp_dir: None,
+            staging_dir: None,
+            output_image: None,
+            consolidate: false,
+            existing: Existing::Overwrite,
+        }
+    }
+}
+
+impl PatchOptions {
+    pub fn resolve(args: &Options) -> Result<PatchOptions> {
+        let progress = match args.value("progress") {
+            None | Some("bars") => ProgressMode::Bars,
+            Some("plain") => ProgressMode::Plain(Duration::from_secs(args.parsed("progress-interval")?.unwrap_or(5))),
+            Some(mode) => return Err(anyhow!("Unknown progress mode {}, expected bars or plain", mode)),
+        };
+        Ok(PatchOptions {
+            verify_mode: args.value("verify-mode").map(str::parse).transpose()?.unwrap_or(VerifyMode::Touched),
+            cleanup: args.value("cleanup").map(str::parse).transpose()?,
+            shared_package: false,
+            keep_workdir: args.flag("keep-workdir"),
+            on_error: args.value("on-error").map(str::parse).transpose()?.unwrap_or(OnError::Continue),
+            skip_failed: args.flag("skip-failed"),
+            progress,
+            hash_threads: args.parsed("hash-threads")?.or(args.parsed("threads")?),
+            io_threads: args.parsed("io-threads")?.or(args.parsed("threads")?),
+            temp_dir: args.value("temp-dir").map(PathBuf::from),
+            staging_dir: args.value("staging-dir").map(PathBuf::from),
+            output_image: args.value("output-image").map(PathBuf::from),
+            consolidate: args.flag("consolidate"),
+            existing: args.value("existing").map(str::parse).transpose()?.unwrap_or(Existing::Overwrite),
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use crate::events::{self, Event};
+use crate::log::{self, Level};
+
+/// Why a file is listed in the outcome of an action
+#[derive(Debug)]
+pub enum Detail {
+    /// Patching the file failed
+    Failed,
+    /// Protected user data that was left alone
+    Protected,
+    /// The read-only attribute had to be cleared to patch or delete the file
+    ReadOnly,
+    /// The file hash does not match the listing
+    Mismatch { expected: String, found: String },
+    /// The file from the listing does not exist
+    Missing,
+    /// The source was missing and got copied back from a file with the same content elsewhere
+    Relocated { from: String },
+    /// The file was written with another size than the package lists, e.g. cut short by a lost write
+    WrongSize { expected: u64, found: u64 },
+    /// The file differed from the package and was left as it was
+    Kept,
+    /// The file differed from the package and was moved aside to `<name>.bak` before being written
+    BackedUp,
+}
+
+impl Detail {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Detail::Failed => "failed",
+            Detail::Protected => "protected",
+            Detail::ReadOnly => "read_only",
+            Detail::Mismatch { .. } => "mismatch",
+            Detail::Missing => "missing",
+            Detail::Relocated { .. } => "relocated",
+            Detail::WrongSize { .. } => "wrong_size",
+            Detail::Kept => "kept",
+            Detail::BackedUp => "backed_up",
+        }
+    }
+}
+
+/// What an action did to a game folder
+#[derive(Default, Debug)]
+pub struct PatchOutcome {
+    pub patched: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub deleted: u64,
+    pub verified: u64,
+    pub details: Vec<(String, Detail)>,
+    /// Game relative paths of every file the action wrote
+    pub written: Vec<String>,
+}
+
+impl PatchOutcome {
+    pub fn detail(&mut self, path: &str, detail: Detail) {
+        events::emit(Event::Detail { path: path.to_string(), detail: detail.name().to_string() });
+        self.details.push((path.to_string(), detail));
+    }
+
+    /// Flag a file just written with another size than the package lists, printed right away rather than with the
+    /// report as it points at writes that went missing
+    pub fn check_size(&mut self, path: &str, expected: Option<u64>, found: u64) {
+        let Some(expected) = expected.filter(|&expected| expected != found) else {
+            return;
+        };
+        log::queue(Level::Error, format!("{} was written with {} bytes, expected {}", path, found, expected));
+        self.detail(path, Detail::WrongSize { expected, found });
+    }
+
+    /// Whether every file patched and verified fine, protected and read-only files aside
+    pub fn is_clean(&self) -> bool {
+        self.failed == 0 && !self.details.iter().any(|(_, detail)| {
+            matches!(detail, Detail::Failed | Detail::Mismatch { .. } | Detail::Missing | Detail::WrongSize { .. })
+        })
+    }
+
+    /// Add the counts and details of another outcome, e.g. of every target of a run
+    pub fn merge(&mut self, other: PatchOutcome) {
+        self.patched += other.patched;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.deleted += other.deleted;
+        self.verified += other.verified;
+        self.details.extend(other.details);
+        self.written.extend(other.written);
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::Path;
+use anyhow::Result;
+use sophon::sophon::reserve_handles;
+use crate::hpatchz::HPatchZ;
+use crate::util;
+
+/// How a patch file ended up being applied
+pub struct Applied {
+    /// The target was read-only, the attribute was cleared for patching and restored afterwards
+    pub readonly: bool,
+}
+
+/// Apply a patch file onto its source, or move it into place when it is the new file itself
+///
+/// The patch file is removed afterwards unless `keep_workdir` is set, the source only once patched into a different
+/// target. The target folder has to exist, callers create every folder up front with `create_parent_dirs`
+pub fn apply_patch(
+    source_path: Option<&Path>,
+    patch_path: &Path,
+    target_path: &Path,
+    keep_workdir: bool,
+) -> Result<Applied> {
+    // Read-only files can be neither overwritten nor removed, the target gets its attribute back afterwards
+    let readonly = util::clear_readonly(target_path).unwrap_or(false);
+    if let Some(source_path) = source_path.filter(|&source_path| source_path != target_path) {
+        let _ = util::clear_readonly(source_path);
+    }
+
+    // hpatchz holds the source, the patch and the target open
+    let _handles = reserve_handles(3);
+    let result = if HPatchZ::is_diff(patch_path)? {
+        match source_path {
+            Some(source_path) => HPatchZ::apply_patch(source_path, patch_path, target_path),
+            None => HPatchZ::apply_patch_empty(patch_path, target_path),
+        }.map(|_| Applied { readonly })
+    } else if keep_workdir {
+        // Straight file addition, copied so the patch file stays for inspection
+        fs::copy(patch_path, target_path)
+            .map(|_| Applied { readonly })
+            .map_err(Into::into)
+    } else {
+        // Straight file addition, the patch file is the new file
+        util::move_file(patch_path, target_path)
+            .map(|_| Applied { readonly })
+            .map_err(Into::into)
+    };
+    if !keep_workdir {
+        let _ = fs::remove_file(patch_path);
+    }
+
+    if readonly {
+        let _ = util::set_readonly(target_path);
+    }
+    if let Some(source_path) = source_path.filter(|&source_path| result.is_ok() && source_path != target_path) {
+        let _ = fs::remove_file(source_path);
+    }
+
+    result
+}
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use sophon::proto::chunk::{AssetProperty, SophonChunkProto};
+use sophon::sophon::{chunk_diff_to, mismatched_chunks, ChunkLayout, DirectoryTarget};
+use crate::action::install::create_folder_assets;
+use crate::action::{Detail, PatchOptions, PatchOutcome};
+use crate::bars;
+use crate::disk::{self, Stage};
+use crate::quirks::Quirks;
+use crate::util::Digest;
+use crate::{log, metrics, pools, state, util};
+
+/// Hash every file of a chunk manifest in the game folder and assemble only the missing or corrupted ones again
+///
+/// Only the chunks of those files are staged, the chunk folder and the manifest are kept
+pub async fn repair(
+    game_path: &Path,
+    chunk_folder: String,
+    manifest_name: String,
+    options: &PatchOptions,
+) -> Result<PatchOutcome> {
+    log::blank();
+
+    let chunk_path = game_path.join(chunk_folder);
+    if !chunk_path.is_dir() && !matches!(ChunkLayout::detect(&chunk_path, &HashMap::new()), Ok(ChunkLayout::Archive)) {
+        return Err(anyhow!("{:?} is no chunk folder or chunk archive", chunk_path));
+    }
+    let manifest_path = game_path.join(&manifest_name);
+    let mut quirks = Quirks::detect(game_path)?;
+    quirks.check_root(game_path, &chunk_path)?;
+    let game_path = &quirks.root(game_path);
+    quirks.skip.extend(state::skipped(game_path)?);
+    log::info!("Using {} quirks", quirks.name);
+    let mut outcome = PatchOutcome::default();
+
+    let mut manifest = util::with_spinner(&format!("Reading {}", manifest_name), || {
+        SophonChunkProto::from(manifest_path.to_string_lossy().to_string())
+    })?;
+    if manifest.assets.is_empty() {
+        return Err(anyhow!(
+            "manifest {} holds no assets, it is not a chunk manifest or its download was cut short",
+            manifest_name,
+        ));
+    }
+    for asset in &mut manifest.assets {
+        asset.asset_name = quirks.remap(&asset.asset_name);
+    }
+    manifest.assets.retain(|asset| {
+        if quirks.is_protected(&asset.asset_name) {
+            outcome.skipped += 1;
+            outcome.detail(&asset.asset_name, Detail::Protected);
+            return false;
+        }
+        if quirks.is_skipped(&asset.asset_name) {
+            outcome.skipped += 1;
+            return false;
+        }
+        true
+    });
+
+    let broken = broken_assets(game_path, &manifest.assets);
+    if broken.is_empty() {
+        log::ok!("Every file of {} is intact", manifest_name);
+        outcome.verified = manifest.assets.len() as u64;
+        return Ok(outcome);
+    }
+    log::info!("{} of {} files are missing or corrupted", broken.len(), manifest.assets.len());
+    outcome.verified = (manifest.assets.len() - broken.len()) as u64;
+    let broken = broken.into_keys().collect::<HashSet<_>>();
+    manifest.assets.retain(|asset| broken.contains(&asset.asset_name));
+
+    // Chunks left over from another update would assemble garbage, so nothing is written with them
+    if let Some(mismatch) = mismatched_chunks(&manifest, &chunk_path).first() {
+        return Err(anyhow!(
+            "manifest {} does not describe package {}, it lists {} with {} bytes but the package has {}",
+            manifest_name,
+            chunk_path.display(),
+            mismatch.name,
+            mismatch.listed,
+            mismatch.found,
+        ));
+    }
+
+    let mut chunk_sizes = HashMap::new();
+    for chunk in manifest.assets.iter().flat_map(|asset| &asset.asset_chunks) {
+        chunk_sizes.insert(&chunk.chunk_name, chunk.chunk_size_decompressed as u64);
+    }
+    let staged = chunk_sizes.values().sum();
+    let written = manifest.assets.iter().map(|asset| asset.asset_size as u64).sum();
+    let work_path = options.staging_dir.as_deref().unwrap_or(game_path);
+    match &options.staging_dir {
+        Some(staging_dir) => {
+            disk::reserve(staging_dir, &[(Stage::Staging, staged)])?;
+            disk::reserve(game_path, &[(Stage::Write, written)])?;
+        }
+        None => disk::reserve(game_path, &[(Stage::Staging, staged), (Stage::Write, written)])?,
+    }
+
+    let stage = metrics::stage("assemble");
+    let result = chunk_diff_to(
+        &manifest,
+        work_path,
+        &chunk_path,
+        bars::sink(),
+        options.keep_workdir,
+        Arc::new(DirectoryTarget::new(game_path)),
+    ).await;
+    if !options.keep_workdir {
+        disk::release(Stage::Staging);
+    }
+    result?;
+    create_folder_assets(game_path, &manifest.assets).await?;
+    drop(stage);
+
+    // Files still broken after assembly point at broken chunks
+    let mut still_broken = broken_assets(game_path, &manifest.assets);
+    for asset in &manifest.assets {
+        outcome.written.push(asset.asset_name.clone());
+        match still_broken.remove(&asset.asset_name) {
+            Some(detail) => {
+                outcome.failed += 1;
+                outcome.detail(&asset.asset_name, detail);
+            }
+            None => outcome.patched += 1,
+        }
+    }
+    Ok(outcome)
+}
+
+/// Files of the manifest that are missing or whose size or md5 differ, with what is wrong with them
+fn broken_assets(game_path: &Path, assets: &[AssetProperty]) -> HashMap<String, Detail> {
+    let broken = Mutex::new(HashMap::new());
+    let multi = util::create_multi_progress();
+    let pb = multi.add(util::create_progress_bar(assets.len() as u64));
+    pools::hash_pool().install(|| assets.par_iter().for_each(|asset| {
+        pb.inc(1);
+        let path = game_path.join(&asset.asset_name);
+        let detail = match path.metadata() {
+            // Folders have no hash
+            Ok(_) if asset.asset_hash_md5.is_empty() => return,
+            Err(_) => Detail::Missing,
+            Ok(metadata) if metadata.len() != asset.asset_size as u64 => Detail::WrongSize {
+                expected: asset.asset_size as u64,
+                found: metadata.len(),
+            },
+            Ok(_) => match util::calculate_hash_with_progress(&path, Digest::Md5, &multi) {
+                Ok(hash) if hash.eq_ignore_ascii_case(&asset.asset_hash_md5) => return,
+                Ok(hash) => Detail::Mismatch { expected: asset.asset_hash_md5.clone(), found: hash },
+                Err(_) => Detail::Missing,
+            },
+        };
+        broken.lock().unwrap().insert(asset.asset_name.clone(), detail);
+    }));
+    pb.finish_and_clear();
+    broken.into_inner().unwrap()
+}
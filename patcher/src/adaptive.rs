@@ -0,0 +1,119 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Turned off by --no-adaptive, every pool thread then works from the start
+static ADAPTIVE: AtomicBool = AtomicBool::new(true);
+
+// Workers the last throttle settled on, for the --verbose statistics
+static SETTLED: AtomicUsize = AtomicUsize::new(0);
+static MAX_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+// Workers running when a throttle starts
+const START_WORKERS: usize = 2;
+
+// Shortest time throughput is measured over before changing the worker count
+const WINDOW: Duration = Duration::from_millis(500);
+
+pub fn set_adaptive(adaptive: bool) {
+    ADAPTIVE.store(adaptive, Ordering::Relaxed);
+}
+
+/// Workers the last throttle settled on out of the most it could use, if any throttle ran
+pub fn settled() -> Option<(usize, usize)> {
+    match SETTLED.load(Ordering::Relaxed) {
+        0 => None,
+        workers => Some((workers, MAX_WORKERS.load(Ordering::Relaxed))),
+    }
+}
+
+/// Limits how many pool threads work at once, tuned by the throughput they reach
+///
+/// Starts with a few workers and adds one while throughput keeps rising. When it drops, e.g. because a hard disk
+/// or network share starts seeking, the last step is undone and the search turns around
+pub struct Throttle {
+    max: usize,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+struct State {
+    allowed: usize,
+    running: usize,
+    window_start: Instant,
+    window_bytes: u64,
+    last_rate: f64,
+    step: isize,
+}
+
+impl Throttle {
+    /// Throttle for work spread over the current rayon pool
+    pub fn for_pool() -> Throttle {
+        let max = rayon::current_num_threads().max(1);
+        let allowed = if ADAPTIVE.load(Ordering::Relaxed) { START_WORKERS.min(max) } else { max };
+        Throttle {
+            max,
+            state: Mutex::new(State {
+                allowed,
+                running: 0,
+                window_start: Instant::now(),
+                window_bytes: 0,
+                last_rate: 0.0,
+                step: 1,
+            }),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Run `work` once a worker slot is free, counting `bytes` towards the measured throughput
+    pub fn run<T>(&self, bytes: u64, work: impl FnOnce() -> T) -> T {
+        {
+            let mut state = self.state.lock().unwrap();
+            while state.running >= state.allowed {
+                state = self.freed.wait(state).unwrap();
+            }
+            state.running += 1;
+        }
+
+        let result = work();
+
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        state.window_bytes += bytes;
+        if ADAPTIVE.load(Ordering::Relaxed) {
+            self.adjust(&mut state);
+        }
+        drop(state);
+        self.freed.notify_all();
+        result
+    }
+
+    fn adjust(&self, state: &mut State) {
+        let elapsed = state.window_start.elapsed();
+        if elapsed < WINDOW {
+            return;
+        }
+
+        let rate = state.window_bytes as f64 / elapsed.as_secs_f64();
+        if rate < state.last_rate * 0.9 {
+            // Got slower, undo the last step and search the other way
+            state.step = if state.step == 0 { -1 } else { -state.step };
+        } else if rate < state.last_rate * 1.05 {
+            // No clear change, stay put
+            state.step = 0;
+        } else if state.step == 0 {
+            state.step = 1;
+        }
+        state.allowed = state.allowed.saturating_add_signed(state.step).clamp(1, self.max);
+        state.last_rate = rate;
+        state.window_start = Instant::now();
+        state.window_bytes = 0;
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        SETTLED.store(self.state.get_mut().unwrap().allowed, Ordering::Relaxed);
+        MAX_WORKERS.store(self.max, Ordering::Relaxed);
+    }
+}
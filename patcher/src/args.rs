@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::env;
+use clap::builder::BoolishValueParser;
+use clap::builder::ValueRange;
+use clap::{Arg, ArgAction, Command};
+
+// Arguments of a command by ID and value name
+type Params = &'static [(&'static str, &'static str)];
+
+/// Commands with their aliases, what they do and their arguments by ID and value name, a value name ending in `...`
+/// taking the rest of the command line. Subcommands are listed as `<command> <subcommand>` after their command
+const COMMANDS: &[(&str, &[&str], &str, Params)] = &[
+    ("hdiff", &["0"], "Patch game by hdiff", &[("game_folder", "game folder"), ("hdiff_archive", "hdiff archive")]),
+    ("ldiff", &["1"], "Patch game by ldiff", &[("game_folder", "game folder"), ("ldiff_archives", "ldiff archive...")]),
+    ("chunk", &["2"], "Patch game by chunk", &[
+        ("game_folder", "game folder"),
+        ("chunk_folder", "chunk folder"),
+        ("chunk_manifest", "manifest"),
+    ]),
+    ("batch", &["3"], "Patch multiple game folders", &[
+        ("targets_file", "targets file"),
+        ("target_action", "action"),
+        ("package_path", "package"),
+        ("chunk_manifest", "manifest"),
+    ]),
+    ("simulate", &["4"], "Simulate patch against a file listing", &[
+        ("listing", "listing"),
+        ("package_path", "hdiff or ldiff archive"),
+    ]),
+    ("export", &["5"], "Export file listing of game folder", &[
+        ("game_folder", "game folder"),
+        ("output", "output file"),
+    ]),
+    ("diagnose", &["6"], "Create diagnostic bundle", &[("game_folder", "game folder"), ("output", "output zip")]),
+    ("update", &["7"], "Update game with the packages in its folder", &[("game_folder", "game folder")]),
+    ("chunks", &[], "Inspect chunk manifests", &[]),
+    ("chunks stats", &[], "Summarize chunk sharing of a chunk manifest", &[("chunk_manifest", "manifest")]),
+    ("chunks compare", &[], "Tell which assets a chunk update would change", &[
+        ("chunk_folder", "chunk folder"),
+        ("chunk_manifest", "manifest"),
+        ("game_folder", "game folder"),
+    ]),
+    ("download", &[], "Download the full build or the update", &[]),
+    ("download chunk", &[], "Download the chunks of the full build", &[("game_folder", "game folder")]),
+    ("download ldiff", &[], "Download the ldiff files of the update", &[("game_folder", "game folder")]),
+    ("repair", &[], "Assemble missing or corrupted files again", &[
+        ("game_folder", "game folder"),
+        ("chunk_folder", "chunk folder"),
+        ("chunk_manifest", "manifest"),
+    ]),
+    ("install", &[], "Install the full build into an empty folder", &[("target_folder", "target folder")]),
+    ("estimate", &[], "Tell the space and time a package takes", &[
+        ("game_folder", "game folder"),
+        ("package_path", "package or chunk manifest"),
+    ]),
+    ("support-matrix", &[], "List the package formats and profiles this build supports", &[]),
+    ("report", &[], "Summarize event logs", &[]),
+    ("report analyze", &[], "Summarize an event log", &[("event_log", "event log")]),
+    ("state", &[], "Manage skipped files", &[]),
+    ("state skip", &[], "Never patch or verify files again", &[("game_folder", "game folder"), ("files", "file...")]),
+    ("state list", &[], "List the files skipped in a game folder", &[("game_folder", "game folder")]),
+    ("state clear", &[], "Forget the files skipped in a game folder", &[("game_folder", "game folder")]),
+];
+
+/// Values the arguments of commands are limited to
+const CHOICES: &[(&str, &[&str])] = &[
+    ("target_action", &["0", "1", "2", "hdiff", "ldiff", "chunk"]),
+];
+
+/// Flags that are on or off, `--flag=false` turns one off
+const FLAGS: &[(&str, &str)] = &[
+    ("background", "Answer follow-up prompts with their defaults and notify when done"),
+    ("non-interactive", "Never read from the terminal"),
+    ("yes", "Answer every yes/no question --answers does not with yes"),
+    ("no-verify", "Skip verification after patching"),
+    ("all", "Apply every package of the kind the action takes found in the game folder"),
+    ("parallel", "Patch every target of action 3 at the same time"),
+    ("redact", "Replace the game folder and home directory paths in the diagnostic bundle"),
+    ("no-local-chunks", "Download every chunk instead of copying those the installed files hold"),
+    ("no-adaptive", "Patch with every --io-threads thread from the start"),
+    ("background-io", "Run with low CPU and I/O priority"),
+    ("allow-protected", "Let deletion and patching touch protected paths"),
+    ("no-color", "Disable colored output"),
+    ("ignore-receipt", "Patch even when the receipt says the package is already applied"),
+    ("skip-failed", "Remember files that failed to patch and skip them in later runs"),
+    ("keep-workdir", "Leave extracted and staged files behind for inspection"),
+    ("consolidate", "Rewrite fragmented large files after action 2 on spinning disks, Windows only"),
+    ("verbose", "Print run statistics at the end"),
+];
+
+/// Flags that take a value, either as `--flag value` or `--flag=value`
+const VALUE_FLAGS: &[(&str, &str, &str)] = &[
+    ("action", "action", "Action to run, instead of the first argument"),
+    ("game-dir", "path", "Game folder, instead of the second argument"),
+    ("package", "path", "Package, instead of the third argument"),
+    ("manifest", "path", "Manifest, instead of the fourth argument"),
+    ("answers", "path", "Answer follow-up questions from a TOML file"),
+    ("start-at", "HH:MM", "Wait until the given local time before starting"),
+    ("wait-for-process-exit", "exe", "Wait until the given process has exited before starting"),
+    ("metrics-file", "path", "Write run counters and stage durations to a Prometheus textfile"),
+    ("progress", "mode", "Print plain progress lines with `plain` instead of drawing bars"),
+    ("progress-interval", "secs", "Seconds between plain progress lines"),
+    ("verify-mode", "mode", "touched, full, manifest, quick, modified or official"),
+    ("build-url", "url", "Sophon getBuild URL of the version patched to, for --verify-mode official"),
+    ("api-url", "url", "Sophon build API the download commands and install ask"),
+    ("package-id", "id", "Package ID of the game the download commands and install ask for"),
+    ("branch", "branch", "Branch the download commands and install ask for"),
+    ("password", "password", "Branch password the download commands and install send"),
+    ("matching-field", "field", "Part of the build to fetch, game or an audio language"),
+    ("from-version", "version", "Only download the ldiff files patching from this version"),
+    ("archive-index", "path", "Folder with the _db indexes of the chunk archives download chunk fetches ranges of"),
+    ("mirrors", "urls", "Further hosts serving the build files, separated by commas, downloads fail over to"),
+    ("connections-per-host", "count", "Files downloaded from one host at the same time"),
+    ("threads", "count", "Default for both --hash-threads and --io-threads"),
+    ("hash-threads", "count", "Threads used for hashing"),
+    ("io-threads", "count", "Threads used for patching and assembling files"),
+    ("cpu-policy", "policy", "none, hybrid or performance"),
+    ("ignore-list", "path", "Skip the files listed in the given file"),
+    ("hpatchz-path", "path", "Run the given hpatchz instead of the embedded one"),
+    ("temp-dir", "path", "Folder for the extracted hpatchz and other temporary files"),
+    ("staging-dir", "path", "Stage ldiff payloads and decompressed chunks in the given folder"),
+    ("temp-budget", "size", "Most space extracted and staged files may take, e.g. 20G"),
+    ("cleanup", "policy", "keep, archive-only or all"),
+    ("on-error", "policy", "continue or abort"),
+    ("profile", "name", "Built-in quirk profile or profile file to use"),
+    ("output-image", "path", "Pack the assets of action 2 into the given image file or block device"),
+    ("existing", "policy", "overwrite, skip or backup"),
+    ("zstd-dict-dir", "path", "Folder holding the zstd dictionaries of chunks"),
+    ("prefetch-depth", "count", "Chunks read ahead while action 2 assembles assets"),
+    ("event-log", "path", "Append stages and patched files to the given JSONL file"),
+    ("config", "path", "Read options from the given TOML file"),
+];
+
+pub struct Args {
+    command: Option<String>,
+    positional: HashMap<String, Vec<String>>,
+    flags: HashMap<String, bool>,
+    values: HashMap<String, String>,
+}
+
+impl Args {
+    /// Parse the process arguments, exiting with usage on unknown commands or flags
+    pub fn parse() -> Args {
+        Self::from(env::args())
+    }
+
+    pub fn from<I: IntoIterator<Item = String>>(iter: I) -> Args {
+        let root = command().get_matches_from(iter);
+
+        // Global flags land in the matches of the command they follow as well
+        let mut names = Vec::new();
+        let mut matches = &root;
+        while let Some((name, command)) = matches.subcommand() {
+            names.push(name);
+            matches = command;
+        }
+        let command = (!names.is_empty()).then(|| names.join(" "));
+
+        let positional = command.as_deref().map_or(&[][..], params).iter()
+            .filter_map(|(id, _)| Some((id.to_string(), matches.get_many::<String>(id)?.cloned().collect())))
+            .collect();
+        let flags = FLAGS.iter()
+            .filter_map(|(name, _)| Some((name.to_string(), *matches.get_one::<bool>(name)?)))
+            .collect();
+        let values = VALUE_FLAGS.iter()
+            .filter_map(|(name, _, _)| Some((name.to_string(), matches.get_one::<String>(name)?.clone())))
+            .collect();
+        Args { command, positional, flags, values }
+    }
+
+    /// The command given, by its name with aliases resolved, e.g. `batch` or `chunks stats`
+    pub fn command(&self) -> Option<&str> {
+        self.command.as_deref()
+    }
+
+    /// Get an argument of the command by its ID, the first value of one taking several
+    pub fn arg(&self, id: &str) -> Option<String> {
+        self.positional.get(id)?.first().cloned()
+    }
+
+    /// Get every value of an argument of the command taking several
+    pub fn args(&self, id: &str) -> Vec<String> {
+        self.positional.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Whether a flag was turned on or off, if it was given
+    pub fn flag(&self, name: &str) -> Option<bool> {
+        self.flags.get(name).copied()
+    }
+
+    /// Get the value of a flag, if any
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+/// Resolve a command or one of its aliases to its name, e.g. `3` to `batch`
+pub fn resolve(name: &str) -> Option<&'static str> {
+    COMMANDS.iter()
+        .find(|(command, aliases, _, _)| *command == name || aliases.contains(&name))
+        .map(|(command, _, _, _)| *command)
+}
+
+/// Arguments of a command by ID and value name, in the order they are given
+pub fn params(command: &str) -> Params {
+    COMMANDS.iter()
+        .find(|(name, _, _, _)| *name == command)
+        .map_or(&[], |(_, _, _, params)| params)
+}
+
+fn subcommand(
+    name: &'static str,
+    aliases: &[&'static str],
+    about: &'static str,
+    params: Params,
+) -> Command {
+    let args = params.iter().enumerate().map(|(index, &(id, value_name))| {
+        let (value_name, num_args) = match value_name.strip_suffix("...") {
+            Some(value_name) => (value_name, ValueRange::new(1..)),
+            None => (value_name, ValueRange::SINGLE),
+        };
+        let arg = Arg::new(id).value_name(value_name).index(index + 1).num_args(num_args);
+        match CHOICES.iter().find(|(choice, _)| *choice == id) {
+            Some((_, values)) => arg.value_parser(values.to_vec()),
+            None => arg,
+        }
+    });
+    Command::new(name).visible_aliases(aliases.iter().copied()).about(about).args(args)
+}
+
+fn command() -> Command {
+    let commands = COMMANDS.iter().filter(|(name, _, _, _)| !name.contains(' ')).map(|&(name, aliases, about, params)| {
+        let subcommands = COMMANDS.iter()
+            .filter_map(|&(sub, aliases, about, params)| {
+                let sub = sub.strip_prefix(name)?.strip_prefix(' ')?;
+                Some(subcommand(sub, aliases, about, params))
+            })
+            .collect::<Vec<_>>();
+        subcommand(name, aliases, about, params)
+            .subcommand_required(!subcommands.is_empty())
+            .subcommands(subcommands)
+    });
+    let flags = FLAGS.iter().map(|&(name, help)| {
+        Arg::new(name)
+            .long(name)
+            .help(help)
+            .global(true)
+            .value_name("bool")
+            .hide_possible_values(true)
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("true")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::Set)
+    });
+    let values = VALUE_FLAGS.iter().map(|&(name, value_name, help)| {
+        Arg::new(name).long(name).value_name(value_name).help(help).global(true).action(ArgAction::Set)
+    });
+
+    Command::new("SophonPatcher")
+        .about("A tool to patch game client with multiple ways")
+        .version(env!("CARGO_PKG_VERSION"))
+        .after_help("Any argument left out is asked for interactively")
+        .subcommands(commands)
+        .args(flags)
+        .args(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::from(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_turn_on_and_off() {
+        let args = args(&["SophonPatcher", "hdiff", "--yes", "--no-verify=false", "game", "--background=0"]);
+        assert_eq!(args.flag("yes"), Some(true));
+        assert_eq!(args.flag("no-verify"), Some(false));
+        assert_eq!(args.flag("background"), Some(false));
+        assert_eq!(args.flag("verbose"), None);
+        assert_eq!(args.arg("game_folder").as_deref(), Some("game"));
+    }
+
+    #[test]
+    fn values_and_arguments() {
+        let args = args(&["SophonPatcher", "0", "--cleanup", "keep", "game", "--profile=hsr", "package.zip"]);
+        assert_eq!(args.command(), Some("hdiff"));
+        assert_eq!(args.arg("game_folder").as_deref(), Some("game"));
+        assert_eq!(args.arg("hdiff_archive").as_deref(), Some("package.zip"));
+        assert_eq!(args.value("cleanup"), Some("keep"));
+        assert_eq!(args.value("profile"), Some("hsr"));
+    }
+
+    #[test]
+    fn commands_by_name_and_number() {
+        let batch = args(&["SophonPatcher", "3", "targets.txt", "chunk", "chunks", "manifest", "--parallel"]);
+        assert_eq!(batch.command(), Some("batch"));
+        assert_eq!(batch.arg("target_action").as_deref(), Some("chunk"));
+        assert_eq!(batch.arg("chunk_manifest").as_deref(), Some("manifest"));
+        assert_eq!(batch.flag("parallel"), Some(true));
+        assert_eq!(resolve("6"), Some("diagnose"));
+
+        let skip = args(&["SophonPatcher", "state", "skip", "game", "a.dat", "b.dat", "--yes"]);
+        assert_eq!(skip.command(), Some("state skip"));
+        assert_eq!(skip.args("files"), ["a.dat", "b.dat"]);
+        assert_eq!(skip.flag("yes"), Some(true));
+    }
+
+    #[test]
+    fn unknown_flags_and_commands_are_rejected() {
+        assert!(command().try_get_matches_from(["SophonPatcher", "hdiff", "--no-verfy"]).is_err());
+        assert!(command().try_get_matches_from(["SophonPatcher", "hdif"]).is_err());
+        assert!(command().try_get_matches_from(["SophonPatcher", "--yes=maybe"]).is_err());
+        assert!(command().try_get_matches_from(["SophonPatcher", "batch", "targets.txt", "patch"]).is_err());
+        assert!(command().try_get_matches_from(["SophonPatcher", "state", "forget", "game"]).is_err());
+        assert!(command().try_get_matches_from(["SophonPatcher", "export", "game", "listing", "extra"]).is_err());
+    }
+
+    #[test]
+    fn command_table_is_valid() {
+        command().debug_assert();
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use indicatif::{ProgressBar, ProgressStyle};
+use sophon::sophon::{Phase, ProgressSink};
+use crate::{log, metrics, overall, status, util};
+
+// Sink of a launcher embedding the patcher, the sophon stages are drawn as bars without one
+static SINK: OnceLock<Arc<dyn ProgressSink>> = OnceLock::new();
+
+/// Report the sophon stages of every action to this sink instead of drawing bars
+pub fn set_sink(sink: Arc<dyn ProgressSink>) {
+    let _ = SINK.set(sink);
+}
+
+/// Where an action reports the progress of the stages the sophon crate works through
+pub fn sink() -> Arc<dyn ProgressSink> {
+    match SINK.get() {
+        Some(sink) => Arc::clone(sink),
+        None => Arc::new(BarSink::default()),
+    }
+}
+
+/// Progress bars of the stages of an action, each finished with a line saying what the stage did
+///
+/// Bars still running when the action stops early are left drawn as they were instead of being cleared
+#[derive(Default)]
+pub struct Bars {
+    bars: Vec<ProgressBar>,
+}
+
+impl Bars {
+    pub fn new() -> Bars {
+        Bars::default()
+    }
+
+    /// Show the bar of a stage going through `len` items
+    pub fn add(&mut self, len: u64) -> ProgressBar {
+        let pb = util::create_progress_bar(len);
+        self.bars.push(pb.clone());
+        pb
+    }
+
+    /// Replace the bar of a finished stage with a line like "Patched 1532 files in 4m12s"
+    pub fn finish(&self, pb: &ProgressBar, done: &str, items: &str) {
+        let line = format!("{} {} {} in {}", done, pb.position(), items, duration(pb.elapsed()));
+        if pb.is_hidden() {
+            log::info!("{}", line);
+        } else {
+            pb.set_style(ProgressStyle::with_template("  {prefix}").expect("Failed to set progress bar template"));
+            pb.set_prefix(line);
+        }
+        pb.finish();
+    }
+}
+
+/// Sophon stages drawn as bars like the other stages of an action, their bytes counted into the overall progress
+#[derive(Default)]
+pub struct BarSink {
+    bars: Mutex<Bars>,
+    running: Mutex<Vec<(Phase, ProgressBar)>>,
+}
+
+impl BarSink {
+    fn bar(&self, phase: Phase) -> Option<ProgressBar> {
+        self.running.lock().unwrap().iter().find(|(running, _)| *running == phase).map(|(_, pb)| pb.clone())
+    }
+}
+
+impl ProgressSink for BarSink {
+    fn on_stage_start(&self, phase: Phase, items: u64) {
+        let mut running = self.running.lock().unwrap();
+        if let Some((_, pb)) = running.iter().find(|(running, _)| *running == phase) {
+            pb.inc_length(items);
+            return;
+        }
+        log::info!("{}", match phase {
+            Phase::Staging => "Extracting chunk files",
+            Phase::Assembly => "Merging chunk files",
+            Phase::Download => "Downloading files",
+            Phase::Matching => "Looking for chunks in local files",
+            _ => "Working",
+        });
+        let pb = self.bars.lock().unwrap().add(items);
+        if phase == Phase::Assembly {
+            status::attach(&pb);
+        }
+        running.push((phase, pb));
+    }
+
+    fn on_item_done(&self, phase: Phase) {
+        if let Some(pb) = self.bar(phase) {
+            pb.inc(1);
+        }
+        if phase == Phase::Assembly {
+            metrics::inc(&metrics::FILES_PATCHED);
+        }
+    }
+
+    fn on_bytes(&self, phase: Phase, bytes: u64) {
+        if phase == Phase::Assembly {
+            metrics::add(&metrics::BYTES_WRITTEN, bytes);
+        }
+        overall::sophon_progress(phase, bytes);
+    }
+
+    fn on_finish(&self, phase: Phase) {
+        let Some(pb) = self.bar(phase) else {
+            return;
+        };
+        self.running.lock().unwrap().retain(|(running, _)| *running != phase);
+        match phase {
+            Phase::Staging => self.bars.lock().unwrap().finish(&pb, "Staged", "chunk files"),
+            Phase::Assembly => self.bars.lock().unwrap().finish(&pb, "Assembled", "files"),
+            Phase::Download => self.bars.lock().unwrap().finish(&pb, "Downloaded", "files"),
+            Phase::Matching => self.bars.lock().unwrap().finish(&pb, "Searched", "local files"),
+            _ => self.bars.lock().unwrap().finish(&pb, "Done", "items"),
+        }
+    }
+}
+
+impl Drop for Bars {
+    fn drop(&mut self) {
+        for pb in self.bars.iter().filter(|pb| !pb.is_finished()) {
+            pb.abandon();
+        }
+    }
+}
+
+/// Short duration like `4m12s`
+fn duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{:.1}s", duration.as_secs_f64()),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m{:02}s", seconds / 3600, seconds / 60 % 60, seconds % 60),
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use indicatif::{HumanBytes, MultiProgress};
+use rayon::prelude::*;
+use sophon::proto::chunk::{AssetProperty, SophonChunkProto};
+use sophon::sophon::ChunkLayout;
+use crate::{log, util};
+
+/// Print how a chunk manifest splits its assets, how much chunks are shared and what that saves
+pub fn stats(manifest_path: &Path) -> Result<()> {
+    let manifest = read_manifest(manifest_path)?;
+
+    // Decompressed and compressed size of every chunk, however many assets use it
+    let mut references = 0u64;
+    let mut referenced_bytes = 0u64;
+    let mut unique: HashMap<&str, (u64, u64)> = HashMap::new();
+    for chunk in manifest.assets.iter().flat_map(|asset| &asset.asset_chunks) {
+        references += 1;
+        referenced_bytes += chunk.chunk_size_decompressed as u64;
+        unique.insert(&chunk.chunk_name, (chunk.chunk_size_decompressed as u64, chunk.chunk_size as u64));
+    }
+    let unique_bytes = unique.values().map(|(size, _)| size).sum::<u64>();
+    let download_bytes = unique.values().map(|(_, size)| size).sum::<u64>();
+    let saved = referenced_bytes.saturating_sub(unique_bytes);
+
+    log::info!(
+        "{} assets, {} in total",
+        manifest.assets.len(),
+        HumanBytes(manifest.assets.iter().map(|asset| asset.asset_size as u64).sum()),
+    );
+    log::info!(
+        "{} chunk references, {} unique chunks ({:.1}%)",
+        references,
+        unique.len(),
+        unique.len() as f64 * 100.0 / references.max(1) as f64,
+    );
+    log::info!(
+        "Dedup saves {} of {} ({:.1}%)",
+        HumanBytes(saved),
+        HumanBytes(referenced_bytes),
+        saved as f64 * 100.0 / referenced_bytes.max(1) as f64,
+    );
+    if download_bytes > 0 {
+        log::info!(
+            "Download size {}, {} once decompressed ({:.1}% compression)",
+            HumanBytes(download_bytes),
+            HumanBytes(unique_bytes),
+            100.0 - download_bytes as f64 * 100.0 / unique_bytes.max(1) as f64,
+        );
+    }
+
+    // Unique chunks by decompressed size, in power of two buckets
+    let mut histogram: BTreeMap<u32, u64> = BTreeMap::new();
+    for (size, _) in unique.values() {
+        *histogram.entry(size.max(&1).ilog2()).or_default() += 1;
+    }
+    if !histogram.is_empty() {
+        log::info!("Chunk sizes:");
+    }
+    let widest = histogram.values().copied().max().unwrap_or(1);
+    for (bucket, count) in histogram {
+        log::info!(
+            "  {:>10} - {:<10} {:>8} {}",
+            HumanBytes(1 << bucket).to_string(),
+            HumanBytes((1 << (bucket + 1)) - 1).to_string(),
+            count,
+            "#".repeat((count * 40).div_ceil(widest) as usize),
+        );
+    }
+    Ok(())
+}
+
+/// Where an asset of the manifest stands in the game folder
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Matching,
+    Changed,
+    Missing,
+}
+
+/// Report which assets of a chunk manifest the game folder already has and which assembling would write, without
+/// touching either folder
+///
+/// Assets are told apart by size first and md5 second, so only files of the right size are hashed. The chunks the
+/// changed assets need are looked up in the chunk folder when it holds loose chunk files
+pub fn compare(chunk_path: &Path, manifest_path: &Path, game_path: &Path) -> Result<()> {
+    let manifest = read_manifest(manifest_path)?;
+    if !game_path.is_dir() {
+        return Err(anyhow!("{:?} does not exist", game_path));
+    }
+
+    log::info!("Comparing {} assets with {}", manifest.assets.len(), game_path.display());
+    let pb = util::create_progress_bar(manifest.assets.len() as u64);
+    let multi = util::create_multi_progress();
+    let mut statuses = manifest.assets.par_iter()
+        .map(|asset| {
+            pb.inc(1u64);
+            (asset, status(asset, game_path, &multi))
+        })
+        .collect::<Vec<_>>();
+    pb.finish_and_clear();
+    statuses.sort_by(|(a, _), (b, _)| a.asset_name.cmp(&b.asset_name));
+
+    let count = |wanted: Status| statuses.iter().filter(|&&(_, status)| status == wanted).count();
+    for &(asset, status) in &statuses {
+        match status {
+            Status::Changed => log::info!("  changed {}", asset.asset_name),
+            Status::Missing => log::info!("  new     {}", asset.asset_name),
+            Status::Matching => {}
+        }
+    }
+    let changing = statuses.iter()
+        .filter(|&&(_, status)| status != Status::Matching)
+        .map(|&(asset, _)| asset)
+        .collect::<Vec<_>>();
+    log::info!(
+        "{} assets already match, {} would change and {} would be added, writing {}",
+        count(Status::Matching),
+        count(Status::Changed),
+        count(Status::Missing),
+        HumanBytes(changing.iter().map(|asset| asset.asset_size as u64).sum()),
+    );
+
+    // Chunks the changing assets are assembled from
+    let needed = changing.iter()
+        .flat_map(|asset| &asset.asset_chunks)
+        .map(|chunk| (chunk.chunk_name.clone(), chunk.chunk_size_decompressed))
+        .collect::<HashMap<_, _>>();
+    if needed.is_empty() {
+        log::ok!("The game folder already matches the manifest, no assembly needed");
+        return Ok(());
+    }
+    match ChunkLayout::detect(chunk_path, &needed) {
+        Ok(ChunkLayout::Loose) => {
+            let missing = needed.keys().filter(|name| !chunk_path.join(name).is_file()).count();
+            if missing == 0 {
+                log::ok!("All {} chunks the changes need are in {}", needed.len(), chunk_path.display());
+            } else {
+                log::warn!("{} of the {} chunks the changes need are missing", missing, needed.len());
+            }
+        }
+        Ok(_) => log::info!("The changes need {} chunks from the indexed archives", needed.len()),
+        Err(_) => log::warn!("{} holds none of the {} chunks the changes need", chunk_path.display(), needed.len()),
+    }
+    Ok(())
+}
+
+fn status(asset: &AssetProperty, game_path: &Path, multi: &MultiProgress) -> Status {
+    let path = game_path.join(&asset.asset_name);
+    match path.metadata() {
+        Ok(metadata) if metadata.len() != asset.asset_size as u64 => Status::Changed,
+        Ok(_) if asset.asset_hash_md5.is_empty() => Status::Matching,
+        Ok(_) => match util::calculate_md5_hash_with_progress(&path, multi) {
+            Ok(md5) if md5.eq_ignore_ascii_case(&asset.asset_hash_md5) => Status::Matching,
+            _ => Status::Changed,
+        },
+        Err(_) => Status::Missing,
+    }
+}
+
+fn read_manifest(manifest_path: &Path) -> Result<SophonChunkProto> {
+    if !manifest_path.is_file() {
+        return Err(anyhow!("{:?} does not exist", manifest_path));
+    }
+    let manifest = util::with_spinner(&format!("Reading {}", manifest_path.display()), || {
+        SophonChunkProto::from(manifest_path.to_string_lossy().to_string())
+    }).map_err(|e| anyhow!("Failed to read chunk manifest {:?}: {}", manifest_path, e))?;
+    if manifest.assets.is_empty() {
+        return Err(anyhow!("Chunk manifest {:?} holds no assets, its download may have been cut short", manifest_path));
+    }
+    Ok(manifest)
+}
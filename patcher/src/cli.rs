@@ -0,0 +1,537 @@
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use sophon::download::BuildQuery;
+use crate::action::{Detail, PatchOptions, PatchOutcome, ProgressMode};
+use crate::args::{self, Args};
+use crate::options::Options;
+use crate::hpatchz::HPatchZ;
+use crate::util::PathKind;
+use crate::{
+    action, adaptive, chunks, cpu, diagnose, disk, download, estimate, events, handles, log, metrics, notify, official,
+    orchestrate, overall, pools, priority, quirks, receipt, schedule, simulate, state, support, update, util,
+};
+
+/// Run the command line of the SophonPatcher binary and exit with its status
+pub async fn main() {
+    let args = match Options::load(Args::parse()) {
+        Ok(options) => options,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let options = match PatchOptions::resolve(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let background = args.flag("background");
+
+    // Configure output
+    log::init(args.flag("no-color"));
+    log::set_verbose(args.flag("verbose"));
+    if let ProgressMode::Plain(interval) = options.progress {
+        util::set_plain_progress(interval);
+    }
+
+    // Never block on prompts nobody can answer
+    let interactive = io::stdin().is_terminal() && !args.flag("non-interactive");
+    if !interactive {
+        if !args.flag("non-interactive") {
+            log::info!("Input is not a terminal, prompts are answered with their defaults");
+        }
+        util::set_unattended(true);
+    }
+
+    if let Err(err) = args.value("event-log").map(|path| events::set_path(Path::new(path))).transpose() {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+    events::emit(events::Event::RunStart { action: args.command().unwrap_or_default() });
+    if background {
+        notify::show_tray();
+    }
+    overall::start();
+    sophon::sophon::set_message_hook(log::sophon_message);
+    let result = run(&args, options.clone()).await;
+    overall::finish();
+    notify::hide_tray();
+
+    // Cleanup hpatchz temp file
+    if !options.keep_workdir {
+        let _ = HPatchZ::cleanup();
+    } else if let Some(work_dir) = HPatchZ::work_dir() {
+        log::info!("Keeping hpatchz work directory {}", work_dir.display());
+    }
+
+    // Report result
+    let status = match result {
+        Ok(outcome) => {
+            let status = match outcome {
+                Some(outcome) => {
+                    report(&outcome);
+                    disk::report();
+                    if log::is_verbose() {
+                        report_stats();
+                    }
+                    if outcome.is_clean() { 0 } else { 2 }
+                }
+                None => 0,
+            };
+            log::ok!("Done");
+            if background {
+                notify::send("SophonPatcher", "Patching finished successfully");
+            }
+            status
+        }
+        Err(err) => {
+            log::error!("{}", err);
+            if background {
+                notify::send("SophonPatcher", &format!("Patching failed: {}", err));
+            }
+            1
+        }
+    };
+
+    events::emit(events::Event::RunEnd { status });
+
+    // Pause
+    if !background && interactive {
+        util::input("Press Enter to continue...");
+    }
+    std::process::exit(status);
+}
+
+async fn run(args: &Options, mut options: PatchOptions) -> Result<Option<PatchOutcome>> {
+    // Ask for input
+    let command = args.command()
+        .unwrap_or_else(|| {
+            println!("[Options]");
+            println!("0 - Patch game by hdiff");
+            println!("1 - Patch game by ldiff");
+            println!("2 - Patch game by chunk");
+            println!("3 - Patch multiple game folders");
+            println!("4 - Simulate patch against a file listing");
+            println!("5 - Export file listing of game folder");
+            println!("6 - Create diagnostic bundle");
+            println!("7 - Update game with the packages in its folder");
+            let action = util::input("Please select action: ");
+            args::resolve(&action).map_or(action, str::to_string)
+        });
+    match command.as_str() {
+        "hdiff" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            let hdiff_files = match args.arg("hdiff_archive") {
+                Some(hdiff_file) => vec![hdiff_file],
+                None => match update::detect(&game_folder)?.hdiff {
+                    found if found.is_empty() => vec![util::input_path(
+                        "Please enter hdiff file name: ",
+                        Some(&game_folder),
+                        PathKind::File,
+                    )?],
+                    found => pick(args, &game_folder, &found, "hdiff archives", String::clone)?,
+                },
+            };
+            let hdiff_files = hdiff_files.iter()
+                .map(|hdiff_file| util::resolve_package(&game_folder, hdiff_file))
+                .collect::<io::Result<Vec<_>>>()?;
+            prepare(args, &options).await?;
+            let mut outcome = PatchOutcome::default();
+            for hdiff_file in hdiff_files {
+                if !update::proceeds(&outcome) {
+                    break;
+                }
+                outcome.merge(action::hdiff(&game_folder, hdiff_file, &options).await?);
+            }
+            Ok(Some(outcome))
+        },
+        "ldiff" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            // Archives picked from the menu are parts of one update, applied together
+            let ldiff_archives = match args.args("ldiff_archives") {
+                found if !found.is_empty() => found,
+                _ => match update::detect(&game_folder)?.ldiff {
+                    found if found.is_empty() => vec![util::input_path(
+                        "Please enter ldiff folder: ",
+                        Some(&game_folder),
+                        PathKind::File,
+                    )?],
+                    found => pick(args, &game_folder, &found, "ldiff archives", String::clone)?,
+                },
+            };
+            let mut ldiff_files = Vec::new();
+            for ldiff_file in ldiff_archives {
+                ldiff_files.extend(util::expand_package(&game_folder, &ldiff_file)?);
+            }
+            prepare(args, &options).await?;
+            action::ldiff(&game_folder, ldiff_files, &options).await.map(Some)
+        },
+        "chunk" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            let packages = chunk_packages(args, &game_folder)?;
+            prepare(args, &options).await?;
+            let mut outcome = PatchOutcome::default();
+            for (chunk_folder, manifest_name) in packages {
+                if !update::proceeds(&outcome) {
+                    break;
+                }
+                outcome.merge(action::chunk(&game_folder, chunk_folder, manifest_name, &options).await?);
+            }
+            Ok(Some(outcome))
+        },
+        "repair" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            let packages = chunk_packages(args, &game_folder)?;
+            prepare(args, &options).await?;
+            let mut outcome = PatchOutcome::default();
+            for (chunk_folder, manifest_name) in packages {
+                outcome.merge(action::repair(&game_folder, chunk_folder, manifest_name, &options).await?);
+            }
+            Ok(Some(outcome))
+        },
+        "update" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            prepare(args, &options).await?;
+            update::update(&game_folder, &options).await.map(Some)
+        },
+        "batch" => {
+            let targets_file = args.arg("targets_file")
+                .map_or_else(|| util::input_path("Please enter targets list file: ", None, PathKind::File), Ok)?;
+            let targets_file = util::absolute_path(&targets_file)?;
+            let action = args.arg("target_action")
+                .unwrap_or_else(|| util::input("Please select action for every target (0/1/2): "));
+            let action = match action.as_str() {
+                "hdiff" => "0".to_string(),
+                "ldiff" => "1".to_string(),
+                "chunk" => "2".to_string(),
+                _ => action,
+            };
+            let package = args.arg("package_path")
+                .map_or_else(|| util::input_path("Please enter package path: ", None, PathKind::File), Ok)?;
+            let manifest = match action.as_str() {
+                "2" => Some(args.arg("chunk_manifest")
+                    .map_or_else(|| util::input_path("Please enter manifest path: ", None, PathKind::File), Ok)?),
+                _ => None,
+            };
+            prepare(args, &options).await?;
+
+            // Never prompt per target and keep the shared package around
+            util::set_unattended(true);
+            util::set_answer("delete", false);
+            options.shared_package = true;
+            orchestrate::orchestrate(
+                &targets_file,
+                action,
+                package,
+                manifest,
+                args.flag("parallel"),
+                &options,
+            ).await.map(Some)
+        },
+        "simulate" => {
+            let listing = args.arg("listing")
+                .map_or_else(|| util::input_path("Please enter file listing path: ", None, PathKind::File), Ok)?;
+            let package = args.arg("package_path")
+                .map_or_else(|| util::input_path("Please enter package path: ", None, PathKind::File), Ok)?;
+            prepare(args, &options).await?;
+            simulate::simulate(Path::new(&listing), Path::new(&package)).map(|_| None)
+        },
+        "export" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            let output = args.arg("output")
+                .map_or_else(|| util::input_path("Please enter output file path: ", None, PathKind::Any), Ok)?;
+            prepare(args, &options).await?;
+            simulate::export_listing(&game_folder, Path::new(&output)).map(|_| None)
+        },
+        "diagnose" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            let output = args.arg("output")
+                .unwrap_or_else(|| "sophon_diagnose.zip".to_string());
+            diagnose::diagnose(&game_folder, Path::new(&output), args.flag("redact")).map(|_| None)
+        },
+        "state skip" | "state clear" | "state list" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            match command.as_str() {
+                "state skip" => {
+                    let paths = args.args("files");
+                    if paths.is_empty() {
+                        return Err(anyhow!("No files given to skip"));
+                    }
+                    state::skip(&game_folder, &paths).map(|_| None)
+                }
+                "state clear" => state::clear(&game_folder).map(|_| None),
+                _ => state::list(&game_folder).map(|_| None),
+            }
+        },
+        "chunks stats" => {
+            let manifest = args.arg("chunk_manifest")
+                .map_or_else(|| util::input_path("Please enter chunk manifest path: ", None, PathKind::File), Ok)?;
+            chunks::stats(&util::absolute_path(&manifest)?).map(|_| None)
+        },
+        "chunks compare" => {
+            let chunk_folder = args.arg("chunk_folder")
+                .map_or_else(|| util::input_path("Please enter chunk folder: ", None, PathKind::Folder), Ok)?;
+            let manifest = args.arg("chunk_manifest")
+                .map_or_else(|| util::input_path("Please enter chunk manifest path: ", None, PathKind::File), Ok)?;
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let chunk_folder = util::absolute_path(&chunk_folder)?;
+            let manifest = util::absolute_path(&manifest)?;
+            chunks::compare(&chunk_folder, &manifest, &util::absolute_path(&game_folder)?).map(|_| None)
+        },
+        "download chunk" | "download ldiff" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            let query = build_query(args)?;
+            let matching_field = args.value("matching-field").unwrap_or("game");
+            prepare(args, &options).await?;
+            match command.as_str() {
+                "download chunk" => download::chunk(&game_folder, &query, matching_field, args.flag("no-local-chunks"))
+                    .map(|_| None),
+                _ => download::ldiff(&game_folder, &query, matching_field, args.value("from-version"))
+                    .map(|_| None),
+            }
+        },
+        "install" => {
+            let target_folder = args.arg("target_folder")
+                .map_or_else(|| util::input_path("Please enter folder to install to: ", None, PathKind::Any), Ok)?;
+            let target_folder = util::absolute_path(&target_folder)?;
+            let query = build_query(args)?;
+            prepare(args, &options).await?;
+            action::install(&target_folder, &query, args.value("matching-field").unwrap_or("game"), &options)
+                .await
+                .map(Some)
+        },
+        "estimate" => {
+            let game_folder = args.arg("game_folder")
+                .map_or_else(|| util::input_path("Please enter game folder: ", None, PathKind::Folder), Ok)?;
+            let game_folder = util::absolute_path(&game_folder)?;
+            let package = args.arg("package_path")
+                .map_or_else(|| util::input_path(
+                    "Please enter package or chunk manifest: ",
+                    Some(&game_folder),
+                    PathKind::Any,
+                ), Ok)?;
+            let package = game_folder.join(util::resolve_package(&game_folder, &package)?);
+            estimate::report(&game_folder, &package).map(|_| None)
+        },
+        "support-matrix" => support::matrix().map(|_| None),
+        "report analyze" => {
+            let event_log = args.arg("event_log")
+                .map_or_else(|| util::input_path("Please enter event log path: ", None, PathKind::File), Ok)?;
+            events::analyze(Path::new(&event_log)).map(|_| None)
+        },
+        _ => Err(anyhow!("Unknown command.")),
+    }
+}
+
+/// Packages found in the game folder an action applies, every one with `--all` and otherwise those picked from a menu
+fn pick<T: Clone>(
+    args: &Options,
+    game_folder: &Path,
+    found: &[T],
+    what: &str,
+    name: fn(&T) -> String,
+) -> Result<Vec<T>> {
+    if args.flag("all") {
+        return Ok(found.to_vec());
+    }
+    let names = found.iter().map(name).collect::<Vec<_>>();
+    Ok(util::select(what, game_folder, &names)?.into_iter().map(|index| found[index].clone()).collect())
+}
+
+/// Chunk folders with their manifest for actions `2` and `repair`, given as arguments or found in the game folder
+fn chunk_packages(args: &Options, game_folder: &Path) -> Result<Vec<(String, String)>> {
+    let found = match args.arg("chunk_folder") {
+        Some(_) => Vec::new(),
+        None => update::detect(game_folder)?.chunk,
+    };
+    if !found.is_empty() {
+        return pick(args, game_folder, &found, "chunk manifests", |(_, manifest_name)| manifest_name.clone());
+    }
+
+    let chunk_folder = args.arg("chunk_folder")
+        .map_or_else(|| util::input_path(
+            "Please enter chunk folder: ",
+            Some(game_folder),
+            PathKind::Folder,
+        ), Ok)?;
+    let manifest_name = args.arg("chunk_manifest")
+        .map_or_else(|| util::input_path(
+            "Please enter manifest name: ",
+            Some(game_folder),
+            PathKind::File,
+        ), Ok)?;
+    let chunk_folder = util::resolve_package(game_folder, &chunk_folder)?;
+    let manifest_name = util::resolve_package(game_folder, &manifest_name)?;
+    Ok(vec![(chunk_folder, manifest_name)])
+}
+
+/// The package the download commands ask the build API for
+fn build_query(args: &Options) -> Result<BuildQuery> {
+    Ok(BuildQuery {
+        api_url: args.value("api-url")
+            .ok_or_else(|| anyhow!("Downloading needs the build API given with --api-url"))?
+            .to_string(),
+        branch: args.value("branch").unwrap_or("main").to_string(),
+        package_id: args.value("package-id")
+            .ok_or_else(|| anyhow!("Downloading needs the package ID given with --package-id"))?
+            .to_string(),
+        password: args.value("password").unwrap_or_default().to_string(),
+    })
+}
+
+/// Print what an action did to the game folder
+fn report(outcome: &PatchOutcome) {
+    println!();
+    for (path, detail) in &outcome.details {
+        match detail {
+            Detail::Failed => log::error!("{} failed to patch!", path),
+            Detail::Protected => log::warn!("{} is protected, left untouched", path),
+            Detail::ReadOnly => log::warn!("{} was read-only, the attribute was cleared", path),
+            Detail::Mismatch { expected, found } => log::error!(
+                "{} hash does not match! Expected: {}, found: {}",
+                path,
+                expected,
+                found,
+            ),
+            Detail::Missing => log::error!("{} does not exist!", path),
+            Detail::Relocated { from } => log::warn!("{} was missing, patched from a copy of {}", path, from),
+            Detail::WrongSize { expected, found } => log::error!(
+                "{} was written with {} bytes, expected {}!",
+                path,
+                found,
+                expected,
+            ),
+            Detail::Kept => log::warn!("{} differs from the package, left as it was", path),
+            Detail::BackedUp => log::warn!("{} differed from the package, the old file is {}.bak", path, path),
+        }
+    }
+    log::info!(
+        "{} patched, {} skipped, {} failed, {} deleted, {} verified",
+        outcome.patched,
+        outcome.skipped,
+        outcome.failed,
+        outcome.deleted,
+        outcome.verified,
+    );
+}
+
+/// Statistics printed with --verbose
+fn report_stats() {
+    if let Some((workers, max)) = adaptive::settled() {
+        log::info!("Patching settled at {} of {} workers", workers, max);
+    }
+    let (hits, misses) = sophon::sophon::prefetch_stats();
+    if hits + misses > 0 {
+        log::info!(
+            "Prefetch: {} of {} chunks read ahead ({:.0}% hit rate)",
+            hits,
+            hits + misses,
+            hits as f64 * 100.0 / (hits + misses) as f64,
+        );
+    }
+    for host in sophon::download::host_stats() {
+        log::info!(
+            "Download: {} at up to {} connections, {} bytes, {} failed attempts",
+            host.url_prefix,
+            host.peak_connections,
+            host.bytes,
+            host.failures,
+        );
+    }
+}
+
+/// Everything that happens between collecting the inputs and starting the action
+async fn prepare(args: &Options, options: &PatchOptions) -> Result<()> {
+    if args.flag("background") {
+        util::set_unattended(true);
+    }
+    if args.flag("background-io") {
+        priority::lower();
+    }
+    if let Some(policy) = args.value("cpu-policy") {
+        cpu::set_policy(policy.parse()?);
+    }
+    pools::configure(options.hash_threads, options.io_threads)?;
+    handles::configure()?;
+    adaptive::set_adaptive(!args.flag("no-adaptive"));
+    quirks::set_allow_protected(args.flag("allow-protected"));
+    if let Some(profile) = args.value("profile") {
+        quirks::set_profile(profile.to_string());
+    }
+    receipt::set_ignored(args.flag("ignore-receipt"));
+    if let Some(path) = args.value("zstd-dict-dir") {
+        sophon::sophon::set_dictionary_path(path.into());
+    }
+    if let Some(path) = args.value("archive-index") {
+        sophon::download::set_archive_index(path.into());
+    }
+    if let Some(mirrors) = args.value("mirrors") {
+        let mirrors = mirrors.split(',').map(str::trim).filter(|mirror| !mirror.is_empty()).map(String::from);
+        sophon::download::set_mirrors(mirrors.collect());
+    }
+    if let Some(connections) = args.parsed("connections-per-host")? {
+        sophon::download::set_connections_per_host(connections);
+    }
+    if let Some(url) = args.value("build-url") {
+        official::set_build_url(url.to_string());
+    }
+    if let Some(path) = args.value("metrics-file") {
+        metrics::set_textfile(path.into());
+    }
+    if let Some(path) = args.value("hpatchz-path") {
+        HPatchZ::set_exe_path(path.into());
+    }
+    if let Some(path) = &options.temp_dir {
+        HPatchZ::set_temp_root(path.clone());
+    }
+    if let Some(depth) = args.parsed("prefetch-depth")? {
+        sophon::sophon::set_prefetch_depth(depth);
+    }
+    if let Some(size) = args.value("temp-budget") {
+        disk::set_budget(disk::parse_size(size)?);
+    }
+    if let Some(path) = args.value("answers") {
+        util::load_answers(Path::new(path))?;
+    }
+    if args.flag("yes") {
+        util::set_assume_yes(true);
+    }
+    if args.flag("no-verify") {
+        util::set_answer("verify", false);
+    }
+    if let Some(path) = args.value("ignore-list") {
+        util::load_ignore_list(Path::new(path))?;
+    }
+
+    // Defer the start if requested
+    if let Some(name) = args.value("wait-for-process-exit") {
+        schedule::wait_for_process_exit(name).await?;
+    }
+    if let Some(time) = args.value("start-at") {
+        schedule::wait_until(time).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+use crate::log;
+
+// Files smaller than this load fast enough however fragmented they are
+#[cfg(target_os = "windows")]
+const MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+// Files split into more pieces than this are rewritten
+#[cfg(target_os = "windows")]
+const MAX_EXTENTS: u64 = 32;
+
+/// Rewrite heavily fragmented large files the action wrote, as asked with --consolidate
+///
+/// Chunks are assembled in parallel at their offsets, which can leave huge files in thousands of pieces on spinning
+/// disks. Each such file is copied into a file allocated at its full size up front, so the file system can place it
+/// in one piece, and replaces the original. Disks without seek penalty are left alone
+#[cfg(target_os = "windows")]
+pub fn consolidate(game_path: &Path, written: &[String]) {
+    if windows::seek_penalty(game_path) == Some(false) {
+        log::info!("Skipping consolidation, {} is on a solid state disk", game_path.display());
+        return;
+    }
+
+    let large = written.iter()
+        .map(|path| game_path.join(path))
+        .filter(|path| path.metadata().is_ok_and(|metadata| metadata.len() >= MIN_SIZE))
+        .collect::<Vec<_>>();
+    let mut consolidated = 0;
+    for path in &large {
+        let extents = match windows::extents(path) {
+            Ok(extents) if extents > MAX_EXTENTS => extents,
+            Ok(_) => continue,
+            Err(e) => {
+                log::warn!("Could not tell how fragmented {} is: {}", path.display(), e);
+                continue;
+            }
+        };
+        match rewrite(path) {
+            Ok(()) => {
+                let now = windows::extents(path).unwrap_or(0);
+                log::info!("Consolidated {} from {} pieces into {}", path.display(), extents, now);
+                consolidated += 1;
+            }
+            Err(e) => log::warn!("Could not consolidate {}: {}", path.display(), e),
+        }
+    }
+    log::info!("Consolidated {} of {} large files", consolidated, large.len());
+}
+
+/// Other systems have no defragmentation API worth calling
+#[cfg(not(target_os = "windows"))]
+pub fn consolidate(_game_path: &Path, _written: &[String]) {
+    log::warn!("File consolidation is only done on Windows");
+}
+
+/// Copy a file into one allocated at its full size up front and put it in place of the original
+#[cfg(target_os = "windows")]
+fn rewrite(path: &Path) -> std::io::Result<()> {
+    use std::fs::{self, File};
+    use std::io::{self, BufReader, BufWriter, Write};
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".consolidating");
+    let temp_path = path.with_file_name(temp_name);
+    let result = (|| {
+        let mut source = BufReader::with_capacity(1 << 20, File::open(path)?);
+        let target = File::create(&temp_path)?;
+        target.set_len(source.get_ref().metadata()?.len())?;
+        let mut target = BufWriter::with_capacity(1 << 20, target);
+        io::copy(&mut source, &mut target)?;
+        target.flush()?;
+        target.get_ref().sync_all()
+    })();
+    match result {
+        Ok(()) => fs::rename(&temp_path, path),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+    use std::ffi::c_void;
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::path::{Component, Path, Prefix};
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn DeviceIoControl(
+            device: *mut c_void,
+            code: u32,
+            in_buffer: *const c_void,
+            in_size: u32,
+            out_buffer: *mut c_void,
+            out_size: u32,
+            returned: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+    }
+
+    const FSCTL_GET_RETRIEVAL_POINTERS: u32 = 0x0009_0073;
+    const IOCTL_STORAGE_QUERY_PROPERTY: u32 = 0x002D_1400;
+    const ERROR_MORE_DATA: i32 = 234;
+    const ERROR_HANDLE_EOF: i32 = 38;
+
+    // STORAGE_PROPERTY_QUERY asking for StorageDeviceSeekPenaltyProperty with PropertyStandardQuery
+    const SEEK_PENALTY_QUERY: [u32; 3] = [7, 0, 0];
+
+    /// Count the extents a file is stored in
+    pub fn extents(path: &Path) -> io::Result<u64> {
+        let file = File::open(path)?;
+
+        // RETRIEVAL_POINTERS_BUFFER: extent count, starting VCN, then a next VCN and LCN pair per extent
+        let mut buffer = vec![0i64; 8 * 1024];
+        let mut starting_vcn = 0i64;
+        let mut extents = 0u64;
+        loop {
+            let mut returned = 0u32;
+            let ok = unsafe {
+                DeviceIoControl(
+                    file.as_raw_handle(),
+                    FSCTL_GET_RETRIEVAL_POINTERS,
+                    &starting_vcn as *const i64 as *const c_void,
+                    size_of::<i64>() as u32,
+                    buffer.as_mut_ptr() as *mut c_void,
+                    (buffer.len() * size_of::<i64>()) as u32,
+                    &mut returned,
+                    std::ptr::null_mut(),
+                )
+            };
+            let error = io::Error::last_os_error();
+            if ok == 0 && error.raw_os_error() == Some(ERROR_HANDLE_EOF) {
+                // Files small enough to live in the file table have no extents
+                return Ok(extents);
+            }
+            if ok == 0 && error.raw_os_error() != Some(ERROR_MORE_DATA) {
+                return Err(error);
+            }
+
+            let count = (buffer[0] as u32) as usize;
+            extents += count as u64;
+            if ok != 0 || count == 0 {
+                return Ok(extents);
+            }
+            starting_vcn = buffer[2 + (count - 1) * 2];
+        }
+    }
+
+    /// Whether the disk a path is on has to seek, none when it cannot be told
+    pub fn seek_penalty(path: &Path) -> Option<bool> {
+        let letter = match path.components().next()? {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => letter as char,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        // Querying the device needs no access rights, so this works without elevation
+        let volume = OpenOptions::new()
+            .access_mode(0)
+            .share_mode(3)
+            .open(format!(r"\\.\{}:", letter))
+            .ok()?;
+
+        // DEVICE_SEEK_PENALTY_DESCRIPTOR: version, size and the flag
+        let mut descriptor = [0u32; 3];
+        let mut returned = 0u32;
+        let ok = unsafe {
+            DeviceIoControl(
+                volume.as_raw_handle(),
+                IOCTL_STORAGE_QUERY_PROPERTY,
+                SEEK_PENALTY_QUERY.as_ptr() as *const c_void,
+                size_of_val(&SEEK_PENALTY_QUERY) as u32,
+                descriptor.as_mut_ptr() as *mut c_void,
+                size_of_val(&descriptor) as u32,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 || (returned as usize) < size_of_val(&descriptor) - 3 {
+            return None;
+        }
+        Some(descriptor[2] & 0xff != 0)
+    }
+}
@@ -0,0 +1,179 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+use anyhow::{anyhow, Result};
+
+static POLICY: OnceLock<CpuPolicy> = OnceLock::new();
+
+// Cores of a hybrid CPU, none on CPUs with only one kind of core
+static CORES: OnceLock<Option<Cores>> = OnceLock::new();
+
+/// Which cores worker threads are placed on
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CpuPolicy {
+    /// Leave placement to the OS
+    None,
+    /// Hashing on performance cores, patching and file I/O on efficiency cores
+    Hybrid,
+    /// Every worker on performance cores
+    Performance,
+}
+
+impl FromStr for CpuPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(CpuPolicy::None),
+            "hybrid" => Ok(CpuPolicy::Hybrid),
+            "performance" => Ok(CpuPolicy::Performance),
+            _ => Err(anyhow!("Unknown cpu policy {}, expected none, hybrid or performance", s)),
+        }
+    }
+}
+
+/// What a worker thread is busy with
+#[derive(Clone, Copy)]
+pub enum Role {
+    /// CPU bound work such as hashing
+    Compute,
+    /// Patching, assembling and other disk bound work
+    Io,
+}
+
+/// Has to be set before the thread pools are built
+pub fn set_policy(policy: CpuPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+pub fn policy() -> CpuPolicy {
+    POLICY.get().copied().unwrap_or(CpuPolicy::None)
+}
+
+/// Place the calling thread on the cores the policy gives its role, nothing happens on CPUs without hybrid cores
+pub fn place(role: Role) {
+    let Some(cores) = CORES.get_or_init(detect_cores) else {
+        return;
+    };
+    let ids = match (policy(), role) {
+        (CpuPolicy::None, _) => return,
+        (CpuPolicy::Hybrid, Role::Io) => &cores.efficiency,
+        (CpuPolicy::Hybrid, Role::Compute) | (CpuPolicy::Performance, _) => &cores.performance,
+    };
+    pin_current_thread(ids);
+}
+
+/// Performance and efficiency core ids, as the OS numbers them for pinning
+struct Cores {
+    performance: Vec<u32>,
+    efficiency: Vec<u32>,
+}
+
+/// Intel hybrid CPUs list their core types as separate perf event sources
+#[cfg(target_os = "linux")]
+fn detect_cores() -> Option<Cores> {
+    let read = |kind: &str| {
+        std::fs::read_to_string(format!("/sys/devices/{}/cpus", kind))
+            .ok()
+            .map(|list| parse_cpu_list(list.trim()))
+            .filter(|ids| !ids.is_empty())
+    };
+    Some(Cores { performance: read("cpu_core")?, efficiency: read("cpu_atom")? })
+}
+
+/// Parse a kernel cpu list such as `0-11,16`
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Vec<u32> {
+    list.split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some(start.parse().ok()?..=end.parse().ok()?),
+            None => range.parse().ok().map(|id| id..=id),
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(ids: &[u32]) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &id in ids {
+            libc::CPU_SET(id as usize, &mut set);
+        }
+        // Pid zero is the calling thread
+        libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set);
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+    use std::ffi::c_void;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        pub fn GetSystemCpuSetInformation(
+            information: *mut u8,
+            buffer_length: u32,
+            returned_length: *mut u32,
+            process: *mut c_void,
+            flags: u32,
+        ) -> i32;
+        pub fn SetThreadSelectedCpuSets(thread: *mut c_void, cpu_set_ids: *const u32, count: u32) -> i32;
+        pub fn GetCurrentThread() -> *mut c_void;
+    }
+
+    // Offsets into SYSTEM_CPU_SET_INFORMATION
+    pub const SIZE_OFFSET: usize = 0;
+    pub const ID_OFFSET: usize = 8;
+    pub const EFFICIENCY_CLASS_OFFSET: usize = 18;
+}
+
+/// Cores of the highest efficiency class are performance cores, every other class counts as efficiency cores
+#[cfg(target_os = "windows")]
+fn detect_cores() -> Option<Cores> {
+    use std::ptr::null_mut;
+
+    let mut length = 0u32;
+    unsafe { windows::GetSystemCpuSetInformation(null_mut(), 0, &mut length, null_mut(), 0) };
+    let mut buffer = vec![0u8; length as usize];
+    if unsafe { windows::GetSystemCpuSetInformation(buffer.as_mut_ptr(), length, &mut length, null_mut(), 0) } == 0 {
+        return None;
+    }
+
+    let mut sets = Vec::new();
+    let mut offset = 0;
+    while offset + windows::EFFICIENCY_CLASS_OFFSET < length as usize {
+        let read_u32 = |at: usize| u32::from_le_bytes(buffer[at..at + 4].try_into().unwrap());
+        let size = read_u32(offset + windows::SIZE_OFFSET) as usize;
+        if size == 0 {
+            break;
+        }
+        sets.push((read_u32(offset + windows::ID_OFFSET), buffer[offset + windows::EFFICIENCY_CLASS_OFFSET]));
+        offset += size;
+    }
+
+    let top = sets.iter().map(|&(_, class)| class).max()?;
+    let (performance, efficiency): (Vec<_>, Vec<_>) = sets.into_iter().partition(|&(_, class)| class == top);
+    if efficiency.is_empty() {
+        return None;
+    }
+    Some(Cores {
+        performance: performance.into_iter().map(|(id, _)| id).collect(),
+        efficiency: efficiency.into_iter().map(|(id, _)| id).collect(),
+    })
+}
+
+#[cfg(target_os = "windows")]
+fn pin_current_thread(ids: &[u32]) {
+    unsafe {
+        windows::SetThreadSelectedCpuSets(windows::GetCurrentThread(), ids.as_ptr(), ids.len() as u32);
+    }
+}
+
+/// Other systems do not let threads be pinned to core types
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn detect_cores() -> Option<Cores> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn pin_current_thread(_ids: &[u32]) {}
@@ -0,0 +1,133 @@
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+use sophon::proto::chunk::SophonChunkProto;
+use sophon::proto::sophon::SophonManifestProto;
+use crate::log;
+use crate::quirks::Quirks;
+
+// Metadata files copied into the bundle as-is
+const METADATA_FILES: &[&str] = &[
+    "pkg_version",
+    "hdiffmap.json",
+    "hdifffiles.txt",
+    "deletefiles.txt",
+];
+
+/// Bundle environment info and game folder metadata into a zip for bug reports
+pub fn diagnose(game_path: &Path, output: &Path, redact: bool) -> Result<()> {
+    if !game_path.is_dir() {
+        return Err(anyhow!("{:?} does not exist", game_path));
+    }
+
+    let game_path = game_path.canonicalize()?;
+    let game_dir = game_path.to_string_lossy().to_string();
+    let home_dir = std::env::var("USERPROFILE")
+        .or_else(|_| std::env::var("HOME"))
+        .unwrap_or_default();
+    let redact = |text: String| -> String {
+        if !redact {
+            return text;
+        }
+        let text = text.replace(&game_dir, "<game>");
+        if home_dir.is_empty() { text } else { text.replace(&home_dir, "<home>") }
+    };
+
+    let mut zip = ZipWriter::new(File::create(output)?);
+    let options = SimpleFileOptions::default();
+
+    // Environment
+    let mut environment = String::new();
+    let _ = writeln!(environment, "version: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(environment, "os: {}", std::env::consts::OS);
+    let _ = writeln!(environment, "arch: {}", std::env::consts::ARCH);
+    let _ = writeln!(
+        environment,
+        "threads: {}",
+        std::thread::available_parallelism().map(|n| n.get()).unwrap_or(0),
+    );
+    let _ = writeln!(environment, "game folder: {}", game_dir);
+    let _ = writeln!(environment, "quirks: {}", Quirks::detect(&game_path)?.name);
+    let _ = writeln!(
+        environment,
+        "arguments: {}",
+        std::env::args()
+            .skip(1)
+            .map(|arg| match Path::new(&arg).canonicalize() {
+                Ok(path) if path == game_path => game_dir.clone(),
+                _ => arg,
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    );
+    zip.start_file("environment.txt", options)?;
+    zip.write_all(redact(environment).as_bytes())?;
+
+    // Top level listing of the game folder
+    let mut listing = String::new();
+    let mut entries = fs::read_dir(&game_path)?
+        .filter_map(|entry| entry.ok())
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in &entries {
+        let metadata = entry.metadata()?;
+        let _ = writeln!(
+            listing,
+            "{}{} {}",
+            entry.file_name().to_string_lossy(),
+            if metadata.is_dir() { "/" } else { "" },
+            metadata.len(),
+        );
+    }
+    zip.start_file("listing.txt", options)?;
+    zip.write_all(redact(listing).as_bytes())?;
+
+    // Metadata files
+    for name in METADATA_FILES {
+        if let Ok(content) = fs::read_to_string(game_path.join(name)) {
+            zip.start_file(format!("metadata/{}", name), options)?;
+            zip.write_all(redact(content).as_bytes())?;
+        }
+    }
+
+    // Manifest summaries
+    let mut manifests = String::new();
+    for entry in &entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.path().is_file() || !name.starts_with("manifest") {
+            continue;
+        }
+
+        let path = entry.path().to_string_lossy().to_string();
+        if let Ok(manifest) = SophonManifestProto::from_reader(File::open(&path)?) {
+            let _ = writeln!(
+                manifests,
+                "{}: ldiff manifest, {} assets, {} bytes",
+                name,
+                manifest.assets.len(),
+                manifest.assets.iter().map(|asset| asset.asset_size).sum::<i64>(),
+            );
+        } else if let Ok(manifest) = SophonChunkProto::from_reader(File::open(&path)?) {
+            let _ = writeln!(
+                manifests,
+                "{}: chunk manifest, {} assets, {} chunks, {} bytes",
+                name,
+                manifest.assets.len(),
+                manifest.assets.iter().map(|asset| asset.asset_chunks.len()).sum::<usize>(),
+                manifest.assets.iter().map(|asset| asset.asset_size).sum::<i64>(),
+            );
+        } else {
+            let _ = writeln!(manifests, "{}: unreadable", name);
+        }
+    }
+    zip.start_file("manifests.txt", options)?;
+    zip.write_all(manifests.as_bytes())?;
+
+    zip.finish()?;
+    log::ok!("Diagnostic bundle written to {}", output.display());
+    Ok(())
+}
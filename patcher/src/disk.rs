@@ -0,0 +1,137 @@
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use anyhow::{anyhow, bail, Result};
+use indicatif::HumanBytes;
+use crate::{log, overall};
+
+// Limit set by --temp-budget on the space extracted and staged files may take
+static BUDGET: OnceLock<u64> = OnceLock::new();
+
+// Bytes every stage currently holds on disk and the most it held during the run
+static USAGE: Mutex<[(u64, u64); 3]> = Mutex::new([(0, 0); 3]);
+
+/// What the space on disk is taken by
+#[derive(Clone, Copy)]
+pub enum Stage {
+    /// Files extracted from the package
+    Extract,
+    /// Payloads cut out of ldiff chunk files
+    Staging,
+    /// Patched files written next to their sources
+    Write,
+}
+
+impl Stage {
+    const ALL: [Stage; 3] = [Stage::Extract, Stage::Staging, Stage::Write];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Extract => "extract",
+            Stage::Staging => "staging",
+            Stage::Write => "write",
+        }
+    }
+
+    /// Extracted and staged files are gone after the run, patched files are there to stay
+    fn is_temp(self) -> bool {
+        !matches!(self, Stage::Write)
+    }
+}
+
+pub fn set_budget(bytes: u64) {
+    let _ = BUDGET.set(bytes);
+}
+
+/// Parse a size such as `512M`, `20G` or `1.5T`, plain numbers being bytes
+pub fn parse_size(text: &str) -> Result<u64> {
+    let text = text.trim().to_uppercase();
+    let number = text.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match text[number.len()..].trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        unit => bail!("Unknown size unit {} in {}", unit, text),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| anyhow!("Invalid size {}", text))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Account for the space stages are about to take, failing before anything is written when it does not fit
+///
+/// Stages writing at the same time are reserved together. `path` is any path on the disk they write to, it does not
+/// need to exist yet
+pub fn reserve(path: &Path, stages: &[(Stage, u64)]) -> Result<()> {
+    let mut usage = USAGE.lock().unwrap();
+
+    if let Some(&budget) = BUDGET.get() {
+        let in_use = Stage::ALL.iter()
+            .filter(|stage| stage.is_temp())
+            .map(|&stage| usage[stage as usize].0)
+            .sum::<u64>();
+        let needed = stages.iter()
+            .filter(|(stage, _)| stage.is_temp())
+            .map(|(_, bytes)| bytes)
+            .sum::<u64>();
+        if in_use + needed > budget {
+            bail!(
+                "Temporary files need {} on top of the {} already in use, over the temp budget of {}",
+                HumanBytes(needed),
+                HumanBytes(in_use),
+                HumanBytes(budget),
+            );
+        }
+    }
+
+    // Space taken by earlier stages is already gone from the free space
+    let needed = stages.iter().map(|(_, bytes)| bytes).sum::<u64>();
+    let existing = path.ancestors().find(|path| path.exists()).unwrap_or(path);
+    let free = fs4::available_space(existing)?;
+    if needed > free {
+        bail!(
+            "Patching needs {} for {} but only {} is free on {:?}",
+            HumanBytes(needed),
+            stages.iter().map(|(stage, _)| stage.name()).collect::<Vec<_>>().join(" and "),
+            HumanBytes(free),
+            existing,
+        );
+    }
+
+    // What takes space is also the work the overall progress is measured in
+    for &(stage, bytes) in stages {
+        let (current, peak) = &mut usage[stage as usize];
+        *current += bytes;
+        *peak = (*peak).max(*current);
+        overall::plan(stage, bytes);
+    }
+    Ok(())
+}
+
+/// Give back the space of a stage once its files are removed
+pub fn release(stage: Stage) {
+    USAGE.lock().unwrap()[stage as usize].0 = 0;
+}
+
+/// Most bytes every stage held on disk during the run
+pub fn peaks() -> Vec<(&'static str, u64)> {
+    let usage = USAGE.lock().unwrap();
+    Stage::ALL.iter()
+        .map(|&stage| (stage.name(), usage[stage as usize].1))
+        .collect()
+}
+
+/// Log how much space every stage took, if any
+pub fn report() {
+    let peaks = peaks();
+    if peaks.iter().all(|(_, bytes)| *bytes == 0) {
+        return;
+    }
+    log::info!(
+        "Disk usage: {}",
+        peaks.iter()
+            .map(|(name, bytes)| format!("{} {}", name, HumanBytes(*bytes)))
+            .collect::<Vec<_>>()
+            .join(", "),
+    );
+}
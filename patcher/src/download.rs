@@ -0,0 +1,77 @@
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use sophon::download::{self, Build, BuildManifest, BuildQuery};
+use crate::quirks::Quirks;
+use crate::{bars, log, util};
+
+// Folder of the game the chunks of a full build are downloaded into
+pub const CHUNK_FOLDER: &str = "chunks";
+
+/// Download the chunks of the full build and its manifest into the game folder, ready for the chunk action
+///
+/// Chunks the installed files already hold are copied out of them instead of downloaded, unless `fresh` is set
+pub fn chunk(game_path: &Path, query: &BuildQuery, matching_field: &str, fresh: bool) -> Result<()> {
+    let manifest_name = fetch_chunks(game_path, query, matching_field, fresh)?;
+    log::ok!(
+        "Downloaded, patch with: SophonPatcher chunk \"{}\" {} {}",
+        game_path.display(),
+        CHUNK_FOLDER,
+        manifest_name,
+    );
+    Ok(())
+}
+
+/// Download the chunks into `chunks` and return the name the manifest is saved under in the game folder
+pub fn fetch_chunks(game_path: &Path, query: &BuildQuery, matching_field: &str, fresh: bool) -> Result<String> {
+    let manifest_name = manifest_name(game_path, matching_field)?;
+    let build = util::with_spinner("Fetching the build", || query.build())?;
+    let manifest = pick(&build, matching_field)?;
+    log::info!("Downloading {} chunks of build {}", matching_field, build.tag);
+    download::download_chunks(
+        manifest,
+        &game_path.join(&manifest_name),
+        &game_path.join(CHUNK_FOLDER),
+        (!fresh).then_some(game_path),
+        &*bars::sink(),
+    )?;
+    Ok(manifest_name)
+}
+
+/// Download the ldiff chunk files of the update and its manifest into the game folder, as the ldiff action
+/// extracts them from an archive
+pub fn ldiff(game_path: &Path, query: &BuildQuery, matching_field: &str, from_version: Option<&str>) -> Result<()> {
+    let manifest_name = manifest_name(game_path, matching_field)?;
+    let build = util::with_spinner("Fetching the update", || query.patch_build())?;
+    let manifest = pick(&build, matching_field)?;
+    log::info!("Downloading {} ldiff files of build {}", matching_field, build.tag);
+    download::download_ldiff(
+        manifest,
+        &game_path.join(&manifest_name),
+        &game_path.join("ldiff"),
+        from_version,
+        &*bars::sink(),
+    )?;
+    log::ok!("Downloaded, patch with: SophonPatcher ldiff \"{}\" ldiff", game_path.display());
+    Ok(())
+}
+
+/// The manifest of a build for the given part of the game
+fn pick<'a>(build: &'a Build, matching_field: &str) -> Result<&'a BuildManifest> {
+    build.manifests.iter()
+        .find(|manifest| manifest.matching_field == matching_field)
+        .ok_or_else(|| anyhow!(
+            "Build {} has no {} manifest, it has {}",
+            build.tag,
+            matching_field,
+            build.manifests.iter().map(|manifest| manifest.matching_field.as_str()).collect::<Vec<_>>().join(", "),
+        ))
+}
+
+/// Name the manifest is saved under, so the actions and the update command find it in the game folder
+fn manifest_name(game_path: &Path, matching_field: &str) -> Result<String> {
+    if !game_path.is_dir() {
+        return Err(anyhow!("{:?} does not exist", game_path));
+    }
+    let quirks = Quirks::detect(game_path)?;
+    Ok(format!("{}_{}", quirks.manifest_prefix, matching_field))
+}
@@ -0,0 +1,185 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use indicatif::{HumanBytes, HumanDuration};
+use sophon::proto::chunk::SophonChunkProto;
+use sophon::proto::sophon::SophonManifestProto;
+use sophon::sophon::ldiff_entries;
+use crate::extractor::ArchiveExtractor;
+use crate::quirks::Quirks;
+use crate::serialize::{Metadata, ENTRY_FILES};
+use crate::{log, util};
+
+// Written to the game folder to measure how fast its drive writes
+const BENCHMARK_FILE: &str = "sophon_benchmark.tmp";
+const BENCHMARK_SIZE: usize = 32 << 20;
+
+// Folder of the game the download command puts chunks in
+const CHUNK_FOLDER: &str = "chunks";
+
+/// What applying a package to a game folder takes, known before anything is extracted or written
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Estimate {
+    /// Chunks the manifest needs that are not in the chunk folder yet, as downloaded
+    pub download_bytes: u64,
+    /// Space taken while patching by downloaded chunks and extracted and staged files, freed once done
+    pub temp_bytes: u64,
+    /// Space patched and assembled files take on top of the files they replace
+    pub write_bytes: u64,
+    /// Time writing the temporary and patched files takes at the measured speed of the game drive, downloads left out
+    pub est_duration: Duration,
+}
+
+/// Estimate the space and time applying a package takes, without extracting or writing anything but a test file
+///
+/// The package is an hdiff or ldiff archive, the `ldiff` folder the download command fills, or a chunk manifest
+/// whose chunks are looked for in the `chunks` folder of the game
+pub fn estimate(game_path: &Path, package: &Path) -> Result<Estimate> {
+    if !game_path.is_dir() {
+        return Err(anyhow!("{:?} does not exist", game_path));
+    }
+    if !package.exists() {
+        return Err(anyhow!("{:?} does not exist", package));
+    }
+    let quirks = Quirks::detect(game_path)?;
+    let mut estimate = if package.is_dir() && package == game_path.join("ldiff") {
+        ldiff_folder(game_path, package, &quirks)?
+    } else if package.extension().is_some_and(|extension| extension == "zip" || extension == "7z") {
+        archive(game_path, package)?
+    } else {
+        chunk(game_path, package)?
+    };
+
+    let speed = util::with_spinner("Measuring the speed of the game drive", || write_speed(game_path))?;
+    let written = estimate.temp_bytes + estimate.write_bytes;
+    estimate.est_duration = Duration::from_secs_f64(written as f64 / speed as f64);
+    Ok(estimate)
+}
+
+/// Print what applying a package takes
+pub fn report(game_path: &Path, package: &Path) -> Result<()> {
+    let estimate = estimate(game_path, package)?;
+    log::info!("Download: {}", HumanBytes(estimate.download_bytes));
+    log::info!("Temporary space: {}", HumanBytes(estimate.temp_bytes));
+    log::info!("Written to the game folder: {}", HumanBytes(estimate.write_bytes));
+    log::info!("Estimated duration: {}", HumanDuration(estimate.est_duration));
+    Ok(())
+}
+
+/// Hdiff archives take their extraction and the patched files, ldiff archives also the staged payloads
+fn archive(game_path: &Path, package: &Path) -> Result<Estimate> {
+    let names = ArchiveExtractor::entry_names(package)?.into_iter().collect::<HashSet<_>>();
+    let extracted = ArchiveExtractor::uncompressed_size(package)?;
+    if ENTRY_FILES.iter().any(|name| names.contains(*name)) {
+        let sources = ArchiveExtractor::read_entries(package, |name| {
+            ENTRY_FILES.contains(&name) || (!name.contains('/') && name.starts_with("manifest"))
+        })?;
+        let hdiff_map = Metadata::merge(sources, |patch| names.contains(patch))?;
+        let write_bytes = hdiff_map.diff_map.iter()
+            .map(|data| data.target_file_size.unwrap_or_else(|| {
+                game_path.join(&data.source_file_name).metadata().map_or(0, |metadata| metadata.len())
+            }))
+            .sum();
+        return Ok(Estimate { temp_bytes: extracted, write_bytes, ..Estimate::default() });
+    }
+
+    let chunk_names = names.iter()
+        .filter_map(|name| name.strip_prefix("ldiff/"))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let entries = ArchiveExtractor::read_entries(package, |name| !name.contains('/') && name.starts_with("manifest"))?;
+    let manifests = entries.into_iter()
+        .filter_map(|(_, buffer)| SophonManifestProto::from_reader(&*buffer).ok())
+        .collect::<Vec<_>>();
+    if manifests.is_empty() {
+        return Err(anyhow!("{:?} contains neither hdiff entries nor ldiff manifests", package));
+    }
+    let (staged, write_bytes) = ldiff_sizes(&manifests, &chunk_names);
+    Ok(Estimate { temp_bytes: extracted + staged, write_bytes, ..Estimate::default() })
+}
+
+/// The downloaded chunk files are on disk already, only their payloads are staged
+fn ldiff_folder(game_path: &Path, ldiff_path: &Path, quirks: &Quirks) -> Result<Estimate> {
+    let chunk_names = ldiff_path.read_dir()?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut manifests = Vec::new();
+    for entry in game_path.read_dir()? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() || !quirks.is_manifest(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        if let Ok(manifest) = SophonManifestProto::from(entry.path().to_string_lossy().into_owned()) {
+            manifests.push(manifest);
+        }
+    }
+    if manifests.is_empty() {
+        return Err(anyhow!("{:?} holds no ldiff manifest next to the ldiff folder", game_path));
+    }
+    let (temp_bytes, write_bytes) = ldiff_sizes(&manifests, &chunk_names);
+    Ok(Estimate { temp_bytes, write_bytes, ..Estimate::default() })
+}
+
+/// Bytes of the payloads staged and of the files diffs write, as the ldiff action reserves them
+fn ldiff_sizes(manifests: &[SophonManifestProto], chunk_names: &[String]) -> (u64, u64) {
+    let entries = manifests.iter().flat_map(|manifest| ldiff_entries(manifest, chunk_names)).collect::<Vec<_>>();
+    let staged = entries.iter().map(|entry| entry.data.hdiff_file_size as u64).sum();
+    let written = entries.iter()
+        .filter(|entry| entry.kind.is_patch())
+        .map(|entry| entry.asset_size as u64)
+        .sum();
+    (staged, written)
+}
+
+/// Missing chunks are downloaded, every chunk is staged decompressed and assets grow to their new size
+fn chunk(game_path: &Path, manifest_path: &Path) -> Result<Estimate> {
+    let manifest = SophonChunkProto::from(manifest_path.to_string_lossy().into_owned())
+        .map_err(|e| anyhow!("Failed to read chunk manifest {:?}: {}", manifest_path, e))?;
+    if manifest.assets.is_empty() {
+        return Err(anyhow!("Manifest {:?} holds no assets", manifest_path));
+    }
+
+    let chunk_path = game_path.join(CHUNK_FOLDER);
+    let mut chunks = HashMap::new();
+    for chunk in manifest.assets.iter().flat_map(|asset| &asset.asset_chunks) {
+        chunks.insert(&chunk.chunk_name, (chunk.chunk_size as u64, chunk.chunk_size_decompressed as u64));
+    }
+    // Chunks the download found in local files are there decompressed
+    let download_bytes = chunks.iter()
+        .filter(|(name, (size, decompressed))| {
+            !chunk_path.join(name).metadata().is_ok_and(|metadata| {
+                *size == 0 || metadata.len() == *size || metadata.len() == *decompressed
+            })
+        })
+        .map(|(_, (size, _))| size)
+        .sum::<u64>();
+    let staged = chunks.values().map(|(_, decompressed)| decompressed).sum::<u64>();
+    let write_bytes = manifest.assets.iter()
+        .map(|asset| {
+            let existing = game_path.join(&asset.asset_name).metadata().map_or(0, |metadata| metadata.len());
+            (asset.asset_size as u64).saturating_sub(existing)
+        })
+        .sum();
+    Ok(Estimate { download_bytes, temp_bytes: download_bytes + staged, write_bytes, ..Estimate::default() })
+}
+
+/// Bytes per second the drive holding the folder writes, measured with a synced test file
+fn write_speed(path: &Path) -> Result<u64> {
+    let test_path = path.join(BENCHMARK_FILE);
+    let buffer = vec![0x5a; 1 << 20];
+    let started = Instant::now();
+    let result = (|| -> io::Result<()> {
+        let mut file = File::create(&test_path)?;
+        for _ in 0..BENCHMARK_SIZE / buffer.len() {
+            file.write_all(&buffer)?;
+        }
+        file.sync_all()
+    })();
+    let elapsed = started.elapsed();
+    let _ = fs::remove_file(&test_path);
+    result.map_err(|e| anyhow!("Failed to measure the speed of {:?}: {}", path, e))?;
+    Ok((BENCHMARK_SIZE as f64 / elapsed.as_secs_f64().max(0.001)) as u64)
+}
@@ -0,0 +1,163 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use crate::log;
+
+// JSONL file given with --event-log, every event is appended as one line
+static LOG: OnceLock<Mutex<BufWriter<File>>> = OnceLock::new();
+
+// Files listed as slowest by `report analyze`
+const SLOWEST_FILES: usize = 10;
+
+/// Something that happened during a run
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    RunStart { action: String },
+    RunEnd { status: i32 },
+    StageStart { stage: String },
+    StageEnd { stage: String, duration_ms: u64 },
+    /// A file was patched, or failed to
+    File { path: String, duration_ms: u64, ok: bool },
+    /// A file was listed in the outcome, e.g. because its hash did not match
+    Detail { path: String, detail: String },
+    /// md5 of a package or metadata file the run reads, see `Inputs`
+    Input { kind: String, name: String, md5: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// RFC 3339 with milliseconds
+    time: String,
+    #[serde(flatten)]
+    event: Event,
+}
+
+/// Append every event of the run to a JSONL file
+pub fn set_path(path: &Path) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| anyhow!("Failed to open event log {:?}: {}", path, e))?;
+    let _ = LOG.set(Mutex::new(BufWriter::new(file)));
+    Ok(())
+}
+
+pub fn emit(event: Event) {
+    let Some(log) = LOG.get() else {
+        return;
+    };
+    let time = Local::now().to_rfc3339_opts(SecondsFormat::Millis, false);
+    let Ok(line) = serde_json::to_string(&Record { time, event }) else {
+        return;
+    };
+
+    // Flushed right away so the log survives a crash
+    let mut log = log.lock().unwrap();
+    let _ = writeln!(log, "{}", line);
+    let _ = log.flush();
+}
+
+pub fn file(path: &str, duration: Duration, ok: bool) {
+    if LOG.get().is_some() {
+        emit(Event::File { path: path.to_string(), duration_ms: duration.as_millis() as u64, ok });
+    }
+}
+
+/// Summarize where the time of the runs in an event log went
+pub fn analyze(path: &Path) -> Result<()> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read event log {:?}: {}", path, e))?;
+
+    let mut runs = 0;
+    let mut run_time = Duration::ZERO;
+    let mut run_started = None;
+    let mut stages: Vec<(String, Duration)> = Vec::new();
+    let mut files: HashMap<String, (Duration, u32, bool)> = HashMap::new();
+    let mut details: HashMap<String, u64> = HashMap::new();
+    let mut invalid = 0;
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let Some((record, time)) = serde_json::from_str::<Record>(line)
+            .ok()
+            .and_then(|record| DateTime::parse_from_rfc3339(&record.time).ok().map(|time| (record, time)))
+        else {
+            invalid += 1;
+            continue;
+        };
+        match record.event {
+            Event::RunStart { .. } => {
+                runs += 1;
+                run_started = Some(time);
+            }
+            Event::RunEnd { .. } => {
+                if let Some(started) = run_started.take() {
+                    run_time += (time - started).to_std().unwrap_or_default();
+                }
+            }
+            Event::StageStart { .. } | Event::Input { .. } => {}
+            Event::StageEnd { stage, duration_ms } => {
+                let duration = Duration::from_millis(duration_ms);
+                match stages.iter_mut().find(|(name, _)| *name == stage) {
+                    Some((_, total)) => *total += duration,
+                    None => stages.push((stage, duration)),
+                }
+            }
+            Event::File { path, duration_ms, ok } => {
+                let (slowest, attempts, last_ok) = files.entry(path).or_default();
+                *slowest = (*slowest).max(Duration::from_millis(duration_ms));
+                *attempts += 1;
+                *last_ok = ok;
+            }
+            Event::Detail { detail, .. } => *details.entry(detail).or_default() += 1,
+        }
+    }
+
+    log::info!("{} runs taking {}", runs, indicatif::HumanDuration(run_time));
+
+    // Stage breakdown
+    let stage_time = stages.iter().map(|(_, duration)| *duration).sum::<Duration>().max(Duration::from_millis(1));
+    stages.sort_by_key(|(_, duration)| Reverse(*duration));
+    for (stage, duration) in &stages {
+        log::info!(
+            "  {:<10} {:>10.1}s {:>5.1}%",
+            stage,
+            duration.as_secs_f64(),
+            duration.as_secs_f64() * 100.0 / stage_time.as_secs_f64(),
+        );
+    }
+
+    // Slowest files
+    let mut slowest = files.iter().collect::<Vec<_>>();
+    slowest.sort_by_key(|(_, (duration, _, _))| Reverse(*duration));
+    if !slowest.is_empty() {
+        log::info!("Slowest files:");
+    }
+    for (path, (duration, _, _)) in slowest.iter().take(SLOWEST_FILES) {
+        log::info!("  {:>8.2}s {}", duration.as_secs_f64(), path);
+    }
+
+    // Files patched more than once were retried, by a later run or a rerun of the same package
+    let retried = files.values().filter(|(_, attempts, _)| *attempts > 1).count();
+    let retries = files.values().map(|(_, attempts, _)| attempts.saturating_sub(1) as u64).sum::<u64>();
+    let failing = files.values().filter(|(_, _, ok)| !ok).count();
+    log::info!(
+        "{} files, {} retried {} times in total, {} still failing at their last attempt",
+        files.len(),
+        retried,
+        retries,
+        failing,
+    );
+    let mut details = details.into_iter().collect::<Vec<_>>();
+    details.sort();
+    for (detail, count) in details {
+        log::info!("  {} {}", count, detail);
+    }
+    if invalid > 0 {
+        log::warn!("{} lines of the event log could not be read", invalid);
+    }
+    Ok(())
+}
@@ -1,7 +1,7 @@
-/// Auto-generated by Anthropic Claude Sonnet 4
+// Auto-generated by Anthropic Claude Sonnet 4
 
 use std::fs::{self, File};
-use std::io::{self, BufReader, Write};
+use std::io::{self, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
@@ -61,6 +61,161 @@ impl ArchiveExtractor {
         }
     }
 
+    /// Read the entries accepted by the filter into memory without extracting anything
+    pub fn read_entries<P: AsRef<Path>, F>(
+        archive_path: P,
+        filter: F,
+    ) -> Result<Vec<(String, Vec<u8>)>, ArchiveError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let archive_path = archive_path.as_ref();
+        let extension = archive_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(ArchiveError::UnsupportedFormat)?
+            .to_lowercase();
+
+        let mut entries = Vec::new();
+        match extension.as_str() {
+            "zip" => {
+                let file = File::open(archive_path)?;
+                let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+                for i in 0..archive.len() {
+                    let mut file = archive.by_index(i)?;
+                    if file.is_dir() || !filter(file.name()) {
+                        continue;
+                    }
+
+                    let mut buffer = Vec::new();
+                    file.read_to_end(&mut buffer)?;
+                    entries.push((file.name().to_string(), buffer));
+                }
+            }
+            "7z" => {
+                use sevenz_rust::*;
+
+                let mut sz_archive = SevenZReader::open(archive_path, Password::empty())
+                    .map_err(|e| ArchiveError::SevenZ(format!("Failed to open 7z archive: {:?}", e)))?;
+                sz_archive.for_each_entries(|entry, reader| -> Result<bool, Error> {
+                    // Solid blocks have to be read through even when skipping an entry
+                    if entry.is_directory() || !filter(&entry.name) {
+                        io::copy(reader, &mut io::sink())?;
+                        return Ok(true);
+                    }
+
+                    let mut buffer = Vec::new();
+                    reader.read_to_end(&mut buffer)?;
+                    entries.push((entry.name.clone(), buffer));
+                    Ok(true)
+                }).map_err(|e| ArchiveError::SevenZ(format!("Reading failed: {:?}", e)))?;
+            }
+            _ => return Err(ArchiveError::UnsupportedFormat),
+        }
+
+        Ok(entries)
+    }
+
+    /// Sum of the uncompressed sizes of all entries, the space a full extraction takes
+    pub fn uncompressed_size<P: AsRef<Path>>(archive_path: P) -> Result<u64, ArchiveError> {
+        let archive_path = archive_path.as_ref();
+        let extension = archive_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(ArchiveError::UnsupportedFormat)?
+            .to_lowercase();
+
+        match extension.as_str() {
+            "zip" => {
+                let file = File::open(archive_path)?;
+                let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+                let mut size = 0;
+                for i in 0..archive.len() {
+                    size += archive.by_index_raw(i)?.size();
+                }
+                Ok(size)
+            }
+            "7z" => {
+                use sevenz_rust::*;
+
+                let sz_archive = SevenZReader::open(archive_path, Password::empty())
+                    .map_err(|e| ArchiveError::SevenZ(format!("Failed to open 7z archive: {:?}", e)))?;
+                Ok(sz_archive.archive().files.iter().map(|entry| entry.size()).sum())
+            }
+            _ => Err(ArchiveError::UnsupportedFormat),
+        }
+    }
+
+    /// List the names of all file entries in an archive
+    pub fn entry_names<P: AsRef<Path>>(archive_path: P) -> Result<Vec<String>, ArchiveError> {
+        let archive_path = archive_path.as_ref();
+        let extension = archive_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(ArchiveError::UnsupportedFormat)?
+            .to_lowercase();
+
+        match extension.as_str() {
+            "zip" => {
+                let file = File::open(archive_path)?;
+                let archive = zip::ZipArchive::new(BufReader::new(file))?;
+                Ok(archive.file_names()
+                    .filter(|name| !name.ends_with('/'))
+                    .map(|name| name.to_string())
+                    .collect())
+            }
+            "7z" => {
+                use sevenz_rust::*;
+
+                let sz_archive = SevenZReader::open(archive_path, Password::empty())
+                    .map_err(|e| ArchiveError::SevenZ(format!("Failed to open 7z archive: {:?}", e)))?;
+                Ok(sz_archive.archive().files
+                    .iter()
+                    .filter(|entry| !entry.is_directory())
+                    .map(|entry| entry.name.clone())
+                    .collect())
+            }
+            _ => Err(ArchiveError::UnsupportedFormat),
+        }
+    }
+
+    /// List the names of all file entries in an archive with their uncompressed sizes
+    pub fn entry_sizes<P: AsRef<Path>>(archive_path: P) -> Result<Vec<(String, u64)>, ArchiveError> {
+        let archive_path = archive_path.as_ref();
+        let extension = archive_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(ArchiveError::UnsupportedFormat)?
+            .to_lowercase();
+
+        match extension.as_str() {
+            "zip" => {
+                let file = File::open(archive_path)?;
+                let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+                let mut entries = Vec::new();
+                for i in 0..archive.len() {
+                    let file = archive.by_index_raw(i)?;
+                    if !file.is_dir() {
+                        entries.push((file.name().to_string(), file.size()));
+                    }
+                }
+                Ok(entries)
+            }
+            "7z" => {
+                use sevenz_rust::*;
+
+                let sz_archive = SevenZReader::open(archive_path, Password::empty())
+                    .map_err(|e| ArchiveError::SevenZ(format!("Failed to open 7z archive: {:?}", e)))?;
+                Ok(sz_archive.archive().files
+                    .iter()
+                    .filter(|entry| !entry.is_directory())
+                    .map(|entry| (entry.name.clone(), entry.size()))
+                    .collect())
+            }
+            _ => Err(ArchiveError::UnsupportedFormat),
+        }
+    }
+
     /// Extract ZIP archive with progress callback
     fn extract_zip_with_progress<P: AsRef<Path>, Q: AsRef<Path>, F>(
         archive_path: P,
@@ -85,6 +240,10 @@ impl ArchiveExtractor {
 
             if file.is_dir() {
                 fs::create_dir_all(&output_path)?;
+            } else if Self::is_extracted(&output_path, file.size(), Some(file.crc32())) {
+                // Left by an earlier run, e.g. one that failed halfway
+                extracted_files.push(output_path);
+                continue;
             } else {
                 // Create parent directories if they don't exist
                 if let Some(parent) = output_path.parent() {
@@ -145,12 +304,15 @@ impl ArchiveExtractor {
                 if let Err(e) = fs::create_dir_all(&output_path) {
                     return Err(Error::other(format!("Failed to create directory: {}", e)));
                 }
+            } else if Self::is_extracted(&output_path, entry.size(), entry.has_crc.then_some(entry.crc as u32)) {
+                // Left by an earlier run, solid blocks still have to be read through
+                io::copy(reader, &mut io::sink())?;
+                extracted_files.push(output_path);
             } else {
                 // Create parent directories if they don't exist
                 if let Some(parent) = output_path.parent() {
-                    if let Err(e) = fs::create_dir_all(parent) {
-                        return Err(Error::other(format!("Failed to create parent directory: {}", e)));
-                    }
+                    fs::create_dir_all(parent)
+                        .map_err(|e| Error::other(format!("Failed to create parent directory: {}", e)))?;
                 }
 
                 match File::create(&output_path) {
@@ -175,6 +337,34 @@ impl ArchiveExtractor {
         Ok(extracted_files)
     }
 
+    /// Check whether a file already holds an entry, comparing sizes first and only hashing when they match
+    ///
+    /// Entries without a CRC-32 are never considered extracted
+    fn is_extracted(path: &Path, size: u64, crc: Option<u32>) -> bool {
+        let Some(crc) = crc else {
+            return false;
+        };
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() == size => {}
+            _ => return false,
+        }
+
+        let Ok(file) = File::open(path) else {
+            return false;
+        };
+        let mut reader = BufReader::new(file);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => hasher.update(&buffer[..read]),
+                Err(_) => return false,
+            }
+        }
+        hasher.finalize() == crc
+    }
+
     /// Sanitize file paths to prevent directory traversal attacks
     fn sanitize_path(path: &str) -> Result<PathBuf, ArchiveError> {
         let path = PathBuf::from(path);
@@ -190,9 +380,7 @@ impl ArchiveExtractor {
                 }
                 std::path::Component::ParentDir => {
                     // Remove parent directory references for security
-                    if !components.is_empty() {
-                        components.pop();
-                    }
+                    components.pop();
                 }
                 _ => {
                     // Skip other components like root directory
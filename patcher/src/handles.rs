@@ -0,0 +1,53 @@
+use anyhow::{anyhow, Result};
+use sophon::sophon::set_handle_budget;
+
+// Handles kept out of the worker budget for stdio, logs, the chunk index and hpatchz processes
+const RESERVED_HANDLES: u64 = 64;
+
+// Fewest handles workers can get by with
+const MIN_BUDGET: u64 = 16;
+
+/// Raise the open file limit as far as allowed and budget the files workers may hold open at once
+pub fn configure() -> Result<()> {
+    let Some(limit) = raise_limit() else {
+        return Ok(());
+    };
+    if limit < RESERVED_HANDLES + MIN_BUDGET {
+        return Err(anyhow!(
+            "Only {} files may be open at once, at least {} are needed. Raise the open file limit, e.g. with `ulimit -n`",
+            limit,
+            RESERVED_HANDLES + MIN_BUDGET,
+        ));
+    }
+
+    set_handle_budget((limit - RESERVED_HANDLES) as usize);
+    Ok(())
+}
+
+/// Lift the soft open file limit to the hard one, returns the limit now in effect
+#[cfg(unix)]
+fn raise_limit() -> Option<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+
+    // macOS refuses soft limits above OPEN_MAX even when the hard limit is unlimited
+    #[cfg(target_os = "macos")]
+    let wanted = limit.rlim_max.min(10240);
+    #[cfg(not(target_os = "macos"))]
+    let wanted = limit.rlim_max;
+    if wanted > limit.rlim_cur {
+        let raised = libc::rlimit { rlim_cur: wanted, rlim_max: limit.rlim_max };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit.rlim_cur = wanted;
+        }
+    }
+    Some(limit.rlim_cur)
+}
+
+/// Windows has no per process soft limit on handles worth raising
+#[cfg(not(unix))]
+fn raise_limit() -> Option<u64> {
+    None
+}
@@ -2,22 +2,62 @@ use std::sync::OnceLock;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::fs;
-use std::io::Write;
+use std::io::{Read, Write};
 use anyhow::{Result, Context};
 
 // Global static for the extracted executable path
 static HPATCHZ_EXE_PATH: OnceLock<PathBuf> = OnceLock::new();
 
+// Executable given with --hpatchz-path, used instead of the embedded one
+static EXTERNAL_EXE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// Folder the per-process work directory is created in, the system temp dir by default
+static TEMP_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+// Global static for the per-process work directory
+static WORK_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// Global static for the zero-byte file used as source of new files
+static EMPTY_SOURCE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
 pub struct HPatchZ;
 
 impl HPatchZ {
+    /// Use an existing hpatchz instead of extracting the embedded one, e.g. where temp folders are noexec
+    pub fn set_exe_path(path: PathBuf) {
+        let _ = EXTERNAL_EXE_PATH.set(path);
+    }
+
+    /// Create the work directory under the given folder instead of the system temp dir
+    pub fn set_temp_root(path: PathBuf) {
+        let _ = TEMP_ROOT.set(path);
+    }
+
     /// Get the path to the extracted hpatchz executable (extract once, reuse many times)
     pub fn get_exe_path() -> Result<&'static PathBuf> {
+        if let Some(path) = EXTERNAL_EXE_PATH.get() {
+            return Ok(path);
+        }
         HPATCHZ_EXE_PATH.get_or_try_init(|| {
             Self::extract_exe_once()
         })
     }
 
+    /// Get the work directory if anything was put into it yet
+    pub fn work_dir() -> Option<&'static PathBuf> {
+        WORK_DIR.get()
+    }
+
+    /// Get the per-process work directory holding the extracted executable and the empty source file
+    fn get_work_dir() -> Result<&'static PathBuf> {
+        WORK_DIR.get_or_try_init(|| {
+            let temp_root = TEMP_ROOT.get().cloned().unwrap_or_else(std::env::temp_dir);
+            let work_dir = temp_root.join(format!("rust_hpatchz_global_{}", std::process::id()));
+            fs::create_dir_all(&work_dir).context("Failed to create temp directory")?;
+            Ok(work_dir)
+        })
+    }
+
     /// Extract the executable once to a temporary location
     fn extract_exe_once() -> Result<PathBuf> {
         // Embed the executable based on target platform
@@ -31,10 +71,7 @@ impl HPatchZ {
         const HPATCHZ_BYTES: &[u8] = include_bytes!("../bin/hpatchz_macos");
 
         // Create a persistent temp directory for this process
-        let temp_dir = std::env::temp_dir()
-            .join(format!("rust_hpatchz_global_{}", std::process::id()));
-
-        fs::create_dir_all(&temp_dir).context("Failed to create temp directory")?;
+        let temp_dir = Self::get_work_dir()?;
 
         let exe_name = if cfg!(target_os = "windows") {
             "hpatchz.exe"
@@ -93,37 +130,147 @@ impl HPatchZ {
         }
     }
 
-    /// Apply a patch using the globally extracted executable
+    /// Apply a patch made against an empty file, creating a new file
     pub fn apply_patch_empty<P: AsRef<Path>>(
         diff_file: P,
         new_file: P,
     ) -> Result<()> {
-        let exe_path = Self::get_exe_path()?;
+        let empty_path = Self::get_empty_source_path()?;
+        Self::apply_patch(empty_path.as_path(), diff_file.as_ref(), new_file.as_ref())
+    }
 
-        let output = Command::new(exe_path)
-            .arg("-f")
-            .arg("")
-            .arg(diff_file.as_ref())
-            .arg(new_file.as_ref())
-            .output()
-            .context("Failed to execute hpatchz")?;
+    /// Get a zero-byte file in the work directory, so new files are patched from a real empty source
+    fn get_empty_source_path() -> Result<&'static PathBuf> {
+        EMPTY_SOURCE_PATH.get_or_try_init(|| {
+            let empty_path = Self::get_work_dir()?.join("empty");
+            fs::File::create(&empty_path)
+                .context("Failed to create empty source file")?;
+            Ok(empty_path)
+        })
+    }
 
-        if output.status.success() {
-            Ok(())
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("hpatchz failed: {}", stderr)
-        }
+    /// Check for the HDiffPatch magic, anything else is a plain file rather than a diff
+    pub fn is_diff<P: AsRef<Path>>(file: P) -> Result<bool> {
+        let mut magic = [0u8; 5];
+        let read = fs::File::open(file.as_ref())
+            .and_then(|mut file| file.read(&mut magic))
+            .context("Failed to read patch file")?;
+
+        // HDIFF13, HDIFF19 and HDIFFSF20 for single files, HDIRDIFF and HDIRSF for directories
+        Ok(read == magic.len() && (&magic == b"HDIFF" || magic.starts_with(b"HDIR")))
     }
 
-    /// Clean up the extracted executable (call this when your program exits)
+    /// Clean up the work directory (call this when your program exits), an external executable is left alone
     pub fn cleanup() -> Result<()> {
-        if let Some(exe_path) = HPATCHZ_EXE_PATH.get() {
-            if let Some(parent) = exe_path.parent() {
-                fs::remove_dir_all(parent)
-                    .context("Failed to cleanup temporary directory")?;
-            }
+        if let Some(work_dir) = WORK_DIR.get() {
+            fs::remove_dir_all(work_dir)
+                .context("Failed to cleanup temporary directory")?;
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Build an HDIFF13 diff the way hdiffz lays one out: `new` starts with the first `covered` bytes of the old
+    // file, the rest is stored in the diff, zstd compressed when asked
+    pub(crate) fn diff(old_size: usize, covered: usize, new: &[u8], zstd: bool) -> Vec<u8> {
+        // Single byte sizes only, enough for these tests
+        assert!(new.len() <= 32 && covered <= old_size.min(new.len()));
+        let stored = &new[covered..];
+        let (compress_type, data) = match zstd {
+            true => ("zstd", zstd::encode_all(stored, 3).unwrap()),
+            false => ("", stored.to_vec()),
+        };
+        let compressed_size = if zstd { data.len() } else { 0 };
+        let cover = match covered {
+            0 => Vec::new(),
+            _ => vec![0, 0, covered as u8],
+        };
+
+        let mut diff = format!("HDIFF13&{}\0", compress_type).into_bytes();
+        diff.extend([new.len() as u8, old_size as u8, !cover.is_empty() as u8]);
+        diff.extend([cover.len() as u8, 0]);
+        // Covered bytes equal the old ones and the rest is copied, the add stream is one run of zeros
+        diff.extend([1, 0, 0, 0]);
+        diff.extend([stored.len() as u8, compressed_size as u8]);
+        diff.extend(cover);
+        diff.push(new.len() as u8 - 1);
+        diff.extend(data);
+        diff
+    }
+
+    // A fresh folder for one test, tests run in parallel
+    fn folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("sophon_hpatchz_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    #[test]
+    fn new_file_from_empty_source() {
+        let folder = folder("new_file");
+        fs::write(folder.join("patch"), diff(0, 0, b"new asset", false)).unwrap();
+        assert!(HPatchZ::is_diff(folder.join("patch")).unwrap());
+        HPatchZ::apply_patch_empty(folder.join("patch"), folder.join("target")).unwrap();
+        assert_eq!(fs::read(folder.join("target")).unwrap(), b"new asset");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn new_file_from_zstd_diff() {
+        let folder = folder("zstd");
+        fs::write(folder.join("patch"), diff(0, 0, b"compressed new asset", true)).unwrap();
+        HPatchZ::apply_patch_empty(folder.join("patch"), folder.join("target")).unwrap();
+        assert_eq!(fs::read(folder.join("target")).unwrap(), b"compressed new asset");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn empty_source_stays_empty() {
+        let folder = folder("reuse");
+        for (index, content) in [b"first".as_slice(), b"second", b"third"].into_iter().enumerate() {
+            let patch = folder.join(format!("patch{}", index));
+            let target = folder.join(format!("target{}", index));
+            fs::write(&patch, diff(0, 0, content, false)).unwrap();
+            HPatchZ::apply_patch_empty(&patch, &target).unwrap();
+            assert_eq!(fs::read(&target).unwrap(), content);
+        }
+        assert_eq!(fs::metadata(HPatchZ::get_empty_source_path().unwrap()).unwrap().len(), 0);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    // A listed source that exists as a zero-byte file is patched like any other, in place
+    #[test]
+    fn zero_byte_source_in_place() {
+        let folder = folder("zero_byte");
+        fs::write(folder.join("asset"), b"").unwrap();
+        fs::write(folder.join("patch"), diff(0, 0, b"filled placeholder", false)).unwrap();
+        HPatchZ::apply_patch(folder.join("asset"), folder.join("patch"), folder.join("asset")).unwrap();
+        assert_eq!(fs::read(folder.join("asset")).unwrap(), b"filled placeholder");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn existing_source() {
+        let folder = folder("existing");
+        fs::write(folder.join("source"), b"hello").unwrap();
+        fs::write(folder.join("patch"), diff(5, 5, b"hello world", false)).unwrap();
+        HPatchZ::apply_patch(folder.join("source"), folder.join("patch"), folder.join("target")).unwrap();
+        assert_eq!(fs::read(folder.join("target")).unwrap(), b"hello world");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    // A diff made against a real file must not be applied to nothing
+    #[test]
+    fn diff_against_content_fails_from_empty_source() {
+        let folder = folder("mismatch");
+        fs::write(folder.join("patch"), diff(5, 5, b"hello world", false)).unwrap();
+        assert!(HPatchZ::apply_patch_empty(folder.join("patch"), folder.join("target")).is_err());
+        assert!(!folder.join("target").exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::events::{self, Event};
+use crate::{log, util};
+
+/// md5 of the files a run takes its instructions from, by file name
+///
+/// Kept in the receipt and the state file and written to the event log, so a bad result can be traced back to the
+/// exact package and metadata that produced it
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Inputs {
+    /// The archives applied, folders such as a chunk folder have no hash and are left out
+    pub packages: BTreeMap<String, String>,
+    /// Hdiff maps, delete lists and manifests read from the packages or next to them
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Inputs {
+    /// Hash the package archives of a run
+    pub fn of_packages(packages: &[PathBuf]) -> Result<Inputs> {
+        let mut inputs = Inputs::default();
+        for package in packages.iter().filter(|package| package.is_file()) {
+            log::info!("Hashing {}", file_name(package));
+            inputs.packages.insert(file_name(package), hash("package", package)?);
+        }
+        Ok(inputs)
+    }
+
+    /// Hash metadata files the run reads
+    pub fn add_metadata(&mut self, paths: &[PathBuf]) -> Result<()> {
+        for path in paths.iter().filter(|path| path.is_file()) {
+            self.metadata.insert(file_name(path), hash("metadata", path)?);
+        }
+        Ok(())
+    }
+
+    /// Name of the first package an earlier record holds with another hash
+    pub fn changed_package(&self, earlier: &Inputs) -> Option<&str> {
+        self.packages.iter()
+            .find(|(name, md5)| earlier.packages.get(*name).is_some_and(|earlier| earlier != *md5))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+fn hash(kind: &str, path: &Path) -> Result<String> {
+    let md5 = util::calculate_md5_hash_with_progress(path, &util::create_multi_progress())
+        .map_err(|e| anyhow!("Failed to hash {:?}: {}", path, e))?;
+    events::emit(Event::Input { kind: kind.to_string(), name: file_name(path), md5: md5.clone() });
+    Ok(md5)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
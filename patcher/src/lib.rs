@@ -0,0 +1,54 @@
+#![feature(once_cell_try)]
+
+//! Patching game folders with hdiff, ldiff and chunk packages
+//!
+//! The SophonPatcher binary is a thin wrapper around [`cli::main`]. Launchers embed the patching itself through
+//! [`Patcher`], which never reads from stdin or writes to stdout and returns what it did as a [`PatchOutcome`]
+
+pub mod cli;
+mod patcher;
+mod args;
+mod options;
+mod log;
+mod util;
+mod notify;
+mod schedule;
+mod metrics;
+mod quirks;
+mod mods;
+mod verify;
+mod official;
+mod download;
+mod estimate;
+mod pools;
+mod cpu;
+mod priority;
+mod overall;
+mod status;
+mod bars;
+mod events;
+mod adaptive;
+mod handles;
+mod disk;
+mod consolidate;
+mod receipt;
+mod relocate;
+mod state;
+mod inputs;
+mod orchestrate;
+mod update;
+mod simulate;
+mod diagnose;
+mod chunks;
+mod support;
+mod hpatchz;
+mod action;
+mod serialize;
+mod extractor;
+
+pub use patcher::Patcher;
+pub use action::{Cleanup, Detail, OnError, PatchOptions, PatchOutcome, ProgressMode};
+pub use estimate::Estimate;
+pub use log::Level;
+pub use verify::VerifyMode;
+pub use sophon::sophon::{Existing, NoProgress, Phase, ProgressSink};
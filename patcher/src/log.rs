@@ -0,0 +1,139 @@
+use std::fmt::Arguments;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use console::style;
+use crate::util;
+
+/// How bad a line is, given to the hook set with `set_hook`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+    Ok,
+}
+
+/// Disable colors when requested by `--no-color` or the `NO_COLOR` convention
+pub fn init(no_color: bool) {
+    let no_color = no_color || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    if no_color {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+}
+
+// Set by --verbose to print run statistics at the end
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+// Where lines go instead of the terminal, set by launchers embedding the patcher
+static HOOK: OnceLock<fn(Level, String)> = OnceLock::new();
+
+/// Hand every line to `hook` instead of printing it
+pub fn set_hook(hook: fn(Level, String)) {
+    let _ = HOOK.set(hook);
+}
+
+/// Print an empty line between sections of the output
+pub fn blank() {
+    if HOOK.get().is_none() && !util::is_quiet() {
+        flush();
+        util::suspend_bars(|| println!());
+    }
+}
+
+/// Something for the printer thread to do
+enum Line {
+    Print(Level, String),
+    /// Tell the sender once every line queued before is printed
+    Flush(Sender<()>),
+}
+
+// Lines of worker threads are printed one at a time by a single thread, started with the first line
+static PRINTER: OnceLock<Sender<Line>> = OnceLock::new();
+
+/// Print a message with a colored level prefix, errors and warnings go to stderr
+///
+/// Lines queued by workers are printed first, so the output keeps the order things happened in
+pub fn print(level: Level, message: Arguments) {
+    flush();
+    util::suspend_bars(|| write(level, message));
+}
+
+/// Print a message from a worker thread without tearing progress bars or other workers' lines
+pub fn queue(level: Level, message: String) {
+    let printer = PRINTER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in receiver {
+                match line {
+                    Line::Print(level, message) => util::suspend_bars(|| write(level, format_args!("{}", message))),
+                    Line::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        sender
+    });
+    let _ = printer.send(Line::Print(level, message));
+}
+
+/// Wait until every queued line is printed
+pub fn flush() {
+    let Some(printer) = PRINTER.get() else {
+        return;
+    };
+    let (done, printed) = mpsc::channel();
+    if printer.send(Line::Flush(done)).is_ok() {
+        let _ = printed.recv();
+    }
+}
+
+/// Error lines of sophon workers, given to `sophon::sophon::set_message_hook`
+pub fn sophon_message(message: String) {
+    queue(Level::Error, message);
+}
+
+fn write(level: Level, message: Arguments) {
+    if let Some(hook) = HOOK.get() {
+        hook(level, message.to_string());
+        return;
+    }
+    if util::is_quiet() {
+        return;
+    }
+    match level {
+        Level::Info => println!("{} {}", style("[INFO]").cyan(), message),
+        Level::Ok => println!("{} {}", style("[OK]").green(), message),
+        Level::Warn => eprintln!("{} {}", style("[WARN]").yellow().for_stderr(), message),
+        Level::Error => eprintln!("{} {}", style("[ERROR]").red().bold().for_stderr(), message),
+    }
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::print($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::print($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::print($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+macro_rules! ok {
+    ($($arg:tt)*) => { $crate::log::print($crate::log::Level::Ok, format_args!($($arg)*)) };
+}
+
+pub(crate) use {error, info, ok, warning as warn};
@@ -0,0 +1,95 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use crate::disk;
+use crate::events::{self, Event};
+
+// Global run counters
+pub static FILES_PATCHED: AtomicU64 = AtomicU64::new(0);
+pub static FILES_FAILED: AtomicU64 = AtomicU64::new(0);
+pub static FILES_DELETED: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static STAGES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+// Prometheus textfile the counters get written to
+static TEXTFILE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Write the counters to the given textfile after every stage
+pub fn set_textfile(path: PathBuf) {
+    let _ = TEXTFILE_PATH.set(path);
+}
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn add(counter: &AtomicU64, amount: u64) {
+    counter.fetch_add(amount, Ordering::Relaxed);
+}
+
+/// Start timing a stage, the duration is recorded once the guard is dropped
+pub fn stage(name: &'static str) -> StageTimer {
+    events::emit(Event::StageStart { stage: name.to_string() });
+    StageTimer { name, start: Instant::now() }
+}
+
+pub struct StageTimer {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Drop for StageTimer {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+        events::emit(Event::StageEnd { stage: self.name.to_string(), duration_ms: duration.as_millis() as u64 });
+        if let Ok(mut stages) = STAGES.lock() {
+            stages.push((self.name, duration));
+        }
+        let _ = write_textfile();
+    }
+}
+
+/// Write all counters in the Prometheus text exposition format
+pub fn write_textfile() -> std::io::Result<()> {
+    let Some(path) = TEXTFILE_PATH.get() else {
+        return Ok(());
+    };
+
+    let mut text = String::new();
+    for (name, help, counter) in [
+        ("files_patched_total", "Files patched successfully", &FILES_PATCHED),
+        ("files_failed_total", "Files that failed to patch", &FILES_FAILED),
+        ("files_deleted_total", "Files deleted by the package", &FILES_DELETED),
+        ("bytes_written_total", "Bytes of patched and assembled files written", &BYTES_WRITTEN),
+    ] {
+        let _ = writeln!(text, "# HELP sophon_patcher_{name} {help}");
+        let _ = writeln!(text, "# TYPE sophon_patcher_{name} counter");
+        let _ = writeln!(text, "sophon_patcher_{name} {}", counter.load(Ordering::Relaxed));
+    }
+
+    let _ = writeln!(text, "# HELP sophon_patcher_stage_duration_seconds Time spent in each stage");
+    let _ = writeln!(text, "# TYPE sophon_patcher_stage_duration_seconds gauge");
+    if let Ok(stages) = STAGES.lock() {
+        for (stage, duration) in stages.iter() {
+            let _ = writeln!(
+                text,
+                "sophon_patcher_stage_duration_seconds{{stage=\"{stage}\"}} {:.3}",
+                duration.as_secs_f64(),
+            );
+        }
+    }
+
+    let _ = writeln!(text, "# HELP sophon_patcher_stage_disk_bytes Most disk space taken by each stage");
+    let _ = writeln!(text, "# TYPE sophon_patcher_stage_disk_bytes gauge");
+    for (stage, bytes) in disk::peaks() {
+        let _ = writeln!(text, "sophon_patcher_stage_disk_bytes{{stage=\"{stage}\"}} {bytes}");
+    }
+
+    // Write through a temp file so collectors never see a partial file
+    let temp_path = path.with_extension("prom.tmp");
+    fs::write(&temp_path, text)?;
+    fs::rename(temp_path, path)
+}
@@ -0,0 +1,72 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::{log, util};
+
+// Files and folders mod loaders drop into the game folder
+const MOD_ENTRIES: &[&str] = &[
+    "3dmigoto",
+    "BepInEx",
+    "d3d11.dll",
+    "d3dx.ini",
+    "d3dx_user.ini",
+    "dxgi.dll",
+    "winhttp.dll",
+    "doorstop_config.ini",
+];
+
+const BACKUP_FOLDER: &str = "mods_backup";
+
+/// Mod files moved aside for the duration of a patch, put back when dropped
+pub struct ModBackup {
+    game_path: PathBuf,
+    entries: Vec<String>,
+}
+
+/// Warn about mod loader files and offer to move them aside until patching is done
+pub fn set_aside(game_path: &Path) -> Result<Option<ModBackup>> {
+    let entries = MOD_ENTRIES.iter()
+        .filter(|name| game_path.join(name).exists())
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    log::warn!("Mod loader files found, patching may break or conflict with them: {}", entries.join(", "));
+    if !util::confirm("mods", "Move them into mods_backup until patching is done? (Y/n) [Y]: ", true) {
+        return Ok(None);
+    }
+
+    let backup_path = game_path.join(BACKUP_FOLDER);
+    fs::create_dir_all(&backup_path)?;
+    for name in &entries {
+        fs::rename(game_path.join(name), backup_path.join(name))?;
+    }
+
+    Ok(Some(ModBackup { game_path: game_path.to_path_buf(), entries }))
+}
+
+impl Drop for ModBackup {
+    fn drop(&mut self) {
+        let backup_path = self.game_path.join(BACKUP_FOLDER);
+        let mut restored = true;
+        for name in &self.entries {
+            // The patch may have shipped a file with the same name, keep that one
+            let path = self.game_path.join(name);
+            if path.exists() {
+                log::warn!("{} was added by the patch, the modded one stays in {}", name, BACKUP_FOLDER);
+                restored = false;
+                continue;
+            }
+            if let Err(e) = fs::rename(backup_path.join(name), &path) {
+                log::error!("Failed to restore {}: {}", name, e);
+                restored = false;
+            }
+        }
+
+        if restored {
+            let _ = fs::remove_dir(backup_path);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+// Tray icon of a background run with the tooltip it shows last, none when not shown or the platform has none
+static TRAY: Mutex<Option<(Child, String)>> = Mutex::new(None);
+
+/// Fire a desktop notification, silently doing nothing if the platform has no notifier
+pub fn send(title: &str, body: &str) {
+    let mut command = notifier(title, body);
+    let _ = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+}
+
+/// Show a tray icon whose tooltip follows the progress of the run, silently doing nothing without a tray
+pub fn show_tray() {
+    let Some(mut command) = tray() else {
+        return;
+    };
+    let child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Ok(child) = child {
+        *TRAY.lock().unwrap() = Some((child, String::new()));
+    }
+}
+
+/// Set the tooltip of the tray icon, e.g. to the overall percentage
+pub fn set_tray_progress(text: &str) {
+    let mut tray = TRAY.lock().unwrap();
+    let Some((child, shown)) = tray.as_mut() else {
+        return;
+    };
+    if shown == text {
+        return;
+    }
+    shown.replace_range(.., text);
+    let written = child.stdin.as_mut().map(|stdin| writeln!(stdin, "{}", tray_line(text)));
+    // A tray that went away is not started again
+    if !matches!(written, Some(Ok(()))) {
+        *tray = None;
+    }
+}
+
+/// Remove the tray icon once the run is done
+pub fn hide_tray() {
+    let Some((mut child, _)) = TRAY.lock().unwrap().take() else {
+        return;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = writeln!(stdin, "{}", TRAY_QUIT);
+    }
+    let _ = child.wait();
+}
+
+#[cfg(target_os = "windows")]
+const TRAY_QUIT: &str = "";
+
+#[cfg(target_os = "windows")]
+fn tray_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+#[cfg(target_os = "windows")]
+fn tray() -> Option<Command> {
+    // The tooltip is set from every line read until input ends or is empty, tooltips take 63 characters at most
+    let script = "Add-Type -AssemblyName System.Windows.Forms; \
+        $icon = New-Object System.Windows.Forms.NotifyIcon; \
+        $icon.Icon = [System.Drawing.SystemIcons]::Application; \
+        $icon.Text = 'SophonPatcher'; \
+        $icon.Visible = $true; \
+        while ($line = [Console]::In.ReadLine()) { \
+            $icon.Text = $line.Substring(0, [Math]::Min(63, $line.Length)); \
+            [System.Windows.Forms.Application]::DoEvents() \
+        }; \
+        $icon.Dispose()";
+
+    let mut command = Command::new("powershell");
+    command.args(["-NoProfile", "-WindowStyle", "Hidden", "-Command", script]);
+    Some(command)
+}
+
+// yad takes its tooltip and when to quit as commands on its input
+#[cfg(all(unix, not(target_os = "macos")))]
+const TRAY_QUIT: &str = "quit";
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn tray_line(text: &str) -> String {
+    format!("tooltip:{}", text.replace(['\r', '\n'], " "))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn tray() -> Option<Command> {
+    let mut command = Command::new("yad");
+    command.args(["--notification", "--listen", "--image=system-software-update", "--text=SophonPatcher"]);
+    Some(command)
+}
+
+// The macOS menu bar takes no icons from scripts, background runs only notify there
+#[cfg(target_os = "macos")]
+const TRAY_QUIT: &str = "";
+
+#[cfg(target_os = "macos")]
+fn tray_line(text: &str) -> String {
+    text.to_string()
+}
+
+#[cfg(target_os = "macos")]
+fn tray() -> Option<Command> {
+    None
+}
+
+#[cfg(target_os = "windows")]
+fn notifier(title: &str, body: &str) -> Command {
+    // Show a balloon tip from a short-lived tray icon
+    let script = format!(
+        "Add-Type -AssemblyName System.Windows.Forms; \
+        $icon = New-Object System.Windows.Forms.NotifyIcon; \
+        $icon.Icon = [System.Drawing.SystemIcons]::Information; \
+        $icon.BalloonTipTitle = '{}'; \
+        $icon.BalloonTipText = '{}'; \
+        $icon.Visible = $true; \
+        $icon.ShowBalloonTip(10000); \
+        Start-Sleep -Seconds 10; \
+        $icon.Dispose()",
+        title.replace('\'', "''"),
+        body.replace('\'', "''"),
+    );
+
+    let mut command = Command::new("powershell");
+    command.args(["-NoProfile", "-WindowStyle", "Hidden", "-Command", &script]);
+    command
+}
+
+#[cfg(target_os = "macos")]
+fn notifier(title: &str, body: &str) -> Command {
+    let script = format!(
+        "display notification \"{}\" with title \"{}\"",
+        body.replace('"', "\\\""),
+        title.replace('"', "\\\""),
+    );
+
+    let mut command = Command::new("osascript");
+    command.args(["-e", &script]);
+    command
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn notifier(title: &str, body: &str) -> Command {
+    let mut command = Command::new("notify-send");
+    command.args(["--app-name=SophonPatcher", title, body]);
+    command
+}
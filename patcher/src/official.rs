@@ -0,0 +1,31 @@
+use std::sync::OnceLock;
+use anyhow::{anyhow, Result};
+use sophon::download::fetch_build;
+use sophon::proto::chunk::SophonChunkProto;
+use crate::{log, util};
+
+// getBuild URL of the version the game is patched to, given with --build-url
+static BUILD_URL: OnceLock<String> = OnceLock::new();
+
+/// Verify against the chunk manifests of the given build instead of the local listings
+pub fn set_build_url(url: String) {
+    let _ = BUILD_URL.set(url);
+}
+
+/// Download the chunk manifests of the build given with --build-url, with the part of the game each describes
+pub fn manifests() -> Result<Vec<(String, SophonChunkProto)>> {
+    let url = BUILD_URL.get().ok_or_else(|| anyhow!("Verifying against the official build needs --build-url"))?;
+    let build = util::with_spinner("Fetching the official build", || fetch_build(url))?;
+    log::info!("Verifying against official build {}", build.tag);
+
+    let mut manifests = Vec::new();
+    for entry in build.manifests {
+        let manifest = util::with_spinner(&format!("Downloading {} manifest", entry.matching_field), || {
+            let buffer = entry.fetch_manifest()?;
+            SophonChunkProto::from_reader(&*buffer)
+                .map_err(|e| anyhow!("Failed to read manifest {}: {}", entry.manifest.id, e))
+        })?;
+        manifests.push((entry.matching_field, manifest));
+    }
+    Ok(manifests)
+}
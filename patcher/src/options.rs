@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use crate::args::{self, Args};
+
+// Names the arguments of a command go by in the environment and the config file, by their position
+const POSITIONAL: &[(usize, &str)] = &[
+    (0, "game-dir"),
+    (1, "package"),
+    (2, "manifest"),
+];
+
+// Alternative names accepted for options, e.g. `SOPHON_NONINTERACTIVE`
+const ALIASES: &[(&str, &str)] = &[
+    ("noninteractive", "non-interactive"),
+];
+
+const CONFIG_FILE: &str = "SophonPatcher.toml";
+
+/// Options resolved from the command line, then `SOPHON_*` environment variables, then the config file
+pub struct Options {
+    args: Args,
+    env: HashMap<String, String>,
+    config: HashMap<String, String>,
+}
+
+impl Options {
+    pub fn load(args: Args) -> Result<Options> {
+        let env = env::vars()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix("SOPHON_")?.to_lowercase().replace('_', "-");
+                Some((resolve_alias(name), value))
+            })
+            .collect::<HashMap<_, _>>();
+
+        // Config file from --config or SOPHON_CONFIG, otherwise next to the executable when present
+        let config_path = args.value("config")
+            .map(PathBuf::from)
+            .or_else(|| env.get("config").map(PathBuf::from));
+        let config = match config_path {
+            Some(path) => read_config(&path)?,
+            None => match env::current_exe()?.with_file_name(CONFIG_FILE) {
+                path if path.is_file() => read_config(&path)?,
+                _ => HashMap::new(),
+            },
+        };
+
+        Ok(Options { args, env, config })
+    }
+
+    /// The command to run, given on the command line or as the `action` option, with aliases resolved
+    pub fn command(&self) -> Option<String> {
+        let command = self.args.command().or_else(|| self.value("action"))?;
+        Some(args::resolve(command).unwrap_or(command).to_string())
+    }
+
+    /// Get an argument of the command by its ID, or the option named after its position, e.g. `--game-dir`
+    pub fn arg(&self, id: &str) -> Option<String> {
+        self.args.arg(id).or_else(|| {
+            let position = args::params(&self.command()?).iter().position(|(param, _)| *param == id)?;
+            let (_, name) = POSITIONAL.iter().find(|(index, _)| *index == position)?;
+            self.value(name).map(str::to_string)
+        })
+    }
+
+    /// Get every value of an argument of the command taking several
+    pub fn args(&self, id: &str) -> Vec<String> {
+        match self.args.args(id) {
+            values if values.is_empty() => self.arg(id).into_iter().collect(),
+            values => values,
+        }
+    }
+
+    /// Check whether a flag is on, `0`, `false` and `no` turn it off on the command line, in the environment and config
+    pub fn flag(&self, name: &str) -> bool {
+        if let Some(on) = self.args.flag(name) {
+            return on;
+        }
+        self.env.get(name)
+            .or_else(|| self.config.get(name))
+            .is_some_and(|value| !matches!(value.to_lowercase().as_str(), "" | "0" | "false" | "no"))
+    }
+
+    /// Get the value of an option, if any
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.args.value(name)
+            .or_else(|| self.env.get(name).map(String::as_str))
+            .or_else(|| self.config.get(name).map(String::as_str))
+    }
+
+    /// Get the value of an option parsed into `T`, failing with the option name when it does not parse
+    pub fn parsed<T: FromStr>(&self, name: &str) -> Result<Option<T>> {
+        self.value(name)
+            .map(|value| value.parse().map_err(|_| anyhow!("Invalid value {} for --{}", value, name)))
+            .transpose()
+    }
+}
+
+fn resolve_alias(name: String) -> String {
+    ALIASES.iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, target)| target.to_string())
+}
+
+/// Read a flat TOML table of option names to values
+fn read_config(path: &PathBuf) -> Result<HashMap<String, String>> {
+    let table = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read config {:?}: {}", path, e))?
+        .parse::<toml::Table>()?;
+    Ok(table.into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(value) => value,
+                value => value.to_string(),
+            };
+            (resolve_alias(key), value)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noninteractive_resolves_to_non_interactive() {
+        assert_eq!(resolve_alias("noninteractive".to_string()), "non-interactive");
+        assert_eq!(resolve_alias("background".to_string()), "background");
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use crate::action::{PatchOptions, PatchOutcome};
+use crate::{action, log};
+
+/// Apply the same package to every target directory listed in a file
+pub async fn orchestrate(
+    targets_file: &Path,
+    action: String,
+    package: String,
+    manifest: Option<String>,
+    parallel: bool,
+    options: &PatchOptions,
+) -> Result<PatchOutcome> {
+    let targets = read_targets(targets_file)?;
+    if targets.is_empty() {
+        return Err(anyhow!("{:?} does not list any targets", targets_file));
+    }
+
+    // Run every target, either one after another or all at once
+    let mut reports: Vec<(PathBuf, Result<PatchOutcome>, Duration)> = Vec::new();
+    if parallel {
+        let handles = targets.iter()
+            .map(|target| {
+                let target = target.clone();
+                let (action, package, manifest) = (action.clone(), package.clone(), manifest.clone());
+                let options = options.clone();
+                tokio::spawn(async move {
+                    let start = Instant::now();
+                    let result = run_target(&target, action, package, manifest, &options).await;
+                    (result, start.elapsed())
+                })
+            })
+            .collect::<Vec<_>>();
+        for (target, handle) in targets.into_iter().zip(handles) {
+            let (result, elapsed) = handle.await
+                .unwrap_or_else(|e| (Err(anyhow!(e)), Duration::ZERO));
+            reports.push((target, result, elapsed));
+        }
+    } else {
+        for target in targets {
+            log::info!("Patching {}", target.display());
+            let start = Instant::now();
+            let result = run_target(&target, action.clone(), package.clone(), manifest.clone(), options).await;
+            reports.push((target, result, start.elapsed()));
+        }
+    }
+
+    // Print per-target report, details are merged into one outcome keyed by full path
+    log::blank();
+    log::info!("Report");
+    let mut failed = 0;
+    let total = reports.len();
+    let mut merged = PatchOutcome::default();
+    for (target, result, elapsed) in reports {
+        match result {
+            Ok(mut outcome) => {
+                log::ok!(
+                    "{} ({}s): {} patched, {} failed",
+                    target.display(),
+                    elapsed.as_secs(),
+                    outcome.patched,
+                    outcome.failed,
+                );
+                for (path, _) in &mut outcome.details {
+                    *path = target.join(&*path).display().to_string();
+                }
+                for path in &mut outcome.written {
+                    *path = target.join(&*path).display().to_string();
+                }
+                merged.merge(outcome);
+            }
+            Err(e) => {
+                failed += 1;
+                log::error!("{} ({}s): {}", target.display(), elapsed.as_secs(), e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(anyhow!("{} of {} targets failed", failed, total))
+    } else {
+        Ok(merged)
+    }
+}
+
+async fn run_target(
+    target: &Path,
+    action: String,
+    package: String,
+    manifest: Option<String>,
+    options: &PatchOptions,
+) -> Result<PatchOutcome> {
+    match action.as_str() {
+        "0" => action::hdiff(target, package, options).await,
+        "1" => action::ldiff(target, vec![package], options).await,
+        "2" => action::chunk(target, package, manifest.unwrap_or_default(), options).await,
+        _ => Err(anyhow!("Unknown action {}", action)),
+    }
+}
+
+/// Read one target directory per line, skipping blank lines and `#` comments
+///
+/// Relative targets are relative to the folder of the targets file
+fn read_targets(path: &Path) -> Result<Vec<PathBuf>> {
+    let folder = path.parent().unwrap_or(Path::new(""));
+    let targets = fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| folder.join(line))
+        .collect();
+    Ok(targets)
+}
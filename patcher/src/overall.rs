@@ -0,0 +1,121 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use indicatif::{HumanDuration, ProgressBar, WeakProgressBar};
+use sophon::sophon::Phase;
+use crate::disk::Stage;
+use crate::notify;
+
+// Progress of the whole run, none while no action is running
+static OVERALL: Mutex<Option<Overall>> = Mutex::new(None);
+
+// Least time between updates of the shown percentage
+const REFRESH: Duration = Duration::from_millis(200);
+
+/// Bytes of work planned and done per stage, shown as one percentage with an ETA next to the stage bars
+struct Overall {
+    started: Instant,
+    planned: [u64; 3],
+    /// Stages whose planned bytes are a guess, replaced once they are planned for real
+    estimated: [bool; 3],
+    done: [u64; 3],
+    bar: Option<WeakProgressBar>,
+    refreshed: Option<Instant>,
+}
+
+impl Overall {
+    fn refresh(&mut self, force: bool) {
+        let total = self.planned.iter().sum::<u64>().max(1);
+        let done = self.planned.iter().zip(self.done).map(|(&planned, done)| done.min(planned)).sum::<u64>();
+
+        // The last update of a stage is always shown so it never stops short of where it got
+        let stage_done = self.planned.iter().zip(self.done).all(|(&planned, done)| done == 0 || done >= planned);
+        if !force && !stage_done && self.refreshed.is_some_and(|refreshed| refreshed.elapsed() < REFRESH) {
+            return;
+        }
+        self.refreshed = Some(Instant::now());
+
+        let mut message = format!("{}% overall", done * 100 / total);
+        if done > 0 {
+            let left = self.started.elapsed().mul_f64((total - done) as f64 / done as f64);
+            message.push_str(&format!(", ETA {}", HumanDuration(left)));
+        }
+        notify::set_tray_progress(&format!("SophonPatcher: {}", message));
+        if let Some(bar) = self.bar.as_ref().and_then(WeakProgressBar::upgrade) {
+            bar.set_message(message);
+        }
+    }
+}
+
+/// Start tracking a run, the stages it goes through are planned as their sizes become known
+pub fn start() {
+    *OVERALL.lock().unwrap() = Some(Overall {
+        started: Instant::now(),
+        planned: [0; 3],
+        estimated: [false; 3],
+        done: [0; 3],
+        bar: None,
+        refreshed: None,
+    });
+}
+
+pub fn finish() {
+    *OVERALL.lock().unwrap() = None;
+}
+
+/// Add bytes a stage works through, replacing an earlier estimate
+pub fn plan(stage: Stage, bytes: u64) {
+    if let Some(overall) = OVERALL.lock().unwrap().as_mut() {
+        let index = stage as usize;
+        if overall.estimated[index] {
+            overall.estimated[index] = false;
+            overall.planned[index] = 0;
+        }
+        overall.planned[index] += bytes;
+        overall.refresh(true);
+    }
+}
+
+/// Guess the bytes of a stage that is planned later, so the percentage does not drop once it is
+pub fn estimate(stage: Stage, bytes: u64) {
+    if let Some(overall) = OVERALL.lock().unwrap().as_mut() {
+        let index = stage as usize;
+        if overall.planned[index] == 0 {
+            overall.planned[index] = bytes;
+            overall.estimated[index] = true;
+        }
+    }
+}
+
+pub fn advance(stage: Stage, bytes: u64) {
+    if let Some(overall) = OVERALL.lock().unwrap().as_mut() {
+        overall.done[stage as usize] += bytes;
+        overall.refresh(false);
+    }
+}
+
+/// Report a stage whose progress is only known as a count, e.g. archive entries
+pub fn set_fraction(stage: Stage, current: usize, max: usize) {
+    if let Some(overall) = OVERALL.lock().unwrap().as_mut() {
+        let index = stage as usize;
+        overall.done[index] = overall.planned[index] * current as u64 / max.max(1) as u64;
+        overall.refresh(false);
+    }
+}
+
+/// Show the overall progress next to the stage bar created last
+pub fn attach(bar: &ProgressBar) {
+    if let Some(overall) = OVERALL.lock().unwrap().as_mut() {
+        overall.bar = Some(bar.downgrade());
+        overall.refresh(true);
+    }
+}
+
+/// Count bytes of work done inside the sophon crate
+pub fn sophon_progress(phase: Phase, bytes: u64) {
+    match phase {
+        Phase::Staging => advance(Stage::Staging, bytes),
+        Phase::Assembly => advance(Stage::Write, bytes),
+        // Downloads happen before any action starts
+        _ => {}
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use anyhow::{anyhow, Result};
+use sophon::sophon::ProgressSink;
+use crate::action::{PatchOptions, PatchOutcome};
+use crate::estimate::{self, Estimate};
+use crate::hpatchz::HPatchZ;
+use crate::log::{self, Level};
+use crate::{action, bars, handles, pools, update, util};
+
+// Thread pools and the open file budget are set up once per process, by the first patcher
+static SETUP: OnceLock<()> = OnceLock::new();
+
+/// Patches one game folder for a launcher embedding the patcher, without reading stdin or writing to stdout
+///
+/// Questions an action would ask get the answer given with `answer`, or their default. Lines the actions log go to
+/// the hook given with `on_message` and are dropped without one. Like the settings of the binary these hold for the
+/// whole process, so patchers should not run at the same time
+pub struct Patcher {
+    game_path: PathBuf,
+    options: PatchOptions,
+}
+
+impl Patcher {
+    /// Patcher for a game folder, the thread counts of the first one created hold for the whole process
+    pub fn new<P: AsRef<Path>>(game_path: P, options: PatchOptions) -> Result<Patcher> {
+        let game_path = util::absolute_path(&game_path.as_ref().to_string_lossy())?;
+        if !game_path.is_dir() {
+            return Err(anyhow!("{:?} does not exist", game_path));
+        }
+        SETUP.get_or_try_init(|| -> Result<()> {
+            pools::configure(options.hash_threads, options.io_threads)?;
+            handles::configure()?;
+            sophon::sophon::set_message_hook(log::sophon_message);
+            Ok(())
+        })?;
+        util::set_quiet(true);
+        util::set_unattended(true);
+        if let Some(path) = &options.temp_dir {
+            HPatchZ::set_temp_root(path.clone());
+        }
+        Ok(Patcher { game_path, options })
+    }
+
+    /// Answer a question like `verify` or `delete` instead of taking its default
+    pub fn answer(&self, key: &str, answer: bool) -> Result<()> {
+        if !util::QUESTIONS.contains(&key) {
+            return Err(anyhow!("Unknown question {}, expected one of {}", key, util::QUESTIONS.join(", ")));
+        }
+        util::set_answer(key, answer);
+        Ok(())
+    }
+
+    /// Hand every line the actions log to `hook`, only the first hook set is used
+    pub fn on_message(&self, hook: fn(Level, String)) {
+        log::set_hook(hook);
+    }
+
+    /// Report the staging and assembly of every action to `sink` instead of dropping it, only the first sink set
+    /// is used
+    pub fn on_progress(&self, sink: Arc<dyn ProgressSink>) {
+        bars::set_sink(sink);
+    }
+
+    /// Apply an hdiff archive, given relative to the game folder or as a path of its own
+    pub async fn hdiff(&self, package: &str) -> Result<PatchOutcome> {
+        let package = util::resolve_package(&self.game_path, package)?;
+        action::hdiff(&self.game_path, package, &self.options).await
+    }
+
+    /// Apply the parts of an ldiff update together, a `*` in a file name picking every matching archive
+    pub async fn ldiff(&self, packages: &[&str]) -> Result<PatchOutcome> {
+        let mut ldiff_files = Vec::new();
+        for package in packages {
+            ldiff_files.extend(util::expand_package(&self.game_path, package)?);
+        }
+        action::ldiff(&self.game_path, ldiff_files, &self.options).await
+    }
+
+    /// Assemble the assets of a chunk manifest from a folder of chunks
+    pub async fn chunk(&self, chunk_folder: &str, manifest: &str) -> Result<PatchOutcome> {
+        let chunk_folder = util::resolve_package(&self.game_path, chunk_folder)?;
+        let manifest = util::resolve_package(&self.game_path, manifest)?;
+        action::chunk(&self.game_path, chunk_folder, manifest, &self.options).await
+    }
+
+    /// Assemble only the assets of a chunk manifest that are missing or corrupted in the game folder
+    pub async fn repair(&self, chunk_folder: &str, manifest: &str) -> Result<PatchOutcome> {
+        let chunk_folder = util::resolve_package(&self.game_path, chunk_folder)?;
+        let manifest = util::resolve_package(&self.game_path, manifest)?;
+        action::repair(&self.game_path, chunk_folder, manifest, &self.options).await
+    }
+
+    /// Apply every update package found in the game folder, answered by the `update` question
+    pub async fn update(&self) -> Result<PatchOutcome> {
+        update::update(&self.game_path, &self.options).await
+    }
+
+    /// Space and time a package takes to apply, for showing the requirements before patching starts
+    pub fn estimate(&self, package: &str) -> Result<Estimate> {
+        let package = util::resolve_package(&self.game_path, package)?;
+        estimate::estimate(&self.game_path, &self.game_path.join(package))
+    }
+
+    /// Remove the hpatchz work directory in the temp folder, once no patcher is used anymore
+    pub fn clean_up() -> Result<()> {
+        HPatchZ::cleanup()
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::OnceLock;
+use anyhow::Result;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use crate::cpu::{self, CpuPolicy, Role};
+
+// Pool for CPU bound hashing, kept apart from the global rayon pool doing disk I/O
+static HASH_POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+/// Size the hashing pool and the global pool used for patching and assembling files
+///
+/// Has to run before anything uses rayon, zero or none picks one thread per core. Threads are placed on cores by
+/// the cpu policy, which has to be set before
+pub fn configure(hash_threads: Option<usize>, io_threads: Option<usize>) -> Result<()> {
+    if io_threads.is_some() || cpu::policy() != CpuPolicy::None {
+        ThreadPoolBuilder::new()
+            .num_threads(io_threads.unwrap_or(0))
+            .start_handler(|_| cpu::place(Role::Io))
+            .build_global()?;
+    }
+    if let Some(threads) = hash_threads {
+        let _ = HASH_POOL.set(build_hash_pool(threads)?);
+    }
+    Ok(())
+}
+
+pub fn hash_pool() -> &'static ThreadPool {
+    HASH_POOL.get_or_init(|| build_hash_pool(0).expect("Failed to build hash thread pool"))
+}
+
+fn build_hash_pool(threads: usize) -> Result<ThreadPool> {
+    Ok(ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("hash-{}", index))
+        .start_handler(|_| cpu::place(Role::Compute))
+        .build()?)
+}
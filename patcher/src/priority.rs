@@ -0,0 +1,68 @@
+use std::io;
+use crate::log;
+
+// Nice value of background work, 19 being the lowest priority
+#[cfg(unix)]
+const BACKGROUND_NICE: i32 = 10;
+
+/// Lower the CPU and I/O priority of the whole process and whatever it starts, e.g. hpatchz, so games running at
+/// the same time do not stutter
+pub fn lower() {
+    match lower_priority() {
+        Ok(()) => log::info!("Running with background CPU and I/O priority"),
+        Err(e) => log::warn!("Could not lower the process priority: {}", e),
+    }
+}
+
+/// Threads have their own priority on Linux, every thread running so far is lowered and later ones inherit it
+#[cfg(target_os = "linux")]
+fn lower_priority() -> io::Result<()> {
+    // Idle I/O class, only served when no one else uses the disk
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        let Ok(tid) = entry?.file_name().to_string_lossy().parse::<libc::id_t>() else {
+            continue;
+        };
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, tid, BACKGROUND_NICE) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let io_priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+            if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, io_priority) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Other unix systems only get a lower CPU priority
+#[cfg(all(unix, not(target_os = "linux")))]
+fn lower_priority() -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, BACKGROUND_NICE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Background mode lowers CPU, I/O and memory priority in one go
+#[cfg(windows)]
+fn lower_priority() -> io::Result<()> {
+    use std::ffi::c_void;
+
+    const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x0010_0000;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn SetPriorityClass(process: *mut c_void, priority_class: u32) -> i32;
+    }
+
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,353 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Deserialize;
+use crate::log;
+
+// Profiles shipped with the binary, picked by the executable found in the game folder or by name
+const PROFILES: &[(&str, &str)] = &[
+    ("hsr", include_str!("../quirks/hsr.toml")),
+    ("genshin", include_str!("../quirks/genshin.toml")),
+    ("zzz", include_str!("../quirks/zzz.toml")),
+    ("server", include_str!("../quirks/server.toml")),
+    ("generic", include_str!("../quirks/generic.toml")),
+];
+
+// User data every game keeps in its folder, unless a profile turns `default_protected` off
+const DEFAULT_PROTECTED: &[&str] = &[
+    "config.ini",
+    "*.log",
+    "Mods/*",
+];
+
+static ALLOW_PROTECTED: AtomicBool = AtomicBool::new(false);
+
+// Profile name or file given with --profile, used instead of detection
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Let deletion and patching touch protected paths
+pub fn set_allow_protected(allow: bool) {
+    ALLOW_PROTECTED.store(allow, Ordering::Relaxed);
+}
+
+/// Use a built-in profile by name or a profile file instead of detecting one
+pub fn set_profile(profile: String) {
+    let _ = PROFILE.set(profile);
+}
+
+/// Game specific behaviors, loaded from a quirk profile
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Quirks {
+    pub name: String,
+    /// Executables that identify the game folder
+    pub executables: Vec<String>,
+    /// File listings used to verify the game, `*` matches any part of the name
+    pub pkg_versions: Vec<String>,
+    /// Name prefix of ldiff manifests
+    pub manifest_prefix: String,
+    /// Patch missing source files from an empty file instead of skipping them
+    pub empty_source_diffs: bool,
+    /// Paths never deleted or overwritten on top of the defaults, `*` matches any part of the name
+    pub protected: Vec<String>,
+    /// Whether the user data every game keeps, e.g. `config.ini` and `Mods/*`, is protected as well
+    pub default_protected: bool,
+    /// Folder package paths are relative to, the game folder itself when empty or missing
+    pub path_root: String,
+    /// Whether the folder is a game client, servers skip the verification prompt and the mods check
+    pub client: bool,
+    /// Offer verification after patching, follows `client` when not set
+    pub verify: Option<bool>,
+    /// Look for mod loaders before patching, follows `client` when not set
+    pub mods: Option<bool>,
+    /// Paths never patched, e.g. client only audio packs on servers, `*` matches any part of the name
+    pub skip: Vec<String>,
+    /// Rules rewriting package paths to where the files are in the game folder, applied in order
+    pub remap: Vec<Remap>,
+    /// Executable names without `.exe` of editions sharing packages, e.g. CN and global, whose executable and data
+    /// folder paths are translated to the edition installed
+    pub editions: Vec<String>,
+}
+
+/// Rewrites package paths, e.g. for installs whose data folder was renamed
+#[derive(Deserialize)]
+#[serde(try_from = "RemapRule")]
+pub enum Remap {
+    /// Replace what the regex matches, `$1` and `${name}` refer to its groups
+    Regex { pattern: Regex, replace: String },
+    /// Remove a leading folder
+    StripPrefix(String),
+}
+
+/// A remap rule as written in a profile
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RemapRule {
+    pattern: Option<String>,
+    #[serde(default)]
+    replace: String,
+    strip_prefix: Option<String>,
+}
+
+impl TryFrom<RemapRule> for Remap {
+    type Error = String;
+
+    fn try_from(rule: RemapRule) -> Result<Self, Self::Error> {
+        match (rule.pattern, rule.strip_prefix) {
+            (Some(pattern), None) => Regex::new(&pattern)
+                .map(|pattern| Remap::Regex { pattern, replace: rule.replace })
+                .map_err(|e| format!("Invalid remap pattern {}: {}", pattern, e)),
+            (None, Some(prefix)) => Ok(Remap::StripPrefix(prefix)),
+            _ => Err("Remap rules need either a pattern or a strip_prefix".to_string()),
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            name: "Unknown game".to_string(),
+            executables: Vec::new(),
+            pkg_versions: vec!["pkg_version".to_string()],
+            manifest_prefix: "manifest".to_string(),
+            empty_source_diffs: true,
+            protected: Vec::new(),
+            default_protected: true,
+            path_root: String::new(),
+            client: true,
+            verify: None,
+            mods: None,
+            skip: Vec::new(),
+            remap: Vec::new(),
+            editions: Vec::new(),
+        }
+    }
+}
+
+impl Quirks {
+    /// Pick the profile given with --profile, otherwise the one whose executable exists in the game folder
+    pub fn detect(game_path: &Path) -> Result<Quirks> {
+        let mut quirks = Self::load(game_path)?;
+        quirks.translate_editions(game_path);
+        Ok(quirks)
+    }
+
+    /// Every profile shipped with the binary with its name
+    pub fn builtin() -> Result<Vec<(&'static str, Quirks)>> {
+        PROFILES.iter()
+            .map(|&(name, text)| Ok((name, toml::from_str(text)?)))
+            .collect()
+    }
+
+    fn load(game_path: &Path) -> Result<Quirks> {
+        if let Some(profile) = PROFILE.get() {
+            return match PROFILES.iter().find(|(name, _)| name == profile) {
+                Some((_, text)) => Ok(toml::from_str(text)?),
+                None => {
+                    let text = std::fs::read_to_string(profile)
+                        .map_err(|e| anyhow!("Unknown profile {}: {}", profile, e))?;
+                    Ok(toml::from_str(&text)?)
+                }
+            };
+        }
+
+        for (_, profile) in PROFILES {
+            let quirks = toml::from_str::<Quirks>(profile)?;
+            if quirks.executables.iter().any(|exe| game_path.join(exe).is_file()) {
+                return Ok(quirks);
+            }
+        }
+        Ok(Quirks::default())
+    }
+
+    /// Translate the executable and data folder of every other edition to the installed one, ahead of the profile
+    /// remap rules, so e.g. a global package applies to a CN install and the other way around
+    fn translate_editions(&mut self, game_path: &Path) {
+        let installed = self.editions.iter().find(|edition| game_path.join(format!("{}.exe", edition)).is_file());
+        let Some(installed) = installed else {
+            return;
+        };
+        let rules = self.editions.iter()
+            .filter(|&edition| edition != installed)
+            .filter_map(|edition| {
+                let pattern = Regex::new(&format!(r"^{}(_Data/|\.exe$)", regex::escape(edition))).ok()?;
+                Some(Remap::Regex { pattern, replace: format!("{}${{1}}", installed) })
+            })
+            .collect::<Vec<_>>();
+        self.remap.splice(0..0, rules);
+    }
+
+    /// Make sure the package is applied to the game folder itself, not the folder above it or an empty folder
+    ///
+    /// `package` is left out when looking at what the folder holds, as are manifests
+    pub fn check_root(&self, game_path: &Path, package: &Path) -> Result<()> {
+        let entries = game_path.read_dir()?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path() != package && !self.is_manifest(&entry.file_name().to_string_lossy()))
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return Err(anyhow!("{:?} holds nothing but the package, expected the game folder", game_path));
+        }
+        if self.is_root(game_path) {
+            return Ok(());
+        }
+
+        // Pointing at the folder the game is installed in, e.g. `Games` instead of `Games/Genshin Impact game`
+        if let Some(entry) = entries.iter().find(|entry| entry.path().is_dir() && self.is_root(&entry.path())) {
+            return Err(anyhow!(
+                "{:?} is not the game folder but holds one, did you mean {:?}?",
+                game_path,
+                entry.path(),
+            ));
+        }
+        if self.client {
+            log::warn!(
+                "{} does not look like a game folder, it has no game executable, data folder or pkg_version",
+                game_path.display(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether a folder holds a known game executable, a Unity data folder or a file listing
+    fn is_root(&self, path: &Path) -> bool {
+        let executables = PROFILES.iter()
+            .filter_map(|(_, text)| toml::from_str::<Quirks>(text).ok())
+            .flat_map(|quirks| quirks.executables)
+            .chain(self.executables.iter().cloned())
+            .collect::<Vec<_>>();
+        if executables.iter().any(|exe| path.join(exe).is_file()) {
+            return true;
+        }
+        let Ok(entries) = path.read_dir() else {
+            return false;
+        };
+        entries.filter_map(Result::ok).any(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => name.ends_with("_Data"),
+                Ok(_) => name == "pkg_version" || self.pkg_versions.iter().any(|pattern| matches(pattern, &name)),
+                Err(_) => false,
+            }
+        })
+    }
+
+    /// Find every file listing of the game folder
+    pub fn pkg_version_paths(&self, game_path: &Path) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in game_path.read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_file() && self.pkg_versions.iter().any(|pattern| matches(pattern, &name)) {
+                paths.push(entry.path());
+            }
+        }
+
+        if paths.is_empty() {
+            return Err(anyhow!("{:?} does not contain a pkg_version", game_path));
+        }
+        Ok(paths)
+    }
+
+    /// Folder the package gets applied to, the game folder itself when it holds no `path_root` folder, e.g. as it is
+    /// that folder already
+    pub fn root(&self, game_path: &Path) -> PathBuf {
+        match game_path.join(&self.path_root) {
+            root if root.is_dir() => root,
+            _ => game_path.to_path_buf(),
+        }
+    }
+
+    /// Map a package path to the game relative path of the file, unchanged when no rule applies
+    pub fn remap(&self, path: &str) -> String {
+        let mut path = path.to_string();
+        for rule in &self.remap {
+            path = match rule {
+                Remap::Regex { pattern, replace } => pattern.replace(&path, replace.as_str()).into_owned(),
+                Remap::StripPrefix(prefix) => path.strip_prefix(prefix.as_str()).map_or(path.clone(), str::to_string),
+            };
+        }
+        path
+    }
+
+    /// Whether the user is offered verification once patching is done
+    pub fn offers_verify(&self) -> bool {
+        self.verify.unwrap_or(self.client)
+    }
+
+    /// Whether mod loaders are looked for and set aside before patching
+    pub fn checks_mods(&self) -> bool {
+        self.mods.unwrap_or(self.client)
+    }
+
+    /// Whether a game relative path is left out of patching
+    pub fn is_skipped(&self, path: &str) -> bool {
+        let path = path.replace('\\', "/");
+        self.skip.iter().any(|pattern| matches(pattern, &path))
+    }
+
+    pub fn is_manifest(&self, name: &str) -> bool {
+        name.starts_with(&self.manifest_prefix)
+    }
+
+    /// Whether a game relative path holds user data that must not be deleted or overwritten
+    pub fn is_protected(&self, path: &str) -> bool {
+        if ALLOW_PROTECTED.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let path = path.replace('\\', "/");
+        DEFAULT_PROTECTED.iter()
+            .filter(|_| self.default_protected)
+            .copied()
+            .chain(self.protected.iter().map(String::as_str))
+            .any(|pattern| matches(pattern, &path))
+    }
+}
+
+/// Case insensitive match where every `*` matches any part of the name
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    // Without a wildcard the whole name has to match
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_packages_apply_to_the_server_folder() {
+        let profiles = Quirks::builtin().unwrap();
+        let quirks = |name| &profiles.iter().find(|(profile, _)| *profile == name).unwrap().1;
+        let game_path = std::env::temp_dir().join(format!("sophon_quirks_root_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&game_path);
+        std::fs::create_dir_all(&game_path).unwrap();
+
+        // Given the server folder itself
+        assert_eq!(quirks("server").root(&game_path), game_path);
+        std::fs::create_dir(game_path.join("Server")).unwrap();
+        assert_eq!(quirks("server").root(&game_path), game_path.join("Server"));
+        assert_eq!(quirks("generic").root(&game_path), game_path);
+        std::fs::remove_dir_all(game_path).unwrap();
+    }
+}
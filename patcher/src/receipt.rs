@@ -0,0 +1,142 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{anyhow, Result};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use crate::inputs::Inputs;
+use crate::{log, pools, util};
+
+const RECEIPT_FILE: &str = "sophon_receipt.json";
+
+// Set by --ignore-receipt to patch even when the receipt says the package is applied
+static IGNORED: AtomicBool = AtomicBool::new(false);
+
+/// What a run produced in a game folder and from which package
+#[derive(Serialize, Deserialize)]
+pub struct Receipt {
+    pub created: String,
+    pub package: Source,
+    /// Further archives of a package shipped in parts, applied in the same run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<Source>,
+    pub manifests: Vec<Source>,
+    /// md5 of the package archives and the metadata the run read from them
+    #[serde(default)]
+    pub inputs: Inputs,
+    pub files: Vec<ReceiptFile>,
+}
+
+/// Identity of a package or manifest, manifests being small enough to hash
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReceiptFile {
+    pub path: String,
+    pub size: u64,
+    pub md5: String,
+}
+
+impl Source {
+    fn of(path: &Path, hash: bool) -> Result<Source> {
+        let metadata = path.metadata()?;
+        Ok(Source {
+            name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            size: if metadata.is_file() { metadata.len() } else { 0 },
+            md5: match hash && metadata.is_file() {
+                true => Some(util::calculate_md5_hash_with_progress(path, &util::create_multi_progress())?),
+                false => None,
+            },
+        })
+    }
+}
+
+pub fn set_ignored(ignored: bool) {
+    IGNORED.store(ignored, Ordering::Relaxed);
+}
+
+/// Check whether the receipt says the package and manifests were applied and no listed file changed since
+///
+/// `packages` holds the package followed by its further parts, if any
+pub fn is_applied(game_path: &Path, packages: &[PathBuf], manifests: &[PathBuf]) -> bool {
+    if IGNORED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let receipt_path = game_path.join(RECEIPT_FILE);
+    let Some(receipt) = File::open(&receipt_path)
+        .ok()
+        .and_then(|file| serde_json::from_reader::<_, Receipt>(file).ok()) else {
+        return false;
+    };
+    let Ok(written) = receipt_path.metadata().and_then(|metadata| metadata.modified()) else {
+        return false;
+    };
+
+    let Some((package, parts)) = packages.split_first() else {
+        return false;
+    };
+    let same_package = Source::of(package, false).is_ok_and(|source| source == receipt.package)
+        && parts.len() == receipt.parts.len()
+        && parts.iter()
+            .zip(&receipt.parts)
+            .all(|(part, listed)| Source::of(part, false).is_ok_and(|source| source == *listed));
+    let same_manifests = manifests.iter().all(|manifest| {
+        Source::of(manifest, true).is_ok_and(|source| receipt.manifests.contains(&source))
+    });
+    let untouched = receipt.files.iter().all(|file| {
+        game_path.join(&file.path).metadata().is_ok_and(|metadata| {
+            metadata.len() == file.size && metadata.modified().is_ok_and(|modified| modified <= written)
+        })
+    });
+    same_package && same_manifests && untouched
+}
+
+/// Hash every written file and save the receipt in the game folder
+pub fn write(
+    game_path: &Path,
+    packages: &[PathBuf],
+    manifests: &[PathBuf],
+    inputs: &Inputs,
+    written: &[String],
+) -> Result<()> {
+    log::info!("Writing {}", RECEIPT_FILE);
+    let multi = util::create_multi_progress();
+    let pb = multi.add(util::create_progress_bar(written.len() as u64));
+    let mut files = pools::hash_pool().install(|| written.par_iter()
+        .filter_map(|path| {
+            pb.inc(1u64);
+            let full_path = game_path.join(path);
+            let size = full_path.metadata().ok()?.len();
+            let md5 = util::calculate_md5_hash_with_progress(&full_path, &multi).ok()?;
+            Some(ReceiptFile { path: path.clone(), size, md5 })
+        })
+        .collect::<Vec<_>>());
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let receipt = Receipt {
+        created: chrono::Local::now().to_rfc3339(),
+        package: Source::of(packages.first().ok_or_else(|| anyhow!("No package to write a receipt for"))?, false)?,
+        parts: packages.iter()
+            .skip(1)
+            .map(|part| Source::of(part, false))
+            .collect::<Result<_>>()?,
+        manifests: manifests.iter()
+            .map(|manifest| Source::of(manifest, true))
+            .collect::<Result<_>>()?,
+        inputs: inputs.clone(),
+        files,
+    };
+
+    // Write through a temp file so a crash never leaves a partial receipt
+    let receipt_path = game_path.join(RECEIPT_FILE);
+    let temp_path = receipt_path.with_extension("json.tmp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(&receipt)?)?;
+    fs::rename(temp_path, receipt_path)?;
+    Ok(())
+}
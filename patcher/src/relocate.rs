@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use walkdir::WalkDir;
+use crate::quirks::Quirks;
+use crate::serialize::PkgVersion;
+use crate::util;
+
+/// Finds patch sources that were moved or renamed, e.g. by client converters, by their size and md5
+pub struct Relocator<'a> {
+    game_path: &'a Path,
+    /// Size and md5 of every file as listed before patching, for packages that do not list their sources
+    listed: HashMap<String, (u64, String)>,
+    /// Files of the game folder by size, only walked once a source is missing
+    by_size: OnceLock<HashMap<u64, Vec<PathBuf>>>,
+    /// Md5 of every file hashed so far, so a candidate is hashed once however many sources it could be
+    hashes: Mutex<HashMap<PathBuf, Option<String>>>,
+}
+
+impl<'a> Relocator<'a> {
+    pub fn new(game_path: &'a Path, quirks: &Quirks) -> Relocator<'a> {
+        let listed = quirks.pkg_version_paths(game_path)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|listing| PkgVersion::from(listing).ok())
+            .flatten()
+            .filter_map(|file| Some((file.remote_file, (file.file_size?, file.md5.to_lowercase()))))
+            .collect();
+        Relocator {
+            game_path,
+            listed,
+            by_size: OnceLock::new(),
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Put a missing source back from a file with the same size and md5 elsewhere in the game folder
+    ///
+    /// `hash` is the size and md5 of the source when the package lists them, the pkg_version listings are used
+    /// otherwise. The file found is copied rather than moved, as it may be needed where it is. Returns the game
+    /// relative path of the file found
+    pub fn restore(&self, source: &str, hash: Option<&(u64, String)>) -> Option<String> {
+        let (size, md5) = hash.or_else(|| self.listed.get(source))?;
+        let candidates = self.by_size.get_or_init(|| index(self.game_path)).get(size)?;
+        let found = candidates.iter().find(|candidate| self.md5(candidate).as_ref() == Some(md5))?;
+
+        let source_path = self.game_path.join(source);
+        if let Some(parent) = source_path.parent() {
+            fs::create_dir_all(parent).ok()?;
+        }
+        fs::copy(found, &source_path).ok()?;
+        Some(found.strip_prefix(self.game_path).unwrap_or(found).to_string_lossy().replace('\\', "/"))
+    }
+
+    fn md5(&self, path: &Path) -> Option<String> {
+        if let Some(md5) = self.hashes.lock().unwrap().get(path) {
+            return md5.clone();
+        }
+        let md5 = util::calculate_md5_hash_with_progress(path, &util::create_multi_progress())
+            .ok()
+            .map(|md5| md5.to_lowercase());
+        self.hashes.lock().unwrap().insert(path.to_path_buf(), md5.clone());
+        md5
+    }
+}
+
+/// Every file of the game folder by size
+fn index(game_path: &Path) -> HashMap<u64, Vec<PathBuf>> {
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for entry in WalkDir::new(game_path).into_iter().filter_map(Result::ok) {
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_file() => by_size.entry(metadata.len()).or_default().push(entry.into_path()),
+            _ => {}
+        }
+    }
+    by_size
+}
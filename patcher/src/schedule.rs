@@ -0,0 +1,114 @@
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use chrono::{Local, NaiveTime, TimeDelta};
+use indicatif::{ProgressBar, ProgressStyle};
+use crate::log;
+
+/// Wait until the next occurrence of a local `HH:MM` time, showing a countdown
+pub async fn wait_until(time: &str) -> Result<()> {
+    let target = NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| anyhow!("Invalid start time {:?}, expected HH:MM", time))?;
+
+    // Schedule for tomorrow if the time already passed today
+    let now = Local::now().naive_local();
+    let mut start = now.date().and_time(target);
+    if start <= now {
+        start += TimeDelta::days(1);
+    }
+
+    log::info!("Waiting until {} to start, press Ctrl+C to cancel", start.format("%Y-%m-%d %H:%M"));
+    let pb = create_spinner();
+    let countdown = async {
+        loop {
+            let remaining = start - Local::now().naive_local();
+            if remaining <= TimeDelta::zero() {
+                break;
+            }
+
+            let secs = remaining.num_seconds();
+            pb.set_message(format!(
+                "Starting in {:02}:{:02}:{:02}",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60,
+            ));
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    };
+
+    cancellable(&pb, countdown).await
+}
+
+/// Wait until no process with the given executable name is running
+pub async fn wait_for_process_exit(name: &str) -> Result<()> {
+    if !is_running(name) {
+        return Ok(());
+    }
+
+    log::info!("Waiting for {} to exit, press Ctrl+C to cancel", name);
+    let pb = create_spinner();
+    pb.set_message(format!("{} is running", name));
+    let wait = async {
+        while is_running(name) {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    };
+
+    cancellable(&pb, wait).await
+}
+
+async fn cancellable<F: Future<Output = ()>>(pb: &ProgressBar, future: F) -> Result<()> {
+    tokio::select! {
+        _ = future => {
+            pb.finish_and_clear();
+            Ok(())
+        }
+        _ = tokio::signal::ctrl_c() => {
+            pb.abandon();
+            Err(anyhow!("Cancelled while waiting to start"))
+        }
+    }
+}
+
+fn create_spinner() -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} {msg}")
+            .expect("Failed to set progress bar template"),
+    );
+    pb.enable_steady_tick(Duration::from_millis(200));
+    pb
+}
+
+#[cfg(target_os = "windows")]
+fn is_running(name: &str) -> bool {
+    let name = if name.to_lowercase().ends_with(".exe") {
+        name.to_string()
+    } else {
+        format!("{}.exe", name)
+    };
+
+    Command::new("tasklist")
+        .args(["/FI", &format!("IMAGENAME eq {}", name), "/NH"])
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .to_lowercase()
+                .contains(&name.to_lowercase())
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_running(name: &str) -> bool {
+    Command::new("pgrep")
+        .args(["-x", name])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
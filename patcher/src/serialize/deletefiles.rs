@@ -1,7 +1,8 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
 use anyhow::Result;
+use crate::serialize::{normalize_path, parse_lines};
 
 pub struct DeleteFiles;
 
@@ -13,11 +14,18 @@ impl DeleteFiles {
         let mut string = String::new();
         file.read_to_string(&mut string)?;
 
-        // Deserialize string into json
-        let vector = string
-            .split("\n")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        Ok(vector)
+        // One game relative path per line, paths leaving the game folder are never deleted
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        Ok(parse_lines(&string, &name, |line| normalize_path(line).ok()))
+    }
+
+    #[allow(dead_code)]
+    pub fn write(path: &Path, files: &[String]) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for entry in files {
+            writeln!(file, "{}", entry)?;
+        }
+        file.flush()?;
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,16 +1,18 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
-use anyhow::{Result};
-use serde::Deserialize;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::serialize::parse_lines;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct HDiffFiles {
     #[serde(rename = "remoteName")]
     pub remote_file: String,
 }
 
 impl HDiffFiles {
+    #[allow(dead_code)]
     pub fn from(path: &Path) -> Result<Vec<HDiffFiles>> {
         let mut file = File::open(path)?;
 
@@ -18,16 +20,21 @@ impl HDiffFiles {
         let mut string = String::new();
         file.read_to_string(&mut string)?;
 
-        // Deserialize string into json
-        let vector = string.split("\n")
-            .filter_map(|s| {
-                if let Ok(result) = serde_json::from_str::<HDiffFiles>(s) {
-                    Some(result)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<HDiffFiles>>();
-        Ok(vector)
+        Ok(Self::parse(&string))
+    }
+
+    #[allow(dead_code)]
+    pub fn write(path: &Path, files: &[HDiffFiles]) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for entry in files {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn parse(string: &str) -> Vec<HDiffFiles> {
+        // One json object per line
+        parse_lines(string, "hdifffiles.txt", |line| serde_json::from_str::<HDiffFiles>(line).ok())
     }
 }
\ No newline at end of file
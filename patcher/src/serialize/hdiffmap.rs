@@ -1,22 +1,39 @@
 use std::fs::File;
-use std::io::Read;
-use std::path::Path;
-use serde::Deserialize;
+use std::io::{BufWriter, Read};
+use std::path::{Component, Path};
+use serde::{Deserialize, Serialize};
 use anyhow::{anyhow, Result};
+use crate::serialize::strip_bom;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct HDiffMap {
     pub diff_map: Vec<HDiffData>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
+#[serde(try_from = "RawHDiffData")]
 pub struct HDiffData {
     pub source_file_name: String,
     pub target_file_name: String,
     pub patch_file_name: String,
+    #[serde(skip_serializing)]
+    pub target_file_size: Option<u64>,
+    #[serde(skip_serializing)]
+    pub target_file_md5: Option<String>,
+    /// Size and md5 of the source when the package lists them, used to find sources that were moved
+    #[serde(skip_serializing)]
+    pub source_file_hash: Option<(u64, String)>,
+}
+
+#[derive(Deserialize)]
+struct RawHDiffData {
+    source_file_name: String,
+    target_file_name: String,
+    patch_file_name: String,
 }
 
 impl HDiffMap {
+    #[allow(dead_code)]
     pub fn from(path: &Path) -> Result<HDiffMap> {
         let mut file = File::open(path)?;
 
@@ -24,7 +41,110 @@ impl HDiffMap {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
-        // Deserialize buffer into json
-        serde_json::from_slice(&buffer).map_err(|e| anyhow!(e))
+        Self::from_slice(&buffer).map_err(|e| anyhow!("Invalid {}: {}", path.display(), e))
+    }
+
+    #[allow(dead_code)]
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn from_slice(buffer: &[u8]) -> Result<HDiffMap> {
+        let text = std::str::from_utf8(buffer)?;
+        let text = strip_bom(text);
+
+        // Deserialize buffer into json, pointing at the offending entry on failure
+        serde_json::from_str(text).map_err(|e| {
+            anyhow!("{} near `{}`", e, excerpt(text, e.line(), e.column()))
+        })
+    }
+}
+
+impl HDiffData {
+    /// Make a validated entry, an empty source meaning the patch creates a new file
+    pub fn new(source_file_name: &str, target_file_name: &str, patch_file_name: &str) -> Result<HDiffData> {
+        let source_file_name = if source_file_name.is_empty() {
+            String::new()
+        } else {
+            normalize_path(source_file_name)?
+        };
+
+        Ok(HDiffData {
+            source_file_name,
+            target_file_name: normalize_path(target_file_name)?,
+            patch_file_name: normalize_path(patch_file_name)?,
+            target_file_size: None,
+            target_file_md5: None,
+            source_file_hash: None,
+        })
+    }
+
+    /// Attach the expected size of the patched file
+    pub fn with_target_size(mut self, size: u64) -> HDiffData {
+        self.target_file_size = Some(size);
+        self
+    }
+
+    /// Attach the expected md5 of the patched file
+    pub fn with_target_md5(mut self, md5: &str) -> HDiffData {
+        if !md5.is_empty() {
+            self.target_file_md5 = Some(md5.to_lowercase());
+        }
+        self
+    }
+
+    /// Attach the size and md5 the source has before patching
+    pub fn with_source_hash(mut self, size: u64, md5: &str) -> HDiffData {
+        if !md5.is_empty() {
+            self.source_file_hash = Some((size, md5.to_lowercase()));
+        }
+        self
     }
-}
\ No newline at end of file
+}
+
+impl TryFrom<RawHDiffData> for HDiffData {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawHDiffData) -> Result<HDiffData> {
+        HDiffData::new(&raw.source_file_name, &raw.target_file_name, &raw.patch_file_name)
+    }
+}
+
+/// Text of the entry at a line and column, maps are often a single line so the whole line would be too much
+fn excerpt(text: &str, line: usize, column: usize) -> String {
+    let line = text.lines().nth(line.saturating_sub(1)).unwrap_or_default();
+    let chars = line.chars().collect::<Vec<_>>();
+    let column = column.min(chars.len());
+    let start = chars[..column]
+        .iter()
+        .rposition(|&c| c == '{')
+        .unwrap_or(column.saturating_sub(60))
+        .max(column.saturating_sub(200));
+    let end = (column + 20).min(chars.len());
+    chars[start..end].iter().collect::<String>().trim().to_string()
+}
+
+/// Normalize a game relative path to forward slashes, rejecting paths that escape the game folder
+pub(super) fn normalize_path(path: &str) -> Result<String> {
+    let unified = path.replace('\\', "/");
+    let has_drive = unified.as_bytes().get(1) == Some(&b':');
+    if unified.starts_with('/') || has_drive || Path::new(&unified).has_root() {
+        return Err(anyhow!("{:?} is an absolute path", path));
+    }
+
+    let mut components = Vec::new();
+    for component in Path::new(&unified).components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy().into_owned()),
+            Component::CurDir => {}
+            _ => return Err(anyhow!("{:?} escapes the game folder", path)),
+        }
+    }
+
+    if components.is_empty() {
+        return Err(anyhow!("{:?} is not a file path", path));
+    }
+    Ok(components.join("/"))
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use sophon::proto::sophon::SophonManifestProto;
+use crate::serialize::{HDiffData, HDiffFiles, HDiffMap};
+
+// Entry files of hdiff packages, in priority order
+pub const ENTRY_FILES: [&str; 2] = ["hdiffmap.json", "hdifffiles.txt"];
+
+pub struct Metadata;
+
+impl Metadata {
+    /// Load the hdiff entries of a package extracted into the game folder
+    ///
+    /// `manifests` are the Sophon manifests that came with the package
+    pub fn load(game_path: &Path, manifests: &[PathBuf]) -> Result<HDiffMap> {
+        let mut sources = Vec::new();
+        for name in ENTRY_FILES {
+            let path = game_path.join(name);
+            if path.exists() {
+                sources.push((name.to_string(), fs::read(path)?));
+            }
+        }
+        for path in manifests {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            sources.push((name, fs::read(path)?));
+        }
+
+        Self::merge(sources, |patch| game_path.join(patch).exists())
+    }
+
+    /// Merge the entries of every source, anything besides the entry files being a Sophon manifest
+    ///
+    /// `hdiffmap.json` wins over `hdifffiles.txt` when both list a target. Manifests, taken in name order, fill in the
+    /// size and md5 of listed targets and add the assets whose `.hdiff` file `has_patch` reports
+    pub fn merge<F>(mut sources: Vec<(String, Vec<u8>)>, has_patch: F) -> Result<HDiffMap>
+    where
+        F: Fn(&str) -> bool,
+    {
+        sources.sort_by_key(|(name, _)| {
+            let priority = ENTRY_FILES.iter().position(|file| file == name).unwrap_or(ENTRY_FILES.len());
+            (priority, name.clone())
+        });
+
+        let mut entries: BTreeMap<String, HDiffData> = BTreeMap::new();
+        let mut found = false;
+        for (name, buffer) in sources {
+            match name.as_str() {
+                "hdiffmap.json" => {
+                    found = true;
+                    let map = HDiffMap::from_slice(&buffer).map_err(|e| anyhow!("Invalid {}: {}", name, e))?;
+                    for data in map.diff_map {
+                        entries.entry(data.target_file_name.clone()).or_insert(data);
+                    }
+                }
+                "hdifffiles.txt" => {
+                    found = true;
+                    for file in HDiffFiles::parse(&String::from_utf8_lossy(&buffer)) {
+                        let patch = format!("{}.hdiff", file.remote_file);
+                        let data = HDiffData::new(&file.remote_file, &file.remote_file, &patch)?;
+                        entries.entry(data.target_file_name.clone()).or_insert(data);
+                    }
+                }
+                _ => {
+                    let manifest = SophonManifestProto::from_reader(&*buffer)
+                        .map_err(|e| anyhow!("Invalid manifest {}: {}", name, e))?;
+                    if manifest.assets.is_empty() {
+                        return Err(anyhow!("Invalid manifest {}: it holds no assets", name));
+                    }
+                    for asset in manifest.assets {
+                        let patch = format!("{}.hdiff", asset.asset_name);
+                        let data = match entries.remove(&asset.asset_name) {
+                            Some(data) => data,
+                            None if has_patch(&patch) => {
+                                found = true;
+                                HDiffData::new(&asset.asset_name, &asset.asset_name, &patch)?
+                            }
+                            None => continue,
+                        };
+                        let data = match data.target_file_size {
+                            Some(_) => data,
+                            None => data.with_target_size(asset.asset_size as u64),
+                        };
+                        let data = match data.target_file_md5 {
+                            Some(_) => data,
+                            None => data.with_target_md5(&asset.asset_hash_md5),
+                        };
+                        entries.insert(data.target_file_name.clone(), data);
+                    }
+                }
+            }
+        }
+
+        if !found {
+            return Err(anyhow!("No hdiff entries map exist"));
+        }
+        Ok(HDiffMap { diff_map: entries.into_values().collect() })
+    }
+}
@@ -1,9 +1,40 @@
+use crate::log;
+
 mod hdiffmap;
 mod hdifffiles;
 mod deletefiles;
 mod pkg_version;
+mod metadata;
 
 pub use hdiffmap::*;
 pub use hdifffiles::*;
 pub use deletefiles::*;
-pub use pkg_version::*;
\ No newline at end of file
+pub use pkg_version::*;
+pub use metadata::*;
+
+/// Drop the UTF-8 byte order mark some editors put in front of text files
+fn strip_bom(text: &str) -> &str {
+    text.strip_prefix('\u{feff}').unwrap_or(text)
+}
+
+/// Parse every entry of a line based listing, warning how many lines of `name` did not parse
+///
+/// The byte order mark, CRLF line endings, surrounding whitespace, blank lines and `#` comments are dropped first, as
+/// packages made on Windows have them
+fn parse_lines<T>(text: &str, name: &str, parse: impl Fn(&str) -> Option<T>) -> Vec<T> {
+    let mut unparseable = 0;
+    let entries = strip_bom(text)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let entry = parse(line);
+            unparseable += entry.is_none() as usize;
+            entry
+        })
+        .collect();
+    if unparseable > 0 {
+        log::warn!("Skipped {} lines of {} that could not be read", unparseable, name);
+    }
+    entries
+}
@@ -1,13 +1,18 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use crate::serialize::parse_lines;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct PkgVersion {
     #[serde(rename = "remoteName")]
     pub remote_file: String,
+    /// Md5 of the file, some older listings give a sha1 or crc32 instead
+    #[serde(alias = "sha1", alias = "crc32", alias = "hash")]
     pub md5: String,
+    #[serde(rename = "fileSize", default, skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
 }
 
 impl PkgVersion {
@@ -18,16 +23,17 @@ impl PkgVersion {
         let mut string = String::new();
         file.read_to_string(&mut string)?;
 
-        // Deserialize string into json
-        let vector = string.split("\n")
-            .filter_map(|s| {
-                if let Ok(result) = serde_json::from_str::<PkgVersion>(s) {
-                    Some(result)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<PkgVersion>>();
-        Ok(vector)
+        // One json object per line
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        Ok(parse_lines(&string, &name, |line| serde_json::from_str::<PkgVersion>(line).ok()))
+    }
+
+    pub fn write(path: &Path, files: &[PkgVersion]) -> anyhow::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for entry in files {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        file.flush()?;
+        Ok(())
     }
 }
\ No newline at end of file
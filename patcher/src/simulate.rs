@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use walkdir::WalkDir;
+use sophon::proto::sophon::SophonManifestProto;
+use crate::extractor::ArchiveExtractor;
+use crate::serialize::{Metadata, PkgVersion, ENTRY_FILES};
+use crate::{log, pools, util};
+
+/// Export a pkg_version style listing of every file in a game folder
+pub fn export_listing(game_path: &Path, output: &Path) -> Result<()> {
+    if !game_path.is_dir() {
+        return Err(anyhow!("{:?} does not exist", game_path));
+    }
+
+    let files = WalkDir::new(game_path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .collect::<Vec<_>>();
+
+    // Hash every file
+    log::info!("Hashing {} files", files.len());
+    let multi = util::create_multi_progress();
+    let pb = multi.add(util::create_progress_bar(files.len() as u64));
+    let mut listing = pools::hash_pool().install(|| files.into_par_iter()
+        .filter_map(|entry| {
+            pb.inc(1u64);
+
+            let relative = entry.path().strip_prefix(game_path).ok()?;
+            let md5 = util::calculate_md5_hash_with_progress(entry.path(), &multi).ok()?;
+            Some(PkgVersion {
+                remote_file: relative.to_string_lossy().replace('\\', "/"),
+                md5,
+                file_size: entry.metadata().ok().map(|metadata| metadata.len()),
+            })
+        })
+        .collect::<Vec<_>>());
+    listing.sort_by(|a, b| a.remote_file.cmp(&b.remote_file));
+
+    PkgVersion::write(output, &listing)?;
+
+    log::ok!("Listing written to {}", output.display());
+    Ok(())
+}
+
+/// Simulate applying a package against a listing, reporting every entry that would fail
+pub fn simulate(listing_path: &Path, package_path: &Path) -> Result<()> {
+    let listing = PkgVersion::from(listing_path)?
+        .into_iter()
+        .map(|file| (file.remote_file, file.md5.to_lowercase()))
+        .collect::<HashMap<_, _>>();
+    if listing.is_empty() {
+        return Err(anyhow!("{:?} does not contain any files", listing_path));
+    }
+
+    let names = ArchiveExtractor::entry_names(package_path)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut problems = Vec::new();
+    let mut total = 0;
+    if ENTRY_FILES.iter().any(|name| names.contains(*name)) {
+        // Hdiff package
+        let sources = ArchiveExtractor::read_entries(package_path, |name| {
+            ENTRY_FILES.contains(&name) || (!name.contains('/') && name.starts_with("manifest"))
+        })?;
+        let hdiff_map = Metadata::merge(sources, |patch| names.contains(patch))?;
+        for data in hdiff_map.diff_map {
+            total += 1;
+            if util::is_ignored(&data.source_file_name) {
+                continue;
+            }
+            if !names.contains(&data.patch_file_name) {
+                problems.push(format!(
+                    "{} patch file {} is missing from the package",
+                    data.target_file_name,
+                    data.patch_file_name,
+                ));
+            } else if !data.source_file_name.is_empty() && !listing.contains_key(&data.source_file_name) {
+                problems.push(format!("{} source file {} is missing", data.target_file_name, data.source_file_name));
+            }
+        }
+    } else {
+        // Ldiff package
+        let chunk_names = names.iter()
+            .filter_map(|name| name.strip_prefix("ldiff/"))
+            .collect::<HashSet<_>>();
+        let manifests = ArchiveExtractor::read_entries(package_path, |name| {
+            name.starts_with("manifest")
+        })?;
+        if manifests.is_empty() {
+            return Err(anyhow!("{:?} contains neither hdiff entries nor ldiff manifests", package_path));
+        }
+
+        for (_, buffer) in manifests {
+            let Ok(manifest) = SophonManifestProto::from_reader(&*buffer) else {
+                continue;
+            };
+
+            for asset_group in manifest.assets {
+                let Some(data) = asset_group.asset_data else {
+                    continue;
+                };
+
+                for asset in data.assets {
+                    if !chunk_names.contains(asset.chunk_file_name.as_str()) {
+                        continue;
+                    }
+
+                    total += 1;
+                    if asset.original_file_path.is_empty() || util::is_ignored(&asset.original_file_path) {
+                        continue;
+                    }
+                    match listing.get(&asset.original_file_path) {
+                        None => problems.push(format!(
+                            "{} source file {} is missing",
+                            asset_group.asset_name,
+                            asset.original_file_path,
+                        )),
+                        Some(md5) if !asset.original_file_md5.is_empty()
+                            && md5 != &asset.original_file_md5.to_lowercase() => problems.push(format!(
+                            "{} source file {} is modified! Expected: {}, found: {}",
+                            asset_group.asset_name,
+                            asset.original_file_path,
+                            asset.original_file_md5,
+                            md5,
+                        )),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // Print report
+    for problem in &problems {
+        log::warn!("{}", problem);
+    }
+    log::info!("{} of {} entries would fail to patch", problems.len(), total);
+
+    Ok(())
+}
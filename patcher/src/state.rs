@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::action::{Detail, PatchOutcome};
+use crate::extractor::ArchiveExtractor;
+use crate::inputs::Inputs;
+use crate::{log, util};
+
+const STATE_FILE: &str = "sophon_state.json";
+
+/// What is remembered about a game folder from one run to the next
+#[derive(Serialize, Deserialize, Default)]
+pub struct State {
+    /// Game relative paths never patched or verified again, e.g. community modified files that always fail
+    #[serde(default)]
+    pub skipped: BTreeSet<String>,
+    /// Packages extracted into the game folder by a run that did not get to clean up, by file name
+    #[serde(default)]
+    pub extracted: BTreeMap<String, Extraction>,
+    /// Hashes of the packages and metadata of the run that extracted them, an attempt with other inputs starts over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inputs: Option<Inputs>,
+}
+
+/// Which copy of a package was extracted, told apart by its size and modification time
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Extraction {
+    size: u64,
+    modified: u64,
+}
+
+impl Extraction {
+    fn of(package: &Path) -> Option<Extraction> {
+        let metadata = package.metadata().ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        Some(Extraction { size: metadata.len(), modified })
+    }
+}
+
+impl State {
+    /// Read the state of a game folder, empty when there is none yet
+    pub fn load(game_path: &Path) -> Result<State> {
+        let path = game_path.join(STATE_FILE);
+        match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(anyhow!("Failed to read {:?}: {}", path, e)),
+        }
+    }
+
+    /// Write the state through a temp file, removing it instead when nothing is left to remember
+    pub fn save(&self, game_path: &Path) -> Result<()> {
+        let path = game_path.join(STATE_FILE);
+        if self.skipped.is_empty() && self.extracted.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Paths skipped in a game folder, on top of the ones its quirk profile skips
+pub fn skipped(game_path: &Path) -> Result<BTreeSet<String>> {
+    let skipped = State::load(game_path)?.skipped;
+    if !skipped.is_empty() {
+        log::info!("Skipping {} files listed in {}", skipped.len(), STATE_FILE);
+    }
+    Ok(skipped)
+}
+
+/// Mark game relative paths as permanently skipped
+pub fn skip<S: AsRef<str>>(game_path: &Path, paths: &[S]) -> Result<()> {
+    let mut state = State::load(game_path)?;
+    for path in paths {
+        let path = path.as_ref().replace('\\', "/");
+        if state.skipped.insert(path.clone()) {
+            log::info!("{} is skipped from now on", path);
+        }
+    }
+    state.save(game_path)
+}
+
+/// Mark every file that failed to patch as permanently skipped, as asked with --skip-failed
+pub fn skip_failed(game_path: &Path, outcome: &PatchOutcome) -> Result<()> {
+    let failed = outcome.details.iter()
+        .filter(|(_, detail)| matches!(detail, Detail::Failed))
+        .map(|(path, _)| path.as_str())
+        .collect::<Vec<_>>();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    skip(game_path, &failed)
+}
+
+/// Remember that packages were fully extracted and from which inputs, so a later attempt can reuse what is left
+pub fn extracted(game_path: &Path, packages: &[PathBuf], inputs: &Inputs) -> Result<()> {
+    let mut state = State::load(game_path)?;
+    for package in packages {
+        if let Some(extraction) = Extraction::of(package) {
+            state.extracted.insert(package_name(package), extraction);
+        }
+    }
+    state.inputs = Some(inputs.clone());
+    state.save(game_path)
+}
+
+/// Forget the extraction of packages once what was extracted is removed
+pub fn forget_extracted(game_path: &Path, packages: &[PathBuf]) -> Result<()> {
+    let mut state = State::load(game_path)?;
+    let before = state.extracted.len();
+    state.extracted.retain(|name, _| !packages.iter().any(|package| package_name(package) == *name));
+    if state.extracted.len() == before {
+        return Ok(());
+    }
+    if state.extracted.is_empty() {
+        state.inputs = None;
+    }
+    state.save(game_path)
+}
+
+/// Whether the files a previous attempt extracted from these packages are still there and are used again
+///
+/// Only packages extracted in full from the same copy of the archive count, and every entry has to be on disk at
+/// its size in the archive. Packages or extracted metadata whose md5 changed since are never continued from. The
+/// user is asked before the multi-GB extraction is skipped
+pub fn reuse_extracted(game_path: &Path, packages: &[PathBuf], destination: &Path, inputs: &Inputs) -> Result<bool> {
+    let state = State::load(game_path)?;
+    if let Some(earlier) = &state.inputs {
+        if let Some(name) = inputs.changed_package(earlier) {
+            log::warn!("{} changed since an earlier attempt extracted it, extracting again", name);
+            return Ok(false);
+        }
+        for (name, md5) in &earlier.metadata {
+            let path = destination.join(name);
+            let found = util::calculate_md5_hash_with_progress(&path, &util::create_multi_progress()).ok();
+            if found.as_ref() != Some(md5) {
+                log::warn!("{} changed since an earlier attempt extracted it, extracting again", name);
+                return Ok(false);
+            }
+        }
+    }
+    let mut files = 0;
+    for package in packages {
+        let Some(extraction) = state.extracted.get(&package_name(package)) else {
+            return Ok(false);
+        };
+        if Extraction::of(package).as_ref() != Some(extraction) {
+            return Ok(false);
+        }
+        for (name, size) in ArchiveExtractor::entry_sizes(package)? {
+            if destination.join(&name).metadata().map_or(true, |metadata| metadata.len() != size) {
+                log::info!("{} extracted by an earlier attempt is missing or changed, extracting again", name);
+                return Ok(false);
+            }
+            files += 1;
+        }
+    }
+
+    log::info!("All {} files of the packages are still extracted from an earlier attempt", files);
+    Ok(util::confirm("reuse", "Reuse them instead of extracting again? (Y/n) [Y]: ", true))
+}
+
+fn package_name(package: &Path) -> String {
+    package.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+/// Forget everything remembered about a game folder
+pub fn clear(game_path: &Path) -> Result<()> {
+    let state = State::load(game_path)?;
+    log::info!("Forgetting {} skipped files", state.skipped.len());
+    State::default().save(game_path)
+}
+
+pub fn list(game_path: &Path) -> Result<()> {
+    let state = State::load(game_path)?;
+    for path in &state.skipped {
+        println!("{}", path);
+    }
+    log::info!("{} files are skipped", state.skipped.len());
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressState, WeakProgressBar};
+use crate::{metrics, util};
+
+// Files workers are on right now, by the ID of their guard, with when they started
+static WORKING: Mutex<Option<HashMap<u64, (String, Instant)>>> = Mutex::new(None);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Bar the status line is drawn beneath, with the bytes written at recent refreshes for the throughput
+static STATUS: Mutex<Option<Status>> = Mutex::new(None);
+
+// Time between refreshes of the status line
+const REFRESH: Duration = Duration::from_millis(500);
+
+// Time over which the throughput is averaged
+const WINDOW: Duration = Duration::from_secs(5);
+
+// Time after which the file a worker is on is named on the status line
+const STALL: Duration = Duration::from_secs(10);
+
+struct Status {
+    bar: WeakProgressBar,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+/// A file a worker is on, named on the status line when it takes long
+pub struct Working(u64);
+
+/// Tell the status line a worker started on a file, until the guard is dropped
+pub fn working(path: &str) -> Working {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    WORKING.lock().unwrap().get_or_insert_with(HashMap::new).insert(id, (path.to_string(), Instant::now()));
+    Working(id)
+}
+
+impl Drop for Working {
+    fn drop(&mut self) {
+        if let Some(working) = WORKING.lock().unwrap().as_mut() {
+            working.remove(&self.0);
+        }
+    }
+}
+
+/// Draw the status line beneath the bar of a stage workers write files in, until the bar finishes
+///
+/// In plain mode the line is printed after every progress line instead
+pub fn attach(pb: &ProgressBar) {
+    let written = metrics::BYTES_WRITTEN.load(Ordering::Relaxed);
+    *STATUS.lock().unwrap() = Some(Status {
+        bar: pb.downgrade(),
+        samples: VecDeque::from([(Instant::now(), written)]),
+    });
+    if pb.is_hidden() {
+        return;
+    }
+
+    // The line goes below the bar, finishing the bar replaces the template and the line with it
+    let template = format!("{}\n  {{status}}", util::bar_template(pb.length().unwrap_or(0)));
+    pb.set_style(util::bar_style(&template).with_key("status", |_: &ProgressState, w: &mut dyn fmt::Write| {
+        let _ = w.write_str(&describe());
+    }));
+
+    // Redraw while no file finishes, so a stall shows
+    let weak = pb.downgrade();
+    thread::spawn(move || {
+        while let Some(pb) = weak.upgrade().filter(|pb| !pb.is_finished()) {
+            pb.tick();
+            drop(pb);
+            thread::sleep(REFRESH);
+        }
+    });
+}
+
+/// The status line for plain progress lines, none once the bar it was attached to finished
+pub fn line() -> Option<String> {
+    let attached = STATUS.lock().unwrap().as_ref().and_then(|status| status.bar.upgrade());
+    attached.filter(|bar| !bar.is_finished()).map(|_| describe())
+}
+
+// Called while the bar draws, so the bar itself is never touched here
+fn describe() -> String {
+    let mut status = STATUS.lock().unwrap();
+    let Some(status) = status.as_mut() else {
+        return String::new();
+    };
+
+    // Bytes per second over the last few seconds, so a stall shows right away
+    let now = Instant::now();
+    let written = metrics::BYTES_WRITTEN.load(Ordering::Relaxed);
+    status.samples.push_back((now, written));
+    while status.samples.len() > 2 && now.duration_since(status.samples[0].0) > WINDOW {
+        status.samples.pop_front();
+    }
+    let (since, before) = status.samples[0];
+    let elapsed = now.duration_since(since).as_secs_f64();
+    let rate = if elapsed > 0.0 { ((written - before) as f64 / elapsed) as u64 } else { 0 };
+
+    let mut line = format!(
+        "{} done, {} failed, {} written, {}/s",
+        metrics::FILES_PATCHED.load(Ordering::Relaxed),
+        metrics::FILES_FAILED.load(Ordering::Relaxed),
+        HumanBytes(written),
+        HumanBytes(rate),
+    );
+    let working = WORKING.lock().unwrap();
+    let slowest = working.iter().flat_map(HashMap::values).min_by_key(|(_, started)| *started);
+    if let Some((path, started)) = slowest.filter(|(_, started)| started.elapsed() >= STALL) {
+        let path = util::ellipsize(path, util::terminal_width().saturating_sub(line.len() + 30));
+        line.push_str(&format!(", slowest {} for {}", path, HumanDuration(started.elapsed())));
+    }
+    line
+}
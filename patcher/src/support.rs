@@ -0,0 +1,56 @@
+use anyhow::Result;
+use crate::quirks::Quirks;
+use crate::serialize::ENTRY_FILES;
+use crate::log;
+
+/// Print the package formats this build reads and how every built-in profile handles them
+///
+/// Meant to tell an unsupported package apart from a bug, so it only states what the code and profiles do
+pub fn matrix() -> Result<()> {
+    log::info!("Package formats:");
+    let formats = [
+        ("hdiff", format!("zip or 7z with {} and deletefiles.txt", ENTRY_FILES.join(" or "))),
+        ("ldiff", "zip or 7z with Sophon ldiff manifests and ldiff chunk files, several parts at once".to_string()),
+        ("chunk", "folder, zip or 7z of loose chunks, zstd with or without dictionaries, or _db indexed \
+            archives, with a zstd Sophon chunk manifest".to_string()),
+        ("full", "not supported, assemble a full install from its chunk manifest with action 2".to_string()),
+    ];
+    for (format, schema) in formats {
+        log::info!("  {:<6} {}", format, schema);
+    }
+
+    // Every built-in profile reads every format, they differ in how they find and verify the game
+    let mut rows = vec![["Profile", "hdiff", "ldiff", "chunk", "full", "Manifests", "Verifies against", "Picked by"]
+        .map(str::to_string)];
+    for (name, quirks) in Quirks::builtin()? {
+        let verify = match (quirks.offers_verify(), quirks.pkg_versions.is_empty()) {
+            (false, _) => "not offered".to_string(),
+            (true, true) => "manifests only".to_string(),
+            (true, false) => quirks.pkg_versions.join(", "),
+        };
+        let picked_by = match quirks.executables.is_empty() {
+            true => format!("--profile {}", name),
+            false => quirks.executables.join(", "),
+        };
+        rows.push([
+            name.to_string(),
+            "yes".to_string(),
+            "yes".to_string(),
+            "yes".to_string(),
+            "no".to_string(),
+            format!("{}*", quirks.manifest_prefix),
+            verify,
+            picked_by,
+        ]);
+    }
+
+    let widths = (0..rows[0].len())
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+    println!();
+    for row in rows {
+        let cells = row.iter().zip(&widths).map(|(cell, &width)| format!("{:<width$}", cell)).collect::<Vec<_>>();
+        log::info!("{}", cells.join("  ").trim_end());
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use sophon::proto::chunk::SophonChunkProto;
+use sophon::sophon::ChunkLayout;
+use crate::action::{PatchOptions, PatchOutcome};
+use crate::extractor::ArchiveExtractor;
+use crate::quirks::Quirks;
+use crate::serialize::ENTRY_FILES;
+use crate::{action, log, util};
+
+/// Update packages found in a game folder, applied in this order
+#[derive(Default)]
+pub(crate) struct Packages {
+    pub hdiff: Vec<String>,
+    /// Parts of one ldiff update, applied together
+    pub ldiff: Vec<String>,
+    /// Chunk folders with their manifest
+    pub chunk: Vec<(String, String)>,
+}
+
+impl Packages {
+    fn is_empty(&self) -> bool {
+        self.hdiff.is_empty() && self.ldiff.is_empty() && self.chunk.is_empty()
+    }
+}
+
+/// Find every update package in the game folder and apply them one kind after another once the user agrees
+///
+/// Hdiff archives go first, sorted by name, then all ldiff archives in one run, then every chunk folder. Nothing
+/// after a package with failed files is applied, as it would patch on top of them
+pub async fn update(game_path: &Path, options: &PatchOptions) -> Result<PatchOutcome> {
+    if !game_path.is_dir() {
+        return Err(anyhow!("{:?} does not exist", game_path));
+    }
+    let packages = detect(game_path)?;
+    if packages.is_empty() {
+        return Err(anyhow!(
+            "{:?} holds no hdiff archive, ldiff archive or chunk folder with its manifest",
+            game_path,
+        ));
+    }
+
+    log::info!("Found in {}:", game_path.display());
+    for hdiff_file in &packages.hdiff {
+        log::info!("  hdiff archive {}", hdiff_file);
+    }
+    if !packages.ldiff.is_empty() {
+        log::info!("  ldiff archives {}", packages.ldiff.join(", "));
+    }
+    for (chunk_folder, manifest) in &packages.chunk {
+        log::info!("  chunk folder {} with manifest {}", chunk_folder, manifest);
+    }
+    if !util::confirm("update", "Apply them in this order? (Y/n) [Y]: ", true) {
+        log::info!("Nothing was applied");
+        return Ok(PatchOutcome::default());
+    }
+
+    let mut merged = PatchOutcome::default();
+    for hdiff_file in packages.hdiff {
+        if !proceeds(&merged) {
+            return Ok(merged);
+        }
+        merged.merge(action::hdiff(game_path, hdiff_file, options).await?);
+    }
+    if !packages.ldiff.is_empty() {
+        if !proceeds(&merged) {
+            return Ok(merged);
+        }
+        merged.merge(action::ldiff(game_path, packages.ldiff, options).await?);
+    }
+    for (chunk_folder, manifest) in packages.chunk {
+        if !proceeds(&merged) {
+            return Ok(merged);
+        }
+        merged.merge(action::chunk(game_path, chunk_folder, manifest, options).await?);
+    }
+    Ok(merged)
+}
+
+/// Look for the update packages of a game folder, for `update` and the package menus of the patch actions
+pub(crate) fn detect(game_path: &Path) -> Result<Packages> {
+    let quirks = Quirks::detect(game_path)?;
+    util::with_spinner("Looking for update packages", || find(game_path, &quirks))
+}
+
+/// Whether the next package may be applied after the packages so far, not on top of files that failed to patch
+pub(crate) fn proceeds(merged: &PatchOutcome) -> bool {
+    if merged.failed > 0 {
+        log::error!("Stopping, {} files failed to patch and later packages would patch on top of them", merged.failed);
+    }
+    merged.failed == 0
+}
+
+/// Tell the archives of the game folder apart by their entries and pair chunk manifests with the folder of chunks
+fn find(game_path: &Path, quirks: &Quirks) -> Result<Packages> {
+    let mut packages = Packages::default();
+    let mut folders = Vec::new();
+    let mut manifests = Vec::new();
+    for entry in game_path.read_dir()? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            folders.push(name);
+        } else if quirks.is_manifest(&name) {
+            manifests.push(name);
+        } else if path.extension().is_some_and(|extension| extension == "zip" || extension == "7z") {
+            let Ok(entries) = ArchiveExtractor::entry_names(&path) else {
+                log::warn!("Could not read {}, it is left out", name);
+                continue;
+            };
+            if entries.iter().any(|entry| ENTRY_FILES.contains(&entry.as_str())) {
+                packages.hdiff.push(name);
+            } else if entries.iter().any(|entry| entry.starts_with("ldiff/") || quirks.is_manifest(entry)) {
+                packages.ldiff.push(name);
+            }
+        }
+    }
+    packages.hdiff.sort();
+    packages.ldiff.sort();
+    folders.sort();
+    manifests.sort();
+
+    // Chunk manifests sit next to the folder holding their chunks
+    for manifest_name in manifests {
+        let Ok(manifest) = SophonChunkProto::from(game_path.join(&manifest_name).to_string_lossy().into_owned()) else {
+            continue;
+        };
+        let chunks = manifest.assets.iter()
+            .flat_map(|asset| &asset.asset_chunks)
+            .map(|chunk| (chunk.chunk_name.clone(), chunk.chunk_size_decompressed))
+            .collect::<HashMap<_, _>>();
+        if chunks.is_empty() {
+            continue;
+        }
+        let folder = folders.iter().find(|folder| ChunkLayout::detect(&game_path.join(folder), &chunks).is_ok());
+        if let Some(folder) = folder {
+            packages.chunk.push((folder.clone(), manifest_name));
+        }
+    }
+    Ok(packages)
+}
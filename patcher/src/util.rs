@@ -1,34 +1,441 @@
 use std::fs::File;
+use std::collections::BTreeMap;
 use std::io;
 use std::io::{BufReader, Read, Write};
-use std::path::Path;
-use indicatif::{ProgressBar, ProgressStyle};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use console::{measure_text_width, Term};
+use dialoguer::{BasicHistory, Completion, Input};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, WeakProgressBar};
 use md5::Context;
+use sha1::{Digest as _, Sha1};
+use crate::{overall, quirks, status};
+
+// Whether prompts should be answered with their defaults
+static UNATTENDED: AtomicBool = AtomicBool::new(false);
+
+// Whether nothing is drawn on or read from the terminal, for launchers embedding the patcher
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Keys of every yes/no question that can be answered ahead of time
+pub const QUESTIONS: &[&str] = &["verify", "delete", "mods", "update", "reuse"];
+
+// Whether yes/no questions without a preset answer are answered with yes
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+// Preset answers for yes/no questions, keyed by question
+static ANSWERS: Mutex<BTreeMap<String, bool>> = Mutex::new(BTreeMap::new());
+
+// Paths entered during this run, offered again with the arrow keys
+static PATH_HISTORY: Mutex<Option<BasicHistory>> = Mutex::new(None);
+
+// Files the user modified on purpose, skipped by every hash check
+static IGNORED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Files above this size get their own byte progress bar while hashing
+const LARGE_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+// Seconds between plain progress lines, interactive bars are drawn when zero
+static PLAIN_PROGRESS_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Answer every following prompt with its default instead of reading stdin
+pub fn set_unattended(unattended: bool) {
+    UNATTENDED.store(unattended, Ordering::Relaxed);
+}
+
+/// Never prompt, draw bars or print lines on the terminal, prompts get their default and lines go to the log hook
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
 
 pub fn input(text: &str) -> String {
+    if is_quiet() {
+        return String::new();
+    }
     print!("{text}");
+    if UNATTENDED.load(Ordering::Relaxed) {
+        println!();
+        return String::new();
+    }
     io::stdout().flush().unwrap();
     let mut buffer = String::new();
     io::stdin().read_line(&mut buffer).unwrap();
     buffer.trim().to_string()
 }
 
-/// Calculate MD5 hash of a file
+/// What a path prompt accepts
+#[derive(Clone, Copy)]
+pub enum PathKind {
+    File,
+    Folder,
+    /// Output paths, which do not have to exist yet
+    Any,
+}
+
+/// Ask for a path with tab completion and history, asking again until it exists
+///
+/// Relative paths are completed and checked against `base` when given, e.g. the game folder. Fails when nobody can
+/// answer, the caller decides how the run ends
+pub fn input_path(text: &str, base: Option<&Path>, kind: PathKind) -> anyhow::Result<String> {
+    // An empty answer would resolve to the current folder, so a run nobody can answer stops here
+    if UNATTENDED.load(Ordering::Relaxed) {
+        let name = text.trim_start_matches("Please enter ").trim_end().trim_end_matches(':');
+        return Err(anyhow::anyhow!("No {} given and nobody to ask for it, pass it as an argument", name));
+    }
+    if !Term::stdout().is_term() {
+        return Ok(input(text));
+    }
+
+    let base = base.map(Path::to_path_buf);
+    let completion = PathCompletion { base: base.clone() };
+    let mut history = PATH_HISTORY.lock().unwrap();
+    let history = history.get_or_insert_with(|| BasicHistory::new().max_entries(16).no_duplicates(true));
+    let result = Input::<String>::new()
+        .with_prompt(text.trim_end().trim_end_matches(':'))
+        .completion_with(&completion)
+        .history_with(history)
+        .validate_with(|value: &String| -> Result<(), String> {
+            let path = base.as_deref().map_or_else(|| PathBuf::from(value), |base| base.join(value));
+            match kind {
+                PathKind::File if !path.is_file() => Err(format!("{} is not a file", path.display())),
+                PathKind::Folder if !path.is_dir() => Err(format!("{} is not a folder", path.display())),
+                _ => Ok(()),
+            }
+        })
+        .interact_text_on(&Term::stdout());
+
+    match result {
+        Ok(value) => Ok(value.trim().to_string()),
+        Err(_) => Ok(input(text)),
+    }
+}
+
+/// Ask to pick files of `folder` from a numbered list showing their size and when they were modified
+///
+/// Several numbers separated by commas or spaces pick several files, returned in the order of the list
+pub fn select(what: &str, folder: &Path, names: &[String]) -> anyhow::Result<Vec<usize>> {
+    // Nobody can pick from the list, so a run nobody can answer stops here like a path prompt
+    if UNATTENDED.load(Ordering::Relaxed) {
+        return Err(anyhow::anyhow!("No {} given and nobody to pick one, pass it as an argument or add --all", what));
+    }
+    if is_quiet() {
+        return Ok(Vec::new());
+    }
+
+    println!("[Found {}]", what);
+    for (index, name) in names.iter().enumerate() {
+        let metadata = folder.join(name).metadata().ok();
+        let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+        let modified = metadata.and_then(|metadata| metadata.modified().ok())
+            .map(|modified| chrono::DateTime::<chrono::Local>::from(modified).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!("{} - {} ({}, {})", index + 1, name, HumanBytes(size), modified);
+    }
+    let text = format!("Please select {} (1-{}, several separated by commas): ", what, names.len());
+    loop {
+        let picked = input(&text)
+            .split([',', ' '])
+            .filter(|part| !part.is_empty())
+            .map(|part| part.parse::<usize>().ok().filter(|number| (1..=names.len()).contains(number)))
+            .collect::<Option<Vec<_>>>();
+        match picked {
+            Some(mut picked) if !picked.is_empty() => {
+                picked.sort();
+                picked.dedup();
+                return Ok(picked.into_iter().map(|number| number - 1).collect());
+            }
+            _ => println!("Enter numbers from 1 to {}", names.len()),
+        }
+    }
+}
+
+/// Completes the last path component from the entries of its folder
+struct PathCompletion {
+    base: Option<PathBuf>,
+}
+
+impl Completion for PathCompletion {
+    fn get(&self, input: &str) -> Option<String> {
+        let split = input.rfind(['/', '\\']).map_or(0, |index| index + 1);
+        let (folder, prefix) = input.split_at(split);
+        let folder_path = match &self.base {
+            Some(base) => base.join(folder),
+            None => PathBuf::from(folder),
+        };
+        let folder_path = if folder.is_empty() { folder_path.join(".") } else { folder_path };
+
+        let matches = folder_path.read_dir().ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+                name.starts_with(prefix).then(|| if is_dir { format!("{}/", name) } else { name })
+            })
+            .collect::<Vec<_>>();
+
+        // Complete up to the longest prefix all matches share
+        let first = matches.first()?;
+        let common = matches.iter().fold(first.len(), |len, name| {
+            first.chars()
+                .zip(name.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a.len_utf8())
+                .sum::<usize>()
+                .min(len)
+        });
+        (common > prefix.len()).then(|| format!("{}{}", folder, &first[..common]))
+    }
+}
+
+/// Make a path argument absolute once, so nothing later depends on the directory the tool was started from
+///
+/// Quotes around the path, as left by dragging a folder onto a console on Windows, are removed
+pub fn absolute_path(path: &str) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path.trim().trim_matches('"'))?;
+
+    // Canonical paths on Windows get a `\\?\` prefix hpatchz does not take, absolute ones are already normalized
+    if cfg!(windows) {
+        return Ok(path);
+    }
+    Ok(path.canonicalize().unwrap_or(path))
+}
+
+/// Resolve a package argument against the game folder when it is found there, otherwise against the current
+/// directory
+pub fn resolve_package(game_path: &Path, package: &str) -> io::Result<String> {
+    let package = package.trim().trim_matches('"');
+    if package.is_empty() || game_path.join(package).exists() {
+        return Ok(package.to_string());
+    }
+    Ok(absolute_path(package)?.to_string_lossy().into_owned())
+}
+
+/// Resolve a package like `resolve_package`, a `*` in its file name picking every file next to it that matches
+///
+/// Matches are sorted by name, so numbered parts are applied in order
+pub fn expand_package(game_path: &Path, package: &str) -> io::Result<Vec<String>> {
+    let package = package.trim().trim_matches('"');
+    let path = Path::new(package);
+    let pattern = path.file_name().unwrap_or_default().to_string_lossy();
+    if !pattern.contains('*') {
+        return Ok(vec![resolve_package(game_path, package)?]);
+    }
+
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let folder = match game_path.join(parent) {
+        folder if folder.is_dir() => folder,
+        _ => absolute_path(&parent.to_string_lossy())?,
+    };
+    let mut packages = folder.read_dir()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+        .filter(|entry| quirks::matches(&pattern, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    if packages.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No package matches {}", package)));
+    }
+    packages.sort();
+    Ok(packages)
+}
+
+/// Answer every yes/no question without a preset answer with yes
+pub fn set_assume_yes(yes: bool) {
+    ASSUME_YES.store(yes, Ordering::Relaxed);
+}
+
+/// Preset the answer of a yes/no question
+pub fn set_answer(key: &str, answer: bool) {
+    ANSWERS.lock().unwrap().insert(key.to_string(), answer);
+}
+
+/// Preset answers from a TOML file mapping question keys to `true` or `false`
+pub fn load_answers(path: &Path) -> anyhow::Result<()> {
+    let table = std::fs::read_to_string(path)?.parse::<toml::Table>()?;
+    for (key, value) in table {
+        if !QUESTIONS.contains(&key.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Unknown question {} in {:?}, expected one of {}",
+                key,
+                path,
+                QUESTIONS.join(", "),
+            ));
+        }
+        let Some(answer) = value.as_bool() else {
+            return Err(anyhow::anyhow!("Answer to {} in {:?} must be true or false", key, path));
+        };
+        set_answer(&key, answer);
+    }
+    Ok(())
+}
+
+/// Ask a yes/no question, falling back to the default on empty or unknown input
+pub fn confirm(key: &str, text: &str, default: bool) -> bool {
+    if let Some(&answer) = ANSWERS.lock().unwrap().get(key) {
+        if !is_quiet() {
+            println!("{text}{}", if answer { "y" } else { "n" });
+        }
+        return answer;
+    }
+    if ASSUME_YES.load(Ordering::Relaxed) {
+        if !is_quiet() {
+            println!("{text}y");
+        }
+        return true;
+    }
+
+    match input(text).to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    }
+}
+
+/// Load the files to skip during hash checks, one game relative path per line
+pub fn load_ignore_list(path: &Path) -> io::Result<()> {
+    let list = std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.replace('\\', "/"))
+        .collect();
+    *IGNORED.lock().unwrap() = list;
+    Ok(())
+}
+
+/// Whether a game relative path is on the ignore list
+pub fn is_ignored(path: &str) -> bool {
+    let path = path.replace('\\', "/");
+    IGNORED.lock().unwrap().iter().any(|ignored| ignored == &path)
+}
+
+/// Clear the read-only attribute of a file, returning whether it was set
+pub fn clear_readonly(path: &Path) -> io::Result<bool> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if !permissions.readonly() {
+        return Ok(false);
+    }
+
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(path, permissions)?;
+    Ok(true)
+}
+
+pub fn set_readonly(path: &Path) -> io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(path, permissions)
+}
+
+/// Move a file over another, copying it when the two are on different volumes
+///
+/// The copy keeps the permissions of the file and is synced to disk under a temporary name next to the target
+/// before being renamed over it, so the target is never left half written
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        result => return result,
+    }
+
+    let mut temp_name = to.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".moving");
+    let temp_path = to.with_file_name(temp_name);
+    let result = std::fs::copy(from, &temp_path)
+        .and_then(|_| File::open(&temp_path)?.sync_all())
+        .and_then(|_| std::fs::rename(&temp_path, to));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+        return result;
+    }
+    std::fs::remove_file(from)
+}
+
+/// Digest a file listing gives for it, told apart by its length
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Digest {
+    Md5,
+    /// Listed by some older packages
+    Sha1,
+    /// Listed by some older packages, as 8 hex digits or as a decimal number
+    Crc32,
+}
+
+impl Digest {
+    /// Tell which digest a listed hash is, none when it looks like no digest known
+    pub fn detect(hash: &str) -> Option<Digest> {
+        let hex = hash.chars().all(|c| c.is_ascii_hexdigit());
+        match hash.len() {
+            32 if hex => Some(Digest::Md5),
+            40 if hex => Some(Digest::Sha1),
+            8 if hex => Some(Digest::Crc32),
+            1..=10 if hash.parse::<u32>().is_ok() => Some(Digest::Crc32),
+            _ => None,
+        }
+    }
+
+    /// Whether a listed hash is the one computed, ignoring case and how a crc32 is written
+    pub fn matches(self, listed: &str, computed: &str) -> bool {
+        match (self, listed.parse::<u32>()) {
+            (Digest::Crc32, Ok(value)) if listed.len() != 8 => format!("{:08x}", value) == computed,
+            _ => listed.eq_ignore_ascii_case(computed),
+        }
+    }
+}
+
+/// Calculate MD5 hash of a file, showing a byte progress bar in `multi` for large files
+pub fn calculate_md5_hash_with_progress(file_path: &Path, multi: &MultiProgress) -> Result<String, io::Error> {
+    calculate_hash_with_progress(file_path, Digest::Md5, multi)
+}
+
+/// Calculate any digest of a file as lowercase hex, showing a byte progress bar in `multi` for large files
+pub fn calculate_hash_with_progress(
+    file_path: &Path,
+    digest: Digest,
+    multi: &MultiProgress,
+) -> Result<String, io::Error> {
+    let size = file_path.metadata()?.len();
+    if size < LARGE_FILE_SIZE {
+        return hash_file(file_path, digest, |_| {});
+    }
+
+    let pb = multi.add(create_byte_progress_bar(size));
+    pb.set_message(ellipsize(
+        &file_path.file_name().unwrap_or_default().to_string_lossy(),
+        terminal_width() / 3,
+    ));
+    let result = hash_file(file_path, digest, |read| pb.inc(read));
+    pb.finish_and_clear();
+    result
+}
+
+/// Calculate a digest of a file
 ///
 /// # Arguments
 /// * `file_path` - Path to the file to hash
+/// * `digest` - Which digest to calculate
+/// * `on_read` - Called with the number of bytes read after every read
 ///
 /// # Returns
-/// * `Result<String, io::Error>` - MD5 hash as hex string or IO error
-pub fn calculate_md5_hash<P: AsRef<Path>>(file_path: P) -> Result<String, io::Error> {
+/// * `Result<String, io::Error>` - Digest as lowercase hex string or IO error
+fn hash_file(file_path: &Path, digest: Digest, mut on_read: impl FnMut(u64)) -> Result<String, io::Error> {
     // Open the file
-    let file = File::open(&file_path)?;
+    let file = File::open(file_path)?;
 
     // Create a buffered reader for efficient reading
     let mut reader = BufReader::new(file);
 
-    // Create MD5 context
-    let mut context = Context::new();
+    // Create hash contexts, only the one asked for is fed
+    let mut md5 = Context::new();
+    let mut sha1 = Sha1::new();
+    let mut crc32 = crc32fast::Hasher::new();
 
     // Buffer for reading chunks of the file
     let mut buffer = [0u8; 8192]; // 8KB buffer
@@ -39,21 +446,208 @@ pub fn calculate_md5_hash<P: AsRef<Path>>(file_path: P) -> Result<String, io::Er
         if bytes_read == 0 {
             break; // End of file
         }
-        context.consume(&buffer[..bytes_read]);
+        match digest {
+            Digest::Md5 => md5.consume(&buffer[..bytes_read]),
+            Digest::Sha1 => sha1.update(&buffer[..bytes_read]),
+            Digest::Crc32 => crc32.update(&buffer[..bytes_read]),
+        }
+        on_read(bytes_read as u64);
     }
 
     // Compute final hash and convert to hex string
-    let digest = context.compute();
-    Ok(format!("{:x}", digest))
+    Ok(match digest {
+        Digest::Md5 => format!("{:x}", md5.compute()),
+        Digest::Sha1 => format!("{:x}", sha1.finalize()),
+        Digest::Crc32 => format!("{:08x}", crc32.finalize()),
+    })
+}
+
+/// Print a plain percentage line every `interval` instead of drawing interactive bars
+pub fn set_plain_progress(interval: Duration) {
+    PLAIN_PROGRESS_INTERVAL.store(interval.as_secs().max(1), Ordering::Relaxed);
 }
 
 pub fn create_progress_bar(len: u64) -> ProgressBar {
+    if is_quiet() {
+        return ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+    }
+    let interval = PLAIN_PROGRESS_INTERVAL.load(Ordering::Relaxed);
+    if interval > 0 {
+        let pb = create_plain_progress_bar(len, Duration::from_secs(interval));
+        overall::attach(&pb);
+        return pb;
+    }
+
     let pb = ProgressBar::new(len);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len}")
-            .expect("Failed to set progress bar template")
-            .progress_chars("#>-"),
-    );
+    pb.set_style(bar_style(&bar_template(len)));
+    overall::attach(&pb);
+    track(&pb);
     pb
 }
+
+/// Template of a stage bar going through `len` items, fit into the terminal next to "[00:00:00] [] 123/123"
+pub fn bar_template(len: u64) -> String {
+    let digits = len.max(1).ilog10() as usize + 1;
+    let bar_width = terminal_width()
+        .saturating_sub(20 + digits * 2)
+        .clamp(10, 40);
+
+    // Legacy consoles without ANSI support get a plain ASCII bar
+    if Term::stdout().features().colors_supported() {
+        format!("{{spinner:.green}} [{{elapsed_precise}}] [{{bar:{bar_width}.cyan/blue}}] {{pos}}/{{len}} {{msg}}")
+    } else {
+        format!("{{spinner}} [{{elapsed_precise}}] [{{bar:{bar_width}}}] {{pos}}/{{len}} {{msg}}")
+    }
+}
+
+/// Style of a stage bar drawn with the given template
+pub fn bar_style(template: &str) -> ProgressStyle {
+    let style = ProgressStyle::default_bar()
+        .template(template)
+        .expect("Failed to set progress bar template")
+        .progress_chars("#>-");
+    if Term::stdout().features().colors_supported() {
+        style
+    } else {
+        style.tick_chars("|/-\\ ")
+    }
+}
+
+// Bars drawn so far, newest last, so lines printed meanwhile can be kept clear of them
+static BARS: Mutex<Vec<WeakProgressBar>> = Mutex::new(Vec::new());
+
+fn track(pb: &ProgressBar) {
+    let mut bars = BARS.lock().unwrap();
+    bars.retain(|bar| bar.upgrade().is_some_and(|bar| !bar.is_finished()));
+    bars.push(pb.downgrade());
+}
+
+/// Run `f` with the newest bar still drawn cleared, so what it prints does not tear the bar
+pub fn suspend_bars<T>(f: impl FnOnce() -> T) -> T {
+    let bar = BARS.lock().unwrap()
+        .iter()
+        .rev()
+        .filter_map(WeakProgressBar::upgrade)
+        .find(|bar| !bar.is_finished() && !bar.is_hidden());
+    match bar {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+/// Group for progress bars drawn together, hidden in plain mode where bars print on their own
+pub fn create_multi_progress() -> MultiProgress {
+    if is_quiet() || PLAIN_PROGRESS_INTERVAL.load(Ordering::Relaxed) > 0 {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    }
+}
+
+/// Progress bar counting bytes, hidden in plain mode to keep logs short
+pub fn create_byte_progress_bar(len: u64) -> ProgressBar {
+    if is_quiet() || PLAIN_PROGRESS_INTERVAL.load(Ordering::Relaxed) > 0 {
+        return ProgressBar::hidden();
+    }
+
+    let pb = ProgressBar::new(len);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("  {msg} [{bar:20}] {bytes}/{total_bytes}")
+        .expect("Failed to set progress bar template")
+        .progress_chars("#>-"));
+    track(&pb);
+    pb
+}
+
+/// Show a spinner next to the message while `f` runs, for work without a known length
+pub fn with_spinner<T>(message: &str, f: impl FnOnce() -> T) -> T {
+    if is_quiet() {
+        return f();
+    }
+    if PLAIN_PROGRESS_INTERVAL.load(Ordering::Relaxed) > 0 {
+        println!("{message}");
+        return f();
+    }
+
+    let pb = ProgressBar::new_spinner();
+    pb.set_message(message.to_string());
+    pb.enable_steady_tick(Duration::from_millis(100));
+    let result = f();
+    pb.finish_and_clear();
+    result
+}
+
+/// Hidden progress bar reported by a thread printing a line every interval until it completes
+fn create_plain_progress_bar(len: u64, interval: Duration) -> ProgressBar {
+    let pb = ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+    let weak = pb.downgrade();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let Some(pb) = weak.upgrade() else {
+                break;
+            };
+
+            let pos = pb.position();
+            let len = pb.length().unwrap_or(0).max(1);
+            let message = pb.message();
+            println!(
+                "[{}] {}/{} ({}%){}{}",
+                indicatif::HumanDuration(pb.elapsed()),
+                pos,
+                len,
+                pos * 100 / len,
+                if message.is_empty() { "" } else { ", " },
+                message,
+            );
+            if let Some(line) = status::line().filter(|_| !pb.is_finished()) {
+                println!("  {}", line);
+            }
+            if pos >= len || pb.is_finished() {
+                break;
+            }
+        }
+    });
+    pb
+}
+
+/// Width of the terminal, or a sane default when not attached to one
+pub fn terminal_width() -> usize {
+    Term::stdout()
+        .size_checked()
+        .map(|(_, width)| width as usize)
+        .unwrap_or(80)
+}
+
+/// Shorten text to fit a display width by replacing its middle with an ellipsis
+pub fn ellipsize(text: &str, max_width: usize) -> String {
+    if measure_text_width(text) <= max_width || max_width < 5 {
+        return text.to_string();
+    }
+
+    // Keep as much of both ends as fits, counting wide characters twice
+    let budget = max_width - 3;
+    let mut head = String::new();
+    let mut head_width = 0;
+    for c in text.chars() {
+        let width = measure_text_width(c.encode_utf8(&mut [0; 4]));
+        if head_width + width > budget.div_ceil(2) {
+            break;
+        }
+        head.push(c);
+        head_width += width;
+    }
+
+    let mut tail = Vec::new();
+    let mut tail_width = 0;
+    for c in text.chars().rev() {
+        let width = measure_text_width(c.encode_utf8(&mut [0; 4]));
+        if tail_width + width > budget - head_width {
+            break;
+        }
+        tail.push(c);
+        tail_width += width;
+    }
+
+    format!("{}...{}", head, tail.into_iter().rev().collect::<String>())
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use sophon::proto::chunk::SophonChunkProto;
+use sophon::proto::sophon::SophonManifestProto;
+use crate::action::{Detail, PatchOutcome};
+use crate::quirks::Quirks;
+use crate::serialize::PkgVersion;
+use crate::util::Digest;
+use crate::{log, metrics, official, pools, util};
+
+/// How the game files get checked after patching
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Hash only the files the action wrote against the pkg_version listings
+    Touched,
+    /// Hash every file against the pkg_version listings
+    Full,
+    /// Hash every file and check its size against the manifests of the package
+    Manifest,
+    /// Only check that the files of the pkg_version listings exist with their listed size, without hashing
+    Quick,
+    /// Only hash files that changed size or were modified after the pkg_version listing
+    Modified,
+    /// Hash every file against the chunk manifests of the official build given with --build-url
+    Official,
+}
+
+impl FromStr for VerifyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "touched" => Ok(VerifyMode::Touched),
+            // Named after the listing before touched files were verified on their own
+            "full" | "pkg_version" => Ok(VerifyMode::Full),
+            "manifest" => Ok(VerifyMode::Manifest),
+            "quick" => Ok(VerifyMode::Quick),
+            "modified" => Ok(VerifyMode::Modified),
+            "official" => Ok(VerifyMode::Official),
+            _ => Err(anyhow!(
+                "Unknown verify mode {}, expected touched, full, manifest, quick, modified or official",
+                s,
+            )),
+        }
+    }
+}
+
+/// A file the game folder should contain after patching
+struct Expected {
+    path: String,
+    /// Md5, or the sha1 or crc32 some older listings give
+    hash: String,
+    size: Option<u64>,
+    // Listing the file comes from, verification of modified files trusts files not modified after it
+    listing: Option<PathBuf>,
+}
+
+/// Verify the game files with the selected strategy, recording every problem in the outcome
+///
+/// `manifests` are checked on top of the manifests found in the game folder
+pub fn verify(
+    game_path: &Path,
+    quirks: &Quirks,
+    manifests: &[PathBuf],
+    mode: VerifyMode,
+    outcome: &Mutex<PatchOutcome>,
+) -> Result<()> {
+    let _stage = metrics::stage("verify");
+    let expected = match mode {
+        VerifyMode::Full | VerifyMode::Quick | VerifyMode::Modified => from_pkg_versions(game_path, quirks)?,
+        VerifyMode::Manifest => from_manifests(game_path, quirks, manifests)?,
+        VerifyMode::Official => from_official(game_path, quirks)?,
+        VerifyMode::Touched => {
+            let written = outcome.lock().unwrap().written.iter().cloned().collect::<HashSet<_>>();
+            let mut expected = from_pkg_versions(game_path, quirks)?;
+            expected.retain(|file| written.contains(&file.path));
+            expected
+        }
+    };
+
+    let multi = util::create_multi_progress();
+    let pb = multi.add(util::create_progress_bar(expected.len() as u64));
+    pools::hash_pool().install(|| expected.into_par_iter().for_each(|file| {
+        pb.inc(1u64);
+        if util::is_ignored(&file.path) || quirks.is_skipped(&file.path) {
+            return;
+        }
+
+        let file_path = game_path.join(&file.path);
+        let Ok(metadata) = file_path.metadata() else {
+            outcome.lock().unwrap().detail(&file.path, Detail::Missing);
+            return;
+        };
+
+        // Size mismatches need no hashing
+        if let Some(size) = file.size.filter(|&size| size != metadata.len()) {
+            outcome.lock().unwrap().detail(&file.path, Detail::Mismatch {
+                expected: format!("{} bytes", size),
+                found: format!("{} bytes", metadata.len()),
+            });
+            return;
+        }
+
+        if mode == VerifyMode::Quick
+            || mode == VerifyMode::Modified && !modified_after(&metadata, file.listing.as_deref())
+        {
+            outcome.lock().unwrap().verified += 1;
+            return;
+        }
+
+        // Hashes that are no known digest are compared as md5, which then reports the mismatch
+        let digest = Digest::detect(&file.hash).unwrap_or(Digest::Md5);
+        match util::calculate_hash_with_progress(&file_path, digest, &multi) {
+            Ok(hash) if digest.matches(&file.hash, &hash) => {
+                outcome.lock().unwrap().verified += 1;
+            }
+            Ok(hash) => outcome.lock().unwrap().detail(&file.path, Detail::Mismatch {
+                expected: file.hash,
+                found: hash,
+            }),
+            Err(_) => outcome.lock().unwrap().detail(&file.path, Detail::Missing),
+        }
+    }));
+
+    Ok(())
+}
+
+fn from_pkg_versions(game_path: &Path, quirks: &Quirks) -> Result<Vec<Expected>> {
+    let mut expected = Vec::new();
+    for listing in quirks.pkg_version_paths(game_path)? {
+        expected.extend(PkgVersion::from(&listing)?
+            .into_iter()
+            .map(|file| Expected {
+                path: file.remote_file,
+                hash: file.md5,
+                size: file.file_size,
+                listing: Some(listing.clone()),
+            }));
+    }
+    Ok(expected)
+}
+
+fn from_manifests(game_path: &Path, quirks: &Quirks, manifests: &[PathBuf]) -> Result<Vec<Expected>> {
+    let mut paths = manifests.to_vec();
+    for entry in game_path.read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && quirks.is_manifest(&entry.file_name().to_string_lossy()) {
+            paths.push(entry.path());
+        }
+    }
+
+    // Later manifests win when several list the same file
+    let mut expected = BTreeMap::new();
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let assets = util::with_spinner(&format!("Reading {}", name), || -> Result<Vec<_>> {
+            if let Ok(manifest) = SophonManifestProto::from_reader(File::open(&path)?) {
+                Ok(manifest.assets
+                    .into_iter()
+                    .map(|asset| (asset.asset_name, asset.asset_hash_md5, asset.asset_size))
+                    .collect())
+            } else if let Ok(manifest) = SophonChunkProto::from_reader(File::open(&path)?) {
+                Ok(manifest.assets
+                    .into_iter()
+                    .map(|asset| (asset.asset_name, asset.asset_hash_md5, asset.asset_size))
+                    .collect())
+            } else {
+                Ok(Vec::new())
+            }
+        })?;
+        for (asset_name, md5, size) in assets {
+            expected.insert(quirks.remap(&asset_name), (md5, size));
+        }
+    }
+    if expected.is_empty() {
+        return Err(anyhow!("No manifest to verify {:?} against", game_path));
+    }
+
+    Ok(expected.into_iter()
+        // Folders have no hash
+        .filter(|(_, (md5, _))| !md5.is_empty())
+        .map(|(path, (md5, size))| Expected { path, hash: md5, size: Some(size as u64), listing: None })
+        .collect())
+}
+
+fn from_official(game_path: &Path, quirks: &Quirks) -> Result<Vec<Expected>> {
+    // Later manifests win when several list the same file
+    let mut expected = BTreeMap::new();
+    for (part, manifest) in official::manifests()? {
+        let files = manifest.assets
+            .into_iter()
+            // Folders have no hash
+            .filter(|asset| !asset.asset_hash_md5.is_empty())
+            .map(|asset| Expected {
+                path: quirks.remap(&asset.asset_name),
+                hash: asset.asset_hash_md5,
+                size: Some(asset.asset_size as u64),
+                listing: None,
+            })
+            .collect::<Vec<_>>();
+
+        // Audio packs of languages never installed would only report missing files
+        if part != "game" && !files.iter().any(|file| game_path.join(&file.path).exists()) {
+            log::info!("Leaving out {}, none of its files are installed", part);
+            continue;
+        }
+        expected.extend(files.into_iter().map(|file| (file.path.clone(), file)));
+    }
+    Ok(expected.into_values().collect())
+}
+
+fn modified_after(metadata: &std::fs::Metadata, listing: Option<&Path>) -> bool {
+    let listing_modified = listing
+        .and_then(|listing| listing.metadata().ok())
+        .and_then(|listing| listing.modified().ok());
+    match (metadata.modified().ok(), listing_modified) {
+        (Some(modified), Some(listing_modified)) => modified > listing_modified,
+        _ => true,
+    }
+}
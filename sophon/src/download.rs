@@ -0,0 +1,1103 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Deserialize;
+use ureq::{Agent, Body};
+use ureq::http::Response;
+use zstd::zstd_safe;
+use crate::proto::chunk::SophonChunkProto;
+use crate::proto::sophon::SophonManifestProto;
+use crate::sophon::{create_parent_dirs, explain_handle_error, read_index, reserve_handles, Phase, ProgressSink};
+
+// Attempts at a file before the download gives up on it, at least one per host
+const ATTEMPTS: usize = 3;
+
+// Hosts serving the same files as those the build API names
+static MIRRORS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Fail over to these hosts as well, e.g. `https://cdn2.example.com`, each serving the files under the same paths as
+/// the host of every url prefix the build API names
+pub fn set_mirrors(mirrors: Vec<String>) {
+    let _ = MIRRORS.set(mirrors);
+}
+
+// Connections kept open to and used at the same time with every host, as browsers do by default
+const CONNECTIONS_PER_HOST: usize = 6;
+
+static CONNECTIONS: OnceLock<usize> = OnceLock::new();
+
+// Every request goes through one agent, so connections to a host are reused across files
+static AGENT: OnceLock<Agent> = OnceLock::new();
+
+// How every host did in the downloads so far
+static HOST_STATS: Mutex<Vec<HostStats>> = Mutex::new(Vec::new());
+
+/// Download at most this many files from one host at the same time, keeping as many connections to it open
+///
+/// Requests go over HTTP/1.1 with kept-alive connections, the HTTP client has no HTTP/2 to multiplex them over one
+pub fn set_connections_per_host(connections: usize) {
+    let _ = CONNECTIONS.set(connections.max(1));
+}
+
+fn connections_per_host() -> usize {
+    CONNECTIONS.get().copied().unwrap_or(CONNECTIONS_PER_HOST)
+}
+
+fn agent() -> &'static Agent {
+    AGENT.get_or_init(|| {
+        let connections = connections_per_host();
+        let config = Agent::config_builder()
+            .max_idle_connections_per_host(connections)
+            .max_idle_connections(connections * 4)
+            .build();
+        Agent::new_with_config(config)
+    })
+}
+
+/// What a download did with one host
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct HostStats {
+    pub url_prefix: String,
+    /// Most files fetched from the host at the same time
+    pub peak_connections: usize,
+    pub bytes: u64,
+    pub failures: u32,
+}
+
+/// How every host of the downloads so far did, by download
+pub fn host_stats() -> Vec<HostStats> {
+    HOST_STATS.lock().unwrap().clone()
+}
+
+// Folder with the indexes of the chunk archives a chunk store serves instead of single chunks
+static ARCHIVE_INDEX: OnceLock<PathBuf> = OnceLock::new();
+
+/// Fetch chunks as byte ranges of the concatenated chunk archives of the chunk download, found through the
+/// `<archive>_db` index of every archive in this folder as the indexed chunk layout keeps them
+pub fn set_archive_index(path: PathBuf) {
+    let _ = ARCHIVE_INDEX.set(path);
+}
+
+/// Package a build is asked for, e.g. `https://.../downloader/sophon_chunk/api` with the branch and package ID the
+/// launcher uses
+pub struct BuildQuery {
+    pub api_url: String,
+    /// `main`, or `predownload` ahead of an update
+    pub branch: String,
+    pub package_id: String,
+    pub password: String,
+}
+
+impl BuildQuery {
+    /// The full build, whose chunk manifests install every file
+    pub fn build(&self) -> Result<Build> {
+        let url = self.url("getBuild");
+        read_build(&url, self.query(agent().get(&url)).call())
+    }
+
+    /// The update to the current build, whose ldiff manifests patch every file of the installed versions
+    pub fn patch_build(&self) -> Result<Build> {
+        let url = self.url("getPatchBuild");
+        read_build(&url, self.query(agent().post(&url)).send_empty())
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}/{}", self.api_url.trim_end_matches('/'), endpoint)
+    }
+
+    fn query<B>(&self, request: ureq::RequestBuilder<B>) -> ureq::RequestBuilder<B> {
+        request
+            .query("branch", &self.branch)
+            .query("package_id", &self.package_id)
+            .query("password", &self.password)
+    }
+}
+
+/// Answer of the Sophon build APIs
+#[derive(Deserialize)]
+struct BuildResponse {
+    retcode: i64,
+    message: String,
+    data: Option<Build>,
+}
+
+/// A build as the Sophon API describes it, with a manifest for every part of the game
+#[derive(Deserialize, Clone)]
+#[non_exhaustive]
+pub struct Build {
+    pub tag: String,
+    pub manifests: Vec<BuildManifest>,
+}
+
+/// One manifest of a build, the game itself or one of its audio packs
+#[derive(Deserialize, Clone)]
+#[non_exhaustive]
+pub struct BuildManifest {
+    /// `game`, or the language of an audio pack
+    pub matching_field: String,
+    pub manifest: ManifestFile,
+    pub manifest_download: Download,
+    /// Where the chunks of a full build are
+    #[serde(default)]
+    pub chunk_download: Option<Download>,
+    /// Where the ldiff chunk files of an update are
+    #[serde(default)]
+    pub diff_download: Option<Download>,
+}
+
+#[derive(Deserialize, Clone)]
+#[non_exhaustive]
+pub struct ManifestFile {
+    pub id: String,
+}
+
+/// Where the files of a manifest are served from
+#[derive(Deserialize, Clone)]
+#[non_exhaustive]
+pub struct Download {
+    /// `1` for zstd
+    pub compression: String,
+    pub url_prefix: String,
+}
+
+impl Download {
+    /// Address of a file of this download
+    pub fn url(&self, name: &str) -> String {
+        format!("{}/{}", self.url_prefix.trim_end_matches('/'), name)
+    }
+
+    /// Url prefixes the files of this download are served from, the one the build API names first and then the same
+    /// path on every mirror set with `set_mirrors`
+    pub fn url_prefixes(&self) -> Vec<String> {
+        let url_prefix = self.url_prefix.trim_end_matches('/');
+        let mut prefixes = vec![url_prefix.to_string()];
+        let path = url_prefix.find("://")
+            .and_then(|scheme| url_prefix[scheme + 3..].find('/').map(|host| &url_prefix[scheme + 3 + host..]))
+            .unwrap_or_default();
+        for mirror in MIRRORS.get().into_iter().flatten() {
+            let prefix = format!("{}{}", mirror.trim_end_matches('/'), path);
+            if !prefixes.contains(&prefix) {
+                prefixes.push(prefix);
+            }
+        }
+        prefixes
+    }
+}
+
+/// The url prefixes of a download with how the host of each did so far, files are fetched from the healthiest
+///
+/// At most `limit` files are fetched from one host at the same time, a file goes to the next healthiest host with
+/// room or waits for one
+struct Hosts {
+    prefixes: Vec<String>,
+    health: Mutex<Vec<Health>>,
+    freed: Condvar,
+    limit: usize,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Health {
+    failures: u32,
+    bytes: u64,
+    elapsed: Duration,
+    active: usize,
+    peak: usize,
+}
+
+impl Health {
+    /// Bytes per second over every file fetched, unknown until one was
+    fn throughput(&self) -> Option<f64> {
+        (!self.elapsed.is_zero()).then(|| self.bytes as f64 / self.elapsed.as_secs_f64())
+    }
+}
+
+impl Hosts {
+    fn new(download: &Download, limit: usize) -> Hosts {
+        let prefixes = download.url_prefixes();
+        let health = Mutex::new(vec![Health::default(); prefixes.len()]);
+        Hosts { prefixes, health, freed: Condvar::new(), limit }
+    }
+
+    /// Hosts in the order a file tries them: fewest failures first, then those not tried yet, then the fastest
+    fn order(health: &[Health]) -> Vec<usize> {
+        let mut order = (0..health.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| {
+            let throughput = |host: usize| health[host].throughput().unwrap_or(f64::INFINITY);
+            health[a].failures.cmp(&health[b].failures).then(throughput(b).total_cmp(&throughput(a)))
+        });
+        order
+    }
+
+    /// Take a connection to the healthiest host with room, one the file was not `tried` on yet if any has room
+    fn acquire(&self, tried: &[usize]) -> usize {
+        let mut health = self.health.lock().unwrap();
+        loop {
+            let free = Hosts::order(&health).into_iter()
+                .filter(|&host| health[host].active < self.limit)
+                .collect::<Vec<_>>();
+            let host = free.iter().find(|host| !tried.contains(host)).or(free.first());
+            if let Some(&host) = host {
+                health[host].active += 1;
+                health[host].peak = health[host].peak.max(health[host].active);
+                return host;
+            }
+            health = self.freed.wait(health).unwrap();
+        }
+    }
+
+    fn url(&self, host: usize, name: &str) -> String {
+        format!("{}/{}", self.prefixes[host], name)
+    }
+
+    /// Give the connection back with how the file went
+    fn release(&self, host: usize, result: Result<(u64, Duration), ()>) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[host];
+        health.active -= 1;
+        match result {
+            Ok((bytes, elapsed)) => {
+                health.bytes += bytes;
+                health.elapsed += elapsed;
+            }
+            Err(()) => health.failures += 1,
+        }
+        self.freed.notify_one();
+    }
+
+    /// Add how every host did to the stats of the run
+    fn finish(self) {
+        let stats = self.prefixes.into_iter()
+            .zip(self.health.into_inner().unwrap())
+            .map(|(url_prefix, health)| HostStats {
+                url_prefix,
+                peak_connections: health.peak,
+                bytes: health.bytes,
+                failures: health.failures,
+            });
+        HOST_STATS.lock().unwrap().extend(stats);
+    }
+}
+
+/// Ask a Sophon build API for a build by its full URL, query included
+pub fn fetch_build(url: &str) -> Result<Build> {
+    read_build(url, agent().get(url).call())
+}
+
+fn read_build(url: &str, response: Result<Response<Body>, ureq::Error>) -> Result<Build> {
+    let response = response
+        .and_then(|response| response.into_body().read_to_string())
+        .map_err(|e| anyhow!("Failed to fetch {}: {}", url, e))?;
+    let response = serde_json::from_str::<BuildResponse>(&response)
+        .map_err(|e| anyhow!("Unexpected answer from {}: {}", url, e))?;
+    match response.data {
+        Some(build) if response.retcode == 0 => Ok(build),
+        _ => Err(anyhow!("The build API refused the request: {} ({})", response.message, response.retcode)),
+    }
+}
+
+impl BuildManifest {
+    /// Download the zstd compressed manifest as it is served
+    pub fn fetch_manifest(&self) -> Result<Vec<u8>> {
+        if self.manifest_download.compression != "1" {
+            return Err(anyhow!(
+                "Manifest {} uses compression {}, expected zstd",
+                self.manifest.id,
+                self.manifest_download.compression,
+            ));
+        }
+        // Every host is tried before giving up
+        let mut error = None;
+        for url_prefix in self.manifest_download.url_prefixes() {
+            let url = format!("{}/{}", url_prefix, self.manifest.id);
+            let mut buffer = Vec::new();
+            let result = agent().get(&url).call()
+                .map_err(anyhow::Error::from)
+                .and_then(|response| Ok(response.into_body().into_reader().read_to_end(&mut buffer)?));
+            match result {
+                Ok(_) => return Ok(buffer),
+                Err(e) => error = Some(anyhow!("Failed to fetch {}: {}", url, e)),
+            }
+        }
+        Err(error.unwrap_or_else(|| anyhow!("Manifest {} has no url to fetch it from", self.manifest.id)))
+    }
+}
+
+/// Download a chunk manifest of a full build and every chunk it lists
+///
+/// The manifest is written to `manifest_path` and the chunks into `chunk_path`, as the chunk action takes them.
+/// Chunks already there at their listed size are kept, so a stopped download picks up where it was. Chunks the
+/// files in `local_path` already hold are taken from them instead, see `match_local_chunks`
+pub fn download_chunks(
+    manifest: &BuildManifest,
+    manifest_path: &Path,
+    chunk_path: &Path,
+    local_path: Option<&Path>,
+    progress: &dyn ProgressSink,
+) -> Result<SophonChunkProto> {
+    let download = manifest.chunk_download.as_ref()
+        .ok_or_else(|| anyhow!("Manifest {} comes without a chunk download", manifest.manifest.id))?;
+    let buffer = manifest.fetch_manifest()?;
+    let proto = SophonChunkProto::from_reader(&*buffer)
+        .map_err(|e| anyhow!("Failed to read manifest {}: {}", manifest.manifest.id, e))?;
+
+    // The manifest the local files were installed from knows where their chunks are
+    let previous = fs::read(manifest_path).ok().and_then(|buffer| SophonChunkProto::from_reader(&*buffer).ok());
+    fs::write(manifest_path, &buffer)?;
+
+    let mut files: BTreeMap<String, Remote> = proto.assets.iter()
+        .flat_map(|asset| &asset.asset_chunks)
+        .map(|chunk| (chunk.chunk_name.clone(), Remote {
+            size: chunk.chunk_size.max(0) as u64,
+            check: Check::DecompressedMd5(chunk.chunk_decompressed_hash_md5.clone()),
+            range: None,
+        }))
+        .collect();
+    if let Some(local_path) = local_path {
+        let found = match_local_chunks(&proto, previous.as_ref(), local_path, chunk_path, progress)?;
+        files.retain(|name, _| !found.contains(name));
+    }
+    if let Some(index_path) = ARCHIVE_INDEX.get() {
+        locate_in_archives(&proto, index_path, &mut files)?;
+    }
+    download_files(download, files, chunk_path, progress)?;
+    Ok(proto)
+}
+
+/// Point every chunk still to download that an archive index lists at its byte range in that archive
+///
+/// Archives store chunks decompressed, so they are fetched and checked as such. Chunks no index lists are
+/// downloaded on their own
+fn locate_in_archives(
+    manifest: &SophonChunkProto,
+    index_path: &Path,
+    files: &mut BTreeMap<String, Remote>,
+) -> Result<()> {
+    let chunks = manifest.assets.iter()
+        .flat_map(|asset| &asset.asset_chunks)
+        .filter(|chunk| files.contains_key(&chunk.chunk_name))
+        .map(|chunk| (chunk.chunk_name.clone(), chunk))
+        .collect::<HashMap<_, _>>();
+    let sizes = chunks.iter().map(|(name, chunk)| (name.clone(), chunk.chunk_size_decompressed)).collect();
+
+    for entry in fs::read_dir(index_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(archive) = name.strip_suffix("_db").filter(|_| entry.path().is_dir()) else {
+            continue;
+        };
+        let archive_path = index_path.join(archive);
+        for (chunk_name, offset, size) in read_index(&archive_path, &sizes)? {
+            files.insert(chunk_name.clone(), Remote {
+                size: size.max(0) as u64,
+                check: Check::Md5(chunks[&chunk_name].chunk_decompressed_hash_md5.clone()),
+                range: Some((archive.to_string(), offset)),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Shifts of a local file's data against the manifest followed at most, each one hashes the file once more
+const MAX_SHIFTS: usize = 16;
+
+/// What is known about where chunk data lies in one local file
+#[derive(Default)]
+struct LocalFile<'a> {
+    /// Regions the manifest and the previous one cut the file into
+    boundaries: BTreeSet<(u64, u64)>,
+    /// Chunks the manifest puts into the file, by offset and size
+    layout: Vec<(u64, u64, &'a str)>,
+    /// Size the manifest gives the file
+    size: u64,
+}
+
+/// Write the chunks whose data the files in `local_path` already hold into `chunk_path`, decompressed as the chunk
+/// action takes them too, and return the names of every chunk that needs no download
+///
+/// Local files are hashed along the chunk boundaries the manifest and the `previous` one they were installed from
+/// give them. A region whose size and md5 match a chunk stands in for it, whichever file and offset it is at, so
+/// a minor update only downloads the chunks it really changed. Data moved by bytes inserted into or cut from a file
+/// is found too, by hashing the chunks the manifest puts into it again at every shift a match or its changed size
+/// shows
+pub fn match_local_chunks(
+    manifest: &SophonChunkProto,
+    previous: Option<&SophonChunkProto>,
+    local_path: &Path,
+    chunk_path: &Path,
+    progress: &dyn ProgressSink,
+) -> Result<HashSet<String>> {
+    fs::create_dir_all(chunk_path)?;
+    let mut found = HashSet::new();
+
+    // Chunks still to get, by decompressed size and md5
+    let mut wanted: HashMap<(u64, String), Vec<&str>> = HashMap::new();
+    for chunk in manifest.assets.iter().flat_map(|asset| &asset.asset_chunks) {
+        let size = chunk.chunk_size_decompressed.max(0) as u64;
+        if size == 0 || chunk.chunk_decompressed_hash_md5.is_empty() {
+            continue;
+        }
+        // Chunks found on an earlier run are there decompressed, downloaded ones are left to the download, and
+        // anything else was cut short and is matched again
+        let chunk_file = chunk_path.join(&chunk.chunk_name);
+        match chunk_file.metadata() {
+            Ok(metadata) if metadata.len() == size => {
+                found.insert(chunk.chunk_name.clone());
+                continue;
+            }
+            Ok(metadata) if metadata.len() == chunk.chunk_size.max(0) as u64 => continue,
+            Ok(_) => fs::remove_file(&chunk_file)?,
+            Err(_) => {}
+        }
+        let key = (size, chunk.chunk_decompressed_hash_md5.to_ascii_lowercase());
+        wanted.entry(key).or_default().push(&chunk.chunk_name);
+    }
+    if wanted.is_empty() {
+        return Ok(found);
+    }
+
+    // Regions of the local files as large as a wanted chunk, by file
+    let sizes = wanted.keys().map(|(size, _)| *size).collect::<HashSet<_>>();
+    let mut files: BTreeMap<&str, LocalFile> = BTreeMap::new();
+    for asset in &manifest.assets {
+        let file = files.entry(&asset.asset_name).or_default();
+        file.size = asset.asset_size.max(0) as u64;
+        for chunk in asset.asset_chunks.iter().filter(|chunk| sizes.contains(&(chunk.chunk_size_decompressed as u64))) {
+            let region = (chunk.chunk_on_file_offset.max(0) as u64, chunk.chunk_size_decompressed as u64);
+            file.boundaries.insert(region);
+            file.layout.push((region.0, region.1, &chunk.chunk_name));
+        }
+    }
+    for asset in previous.into_iter().flat_map(|previous| &previous.assets) {
+        for chunk in asset.asset_chunks.iter().filter(|chunk| sizes.contains(&(chunk.chunk_size_decompressed as u64))) {
+            let region = (chunk.chunk_on_file_offset.max(0) as u64, chunk.chunk_size_decompressed as u64);
+            files.entry(&asset.asset_name).or_default().boundaries.insert(region);
+        }
+    }
+
+    progress.on_stage_start(Phase::Matching, files.len() as u64);
+    let wanted = Mutex::new(wanted);
+    let found = Mutex::new(found);
+    files.into_par_iter().try_for_each(|(name, local)| -> Result<()> {
+        // The local file and the chunk being written
+        let _handles = reserve_handles(2);
+        let result = match File::open(local_path.join(name)) {
+            Ok(mut file) => match_file(&mut file, local, &wanted, chunk_path)
+                .map(|names| found.lock().unwrap().extend(names)),
+            // Files not installed yet hold nothing
+            Err(_) => Ok(()),
+        };
+        progress.on_item_done(Phase::Matching);
+        result
+    })?;
+    progress.on_finish(Phase::Matching);
+    Ok(found.into_inner().unwrap())
+}
+
+/// Hash the regions of a local file and write those a chunk is still wanted for, returning the chunks written
+///
+/// The boundaries are hashed first, then the layout of the manifest shifted by how much longer the file is than the
+/// manifest says and by every shift a match showed. A file that cannot be read to the end, e.g. as it is written
+/// to meanwhile, is left with the chunks found so far
+fn match_file(
+    file: &mut File,
+    local: LocalFile,
+    wanted: &Mutex<HashMap<(u64, String), Vec<&str>>>,
+    chunk_path: &Path,
+) -> Result<Vec<String>> {
+    let Ok(length) = file.metadata().map(|metadata| metadata.len()) else {
+        return Ok(Vec::new());
+    };
+    let mut written = Vec::new();
+    let mut buffer = Vec::new();
+    let mut hashed = HashSet::new();
+    let shifted = |shift: i64| local.layout.iter().map(move |&(offset, size, _)| (offset as i64 + shift, size));
+    let mut shifts = vec![length as i64 - local.size as i64];
+    let mut regions = local.boundaries.iter()
+        .map(|&(offset, size)| (offset as i64, size))
+        .chain(shifted(shifts[0]))
+        .collect::<Vec<_>>();
+    let mut next = 0;
+    while let Some(&(offset, size)) = regions.get(next) {
+        next += 1;
+        if offset < 0 || offset as u64 + size > length || !hashed.insert((offset, size)) {
+            continue;
+        }
+        buffer.resize(size as usize, 0);
+        let read = file.seek(SeekFrom::Start(offset as u64)).and_then(|_| file.read_exact(&mut buffer));
+        if read.is_err() {
+            break;
+        }
+        let md5 = format!("{:x}", md5::compute(&buffer));
+        let Some(names) = wanted.lock().unwrap().remove(&(size, md5)) else {
+            continue;
+        };
+        for name in names {
+            // Written next to its target first, so a stopped run never leaves a chunk cut short
+            let path = chunk_path.join(name);
+            let part_path = part_path(&path);
+            create_parent_dirs(chunk_path, [name])?;
+            fs::write(&part_path, &buffer).map_err(explain_handle_error)?;
+            fs::rename(&part_path, &path)?;
+            written.push(name.to_string());
+
+            // The rest of the file may be moved by as much as this chunk
+            let moved = local.layout.iter()
+                .filter(|&&(_, _, chunk)| chunk == name)
+                .map(|&(chunk_offset, _, _)| offset - chunk_offset as i64);
+            for shift in moved {
+                if shifts.len() < MAX_SHIFTS && !shifts.contains(&shift) {
+                    shifts.push(shift);
+                    regions.extend(shifted(shift));
+                }
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Download an ldiff manifest of an update and the ldiff chunk files it needs
+///
+/// The manifest is written to `manifest_path` and the chunk files into `ldiff_path`. Only payloads patching from
+/// `from_version` are fetched when it is given, otherwise every chunk file the manifest lists
+pub fn download_ldiff(
+    manifest: &BuildManifest,
+    manifest_path: &Path,
+    ldiff_path: &Path,
+    from_version: Option<&str>,
+    progress: &dyn ProgressSink,
+) -> Result<SophonManifestProto> {
+    let download = manifest.diff_download.as_ref()
+        .ok_or_else(|| anyhow!("Manifest {} comes without an ldiff download", manifest.manifest.id))?;
+    let buffer = manifest.fetch_manifest()?;
+    let proto = SophonManifestProto::from_reader(&*buffer)
+        .map_err(|e| anyhow!("Failed to read manifest {}: {}", manifest.manifest.id, e))?;
+
+    // Payloads sharing a chunk file do not all list its size and md5
+    let mut files: BTreeMap<String, Remote> = BTreeMap::new();
+    for data in proto.assets.iter()
+        .filter_map(|asset| asset.asset_data.as_ref())
+        .flat_map(|chunk| &chunk.assets)
+        .filter(|data| from_version.is_none_or(|version| data.chunk_file_version == version))
+    {
+        let remote = files.entry(data.chunk_file_name.clone()).or_insert(Remote {
+            size: 0,
+            check: Check::Md5(String::new()),
+            range: None,
+        });
+        if data.chunk_file_size > 0 {
+            remote.size = data.chunk_file_size as u64;
+        }
+        if !data.chunk_file_md5.is_empty() {
+            remote.check = Check::Md5(data.chunk_file_md5.clone());
+        }
+    }
+    if files.is_empty() {
+        return Err(anyhow!(
+            "Manifest {} has no payloads patching from version {}",
+            manifest.manifest.id,
+            from_version.unwrap_or_default(),
+        ));
+    }
+    fs::write(manifest_path, &buffer)?;
+    download_files(download, files, ldiff_path, progress)?;
+    Ok(proto)
+}
+
+/// A file of a download with what the manifest says about it
+struct Remote {
+    /// Size as downloaded, any when zero
+    size: u64,
+    check: Check,
+    /// Archive of the download the file is a byte range of and the offset it starts at, fetched with a range
+    /// request instead of by its own name
+    range: Option<(String, u64)>,
+}
+
+/// Hash a downloaded file is checked against once complete, nothing is checked when the manifest lists none
+enum Check {
+    /// md5 of the file as downloaded
+    Md5(String),
+    /// md5 of the zstd compressed file once decompressed
+    DecompressedMd5(String),
+}
+
+/// Download files of a download into a folder in parallel
+///
+/// Files already there at their listed size are kept. What an interrupted download left in a `.part` file is
+/// continued with a range request, and a file only takes its name once it is complete and its hash matches. Every
+/// file is fetched from the healthiest host, a failed attempt goes on with the next one
+fn download_files(
+    download: &Download,
+    files: BTreeMap<String, Remote>,
+    folder: &Path,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    fs::create_dir_all(folder)?;
+    let hosts = Hosts::new(download, connections_per_host());
+    let attempts = ATTEMPTS.max(hosts.prefixes.len());
+    progress.on_stage_start(Phase::Download, files.len() as u64);
+    let result = files.into_par_iter().try_for_each(|(name, remote)| -> Result<()> {
+        let path = folder.join(&name);
+        let done = path.metadata().is_ok_and(|metadata| remote.size == 0 || metadata.len() == remote.size);
+        if !done {
+            let mut tried = Vec::new();
+            let mut downloaded = 0;
+            loop {
+                let host = hosts.acquire(&tried);
+                let started = Instant::now();
+                let url = hosts.url(host, remote.range.as_ref().map_or(&name, |(archive, _)| archive));
+                let result = download_file(&url, &path, &remote);
+                hosts.release(host, result.as_ref().map(|&bytes| (bytes, started.elapsed())).map_err(|_| ()));
+                tried.push(host);
+                match result {
+                    Ok(bytes) => {
+                        downloaded += bytes;
+                        break;
+                    }
+                    Err((bytes, _)) if tried.len() < attempts => downloaded += bytes,
+                    Err((_, e)) => {
+                        return Err(anyhow!("Failed to download {} after {} attempts: {}", name, attempts, e));
+                    }
+                }
+            }
+            progress.on_bytes(Phase::Download, downloaded);
+        }
+        progress.on_item_done(Phase::Download);
+        Ok(())
+    });
+    hosts.finish();
+    result?;
+    progress.on_finish(Phase::Download);
+    Ok(())
+}
+
+/// Download a file next to its target, continuing a partial download, and put it in place once complete and checked
+///
+/// Returns the bytes fetched, also when failing, as a failed attempt keeps what it got for the next one
+fn download_file(url: &str, path: &Path, remote: &Remote) -> Result<u64, (u64, anyhow::Error)> {
+    let part_path = part_path(path);
+
+    // A part as large as the file is only left to check, a larger one is of no use
+    let mut offset = part_path.metadata().map_or(0, |metadata| metadata.len());
+    if remote.size > 0 && offset > remote.size {
+        let _ = fs::remove_file(&part_path);
+        offset = 0;
+    }
+    let mut fetched = 0;
+    let hashing = if remote.size == 0 || offset < remote.size {
+        let (bytes, hashing) = match &remote.range {
+            Some((_, start)) => fetch_range(url, &part_path, start + offset, start + remote.size, &remote.check)?,
+            None => fetch(url, &part_path, offset, &remote.check)?,
+        };
+        fetched = bytes;
+        offset += fetched;
+        hashing
+    } else {
+        remote.check.hash_part(&part_path).map_err(|e| (0, e.into()))?
+    };
+    if remote.size > 0 && offset != remote.size {
+        return Err((fetched, anyhow!("got {} of {} bytes", offset, remote.size)));
+    }
+
+    // A corrupt part is dropped so the next attempt starts over, from another host
+    if let Err(e) = hashing.finish() {
+        let _ = fs::remove_file(&part_path);
+        return Err((fetched, e));
+    }
+    fs::rename(&part_path, path).map_err(|e| (fetched, e.into()))?;
+    Ok(fetched)
+}
+
+/// Where a file is written until it is complete
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+    part_name.push(".part");
+    path.with_file_name(part_name)
+}
+
+/// Append the file from `offset` on to the part, starting over when the server does not support ranges
+fn fetch(url: &str, part_path: &Path, offset: u64, check: &Check) -> Result<(u64, Hashing), (u64, anyhow::Error)> {
+    // The connection and the file being written
+    let _handles = reserve_handles(2);
+    let mut request = agent().get(url);
+    if offset > 0 {
+        request = request.header("Range", format!("bytes={}-", offset));
+    }
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::StatusCode(416)) => {
+            // The part does not fit the file on the server anymore
+            let _ = fs::remove_file(part_path);
+            return Err((0, anyhow!("the server refused to continue the download, starting over")));
+        }
+        Err(e) => return Err((0, e.into())),
+    };
+    let resumed = offset > 0 && response.status() == 206;
+    write_part(response, part_path, resumed, check)
+}
+
+/// Append the bytes from `start` up to `end` of an archive to the part
+fn fetch_range(
+    url: &str,
+    part_path: &Path,
+    start: u64,
+    end: u64,
+    check: &Check,
+) -> Result<(u64, Hashing), (u64, anyhow::Error)> {
+    let _handles = reserve_handles(2);
+    let response = agent().get(url)
+        .header("Range", format!("bytes={}-{}", start, end - 1))
+        .call()
+        .map_err(|e| (0, e.into()))?;
+    // A whole archive is far more than a chunk, it is never taken instead
+    if response.status() != 206 {
+        return Err((0, anyhow!("the server does not serve parts of {}", url)));
+    }
+    write_part(response, part_path, true, check)
+}
+
+/// Write what a response brings to the part, after what the part already holds when `append` is set
+///
+/// The part is hashed as it is written, so it is checked without reading it again
+fn write_part(
+    response: Response<Body>,
+    part_path: &Path,
+    append: bool,
+    check: &Check,
+) -> Result<(u64, Hashing), (u64, anyhow::Error)> {
+    let mut hashing = match append {
+        true => check.hash_part(part_path).map_err(|e| (0, e.into()))?,
+        false => check.start(),
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(part_path)
+        .map_err(|e| (0, explain_handle_error(e).into()))?;
+    let mut reader = response.into_body().into_reader();
+    let mut writer = BufWriter::new(file);
+
+    // What arrived before the connection broke is kept for the next attempt
+    let mut fetched = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let _ = writer.flush();
+                return Err((fetched, e.into()));
+            }
+        };
+        writer.write_all(&buffer[..read]).map_err(|e| (fetched, e.into()))?;
+        hashing.update(&buffer[..read]);
+        fetched += read as u64;
+    }
+    writer.flush().map_err(|e| (fetched, e.into()))?;
+    Ok((fetched, hashing))
+}
+
+// Bytes of a zstd frame header that hold the dictionary ID
+const FRAME_HEADER: usize = 18;
+
+/// Hash of a file taken while it is written, compared with its check once complete
+enum Hashing {
+    Unchecked,
+    Md5 { expected: String, context: md5::Context },
+    /// Compressed chunks are decompressed on the way, the decoder gives way to the error once they fail to
+    DecompressedMd5 {
+        expected: String,
+        header: Vec<u8>,
+        decoder: Result<zstd::stream::write::Decoder<'static, md5::Context>, String>,
+    },
+}
+
+impl Check {
+    /// Start hashing a file the way this check wants it
+    fn start(&self) -> Hashing {
+        match self {
+            Check::Md5(expected) if !expected.is_empty() => {
+                Hashing::Md5 { expected: expected.clone(), context: md5::Context::new() }
+            }
+            Check::DecompressedMd5(expected) if !expected.is_empty() => Hashing::DecompressedMd5 {
+                expected: expected.clone(),
+                header: Vec::new(),
+                decoder: zstd::stream::write::Decoder::new(md5::Context::new()).map_err(|e| e.to_string()),
+            },
+            _ => Hashing::Unchecked,
+        }
+    }
+
+    /// Hash what a part already holds, nothing when there is no part yet
+    fn hash_part(&self, part_path: &Path) -> io::Result<Hashing> {
+        let mut hashing = self.start();
+        match File::open(part_path) {
+            Ok(mut file) => {
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    match file.read(&mut buffer)? {
+                        0 => break,
+                        read => hashing.update(&buffer[..read]),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(hashing)
+    }
+}
+
+impl Hashing {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hashing::Unchecked => {}
+            Hashing::Md5 { context, .. } => context.consume(data),
+            Hashing::DecompressedMd5 { header, decoder, .. } => {
+                if header.len() < FRAME_HEADER {
+                    header.extend(&data[..data.len().min(FRAME_HEADER - header.len())]);
+                }
+                let failed = match decoder {
+                    Ok(writer) => writer.write_all(data).err(),
+                    Err(_) => None,
+                };
+                if let Some(e) = failed {
+                    *decoder = Err(e.to_string());
+                }
+            }
+        }
+    }
+
+    /// Compare the hash of everything written with the listed one
+    fn finish(self) -> Result<()> {
+        let (expected, found) = match self {
+            Hashing::Unchecked => return Ok(()),
+            Hashing::Md5 { expected, context } => (expected, context.compute()),
+            Hashing::DecompressedMd5 { expected, header, decoder } => {
+                // Chunks compressed with a dictionary are checked once assembled instead
+                if zstd_safe::get_dict_id_from_frame(&header).is_some() {
+                    return Ok(());
+                }
+                let mut decoder = decoder.map_err(|e| anyhow!("not a zstd compressed chunk: {}", e))?;
+                decoder.flush().map_err(|e| anyhow!("not a zstd compressed chunk: {}", e))?;
+                (expected, decoder.into_inner().compute())
+            }
+        };
+        let found = format!("{:x}", found);
+        if !found.eq_ignore_ascii_case(&expected) {
+            return Err(anyhow!("md5 {} does not match the listed {}", found, expected));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(health: &[(u32, u64, u64)], limit: usize) -> Hosts {
+        Hosts {
+            prefixes: (0..health.len()).map(|host| format!("https://cdn{}.example.com/chunks", host)).collect(),
+            health: Mutex::new(health.iter()
+                .map(|&(failures, bytes, secs)| Health {
+                    failures,
+                    bytes,
+                    elapsed: Duration::from_secs(secs),
+                    ..Health::default()
+                })
+                .collect()),
+            freed: Condvar::new(),
+            limit,
+        }
+    }
+
+    #[test]
+    fn healthiest_hosts_first() {
+        // Failures count before speed, hosts not tried yet come before the measured ones
+        let order = Hosts::order(&hosts(&[(0, 100, 10), (0, 100, 1), (1, 0, 0), (0, 0, 0)], 1).health.lock().unwrap());
+        assert_eq!(order, [3, 1, 0, 2]);
+        let hosts = hosts(&[(0, 0, 0), (0, 0, 0)], 2);
+        let host = hosts.acquire(&[]);
+        assert_eq!(host, 0);
+        hosts.release(host, Err(()));
+        assert_eq!(hosts.acquire(&[]), 1);
+        hosts.release(1, Ok((10, Duration::from_secs(1))));
+        assert_eq!(hosts.url(1, "abc"), "https://cdn1.example.com/chunks/abc");
+        assert_eq!(Hosts::order(&hosts.health.lock().unwrap()), [1, 0]);
+    }
+
+    #[test]
+    fn connections_per_host_are_limited() {
+        let hosts = std::sync::Arc::new(hosts(&[(0, 0, 0), (1, 0, 0)], 2));
+        // The healthy host takes two files, further ones go to the other host, and then wait for a free connection
+        assert_eq!([hosts.acquire(&[]), hosts.acquire(&[]), hosts.acquire(&[]), hosts.acquire(&[])], [0, 0, 1, 1]);
+        let waiting = {
+            let hosts = hosts.clone();
+            std::thread::spawn(move || hosts.acquire(&[]))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        hosts.release(1, Ok((1, Duration::from_secs(1))));
+        assert_eq!(waiting.join().unwrap(), 1);
+        // A file goes to a host it was not tried on yet while that has room
+        hosts.release(0, Err(()));
+        hosts.release(1, Ok((1, Duration::from_secs(1))));
+        assert_eq!(hosts.acquire(&[0]), 1);
+        assert_eq!(hosts.health.lock().unwrap().iter().map(|health| health.peak).collect::<Vec<_>>(), [2, 2]);
+    }
+
+    // Serve `data` to one request after another, the ranges asked for when `ranges` is set as a chunk store serving
+    // archives does
+    fn serve(data: &'static [u8], requests: usize, ranges: bool) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap().to_ascii_lowercase();
+                let range = request.lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(start, end)| {
+                        (start.parse::<usize>().unwrap(), end.parse().map_or(data.len(), |end: usize| end + 1))
+                    })
+                    .filter(|_| ranges);
+                let (status, body) = match range {
+                    Some((start, end)) => ("206 Partial Content", &data[start..end]),
+                    None => ("200 OK", data),
+                };
+                let header = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+                stream.write_all(format!("{}Connection: close\r\n\r\n", header).as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        format!("http://{}/archive", address)
+    }
+
+    #[test]
+    fn chunks_fetched_as_ranges_of_archives() {
+        let folder = std::env::temp_dir().join(format!("sophon_download_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let url = serve(b"0123456789", 1, true);
+
+        // A part left by a broken attempt is continued from where it stopped
+        let remote = Remote {
+            size: 4,
+            check: Check::Md5(format!("{:x}", md5::compute(b"3456"))),
+            range: Some(("archive".to_string(), 3)),
+        };
+        fs::write(folder.join("chunk.part"), b"3").unwrap();
+        assert_eq!(download_file(&url, &folder.join("chunk"), &remote).unwrap(), 3);
+        assert_eq!(fs::read(folder.join("chunk")).unwrap(), b"3456");
+
+        // A server sending the whole archive instead of the range is no use
+        let check = Check::Md5(String::new());
+        assert!(fetch_range(&serve(b"0123456789", 1, false), &folder.join("other.part"), 0, 4, &check).is_err());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    // Chunks of an asset by name, offset and data
+    type Chunks<'a> = &'a [(&'a str, i64, &'a [u8])];
+
+    fn manifest(assets: &[(&str, Chunks)]) -> SophonChunkProto {
+        use crate::proto::chunk::{AssetChunk, AssetProperty};
+        let assets = assets.iter().map(|&(name, chunks)| AssetProperty {
+            asset_name: name.to_string(),
+            asset_size: chunks.iter().map(|(_, _, data)| data.len() as i64).sum(),
+            asset_chunks: chunks.iter()
+                .map(|&(chunk_name, offset, data)| AssetChunk {
+                    chunk_name: chunk_name.to_string(),
+                    chunk_decompressed_hash_md5: format!("{:x}", md5::compute(data)),
+                    chunk_on_file_offset: offset,
+                    chunk_size: 1,
+                    chunk_size_decompressed: data.len() as i64,
+                })
+                .collect(),
+            ..Default::default()
+        });
+        SophonChunkProto { assets: assets.collect() }
+    }
+
+    #[test]
+    fn local_chunks_found_where_edits_moved_them() {
+        let folder = std::env::temp_dir().join(format!("sophon_download_local_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(folder.join("game")).unwrap();
+        fs::create_dir_all(folder.join("chunks")).unwrap();
+        let latest = manifest(&[
+            ("a", &[("a1", 0, b"first chunk"), ("a2", 11, b"second chunk")]),
+            ("b", &[("b1", 0, b"third chunk"), ("b2", 11, b"fourth chunk")]),
+        ]);
+        // Bytes put in front move every chunk of a by the size the file grew
+        fs::write(folder.join("game/a"), b"new first chunksecond chunk").unwrap();
+        // The previous manifest tells where the first chunk of b moved, which shows where the second is
+        fs::write(folder.join("game/b"), b"moved third chunkfourth chunk with a tail").unwrap();
+        let previous = manifest(&[("b", &[("old", 6, b"third chunk")])]);
+        // A chunk cut short on an earlier run is matched again
+        fs::write(folder.join("chunks/a1"), b"firs").unwrap();
+
+        let found = match_local_chunks(
+            &latest,
+            Some(&previous),
+            &folder.join("game"),
+            &folder.join("chunks"),
+            &crate::sophon::NoProgress,
+        ).unwrap();
+        assert_eq!(found.len(), 4);
+        assert_eq!(fs::read(folder.join("chunks/a1")).unwrap(), b"first chunk");
+        assert_eq!(fs::read(folder.join("chunks/b2")).unwrap(), b"fourth chunk");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn corrupt_downloads_never_take_their_name() {
+        let folder = std::env::temp_dir().join(format!("sophon_download_check_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let chunk: &'static [u8] = zstd::encode_all(&b"chunk data"[..], 3).unwrap().leak();
+        let remote = Remote {
+            size: chunk.len() as u64,
+            check: Check::DecompressedMd5(format!("{:x}", md5::compute(b"chunk data"))),
+            range: None,
+        };
+
+        let mut corrupt = chunk.to_vec();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(download_file(&serve(corrupt.leak(), 1, true), &folder.join("chunk"), &remote).is_err());
+        assert!(!folder.join("chunk").exists() && !folder.join("chunk.part").exists());
+
+        // The part a broken attempt left is hashed along with the rest
+        fs::write(folder.join("chunk.part"), &chunk[..4]).unwrap();
+        assert!(download_file(&serve(chunk, 1, true), &folder.join("chunk"), &remote).is_ok());
+        assert_eq!(fs::read(folder.join("chunk")).unwrap(), chunk);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn mirrors_serve_the_same_paths() {
+        set_mirrors(vec!["https://mirror.example.com/".to_string()]);
+        let download = Download {
+            compression: "1".to_string(),
+            url_prefix: "https://cdn.example.com/client/chunks/".to_string(),
+        };
+        assert_eq!(download.url_prefixes(), [
+            "https://cdn.example.com/client/chunks",
+            "https://mirror.example.com/client/chunks",
+        ]);
+    }
+}
@@ -1,2 +1,12 @@
+//! Read Sophon manifests, download their chunks and ldiff files, and assemble or extract game files from them
+//!
+//! Everything an application needs is in [`prelude`], and what it names only changes in a new major version.
+//! Enums and the structs this crate fills in are `#[non_exhaustive]`, so new phases, policies or API fields are
+//! not breaking. Anything reached only through the module paths can change with any release
 pub mod proto;
-pub mod sophon;
\ No newline at end of file
+pub mod sophon;
+pub mod download;
+pub mod prelude;
+
+/// Error and result type every fallible function of this crate returns
+pub use anyhow::{Error, Result};
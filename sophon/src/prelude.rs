@@ -0,0 +1,30 @@
+//! The public API of the crate, `use sophon::prelude::*` brings in everything an application calls
+
+// Errors
+pub use crate::{Error, Result};
+
+// Manifests
+pub use crate::proto::chunk::{AssetChunk, AssetProperty, SophonChunkProto};
+pub use crate::proto::sophon::{Asset, SophonManifestProto};
+
+// Downloads
+pub use crate::download::{
+    download_chunks, download_ldiff, fetch_build, match_local_chunks, Build, BuildManifest, BuildQuery,
+};
+
+// Chunk assembly
+pub use crate::sophon::{
+    chunk_diff, chunk_diff_to, mismatched_chunks, AssetWriter, ChunkLayout, ChunkMismatch, DirectoryTarget, Existing,
+    ImageTarget, OutputTarget,
+};
+
+// Ldiff extraction
+pub use crate::sophon::{
+    create_parent_dirs, ldiff_entries, ldiff_file, ldiff_files, mismatched_ldiff_chunks, LdiffEntry, LdiffKind,
+};
+
+// Progress
+pub use crate::sophon::{NoProgress, Phase, ProgressSink};
+
+// Settings
+pub use crate::sophon::{set_dictionary_path, set_handle_budget, set_message_hook, set_prefetch_depth};
@@ -11,12 +11,17 @@ use crate::proto::sophon::SophonManifestProto;
 impl SophonChunkProto {
     pub fn from(path: String) -> Result<Self, DecodeError> {
         let file = File::open(&path).unwrap();
-        let reader = BufReader::new(file);
+        Self::from_reader(BufReader::new(file))
+    }
+
+    /// Decode a zstd compressed manifest from any reader
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, DecodeError> {
         let mut decoder = Decoder::new(reader).unwrap();
 
         // Read file into buffer
         let mut buffer = Vec::new();
-        decoder.read_to_end(&mut buffer).unwrap();
+        decoder.read_to_end(&mut buffer)
+            .map_err(|e| DecodeError::new(e.to_string()))?;
 
         // Parse the Protobuf message
         let proto = Self::decode(&*buffer);
@@ -31,12 +36,17 @@ impl SophonChunkProto {
 impl SophonManifestProto {
     pub fn from(path: String) -> Result<Self, DecodeError> {
         let file = File::open(&path).unwrap();
-        let reader = BufReader::new(file);
+        Self::from_reader(BufReader::new(file))
+    }
+
+    /// Decode a zstd compressed manifest from any reader
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, DecodeError> {
         let mut decoder = Decoder::new(reader).unwrap();
 
         // Read file into buffer
         let mut buffer = Vec::new();
-        decoder.read_to_end(&mut buffer).unwrap();
+        decoder.read_to_end(&mut buffer)
+            .map_err(|e| DecodeError::new(e.to_string()))?;
 
         // Parse the Protobuf message
         let proto = Self::decode(&*buffer);
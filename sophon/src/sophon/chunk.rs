@@ -1,23 +1,45 @@
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use anyhow::{anyhow, Result};
-use futures::future::join_all;
-use indicatif::{ProgressBar, ProgressStyle};
 use leveldb::db::Database;
 use leveldb::iterator::Iterable;
 use leveldb::options::{Options, ReadOptions};
 use memmap2::MmapOptions;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use crate::proto::chunk::SophonChunkProto;
-
+use crate::proto::chunk::{AssetProperty, SophonChunkProto};
+use crate::sophon::{
+    create_parent_dirs, explain_handle_error, report_message, reserve_handles, Dictionaries,
+    DirectoryTarget, OutputTarget, Phase, Prefetcher, ProgressSink,
+};
+
+/// Assemble the assets of a chunk manifest into the output path
+///
+/// Staging and assembly are reported to `progress`, so the caller decides how they are shown.
+/// Decompressed chunks stay in `chunk_tmp` when `keep_temp` is set
 pub async fn chunk_diff(
     manifest: &SophonChunkProto,
     output_path: &'static Path,
     chunk_path: &Path,
-    progress_bar: Option<Option<ProgressBar>>,
+    progress: Arc<dyn ProgressSink>,
+    keep_temp: bool,
+) -> Result<()> {
+    let target = Arc::new(DirectoryTarget::new(output_path));
+    chunk_diff_to(manifest, output_path, chunk_path, progress, keep_temp, target).await
+}
+
+/// Assemble the assets of a chunk manifest into any output target
+///
+/// Decompressed chunks are staged in `chunk_tmp` under `work_path`
+pub async fn chunk_diff_to(
+    manifest: &SophonChunkProto,
+    work_path: &Path,
+    chunk_path: &Path,
+    progress: Arc<dyn ProgressSink>,
+    keep_temp: bool,
+    target: Arc<dyn OutputTarget>,
 ) -> Result<()> {
     // Make chunk caches
     let mut cache_list: HashMap<String, i64> = HashMap::new();
@@ -32,323 +54,390 @@ pub async fn chunk_diff(
         return Err(anyhow!("[Error] Chunk directory does not exist"));
     }
 
-    // Use parallel processing for the chunks
-    let chunk_entries: Vec<_> = match fs::read_dir(chunk_path) {
-        Ok(dir) => dir.filter_map(Result::ok)
-            .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
-            .collect(),
-        Err(e) => {
-            return Err(anyhow!("[Error] Failed reading chunk directory: {}", e));
-        }
-    };
-
-    // Open database
-    let database = chunk_entries
-        .iter()
-        .find(|entry| !entry.file_type().unwrap().is_dir())
-        .map(|entry| {
-            // Open the leveldb database first
-            let leveldb_path = format!("{}_db", entry.file_name().to_string_lossy().into_owned());
-            match Database::open(&entry.path().parent().unwrap_or(Path::new("")).join(leveldb_path), &Options::new()) {
-                Ok(db) => Ok(db),
-                Err(e) => {
-                    return Err((entry.path().to_string_lossy().into_owned(), e));
-                }
-            }
-        });
-    let database = match database {
-        Some(Ok(database)) => database,
-        Some(Err((path, e))) => {
-            return Err(anyhow!("[Error] Failed opening database {}: {}", path, e));
-        }
-        None => {
-            return Err(anyhow!("[Error] No database found"));
-        }
-    };
-
     // Remove folders and create new ones
-    let temp_path = output_path.join("chunk_tmp");
+    let temp_path = work_path.join("chunk_tmp");
     tokio::fs::remove_dir_all(&temp_path).await.unwrap_or_default();
     tokio::fs::create_dir_all(&temp_path).await.unwrap_or_default();
 
-    let mut bars: Vec<&Option<ProgressBar>> = Vec::new();
+    // Staging and assembly wait on the disk and the rayon pool, so they run off the async runtime of the caller
+    let staging = {
+        let chunk_path = chunk_path.to_path_buf();
+        let temp_path = temp_path.clone();
+        let progress = Arc::clone(&progress);
+        tokio::task::spawn_blocking(move || stage(&chunk_path, &cache_list, &temp_path, &*progress))
+    };
+    staging.await??;
 
-    // Process each chunk file in parallel
-    chunk_entries.par_iter().for_each(|entry| {
-        // Process database entries and collect what we need to extract
-        let mut iter = database.iter(&ReadOptions::new());
-        let mut extracted_chunks = Vec::new();
+    let assets = manifest.assets.clone();
+    let assembly = {
+        let temp_path = temp_path.clone();
+        tokio::task::spawn_blocking(move || assemble(&assets, &temp_path, &*progress, &*target))
+    };
+    let result = assembly.await?;
 
-        while let Some((key, value)) = iter.next() {
-            let key = match String::from_utf8(key) {
-                Ok(k) => k,
-                Err(_) => continue,
-            };
+    // Delete chunk folder
+    if !keep_temp {
+        tokio::fs::remove_dir_all(temp_path).await.unwrap_or_default();
+    }
+    result
+}
 
-            let value = match String::from_utf8(value).ok().and_then(|v| v.parse::<u64>().ok()) {
-                Some(v) => v,
-                None => continue,
+/// Put every chunk of the manifest decompressed into the temp folder, from loose files or indexed archives
+fn stage(
+    chunk_path: &Path,
+    cache_list: &HashMap<String, i64>,
+    temp_path: &Path,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    create_parent_dirs(temp_path, cache_list.keys())?;
+    match ChunkLayout::detect(chunk_path, cache_list)? {
+        ChunkLayout::Loose => stage_loose(chunk_path, cache_list, temp_path, progress)?,
+        ChunkLayout::Indexed => {
+            let archives = match fs::read_dir(chunk_path) {
+                Ok(dir) => dir.filter_map(Result::ok)
+                    .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+                    .map(|e| e.path())
+                    .filter(|path| index_path(path).is_dir())
+                    .collect::<Vec<_>>(),
+                Err(e) => {
+                    return Err(anyhow!("[Error] Failed reading chunk directory: {}", e));
+                }
             };
+            stage_indexed(&archives, cache_list, temp_path, progress)?;
+        }
+        ChunkLayout::Archive => stage_indexed(&[chunk_path.to_path_buf()], cache_list, temp_path, progress)?,
+    }
+    progress.on_finish(Phase::Staging);
+    Ok(())
+}
 
-            // Check if file exists in cache list
-            if let Some(&size) = cache_list.get(&key) {
-                extracted_chunks.push((key, value, size));
-            }
+/// Combine the staged chunks into assets, their folders are made up front
+fn assemble(
+    assets: &[AssetProperty],
+    temp_path: &Path,
+    progress: &dyn ProgressSink,
+    target: &dyn OutputTarget,
+) -> Result<()> {
+    let names = assets.iter().map(|asset| asset.asset_name.as_str()).collect::<Vec<_>>();
+    target.prepare(&names)?;
+
+    // Upcoming chunks are read ahead while earlier assets are written
+    let order = assets.iter()
+        .flat_map(|asset| asset.asset_chunks.iter().map(|chunk| chunk.chunk_name.clone()))
+        .collect();
+    let prefetcher = Prefetcher::start(temp_path.to_path_buf(), order);
+
+    // Every asset is attempted, the first failure is returned once all are done
+    progress.on_stage_start(Phase::Assembly, assets.len() as u64);
+    let results = assets.par_iter()
+        .map(|asset| assemble_asset(asset, temp_path, &prefetcher, progress, target))
+        .collect::<Vec<_>>();
+    prefetcher.stop();
+    progress.on_finish(Phase::Assembly);
+
+    results.into_iter().collect::<Result<()>>()?;
+    target.finish()?;
+    Ok(())
+}
+
+fn assemble_asset(
+    asset: &AssetProperty,
+    temp_path: &Path,
+    prefetcher: &Prefetcher,
+    progress: &dyn ProgressSink,
+    target: &dyn OutputTarget,
+) -> Result<()> {
+    // Folders are listed without chunks or hash, files in place already or left alone need no chunk read
+    let folder = asset.asset_chunks.is_empty() && asset.asset_hash_md5.is_empty();
+    if folder || target.keeps(&asset.asset_name, asset.asset_size as u64, &asset.asset_hash_md5) {
+        for chunk in &asset.asset_chunks {
+            prefetcher.release(&chunk.chunk_name);
         }
+        progress.on_bytes(Phase::Assembly, asset.asset_size as u64);
+        progress.on_item_done(Phase::Assembly);
+        return Ok(());
+    }
 
-        let pb = if let Some(_) = progress_bar {
-            println!("Extracting chunk files");
-            let progress_bar = ProgressBar::new(extracted_chunks.len() as u64);
-            progress_bar.set_style(
-                ProgressStyle::default_bar()
-                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len}")
-                    .expect("Failed to set progress bar template")
-                    .progress_chars("#>-"),
-            );
-            Some(progress_bar)
-        } else {
-            None
+    // Every chunk goes straight to its offset in the asset, so no more than the chunks being copied is held
+    // in memory. Assets without chunks are created empty
+    let writer = target.create_asset(&asset.asset_name, asset.asset_size as u64)
+        .map_err(|e| anyhow!("[Error] Failed writing {}: {}", asset.asset_name, e))?;
+    let writer = Mutex::new(writer);
+    let missing = Mutex::new(Vec::new());
+    asset.asset_chunks.par_iter().try_for_each(|chunk| -> std::io::Result<()> {
+        // Read chunk data - from the read-ahead queue, or depending on file size when it was not read ahead
+        let buffer = match prefetcher.take(&chunk.chunk_name) {
+            Some(buffer) => buffer,
+            None => {
+                let path = temp_path.join(&chunk.chunk_name);
+                if !path.exists() {
+                    missing.lock().unwrap().push(chunk.chunk_name.as_str());
+                    return Ok(());
+                }
+                Arc::new(read_chunk_data(&path, chunk.chunk_name.as_str()))
+            }
         };
+        if buffer.is_empty() {
+            missing.lock().unwrap().push(chunk.chunk_name.as_str());
+            return Ok(());
+        }
+        writer.lock().unwrap().write_at(chunk.chunk_on_file_offset as u64, &buffer)
+    }).map_err(|e| anyhow!("[Error] Failed writing {}: {}", asset.asset_name, e))?;
 
-        // Now process all the chunks from this file
-        if !extracted_chunks.is_empty() {
-            let file = match File::open(entry.path()) {
-                Ok(file) => file,
-                #[allow(unused_variables)]
-                Err(e) => {
-                    #[cfg(debug_assertions)]
-                    eprintln!("Error opening file {}: {}", entry.path().display(), e);
-                    return;
-                }
-            };
+    // A chunk left out would stay a hole of zeros in the asset
+    if let Some(chunk_name) = missing.into_inner().unwrap().first() {
+        return Err(anyhow!("[Error] Chunk {} of {} is missing or unreadable", chunk_name, asset.asset_name));
+    }
+    writer.into_inner().unwrap().finish()
+        .map_err(|e| anyhow!("[Error] Failed writing {}: {}", asset.asset_name, e))?;
+    progress.on_bytes(Phase::Assembly, asset.asset_size as u64);
+    progress.on_item_done(Phase::Assembly);
+    Ok(())
+}
 
-            let file_size = match file.metadata() {
-                Ok(metadata) => metadata.len(),
-                Err(_) => return,
-            };
+/// A chunk file whose size does not fit the manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ChunkMismatch {
+    pub name: String,
+    /// Size the manifest lists, or the least the file has to hold when it lists none
+    pub listed: u64,
+    pub found: u64,
+}
 
-            // For large files, use memory mapping
-            if file_size > 10 * 1024 * 1024 {
-                match unsafe { MmapOptions::new().map(&file) } {
-                    Ok(mmap) => {
-                        for (key, offset, size) in extracted_chunks {
-                            if offset as usize + size as usize <= mmap.len() {
-                                let buffer = &mmap[offset as usize..(offset as usize + size as usize)];
-                                let asset_path = temp_path.join(&key);
-
-                                // Create parent directories if needed
-                                if let Some(parent) = asset_path.parent() {
-                                    if !parent.exists() {
-                                        #[allow(unused_variables)]
-                                        if let Err(e) = fs::create_dir_all(parent) {
-                                            #[cfg(debug_assertions)]
-                                            eprintln!(
-                                                "Error creating directory {}: {}",
-                                                parent.display(),
-                                                e,
-                                            );
-                                            continue;
-                                        }
-                                    }
-                                }
-
-                                #[allow(unused_variables)]
-                                if let Err(e) = fs::write(&asset_path, buffer) {
-                                    #[cfg(debug_assertions)]
-                                    eprintln!(
-                                        "Error writing chunk file {}: {}",
-                                        asset_path.display(),
-                                        e,
-                                    );
-                                }
-
-                                if let Some(pb) = &pb {
-                                    pb.inc(1u64);
-                                }
-                            }
-                        }
-                    },
-                    #[allow(unused_variables)]
-                    Err(e) => {
-                        #[cfg(debug_assertions)]
-                        eprintln!("Error memory-mapping file {}: {}", entry.path().display(), e);
-                        // Fall back to using BufReader for this file
-                        process_with_bufreader(&entry.path(), &extracted_chunks, &pb);
-                    }
+/// Loose chunk files that are neither the compressed nor the decompressed size the manifest lists, a sign the two
+/// come from different updates
+///
+/// Chunks without a listed compressed size and archives of the indexed layout are not checked
+pub fn mismatched_chunks(manifest: &SophonChunkProto, chunk_path: &Path) -> Vec<ChunkMismatch> {
+    let mut listed: HashMap<&str, (i64, i64)> = HashMap::new();
+    for chunk in manifest.assets.iter().flat_map(|asset| &asset.asset_chunks) {
+        listed.insert(&chunk.chunk_name, (chunk.chunk_size, chunk.chunk_size_decompressed));
+    }
+
+    let mut mismatches = listed.into_iter()
+        .filter(|&(_, (size, _))| size > 0)
+        .filter_map(|(name, (size, decompressed))| {
+            let found = fs::metadata(chunk_path.join(name)).ok()?.len();
+            let mismatched = found != size as u64 && found != decompressed as u64;
+            mismatched.then(|| ChunkMismatch { name: name.to_string(), listed: size as u64, found })
+        })
+        .collect::<Vec<_>>();
+    mismatches.sort_by(|a, b| a.name.cmp(&b.name));
+    mismatches
+}
+
+/// How the chunks of a chunk folder are stored
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChunkLayout {
+    /// One file per chunk named after it, zstd compressed or not
+    Loose,
+    /// Chunks concatenated into archive files, each with a `<name>_db` leveldb index next to it
+    Indexed,
+    /// A single chunk archive given by its own path, with its `<name>_db` index next to it
+    Archive,
+}
+
+impl ChunkLayout {
+    /// Tell the layout of a chunk folder from its contents, or whether a file is a chunk archive
+    pub fn detect(chunk_path: &Path, chunks: &HashMap<String, i64>) -> Result<ChunkLayout> {
+        if chunk_path.is_file() {
+            if index_path(chunk_path).is_dir() {
+                return Ok(ChunkLayout::Archive);
+            }
+            return Err(anyhow!("[Error] {} is no chunk archive, it has no _db index", chunk_path.display()));
+        }
+
+        let mut loose = false;
+        for entry in fs::read_dir(chunk_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file() {
+                if index_path(&entry.path()).is_dir() {
+                    return Ok(ChunkLayout::Indexed);
                 }
-            } else {
-                // For smaller files, use buffered reader
-                process_with_bufreader(&entry.path(), &extracted_chunks, &pb);
+                loose |= chunks.contains_key(&name);
             }
         }
-    });
 
-    if let Some(pb) = progress_bar.as_ref() {
-        bars.push(pb);
+        if loose {
+            Ok(ChunkLayout::Loose)
+        } else {
+            Err(anyhow!(
+                "[Error] {} holds neither chunk files named after the manifest chunks nor archives with a _db index",
+                chunk_path.display(),
+            ))
+        }
     }
+}
 
-    // Now combine the extracted chunks into assets
-    let mut all_tasks = Vec::new();
-
-    // Make new progress bar
-    let pb = Arc::new(Mutex::new({
-        if progress_bar.is_some() {
-            println!("Merging chunk files");
-            let pb = ProgressBar::new(manifest.assets.len() as u64);
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len}")
-                    .expect("Failed to set progress bar template")
-                    .progress_chars("#>-"),
-            );
-            Some(pb)
-        } else {
-            None
+/// Decompress loose chunk files into the temp folder, chunks already decompressed are copied as they are
+///
+/// Chunks compressed with a zstd dictionary are decompressed with the dictionary of the same ID found by
+/// `Dictionaries::load`
+fn stage_loose(
+    chunk_path: &Path,
+    chunks: &HashMap<String, i64>,
+    temp_path: &Path,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    let dictionaries = Dictionaries::load(chunk_path, chunks)?;
+    progress.on_stage_start(Phase::Staging, chunks.len() as u64);
+
+    chunks.par_iter().try_for_each(|(name, _)| -> Result<()> {
+        progress.on_item_done(Phase::Staging);
+        let path = chunk_path.join(name);
+        if !path.exists() {
+            return Ok(());
         }
-    }));
 
-    for asset in manifest.assets.clone() {
-        let temp_path = temp_path.clone();
-        let pb_clone = Arc::clone(&pb);
-        let task_handle = tokio::spawn(async move {
-            #[cfg(debug_assertions)]
-            println!("[Chunk] Combining asset: {}", asset.asset_name);
+        let _handles = reserve_handles(1);
+        let buffer = fs::read(&path).map_err(explain_handle_error)?;
+        let buffer = if buffer.starts_with(&ZSTD_MAGIC) {
+            dictionaries.decompress(name, &buffer)?
+        } else {
+            buffer
+        };
+        let size = buffer.len() as u64;
+        fs::write(temp_path.join(name), buffer).map_err(explain_handle_error)?;
+        progress.on_bytes(Phase::Staging, size);
+        Ok(())
+    })
+}
 
-            // Increase progress bar
-            let progress_bar = pb_clone.lock().unwrap();
-            if let Some(pb) = progress_bar.as_ref() {
-                pb.inc(1);
-            }
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
 
-            // Estimate buffer size for pre-allocation
-            let asset_chunks = asset.asset_chunks.clone();
-            let estimated_size = asset_chunks.iter()
-                .filter_map(|chunk| {
-                    let path = temp_path.join(&chunk.chunk_name);
-                    if path.exists() {
-                        match fs::metadata(path) {
-                            Ok(metadata) => Some(
-                                chunk.chunk_on_file_offset as usize + metadata.len() as usize
-                            ),
-                            Err(_) => None,
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .max()
-                .unwrap_or(0);
+/// Decompress the chunks of indexed archives into the temp folder, each archive read through its own index
+///
+/// Every index is read first so staging is started once with the chunks of all archives
+fn stage_indexed(
+    archives: &[PathBuf],
+    cache_list: &HashMap<String, i64>,
+    temp_path: &Path,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    let indexes = archives.par_iter()
+        .map(|archive| Ok((archive, read_index(archive, cache_list)?)))
+        .collect::<Result<Vec<_>>>()?;
+    progress.on_stage_start(Phase::Staging, indexes.iter().map(|(_, chunks)| chunks.len() as u64).sum());
 
-            // Create a shared buffer with pre-allocation
-            let buf = Arc::new(Mutex::new(Vec::with_capacity(estimated_size)));
+    indexes.par_iter().for_each(|(archive, extracted_chunks)| {
+        stage_archive(archive, extracted_chunks, temp_path, progress);
+    });
+    Ok(())
+}
 
-            // Process chunks in parallel with rayon
-            asset_chunks.par_iter().for_each(|chunk| {
-                let path = temp_path.join(&chunk.chunk_name);
-                if !path.exists() {
-                    return;
-                }
+/// The `<name>_db` leveldb index next to a chunk archive
+fn index_path(archive: &Path) -> PathBuf {
+    let mut name = archive.file_name().unwrap_or_default().to_os_string();
+    name.push("_db");
+    archive.with_file_name(name)
+}
 
-                // Read chunk data - handle different approaches based on file size
-                let buffer = read_chunk_data(&path, chunk.chunk_name.as_str());
-                if buffer.is_empty() {
-                    return;
-                }
+/// Name, offset and size of every chunk of the manifest the index of an archive lists
+pub(crate) fn read_index(archive: &Path, cache_list: &HashMap<String, i64>) -> Result<Vec<(String, u64, i64)>> {
+    let database = Database::open(&index_path(archive), &Options::new())
+        .map_err(|e| anyhow!("[Error] Failed opening database {}: {}", archive.display(), e))?;
 
-                // Lock the buffer and copy data
-                let mut buf_guard = match buf.lock() {
-                    Ok(guard) => guard,
-                    Err(_) => return,
-                };
+    let mut extracted_chunks = Vec::new();
+    for (key, value) in database.iter(&ReadOptions::new()) {
+        let key = match String::from_utf8(key) {
+            Ok(k) => k,
+            Err(_) => continue,
+        };
 
-                let offset = chunk.chunk_on_file_offset as usize;
-                if buf_guard.len() < offset + buffer.len() {
-                    buf_guard.resize(offset + buffer.len(), 0);
-                }
+        let value = match String::from_utf8(value).ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(v) => v,
+            None => continue,
+        };
 
-                buf_guard[offset..offset + buffer.len()].copy_from_slice(&buffer);
-            });
+        // Check if file exists in cache list
+        if let Some(&size) = cache_list.get(&key) {
+            extracted_chunks.push((key, value, size));
+        }
+    }
+    Ok(extracted_chunks)
+}
 
-            // Extract the buffer from Arc<Mutex<>>
-            let final_buf = match Arc::try_unwrap(buf) {
-                Ok(mutex) => mutex.into_inner().unwrap_or_default(),
-                Err(arc) => match arc.lock() {
-                    Ok(guard) => guard.clone(),
-                    Err(_) => Vec::new(),
-                },
-            };
+/// Write the given chunks of one archive into the temp folder
+fn stage_archive(
+    archive: &Path,
+    extracted_chunks: &[(String, u64, i64)],
+    temp_path: &Path,
+    progress: &dyn ProgressSink,
+) {
+    if extracted_chunks.is_empty() {
+        return;
+    }
 
-            // Write to file using buffered writer if buffer is not empty
-            if !final_buf.is_empty() {
-                let output_path = output_path.join(&asset.asset_name);
+    // The archive, a second reader when falling back to buffered reads, and the chunk being written
+    let _handles = reserve_handles(3);
+    let file = match File::open(archive) {
+        Ok(file) => file,
+        #[allow(unused_variables)]
+        Err(e) => {
+            #[cfg(debug_assertions)]
+            report_message(format!("Error opening file {}: {}", archive.display(), e));
+            return;
+        }
+    };
 
-                // Create parent directories if needed
-                if let Some(parent) = Path::new(&output_path).parent() {
-                    if !parent.exists() {
-                        #[allow(unused_variables)]
-                        if let Err(e) = fs::create_dir_all(parent) {
-                            #[cfg(debug_assertions)]
-                            eprintln!(
-                                "Error creating directory for {}: {}",
-                                output_path.display(),
-                                e,
-                            );
-                            return;
-                        }
-                    }
-                }
+    let file_size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return,
+    };
+
+    // For large files, use memory mapping
+    if file_size > 10 * 1024 * 1024 {
+        match unsafe { MmapOptions::new().map(&file) } {
+            Ok(mmap) => {
+                for (key, offset, size) in extracted_chunks {
+                    let (offset, size) = (*offset as usize, *size as usize);
+                    if offset + size <= mmap.len() {
+                        let buffer = &mmap[offset..(offset + size)];
+                        let asset_path = temp_path.join(key);
 
-                match File::create(&output_path) {
-                    Ok(file) => {
-                        let mut writer = BufWriter::with_capacity(
-                            256 * 1024, file
-                        );
-                        #[allow(unused_variables)]
-                        if let Err(e) = writer.write_all(&final_buf) {
-                            #[cfg(debug_assertions)]
-                            eprintln!("Error writing to {}: {}", output_path.display(), e);
-                            return;
-                        }
                         #[allow(unused_variables)]
-                        if let Err(e) = writer.flush() {
+                        if let Err(e) = fs::write(&asset_path, buffer) {
                             #[cfg(debug_assertions)]
-                            eprintln!("Error flushing buffer for {}: {}", output_path.display(), e);
+                            report_message(format!("Error writing chunk file {}: {}", asset_path.display(), e));
                         }
-                    },
-                    #[allow(unused_variables)]
-                    Err(e) => {
-                        #[cfg(debug_assertions)]
-                        eprintln!("Error creating file {}: {}", output_path.display(), e);
+
+                        progress.on_bytes(Phase::Staging, size as u64);
+                        progress.on_item_done(Phase::Staging);
                     }
                 }
+            },
+            #[allow(unused_variables)]
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                report_message(format!("Error memory-mapping file {}: {}", archive.display(), e));
+                // Fall back to using BufReader for this file
+                process_with_bufreader(archive, extracted_chunks, temp_path, progress);
             }
-        });
-
-        all_tasks.push(task_handle);
+        }
+    } else {
+        // For smaller files, use buffered reader
+        process_with_bufreader(archive, extracted_chunks, temp_path, progress);
     }
-
-    // Wait for all tasks to complete
-    let _ = join_all(all_tasks).await;
-    bars.push(&pb.lock().unwrap().clone());
-
-    // Delete chunk folder
-    tokio::fs::remove_dir_all(temp_path).await.unwrap_or_default();
-
-    Ok(())
 }
 
-/// Helper function for processing with BufReader
+/// Helper function for processing with BufReader, chunks are written into `temp_path`
+///
+/// The folders of every chunk are made up front by `create_parent_dirs`, as for memory mapped archives
 fn process_with_bufreader(
     path: &Path,
     chunks: &[(String, u64, i64)],
-    progress_bar: &Option<ProgressBar>,
+    temp_path: &Path,
+    progress: &dyn ProgressSink,
 ) {
     let file = match File::open(path) {
         Ok(file) => file,
         #[allow(unused_variables)]
         Err(e) => {
             #[cfg(debug_assertions)]
-            eprintln!("Error opening file {}: {}", path.display(), e);
+            report_message(format!("Error opening file {}: {}", path.display(), e));
             return;
         }
     };
@@ -360,7 +449,7 @@ fn process_with_bufreader(
         #[allow(unused_variables)]
         if let Err(e) = reader.seek(SeekFrom::Start(*offset)) {
             #[cfg(debug_assertions)]
-            eprintln!("Error seeking to offset {} in file {}: {}", offset, path.display(), e);
+            report_message(format!("Error seeking to offset {} in file {}: {}", offset, path.display(), e));
             continue;
         }
 
@@ -368,34 +457,19 @@ fn process_with_bufreader(
         #[allow(unused_variables)]
         if let Err(e) = reader.read_exact(&mut buffer) {
             #[cfg(debug_assertions)]
-            eprintln!("Error reading data for chunk {}: {}", key, e);
+            report_message(format!("Error reading data for chunk {}: {}", key, e));
             continue;
         }
 
-        let asset_path = Path::new("chunk_tmp").join(key);
-
-        // Create parent directories
-        if let Some(parent) = asset_path.parent() {
-            if !parent.exists() {
-                #[allow(unused_variables)]
-                #[allow(unused_variables)]
-                if let Err(e) = fs::create_dir_all(parent) {
-                    #[cfg(debug_assertions)]
-                    eprintln!("Error creating directory {}: {}", parent.display(), e);
-                    continue;
-                }
-            }
-        }
-
+        let asset_path = temp_path.join(key);
         #[allow(unused_variables)]
         if let Err(e) = fs::write(&asset_path, &buffer) {
             #[cfg(debug_assertions)]
-            eprintln!("Error writing chunk file {}: {}", asset_path.display(), e);
+            report_message(format!("Error writing chunk file {}: {}", asset_path.display(), e));
         }
 
-        if let Some(pb) = &progress_bar {
-            pb.inc(1u64);
-        }
+        progress.on_bytes(Phase::Staging, *size as u64);
+        progress.on_item_done(Phase::Staging);
     }
 }
 
@@ -403,11 +477,12 @@ fn process_with_bufreader(
 #[allow(unused_variables)]
 fn read_chunk_data(path: &Path, chunk_name: &str) -> Vec<u8> {
     // Error handling for file operations
+    let _handles = reserve_handles(1);
     let file = match File::open(path) {
         Ok(file) => file,
         Err(e) => {
             #[cfg(debug_assertions)]
-            eprintln!("Error opening chunk {}: {}", chunk_name, e);
+            report_message(format!("Error opening chunk {}: {}", chunk_name, e));
             return Vec::new();
         }
     };
@@ -429,7 +504,7 @@ fn read_chunk_data(path: &Path, chunk_name: &str) -> Vec<u8> {
             #[allow(unused_variables)]
             Err(e) => {
                 #[cfg(debug_assertions)]
-                eprintln!("Error memory-mapping chunk {}: {}", chunk_name, e);
+                report_message(format!("Error memory-mapping chunk {}: {}", chunk_name, e));
                 // Fall back to buffered reading
                 read_with_bufreader(file, chunk_size)
             }
@@ -448,3 +523,79 @@ fn read_with_bufreader(file: File, capacity: usize) -> Vec<u8> {
         Err(_) => Vec::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::chunk::AssetChunk;
+    use crate::sophon::NoProgress;
+
+    fn asset(name: &str, chunks: &[(&str, i64, &[u8])]) -> AssetProperty {
+        let data = chunks.iter().flat_map(|(_, _, data)| data.iter().copied()).collect::<Vec<_>>();
+        AssetProperty {
+            asset_name: name.to_string(),
+            asset_size: data.len() as i64,
+            asset_hash_md5: format!("{:x}", md5::compute(&data)),
+            asset_chunks: chunks.iter()
+                .map(|&(chunk_name, offset, data)| AssetChunk {
+                    chunk_name: chunk_name.to_string(),
+                    chunk_on_file_offset: offset,
+                    chunk_size_decompressed: data.len() as i64,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // A fresh folder for one test, tests run in parallel
+    fn folder(name: &str) -> std::path::PathBuf {
+        let folder = std::env::temp_dir().join(format!("sophon_chunk_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(folder.join("tmp")).unwrap();
+        fs::create_dir_all(folder.join("out")).unwrap();
+        folder
+    }
+
+    #[test]
+    fn empty_assets_created_and_folders_left_out() {
+        let folder = folder("empty");
+        fs::write(folder.join("tmp/a"), b"hello").unwrap();
+        let folder_entry = AssetProperty { asset_name: "sub/folder".to_string(), ..Default::default() };
+        let assets = [asset("one.txt", &[("a", 0, b"hello")]), asset("sub/empty.txt", &[]), folder_entry];
+        let target = DirectoryTarget::new(&folder.join("out"));
+        assemble(&assets, &folder.join("tmp"), &NoProgress, &target).unwrap();
+        assert_eq!(fs::read(folder.join("out/one.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(folder.join("out/sub/empty.txt")).unwrap(), b"");
+        assert!(!folder.join("out/sub/folder").exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn missing_chunks_fail_the_asset() {
+        let folder = folder("missing");
+        fs::write(folder.join("tmp/a"), b"hello").unwrap();
+        let target = DirectoryTarget::new(&folder.join("out"));
+        let partly = [asset("one.txt", &[("a", 0, b"hello"), ("b", 5, b" world")])];
+        let error = assemble(&partly, &folder.join("tmp"), &NoProgress, &target).unwrap_err();
+        assert!(error.to_string().contains("Chunk b of one.txt"), "{}", error);
+        let wholly = [asset("two.txt", &[("c", 0, b"gone")])];
+        assert!(assemble(&wholly, &folder.join("tmp"), &NoProgress, &target).is_err());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn layouts_detected_from_folders_and_files() {
+        let folder = folder("layouts");
+        let chunks = HashMap::from([("a".to_string(), 5)]);
+        fs::write(folder.join("tmp/a"), b"hello").unwrap();
+        assert_eq!(ChunkLayout::detect(&folder.join("tmp"), &chunks).unwrap(), ChunkLayout::Loose);
+
+        fs::write(folder.join("out/archive"), b"hello").unwrap();
+        assert!(ChunkLayout::detect(&folder.join("out/archive"), &chunks).is_err());
+        fs::create_dir(folder.join("out/archive_db")).unwrap();
+        assert_eq!(ChunkLayout::detect(&folder.join("out"), &chunks).unwrap(), ChunkLayout::Indexed);
+        assert_eq!(ChunkLayout::detect(&folder.join("out/archive"), &chunks).unwrap(), ChunkLayout::Archive);
+        assert_eq!(index_path(&folder.join("out/archive")), folder.join("out/archive_db"));
+    }
+}
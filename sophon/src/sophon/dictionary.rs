@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use anyhow::{anyhow, Result};
+use zstd::dict::DecoderDictionary;
+use zstd::zstd_safe;
+
+// Every zstd dictionary starts with this, raw content dictionaries carry no ID and cannot be told apart
+const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+// Folder dictionaries are looked up in on top of the chunk folder
+static DICTIONARY_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Look up zstd dictionaries in this folder as well as in the chunk folder
+pub fn set_dictionary_path(path: PathBuf) {
+    let _ = DICTIONARY_PATH.set(path);
+}
+
+/// Zstd dictionaries chunks are compressed with, by the ID both the dictionary and every frame using it carry
+pub(crate) struct Dictionaries {
+    dictionaries: HashMap<u32, DecoderDictionary<'static>>,
+    searched: Vec<PathBuf>,
+}
+
+impl Dictionaries {
+    /// Load every dictionary of the chunk folder and of the folder set with `set_dictionary_path`
+    ///
+    /// Files named after a chunk are never read, so large chunk folders are not opened file by file
+    pub fn load(chunk_path: &Path, chunks: &HashMap<String, i64>) -> Result<Dictionaries> {
+        let mut dictionaries = HashMap::new();
+        let mut searched = Vec::new();
+        for folder in std::iter::once(chunk_path).chain(DICTIONARY_PATH.get().map(PathBuf::as_path)) {
+            if !folder.is_dir() {
+                return Err(anyhow!("[Error] Dictionary folder {} does not exist", folder.display()));
+            }
+            searched.push(folder.to_path_buf());
+            for entry in fs::read_dir(folder)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() || chunks.contains_key(&*entry.file_name().to_string_lossy()) {
+                    continue;
+                }
+
+                let mut magic = [0u8; 4];
+                if File::open(entry.path()).and_then(|mut file| file.read_exact(&mut magic)).is_err()
+                    || magic != DICTIONARY_MAGIC
+                {
+                    continue;
+                }
+                let dictionary = fs::read(entry.path())?;
+                if let Some(id) = zstd_safe::get_dict_id_from_dict(&dictionary) {
+                    dictionaries.insert(id.get(), DecoderDictionary::copy(&dictionary));
+                }
+            }
+        }
+        Ok(Dictionaries { dictionaries, searched })
+    }
+
+    /// Decompress a zstd compressed chunk, with the dictionary its frame header names if any
+    pub fn decompress(&self, name: &str, buffer: &[u8]) -> Result<Vec<u8>> {
+        let Some(id) = zstd_safe::get_dict_id_from_frame(buffer) else {
+            return zstd::decode_all(buffer).map_err(|e| anyhow!("[Error] Failed decompressing chunk {}: {}", name, e));
+        };
+        let dictionary = self.dictionaries.get(&id.get()).ok_or_else(|| anyhow!(
+            "[Error] Chunk {} needs zstd dictionary {}, which is in none of {}",
+            name,
+            id,
+            self.searched.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "),
+        ))?;
+
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::with_prepared_dictionary(buffer, dictionary)
+            .and_then(|mut decoder| decoder.read_to_end(&mut decompressed))
+            .map_err(|e| anyhow!("[Error] Failed decompressing chunk {} with dictionary {}: {}", name, id, e))?;
+        Ok(decompressed)
+    }
+}
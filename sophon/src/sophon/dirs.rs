@@ -0,0 +1,29 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Create the parent folders of every root relative file path in one pass
+///
+/// Folders are created sorted and deduplicated, parents of a listed folder are left to `create_dir_all` of the
+/// deepest one. Workers writing the files afterwards never create folders themselves, so they cannot race each other
+pub fn create_parent_dirs<I, S>(root: &Path, paths: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let dirs = paths.into_iter()
+        .filter_map(|path| Path::new(&path.as_ref().replace('\\', "/")).parent().map(Path::to_path_buf))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect::<BTreeSet<PathBuf>>();
+
+    // Folders sort right before their subfolders, so only the last of a run needs creating
+    let dirs = dirs.iter().collect::<Vec<_>>();
+    for (index, dir) in dirs.iter().enumerate() {
+        if dirs.get(index + 1).is_some_and(|next| next.starts_with(dir)) {
+            continue;
+        }
+        fs::create_dir_all(root.join(dir))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::sync::{Condvar, Mutex, OnceLock};
+
+// Files workers may hold open at once, unlimited until a budget is set
+static BUDGET: OnceLock<HandleBudget> = OnceLock::new();
+
+struct HandleBudget {
+    limit: usize,
+    open: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// Limit how many files workers hold open at once, has to be set before any work starts
+pub fn set_handle_budget(limit: usize) {
+    let _ = BUDGET.set(HandleBudget {
+        limit: limit.max(1),
+        open: Mutex::new(0),
+        freed: Condvar::new(),
+    });
+}
+
+/// Handles taken from the budget, given back when dropped
+pub struct HandleGuard(usize);
+
+/// Wait until `count` more files may be opened and take them from the budget
+pub fn reserve_handles(count: usize) -> HandleGuard {
+    let Some(budget) = BUDGET.get() else {
+        return HandleGuard(0);
+    };
+
+    // A single worker never waits for more than the whole budget
+    let count = count.min(budget.limit);
+    let mut open = budget.open.lock().unwrap();
+    while *open + count > budget.limit {
+        open = budget.freed.wait(open).unwrap();
+    }
+    *open += count;
+    HandleGuard(count)
+}
+
+impl Drop for HandleGuard {
+    fn drop(&mut self) {
+        if let Some(budget) = BUDGET.get().filter(|_| self.0 > 0) {
+            *budget.open.lock().unwrap() -= self.0;
+            budget.freed.notify_all();
+        }
+    }
+}
+
+/// Whether an error comes from the process or system running out of file handles
+pub fn is_handle_exhaustion(err: &io::Error) -> bool {
+    // EMFILE and ENFILE on unix, ERROR_TOO_MANY_OPEN_FILES on Windows
+    #[cfg(unix)]
+    let codes = [24, 23];
+    #[cfg(not(unix))]
+    let codes = [4];
+    err.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// Turn running out of file handles into an error saying so, other errors are kept as they are
+pub fn explain_handle_error(err: io::Error) -> io::Error {
+    if !is_handle_exhaustion(&err) {
+        return err;
+    }
+    let limit = BUDGET.get().map_or("no".to_string(), |budget| budget.limit.to_string());
+    io::Error::other(format!(
+        "Ran out of file handles with a budget of {} open files ({}), lower --io-threads or raise the open file limit",
+        limit,
+        err,
+    ))
+}
@@ -1,9 +1,104 @@
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::Arc;
 use anyhow::Result;
 use memmap2::MmapOptions;
-use crate::proto::sophon::Asset;
+use crate::proto::sophon::{Asset, SophonManifestProto};
+use crate::sophon::{
+    create_parent_dirs, explain_handle_error, report_message, reserve_handles, ChunkMismatch, Phase, ProgressSink,
+};
+
+/// What an ldiff payload is, decided from the manifest fields of its asset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LdiffKind {
+    /// Diff against the file at `original_file_path`
+    Patch,
+    /// Diff against an empty file, creating a new file
+    PatchFromEmpty,
+    /// The payload is the new file itself
+    NewFile,
+}
+
+impl LdiffKind {
+    pub fn of(data: &Asset, asset_size: i64) -> LdiffKind {
+        if !data.original_file_path.is_empty() {
+            // Even zero-byte sources are patched, their payload is still a diff
+            LdiffKind::Patch
+        } else if data.hdiff_file_size == asset_size {
+            LdiffKind::NewFile
+        } else {
+            LdiffKind::PatchFromEmpty
+        }
+    }
+
+    /// Whether the payload has to go through hpatchz
+    pub fn is_patch(&self) -> bool {
+        *self != LdiffKind::NewFile
+    }
+}
+
+/// An asset payload stored in an ldiff chunk file
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LdiffEntry {
+    pub asset_name: String,
+    pub asset_size: i64,
+    pub asset_hash_md5: String,
+    pub data: Asset,
+    pub kind: LdiffKind,
+}
+
+/// List every asset payload of the manifest stored in one of the given chunk files
+pub fn ldiff_entries(manifest: &SophonManifestProto, chunk_names: &[String]) -> Vec<LdiffEntry> {
+    manifest.assets
+        .iter()
+        .filter_map(|asset_group| {
+            asset_group.asset_data.as_ref().map(|chunk| (asset_group, chunk))
+        })
+        .flat_map(|(asset_group, chunk)| {
+            chunk.assets
+                .iter()
+                .filter(|data| chunk_names.iter().any(|name| name == &data.chunk_file_name))
+                .map(|data| LdiffEntry {
+                    asset_name: asset_group.asset_name.clone(),
+                    asset_size: asset_group.asset_size,
+                    asset_hash_md5: asset_group.asset_hash_md5.clone(),
+                    data: data.clone(),
+                    kind: LdiffKind::of(data, asset_group.asset_size),
+                })
+        })
+        .collect()
+}
+
+/// Chunk files of an ldiff folder that do not fit what the manifest lists, a sign the two come from different updates
+///
+/// A chunk file mismatches when its size differs from the listed one or a payload reaches past its end
+pub fn mismatched_ldiff_chunks(manifest: &SophonManifestProto, ldiffs_dir: &Path) -> Vec<ChunkMismatch> {
+    // Size listed for every chunk file, or failing that how far its payloads reach
+    let mut listed: HashMap<&str, (u64, bool)> = HashMap::new();
+    for data in manifest.assets.iter().filter_map(|asset| asset.asset_data.as_ref()).flat_map(|chunk| &chunk.assets) {
+        let (size, exact) = listed.entry(&data.chunk_file_name).or_default();
+        if data.chunk_file_size > 0 {
+            *size = data.chunk_file_size as u64;
+            *exact = true;
+        } else if !*exact {
+            *size = (*size).max((data.hdiff_file_in_chunk_offset + data.hdiff_file_size) as u64);
+        }
+    }
+
+    let mut mismatches = listed.into_iter()
+        .filter_map(|(name, (listed, exact))| {
+            let found = fs::metadata(ldiffs_dir.join(name)).ok()?.len();
+            let mismatched = if exact { found != listed } else { found < listed };
+            mismatched.then(|| ChunkMismatch { name: name.to_string(), listed, found })
+        })
+        .collect::<Vec<_>>();
+    mismatches.sort_by(|a, b| a.name.cmp(&b.name));
+    mismatches
+}
 
 /// Function to process a single asset data
 pub async fn ldiff_file(
@@ -12,20 +107,65 @@ pub async fn ldiff_file(
     asset_size: i64,
     ldiffs_dir: &Path,
     output_dir: &Path,
+    progress: Arc<dyn ProgressSink>,
 ) -> Result<()> {
+    let entry = LdiffEntry {
+        asset_name: asset_name.to_string(),
+        asset_size,
+        asset_hash_md5: String::new(),
+        data: data.clone(),
+        kind: LdiffKind::of(data, asset_size),
+    };
+    create_parent_dirs(output_dir, [asset_name])?;
+    ldiff_files(&[entry], ldiffs_dir, output_dir, progress).await
+}
+
+/// Extract the payloads of entries sharing one chunk file, opening and mapping it only once
+///
+/// Every payload is written to `output_dir` under its asset name, diff or not, applying it is up to the caller.
+/// Folders are not created here, see `create_parent_dirs`. The chunk file counts as one item of the staging stage
+pub async fn ldiff_files(
+    entries: &[LdiffEntry],
+    ldiffs_dir: &Path,
+    output_dir: &Path,
+    progress: Arc<dyn ProgressSink>,
+) -> Result<()> {
+    // Reading and writing payloads blocks, so it runs off the async runtime of the caller
+    let entries = entries.to_vec();
+    let ldiffs_dir = ldiffs_dir.to_path_buf();
+    let output_dir = output_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || extract_payloads(&entries, &ldiffs_dir, &output_dir, &*progress)).await?
+}
+
+fn extract_payloads(
+    entries: &[LdiffEntry],
+    ldiffs_dir: &Path,
+    output_dir: &Path,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    let Some(first) = entries.first() else {
+        return Ok(());
+    };
+    let chunk_file_name = &first.data.chunk_file_name;
+    if entries.iter().any(|entry| &entry.data.chunk_file_name != chunk_file_name) {
+        return Err(anyhow::anyhow!("Entries passed to ldiff_files span several chunk files"));
+    }
+
     // Check if ldiff file exists
-    let path = ldiffs_dir.join(&data.chunk_file_name);
+    let path = ldiffs_dir.join(chunk_file_name);
     if !path.exists() {
-        return Err(anyhow::anyhow!("{} does not exist", data.chunk_file_name));
+        return Err(anyhow::anyhow!("{} does not exist", chunk_file_name));
     }
 
-    // Open the file with error handling
+    // Open the file with error handling, payloads are written one at a time next to it
+    let _handles = reserve_handles(2);
     let file = match File::open(path.clone()) {
         Ok(file) => file,
         #[allow(unused_variables)]
         Err(e) => {
             #[cfg(debug_assertions)]
-            eprintln!("Error opening file {}: {}", path.display(), e);
+            report_message(format!("Error opening file {}: {}", path.display(), e));
+            let e = explain_handle_error(e);
             return Err(anyhow::anyhow!("Error opening file {}: {}", path.display(), e));
         }
     };
@@ -35,86 +175,75 @@ pub async fn ldiff_file(
         #[allow(unused_variables)]
         Err(e) => {
             #[cfg(debug_assertions)]
-            eprintln!("Error getting file size for {}: {}", path.display(), e);
+            report_message(format!("Error getting file size for {}: {}", path.display(), e));
             return Err(anyhow::anyhow!("Error getting file size for {}: {}", path.display(), e));
         }
     };
 
-    let buffer = if file_size > 10 * 1024 * 1024 && data.hdiff_file_size > 1 * 1024 * 1024 {
-        // For large files, use memory mapping
+    // For large files, map them once for every payload
+    let mmap = if file_size > 10 * 1024 * 1024 {
         match unsafe { MmapOptions::new().map(&file) } {
-            Ok(mmap) => {
-                let start = data.hdiff_file_in_chunk_offset as usize;
-                let end = start + data.hdiff_file_size as usize;
-
-                if end <= mmap.len() {
-                    // Create a new buffer with the slice from mmap
-                    let mut buffer = Vec::with_capacity(data.hdiff_file_size as usize);
-                    buffer.extend_from_slice(&mmap[start..end]);
-                    Some(buffer)
-                } else {
-                    #[cfg(debug_assertions)]
-                    eprintln!("Error: Requested range exceeds file size for {}", path.display());
-                    None
-                }
-            },
+            Ok(mmap) => Some(mmap),
             #[allow(unused_variables)]
             Err(e) => {
-                eprintln!("Error memory-mapping file {}: {}", path.display(), e);
+                report_message(format!("Error memory-mapping file {}: {}", path.display(), e));
                 // Fall back to buffered reading
-                read_buffer_with_bufreader(
-                    &file,
-                    data.hdiff_file_in_chunk_offset as i32,
-                    data.hdiff_file_size as i32
-                )
+                None
             }
         }
-    } else {
-        // For smaller files, use buffered reader
-        read_buffer_with_bufreader(
-            &file,
-            data.hdiff_file_in_chunk_offset as i32,
-            data.hdiff_file_size as i32
-        )
-    };
+    } else { None };
 
-    // If buffer is None, return early
-    let buffer = match buffer {
-        Some(buf) => buf,
-        None => return Err(anyhow::anyhow!("Error processing file {}", path.display())),
-    };
+    for entry in entries {
+        let data = &entry.data;
+        let start = data.hdiff_file_in_chunk_offset as usize;
+        let end = start + data.hdiff_file_size as usize;
 
-    // Write assembled asset with proper error handling
-    let extension = if data.original_file_size != 0 || asset_size != data.hdiff_file_size {
-        ".hdiff"
-    } else { "" };
-    let asset_path = output_dir.join(format!("{}{}", asset_name, extension));
-
-    // Create parent directories if needed
-    if let Some(parent) = asset_path.parent() {
-        if !parent.exists() {
-            #[allow(unused_variables)]
-            if let Err(e) = fs::create_dir_all(parent) {
+        let buffer = match &mmap {
+            Some(mmap) if end <= mmap.len() => Some(mmap[start..end].to_vec()),
+            Some(_) => {
                 #[cfg(debug_assertions)]
-                eprintln!("Error creating directory {}: {}", parent.display(), e);
-                return Err(anyhow::anyhow!("Error creating directory {}: {}", parent.display(), e));
+                report_message(format!("Error: Requested range exceeds file size for {}", path.display()));
+                None
             }
-        }
+            // For smaller files, use buffered reader
+            None => read_buffer_with_bufreader(
+                &file,
+                data.hdiff_file_in_chunk_offset as i32,
+                data.hdiff_file_size as i32
+            ),
+        };
+
+        // If buffer is None, return early
+        let buffer = match buffer {
+            Some(buf) => buf,
+            None => return Err(anyhow::anyhow!("Error processing file {}", path.display())),
+        };
+
+        write_payload(entry, &buffer, output_dir)?;
+        progress.on_bytes(Phase::Staging, buffer.len() as u64);
     }
+    progress.on_item_done(Phase::Staging);
+
+    Ok(())
+}
+
+fn write_payload(entry: &LdiffEntry, buffer: &[u8], output_dir: &Path) -> Result<()> {
+    // Write assembled asset with proper error handling
+    let asset_path = output_dir.join(&entry.asset_name);
 
     // Write the file
-    match fs::write(&asset_path, &buffer) {
+    match fs::write(&asset_path, buffer) {
         Ok(_) => Ok(()),
         #[allow(unused_variables)]
         Err(e) => {
             #[cfg(debug_assertions)]
-            eprintln!("Error writing file {}: {}", asset_path.display(), e);
+            report_message(format!("Error writing file {}: {}", asset_path.display(), e));
+            let e = explain_handle_error(e);
             Err(anyhow::anyhow!("Error writing file {}: {}", asset_path.display(), e))
         }
     }
 }
 
-/// Helper function to read a specific section of a file using BufReader
 fn read_buffer_with_bufreader(file: &File, offset: i32, size: i32) -> Option<Vec<u8>> {
     let mut reader = BufReader::with_capacity(128 * 1024, file);
 
@@ -122,7 +251,7 @@ fn read_buffer_with_bufreader(file: &File, offset: i32, size: i32) -> Option<Vec
     #[allow(unused_variables)]
     if let Err(e) = reader.seek(SeekFrom::Start(offset as u64)) {
         #[cfg(debug_assertions)]
-        eprintln!("Error seeking to offset {}: {}", offset, e);
+        report_message(format!("Error seeking to offset {}: {}", offset, e));
         return None;
     }
 
@@ -133,8 +262,218 @@ fn read_buffer_with_bufreader(file: &File, offset: i32, size: i32) -> Option<Vec
         #[allow(unused_variables)]
         Err(e) => {
             #[cfg(debug_assertions)]
-            eprintln!("Error reading data: {}", e);
+            report_message(format!("Error reading data: {}", e));
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use serde::{Deserialize, Serialize};
+    use crate::proto::sophon::{AssetChunk, AssetProperty};
+
+    // Manifest fixture of one game with the kind every payload is expected to get
+    //
+    // A fixture trimmed from a released manifest names the trimmed manifest file next to it, its payloads then only
+    // need the chunk and the kind
+    #[derive(Deserialize)]
+    struct Fixture {
+        #[serde(default)]
+        manifest: Option<String>,
+        assets: Vec<FixtureAsset>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct FixtureAsset {
+        name: String,
+        #[serde(default)]
+        size: i64,
+        payloads: Vec<FixturePayload>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct FixturePayload {
+        chunk: String,
+        #[serde(default)]
+        source: String,
+        #[serde(default)]
+        source_size: i64,
+        #[serde(default)]
+        size: i64,
+        kind: String,
+    }
+
+    fn fixture_path(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ldiff").join(name)
+    }
+
+    // List the fixture chunk by chunk and compare every entry with the kind it expects
+    fn check_fixture(json: &str) {
+        let fixture = serde_json::from_str::<Fixture>(json).unwrap();
+        let manifest = match &fixture.manifest {
+            Some(name) => SophonManifestProto::from(fixture_path(name).to_string_lossy().to_string()).unwrap(),
+            None => SophonManifestProto {
+                assets: fixture.assets.iter()
+                    .map(|fixture_asset| AssetProperty {
+                        asset_name: fixture_asset.name.clone(),
+                        asset_size: fixture_asset.size,
+                        asset_hash_md5: String::new(),
+                        asset_data: Some(AssetChunk {
+                            latest_asset_version: String::new(),
+                            assets: fixture_asset.payloads.iter()
+                                .map(|payload| {
+                                    asset(&payload.chunk, &payload.source, payload.source_size, payload.size)
+                                })
+                                .collect(),
+                        }),
+                    })
+                    .collect(),
+            },
+        };
+
+        let mut chunks = fixture.assets.iter()
+            .flat_map(|fixture_asset| fixture_asset.payloads.iter().map(|payload| payload.chunk.clone()))
+            .collect::<Vec<_>>();
+        chunks.sort();
+        chunks.dedup();
+        for chunk in chunks {
+            let expected = fixture.assets.iter()
+                .flat_map(|fixture_asset| fixture_asset.payloads.iter()
+                    .filter(|payload| payload.chunk == chunk)
+                    .map(|payload| (fixture_asset.name.clone(), payload.kind.clone())))
+                .collect::<Vec<_>>();
+            let found = ldiff_entries(&manifest, std::slice::from_ref(&chunk))
+                .into_iter()
+                .map(|entry| (entry.asset_name, format!("{:?}", entry.kind)))
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected, "chunk {}", chunk);
+        }
+    }
+
+    // Trim a released manifest to the given assets and write it with a fixture listing the kinds they get now:
+    // `LDIFF_MANIFEST=<manifest> LDIFF_ASSETS=<name>,<name> LDIFF_FIXTURE=<game> cargo test -- --ignored trim`
+    //
+    // The kinds written are what the classification says today, check each against the release before committing
+    #[test]
+    #[ignore]
+    fn trim_released_manifest() {
+        let source = std::env::var("LDIFF_MANIFEST").unwrap();
+        let names = std::env::var("LDIFF_ASSETS").unwrap();
+        let game = std::env::var("LDIFF_FIXTURE").unwrap();
+        let names = names.split(',').collect::<Vec<_>>();
+
+        let mut manifest = SophonManifestProto::from(source.clone()).unwrap();
+        manifest.assets.retain(|asset| names.contains(&asset.asset_name.as_str()));
+        assert_eq!(manifest.assets.len(), names.len(), "some assets are not in {}", source);
+        let encoded = zstd::encode_all(&*manifest.encode_to_vec(), 19).unwrap();
+        std::fs::write(fixture_path(&format!("{}.manifest", game)), encoded).unwrap();
+
+        let mut chunks = manifest.assets.iter()
+            .flat_map(|asset| asset.asset_data.iter().flat_map(|data| &data.assets))
+            .map(|asset| asset.chunk_file_name.clone())
+            .collect::<Vec<_>>();
+        chunks.sort();
+        chunks.dedup();
+        let entries = ldiff_entries(&manifest, &chunks);
+        let assets = manifest.assets.iter()
+            .map(|asset| FixtureAsset {
+                name: asset.asset_name.clone(),
+                size: asset.asset_size,
+                payloads: entries.iter()
+                    .filter(|entry| entry.asset_name == asset.asset_name)
+                    .map(|entry| FixturePayload {
+                        chunk: entry.data.chunk_file_name.clone(),
+                        source: entry.data.original_file_path.clone(),
+                        source_size: entry.data.original_file_size,
+                        size: entry.data.hdiff_file_size,
+                        kind: format!("{:?}", entry.kind),
+                    })
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        let fixture = serde_json::json!({
+            "note": format!("Trimmed from {}", std::path::Path::new(&source).file_name().unwrap().to_string_lossy()),
+            "manifest": format!("{}.manifest", game),
+            "assets": assets,
+        });
+        let json = serde_json::to_string_pretty(&fixture).unwrap();
+        std::fs::write(fixture_path(&format!("{}.json", game)), json).unwrap();
+    }
+
+    #[test]
+    fn hsr_manifest() {
+        check_fixture(include_str!("../../tests/fixtures/ldiff/hsr.json"));
+    }
+
+    #[test]
+    fn genshin_manifest() {
+        check_fixture(include_str!("../../tests/fixtures/ldiff/genshin.json"));
+    }
+
+    #[test]
+    fn zzz_manifest() {
+        check_fixture(include_str!("../../tests/fixtures/ldiff/zzz.json"));
+    }
+
+    fn asset(chunk_file_name: &str, original_file_path: &str, original_file_size: i64, hdiff_file_size: i64) -> Asset {
+        Asset {
+            chunk_file_name: chunk_file_name.to_string(),
+            hdiff_file_size,
+            original_file_path: original_file_path.to_string(),
+            original_file_size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn patch_when_source_exists() {
+        assert_eq!(LdiffKind::of(&asset("a", "Data/a.pck", 100, 10), 120), LdiffKind::Patch);
+    }
+
+    #[test]
+    fn patch_when_source_is_empty_file() {
+        // Used to be classified as a new file because the source size is zero
+        assert_eq!(LdiffKind::of(&asset("a", "Data/a.pck", 0, 120), 120), LdiffKind::Patch);
+    }
+
+    #[test]
+    fn new_file_when_payload_is_whole_asset() {
+        assert_eq!(LdiffKind::of(&asset("a", "", 0, 120), 120), LdiffKind::NewFile);
+    }
+
+    #[test]
+    fn patch_from_empty_when_payload_differs_in_size() {
+        assert_eq!(LdiffKind::of(&asset("a", "", 0, 40), 120), LdiffKind::PatchFromEmpty);
+    }
+
+    #[test]
+    fn entries_only_from_given_chunks() {
+        let manifest = SophonManifestProto {
+            assets: vec![
+                AssetProperty {
+                    asset_name: "Data/a.pck".to_string(),
+                    asset_size: 120,
+                    asset_hash_md5: "abc".to_string(),
+                    asset_data: Some(AssetChunk {
+                        latest_asset_version: String::new(),
+                        assets: vec![asset("chunk_1", "Data/a.pck", 100, 10), asset("chunk_2", "", 0, 120)],
+                    }),
+                },
+                AssetProperty {
+                    asset_name: "Data/b.pck".to_string(),
+                    asset_size: 50,
+                    asset_hash_md5: String::new(),
+                    asset_data: None,
+                },
+            ],
+        };
+
+        let entries = ldiff_entries(&manifest, &["chunk_2".to_string()]);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].asset_name, "Data/a.pck");
+        assert_eq!(entries[0].kind, LdiffKind::NewFile);
+    }
+}
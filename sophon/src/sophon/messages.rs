@@ -0,0 +1,16 @@
+use std::sync::OnceLock;
+
+static HOOK: OnceLock<fn(String)> = OnceLock::new();
+
+/// Get the error lines workers print instead of them going straight to stderr, so they can be kept clear of
+/// progress bars
+pub fn set_message_hook(hook: fn(String)) {
+    let _ = HOOK.set(hook);
+}
+
+pub(crate) fn report_message(message: String) {
+    match HOOK.get() {
+        Some(hook) => hook(message),
+        None => eprintln!("{}", message),
+    }
+}
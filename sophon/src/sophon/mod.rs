@@ -1,5 +1,19 @@
 mod ldiff;
 mod chunk;
+mod output;
+mod dirs;
+mod handles;
+mod prefetch;
+mod progress;
+mod messages;
+mod dictionary;
 
 pub use ldiff::*;
 pub use chunk::*;
+pub use output::*;
+pub use dirs::*;
+pub use handles::*;
+pub use prefetch::*;
+pub use progress::*;
+pub use messages::*;
+pub use dictionary::*;
@@ -0,0 +1,309 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use crate::sophon::{create_parent_dirs, explain_handle_error, reserve_handles};
+
+/// Where assembled assets end up
+pub trait OutputTarget: Send + Sync {
+    /// Store the full contents of an asset
+    fn write_asset(&self, name: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Start an asset of `size` bytes whose chunks are written one by one at their offsets, in any order
+    ///
+    /// Unless implemented the chunks are gathered in memory and the whole asset is handed to `write_asset` at the end
+    fn create_asset(&self, name: &str, size: u64) -> io::Result<Box<dyn AssetWriter + '_>> {
+        Ok(Box::new(BufferedAsset { target: self, name: name.to_string(), buffer: Vec::with_capacity(size as usize) }))
+    }
+
+    /// Whether an asset is left as it is instead of assembled, e.g. as it is stored with its listed size and md5
+    /// already
+    fn keeps(&self, _name: &str, _size: u64, _md5: &str) -> bool {
+        false
+    }
+
+    /// Called once with every asset name before any is written
+    fn prepare(&self, _names: &[&str]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once every asset is written
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An asset being written chunk by chunk
+pub trait AssetWriter: Send {
+    /// Write a chunk at its offset in the asset
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Called once every chunk is written
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An asset gathered in memory for targets that only store whole assets
+struct BufferedAsset<'a, T: OutputTarget + ?Sized> {
+    target: &'a T,
+    name: String,
+    buffer: Vec<u8>,
+}
+
+impl<T: OutputTarget + ?Sized> AssetWriter for BufferedAsset<'_, T> {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let offset = offset as usize;
+        if self.buffer.len() < offset + data.len() {
+            self.buffer.resize(offset + data.len(), 0);
+        }
+        self.buffer[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.target.write_asset(&self.name, &self.buffer)
+    }
+}
+
+/// What happens to a file in the output folder whose contents differ from the asset assembled in its place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Existing {
+    /// Replace it with the asset
+    Overwrite,
+    /// Leave it as it is, the asset is not assembled
+    Skip,
+    /// Move it aside to `<name>.bak` before writing the asset
+    Backup,
+}
+
+impl FromStr for Existing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "overwrite" => Ok(Existing::Overwrite),
+            "skip" => Ok(Existing::Skip),
+            "backup" => Ok(Existing::Backup),
+            _ => Err(anyhow!("Unknown existing file policy {}, expected overwrite, skip or backup", s)),
+        }
+    }
+}
+
+/// Write every asset to its own file under a folder, the layout of an installed game
+///
+/// Files already there with the listed md5 are left alone, so running again only writes what is still missing
+pub struct DirectoryTarget {
+    root: PathBuf,
+    existing: Existing,
+    unchanged: Mutex<Vec<String>>,
+    kept: Mutex<Vec<String>>,
+    backed_up: Mutex<Vec<String>>,
+}
+
+impl DirectoryTarget {
+    pub fn new(root: &Path) -> DirectoryTarget {
+        DirectoryTarget {
+            root: root.to_path_buf(),
+            existing: Existing::Overwrite,
+            unchanged: Mutex::new(Vec::new()),
+            kept: Mutex::new(Vec::new()),
+            backed_up: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Set what happens to files whose contents differ from their asset, they are overwritten by default
+    pub fn with_existing(mut self, existing: Existing) -> DirectoryTarget {
+        self.existing = existing;
+        self
+    }
+
+    /// Assets that were in place with their listed md5 already
+    pub fn unchanged(&self) -> Vec<String> {
+        self.unchanged.lock().unwrap().clone()
+    }
+
+    /// Assets whose differing file was left as it is by `Existing::Skip`
+    pub fn kept(&self) -> Vec<String> {
+        self.kept.lock().unwrap().clone()
+    }
+
+    /// Assets whose differing file was moved aside by `Existing::Backup`
+    pub fn backed_up(&self) -> Vec<String> {
+        self.backed_up.lock().unwrap().clone()
+    }
+}
+
+/// Md5 of a file, streamed so large assets are never held in memory
+fn file_md5(path: &Path) -> io::Result<String> {
+    let _handles = reserve_handles(1);
+    let mut context = md5::Context::new();
+    io::copy(&mut File::open(path).map_err(explain_handle_error)?, &mut context)?;
+    Ok(format!("{:x}", context.compute()))
+}
+
+impl OutputTarget for DirectoryTarget {
+    fn keeps(&self, name: &str, size: u64, md5: &str) -> bool {
+        let path = self.root.join(name);
+        let Ok(metadata) = path.metadata() else {
+            return false;
+        };
+        let current = !md5.is_empty()
+            && metadata.len() == size
+            && file_md5(&path).is_ok_and(|found| found.eq_ignore_ascii_case(md5));
+        if current {
+            self.unchanged.lock().unwrap().push(name.to_string());
+            return true;
+        }
+        if self.existing == Existing::Skip && metadata.is_file() {
+            self.kept.lock().unwrap().push(name.to_string());
+            return true;
+        }
+        false
+    }
+
+    fn write_asset(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.make_room(name)?;
+        let _handles = reserve_handles(1);
+        let mut writer = BufWriter::with_capacity(
+            256 * 1024,
+            File::create(path).map_err(explain_handle_error)?,
+        );
+        writer.write_all(data)?;
+        writer.flush()
+    }
+
+    /// The file is created at its full size, chunks are written into it as they come
+    fn create_asset(&self, name: &str, size: u64) -> io::Result<Box<dyn AssetWriter + '_>> {
+        let path = self.make_room(name)?;
+        let _handles = reserve_handles(1);
+        File::create(&path).map_err(explain_handle_error)?.set_len(size)?;
+        Ok(Box::new(FileAsset { path }))
+    }
+
+    fn prepare(&self, names: &[&str]) -> io::Result<()> {
+        create_parent_dirs(&self.root, names)
+    }
+}
+
+impl DirectoryTarget {
+    /// Path of an asset, with a differing file there moved aside first by `Existing::Backup`
+    fn make_room(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.root.join(name);
+        if self.existing == Existing::Backup && path.is_file() {
+            let mut backup_name = path.file_name().unwrap_or_default().to_os_string();
+            backup_name.push(".bak");
+            fs::rename(&path, path.with_file_name(backup_name))?;
+            self.backed_up.lock().unwrap().push(name.to_string());
+        }
+        Ok(path)
+    }
+}
+
+/// An asset file written chunk by chunk
+///
+/// The file is opened for every chunk rather than held open, so workers reading chunks never wait on handles taken
+/// by assets still being written
+struct FileAsset {
+    path: PathBuf,
+}
+
+impl AssetWriter for FileAsset {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let _handles = reserve_handles(1);
+        let mut file = OpenOptions::new().write(true).open(&self.path).map_err(explain_handle_error)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+}
+
+/// Pack every asset into one image file or block device, listed in a JSON index
+///
+/// Assets start on 4 KiB boundaries so they line up with device blocks
+pub struct ImageTarget {
+    image: Mutex<(File, u64)>,
+    index_path: PathBuf,
+    index: Mutex<Vec<IndexEntry>>,
+}
+
+#[derive(Serialize)]
+struct IndexEntry {
+    name: String,
+    offset: u64,
+    size: u64,
+}
+
+const IMAGE_ALIGNMENT: u64 = 4096;
+
+impl ImageTarget {
+    /// Open the image for writing from its start, block devices are written in place rather than truncated
+    pub fn create(image_path: &Path, index_path: &Path) -> io::Result<ImageTarget> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(image_path)?;
+        Ok(ImageTarget {
+            image: Mutex::new((file, 0)),
+            index_path: index_path.to_path_buf(),
+            index: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl OutputTarget for ImageTarget {
+    fn write_asset(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mut image = self.image.lock().unwrap();
+        let (file, end) = &mut *image;
+        let offset = end.div_ceil(IMAGE_ALIGNMENT) * IMAGE_ALIGNMENT;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        *end = offset + data.len() as u64;
+
+        self.index.lock().unwrap().push(IndexEntry {
+            name: name.to_string(),
+            offset,
+            size: data.len() as u64,
+        });
+        Ok(())
+    }
+
+    /// The asset takes its place in the image right away, chunks are written into it as they come
+    fn create_asset(&self, name: &str, size: u64) -> io::Result<Box<dyn AssetWriter + '_>> {
+        let mut image = self.image.lock().unwrap();
+        let offset = image.1.div_ceil(IMAGE_ALIGNMENT) * IMAGE_ALIGNMENT;
+        image.1 = offset + size;
+        self.index.lock().unwrap().push(IndexEntry { name: name.to_string(), offset, size });
+        Ok(Box::new(ImageAsset { image: &self.image, start: offset }))
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut image = self.image.lock().unwrap();
+        let (file, end) = &mut *image;
+        file.flush()?;
+
+        // Regular files drop whatever an earlier, larger image left behind
+        if file.metadata()?.is_file() {
+            file.set_len(*end)?;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        index.sort_by_key(|entry| entry.offset);
+        let json = serde_json::to_vec_pretty(&*index).map_err(io::Error::other)?;
+        fs::write(&self.index_path, json)
+    }
+}
+
+/// An asset written chunk by chunk into its place in an image
+struct ImageAsset<'a> {
+    image: &'a Mutex<(File, u64)>,
+    start: u64,
+}
+
+impl AssetWriter for ImageAsset<'_> {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut image = self.image.lock().unwrap();
+        image.0.seek(SeekFrom::Start(self.start + offset))?;
+        image.0.write_all(data)
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use crate::sophon::reserve_handles;
+
+// Chunks read ahead of assembly, zero turns read-ahead off
+static PREFETCH_DEPTH: AtomicUsize = AtomicUsize::new(8);
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Set how many chunks are read ahead while assets are assembled
+pub fn set_prefetch_depth(depth: usize) {
+    PREFETCH_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// Chunks taken from the read-ahead queue and chunks read on demand so far
+pub fn prefetch_stats() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
+
+/// Reads upcoming chunks on a background thread while the current assets are assembled
+///
+/// Chunks are read in the order they are first used. Every chunk is kept until each asset using it has taken it,
+/// and at most the prefetch depth of chunks waits in the queue
+pub(crate) struct Prefetcher {
+    state: Mutex<State>,
+    taken: Condvar,
+    stopped: AtomicBool,
+}
+
+struct State {
+    ready: HashMap<String, Arc<Vec<u8>>>,
+    /// Uses left of every chunk, chunks are shared between assets
+    uses: HashMap<String, usize>,
+}
+
+impl Prefetcher {
+    /// Start reading the chunks of `order` from `folder`, nothing is read ahead when the depth is zero
+    pub fn start(folder: PathBuf, order: Vec<String>) -> Arc<Prefetcher> {
+        let mut uses: HashMap<String, usize> = HashMap::new();
+        for name in &order {
+            *uses.entry(name.clone()).or_default() += 1;
+        }
+        let prefetcher = Arc::new(Prefetcher {
+            state: Mutex::new(State { ready: HashMap::new(), uses }),
+            taken: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+
+        let depth = PREFETCH_DEPTH.load(Ordering::Relaxed);
+        if depth > 0 {
+            let reader = Arc::clone(&prefetcher);
+            thread::spawn(move || reader.read_ahead(&folder, order, depth));
+        }
+        prefetcher
+    }
+
+    fn read_ahead(&self, folder: &Path, order: Vec<String>, depth: usize) {
+        let mut seen = HashSet::new();
+        for name in order {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            // Wait for room in the queue
+            {
+                let mut state = self.state.lock().unwrap();
+                while state.ready.len() >= depth && !self.stopped.load(Ordering::Relaxed) {
+                    state = self.taken.wait(state).unwrap();
+                }
+                if self.stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                if state.uses.get(&name).is_none_or(|&uses| uses == 0) {
+                    continue;
+                }
+            }
+
+            let data = {
+                let _handles = reserve_handles(1);
+                match fs::read(folder.join(&name)) {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                }
+            };
+
+            // Assets may have read the chunk themselves in the meantime
+            let mut state = self.state.lock().unwrap();
+            if state.uses.get(&name).is_some_and(|&uses| uses > 0) {
+                state.ready.insert(name, Arc::new(data));
+            }
+        }
+    }
+
+    /// Take a chunk from the queue, `None` when it was not read ahead and has to be read by the caller
+    pub fn take(&self, name: &str) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(uses) = state.uses.get_mut(name) {
+            *uses = uses.saturating_sub(1);
+        }
+        let last_use = state.uses.get(name).is_none_or(|&uses| uses == 0);
+        let data = if last_use {
+            state.ready.remove(name)
+        } else {
+            state.ready.get(name).cloned()
+        };
+        drop(state);
+
+        match data {
+            Some(data) => {
+                HITS.fetch_add(1, Ordering::Relaxed);
+                self.taken.notify_all();
+                Some(data)
+            }
+            None => {
+                MISSES.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Give up one use of a chunk without taking it, for an asset that is not assembled after all
+    pub fn release(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(uses) = state.uses.get_mut(name) {
+            *uses = uses.saturating_sub(1);
+        }
+        if state.uses.get(name).is_none_or(|&uses| uses == 0) && state.ready.remove(name).is_some() {
+            drop(state);
+            self.taken.notify_all();
+        }
+    }
+
+    /// Stop reading ahead, chunks still queued are dropped with the prefetcher
+    pub fn stop(&self) {
+        // Taken under the lock so the reader cannot miss it between checking and waiting
+        let state = self.state.lock().unwrap();
+        self.stopped.store(true, Ordering::Relaxed);
+        drop(state);
+        self.taken.notify_all();
+    }
+}
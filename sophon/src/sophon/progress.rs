@@ -0,0 +1,35 @@
+/// Stage of the work reported to a progress sink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Chunks or payloads written to the temp folder, in decompressed bytes
+    Staging,
+    /// Assets assembled into the output, in asset bytes
+    Assembly,
+    /// Files fetched from the Sophon servers, in downloaded bytes
+    Download,
+    /// Local files searched for chunks a download would otherwise fetch, in files
+    Matching,
+}
+
+/// Receives the progress of the work done in this crate, e.g. to draw bars in a terminal or a launcher window
+///
+/// Every method does nothing unless implemented. Workers report from several threads at once
+pub trait ProgressSink: Send + Sync {
+    /// A stage starts going through `items` pieces of work, called again when more work is added to it
+    fn on_stage_start(&self, _phase: Phase, _items: u64) {}
+
+    /// One piece of work of a stage is done, e.g. a chunk staged or an asset assembled
+    fn on_item_done(&self, _phase: Phase) {}
+
+    /// How many bytes a finished piece of work took, for progress spanning several calls
+    fn on_bytes(&self, _phase: Phase, _bytes: u64) {}
+
+    /// Every piece of work of a stage is done
+    fn on_finish(&self, _phase: Phase) {}
+}
+
+/// Sink for callers that do not show progress
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {}